@@ -0,0 +1,87 @@
+// ALSA sequencer MIDI input
+//
+// The events are timestamped when they arrive and placed into the next jack
+// cycle at the same distance from the cycle start, so the timing between the
+// events is kept at the price of one period of latency.
+
+use std::ffi::CString;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use alsa::seq;
+use log::error;
+
+pub struct AlsaMidiEvent {
+    time: Instant,
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl AlsaMidiEvent {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    pub fn frame_in_cycle(&self, last_cycle_start: Instant, samplerate: usize, nsamples: usize) -> usize {
+        let elapsed = self.time.saturating_duration_since(last_cycle_start).as_secs_f64();
+        ((elapsed * samplerate as f64) as usize).min(nsamples.saturating_sub(1))
+    }
+}
+
+fn open_port(client_name: &str) -> Result<alsa::Seq, alsa::Error> {
+    let name = CString::new(client_name).unwrap_or_default();
+    let port_name = CString::new("MIDI input").unwrap();
+
+    let seq = alsa::Seq::open(None, Some(alsa::Direction::Capture), false)?;
+    seq.set_client_name(&name)?;
+    seq.create_simple_port(&port_name,
+                           seq::PortCap::WRITE | seq::PortCap::SUBS_WRITE,
+                           seq::PortType::MIDI_GENERIC | seq::PortType::APPLICATION)?;
+    Ok(seq)
+}
+
+/// Opens an ALSA sequencer port and forwards everything it receives until the
+/// receiving end is gone. Messages longer than three bytes like SysEx are dropped.
+pub fn spawn_input(client_name: &str, sender: mpsc::Sender<AlsaMidiEvent>) {
+    let client_name = client_name.to_string();
+    thread::spawn(move || {
+        let seq = match open_port(&client_name) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Could not open ALSA sequencer: {}", e);
+                return
+            }
+        };
+        let decoder = match seq::MidiEvent::new(256) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Could not create ALSA MIDI decoder: {}", e);
+                return
+            }
+        };
+        decoder.enable_running_status(false);
+
+        let mut input = seq.input();
+        let mut buf = [0u8; 256];
+        loop {
+            let mut event = match input.event_input() {
+                Ok(ev) => ev,
+                Err(e) => {
+                    error!("ALSA MIDI input failed: {}", e);
+                    return
+                }
+            };
+            let time = Instant::now();
+            let len = match decoder.decode(&mut buf, &mut event) {
+                Ok(n) if n > 0 && n <= 3 => n,
+                _ => continue
+            };
+            let mut bytes = [0u8; 3];
+            bytes[..len].copy_from_slice(&buf[..len]);
+            if sender.send(AlsaMidiEvent { time, bytes, len }).is_err() {
+                return
+            }
+        }
+    });
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+
+use soundfonts::midi_filter::VelocityCurve;
+
+const DEFAULT_CROSSFADE_TIME: f64 = 0.5;
+
+pub struct Options {
+    pub sfz_file: Option<String>,
+    pub gain_db: Option<f32>,
+    pub client_name: Option<String>,
+    pub auto_connect: bool,
+    pub max_voices: Option<usize>,
+    pub trim_silence: Option<f32>,
+    pub transpose: i32,
+    pub fold: Option<(u8, u8)>,
+    pub mpe: Option<u8>,
+    pub scala_file: Option<String>,
+    pub keyboard_map_file: Option<String>,
+    pub reference_pitch: Option<f64>,
+    pub limiter: bool,
+    pub crossfade: f64,
+    pub int16_samples: bool,
+    pub mapped_samples: bool,
+    pub memory_limit: Option<usize>,
+    pub purge_after: Option<f64>,
+    pub pitch_variants: Option<u32>,
+    pub gain_table: Option<u32>,
+    pub note_off_velocity: bool,
+    pub pressure_cc: Option<u8>,
+    pub cc_map: Vec<(u8, u8)>,
+    pub velocity_curve: VelocityCurve,
+    pub release_velocity_curve: VelocityCurve,
+    pub click_guard: f64,
+    pub watch: bool,
+    pub watch_samples: bool,
+    pub osc_port: Option<u16>,
+    pub list_regions: bool,
+    pub verbose: bool,
+    pub help: bool,
+}
+
+#[derive(Debug)]
+pub struct ArgumentError {
+    msg: String,
+}
+
+impl fmt::Display for ArgumentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+pub const USAGE: &str = "\
+Usage: sonarigo-jack [OPTIONS] [SFZ_FILE]
+
+Options:
+  -g, --gain <dB>           initial output gain in dB
+  -n, --client-name <name>  name of the jack client (default: Sonarigo)
+  -a, --auto-connect        connect to the physical audio outputs and MIDI inputs
+  -m, --max-voices <n>      maximum number of simultaneously playing voices
+  -t, --trim-silence <dB>   trim sample silence below the given level at load time
+  -T, --transpose <n>       transpose incoming notes by n semitones
+  -f, --fold <lo>-<hi>      fold notes outside the given MIDI note range into it
+  -M, --mpe <n>             MPE mode with n member channels in the lower zone
+  -s, --scala <file>        tune the notes by the given Scala .scl file
+  -k, --kbm <file>          map the keys to the scale by the given Scala .kbm file
+  -r, --ref-pitch <Hz>      frequency of A (note 69) in equal temperament
+  -V, --note-off-velocity   play release triggered regions with the note-off velocity
+  -p, --pressure-cc <n>     send channel pressure as controller n, for example 1
+  -c, --map-cc <from>:<to>  send controller from as controller to, can be repeated
+  -v, --velocity-curve <c>  velocity curve: linear, gamma:<g> playing softer above 1
+                            and louder below 1, fixed:<velocity> or
+                            table:<played>=<velocity>,... interpolated in between
+  -E, --release-curve <c>   curve of the note-off velocities used by -V, as above
+  -C, --click-guard <s>     fade voices in and out over the given time to avoid clicks
+  -L, --limiter             soft clip the output to prevent it from exceeding 0 dBFS
+  -x, --crossfade <s>       crossfade time when loading another SFZ file (default: 0.5)
+  -i, --int16-samples       keep the samples as 16 bit integers to halve the memory usage
+  -F, --mapped-samples      map uncompressed sample files into memory instead of loading them
+  -R, --memory-limit <MB>   refuse to load instruments whose samples need more memory
+  -u, --purge-after <min>   free the samples of regions not played for the given minutes,
+                            they are loaded again when played
+  -P, --pitch-variants <n>  prerender samples played more than n semitones off their
+                            keycenter at every semitone, saves CPU at the cost of memory
+  -G, --gain-table <n>      approximate the note gains by a lookup table with n steps
+                            per dB instead of computing them exactly, saves CPU
+  -w, --watch               reload the SFZ file when it is changed
+  -W, --watch-samples       reload the SFZ file also when its samples are changed
+  -O, --osc-port <port>     play /noteon, /noteoff and /cc OSC messages received on
+                            the UDP port (needs the feature osc-input)
+  -l, --list-regions        list the regions of SFZ_FILE and exit
+  -d, --verbose             log what is going on like the sample files loaded
+  -h, --help                show this help and exit";
+
+fn value_for<I: Iterator<Item = String>>(option: &str, args: &mut I) -> Result<String, ArgumentError> {
+    args.next().ok_or_else(|| ArgumentError { msg: format!("{}: missing value", option) })
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: String) -> Result<T, ArgumentError> {
+    value.parse().map_err(|_| ArgumentError { msg: format!("{}: invalid value {}", option, value) })
+}
+
+fn parse_controller(option: &str, value: &str) -> Result<u8, ArgumentError> {
+    match value.parse::<u8>() {
+        Ok(cc) if cc <= 127 => Ok(cc),
+        _ => Err(ArgumentError { msg: format!("{}: invalid controller {}", option, value) }),
+    }
+}
+
+fn parse_range(option: &str, value: String) -> Result<(u8, u8), ArgumentError> {
+    let invalid = || ArgumentError { msg: format!("{}: invalid note range {}", option, value) };
+    let n = value.find('-').ok_or_else(invalid)?;
+    let lo: u8 = value[..n].parse().map_err(|_| invalid())?;
+    let hi: u8 = value[n+1..].parse().map_err(|_| invalid())?;
+    if lo > hi || hi > 127 {
+        return Err(invalid());
+    }
+    Ok((lo, hi))
+}
+
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, ArgumentError> {
+    let mut options = Options {
+        sfz_file: None,
+        gain_db: None,
+        client_name: None,
+        auto_connect: false,
+        max_voices: None,
+        trim_silence: None,
+        transpose: 0,
+        fold: None,
+        mpe: None,
+        scala_file: None,
+        keyboard_map_file: None,
+        reference_pitch: None,
+        limiter: false,
+        crossfade: DEFAULT_CROSSFADE_TIME,
+        int16_samples: false,
+        mapped_samples: false,
+        memory_limit: None,
+        purge_after: None,
+        pitch_variants: None,
+        gain_table: None,
+        note_off_velocity: false,
+        pressure_cc: None,
+        cc_map: Vec::new(),
+        velocity_curve: VelocityCurve::Linear,
+        release_velocity_curve: VelocityCurve::Linear,
+        click_guard: 0.0,
+        watch: false,
+        watch_samples: false,
+        osc_port: None,
+        list_regions: false,
+        verbose: false,
+        help: false,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-g" | "--gain" => options.gain_db = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-n" | "--client-name" => options.client_name = Some(value_for(&arg, &mut args)?),
+            "-a" | "--auto-connect" => options.auto_connect = true,
+            "-m" | "--max-voices" => options.max_voices = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-t" | "--trim-silence" => options.trim_silence = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-T" | "--transpose" => options.transpose = parse_value(&arg, value_for(&arg, &mut args)?)?,
+            "-f" | "--fold" => options.fold = Some(parse_range(&arg, value_for(&arg, &mut args)?)?),
+            "-M" | "--mpe" => {
+                let members: u8 = parse_value(&arg, value_for(&arg, &mut args)?)?;
+                if members == 0 || members > 15 {
+                    return Err(ArgumentError { msg: format!("{}: member channels must be 1 to 15", arg) })
+                }
+                options.mpe = Some(members);
+            }
+            "-s" | "--scala" => options.scala_file = Some(value_for(&arg, &mut args)?),
+            "-k" | "--kbm" => options.keyboard_map_file = Some(value_for(&arg, &mut args)?),
+            "-r" | "--ref-pitch" => options.reference_pitch = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-C" | "--click-guard" => options.click_guard = parse_value(&arg, value_for(&arg, &mut args)?)?,
+            "-L" | "--limiter" => options.limiter = true,
+            "-x" | "--crossfade" => options.crossfade = parse_value(&arg, value_for(&arg, &mut args)?)?,
+            "-i" | "--int16-samples" => options.int16_samples = true,
+            "-F" | "--mapped-samples" => options.mapped_samples = true,
+            "-R" | "--memory-limit" => {
+                let megabytes: usize = parse_value(&arg, value_for(&arg, &mut args)?)?;
+                options.memory_limit = Some(megabytes << 20);
+            }
+            "-u" | "--purge-after" => options.purge_after = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-P" | "--pitch-variants" => options.pitch_variants = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-G" | "--gain-table" => options.gain_table = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-V" | "--note-off-velocity" => options.note_off_velocity = true,
+            "-p" | "--pressure-cc" => options.pressure_cc = Some(parse_controller(&arg, &value_for(&arg, &mut args)?)?),
+            "-c" | "--map-cc" => {
+                let value = value_for(&arg, &mut args)?;
+                let n = value.find(':').ok_or_else(|| ArgumentError { msg: format!("{}: expected <from>:<to>, got {}", arg, value) })?;
+                options.cc_map.push((parse_controller(&arg, &value[..n])?, parse_controller(&arg, &value[n+1..])?));
+            }
+            "-v" | "--velocity-curve" => {
+                options.velocity_curve = value_for(&arg, &mut args)?.parse()
+                    .map_err(|e| ArgumentError { msg: format!("{}: {}", arg, e) })?;
+            }
+            "-E" | "--release-curve" => {
+                options.release_velocity_curve = value_for(&arg, &mut args)?.parse()
+                    .map_err(|e| ArgumentError { msg: format!("{}: {}", arg, e) })?;
+            }
+            "-w" | "--watch" => options.watch = true,
+            "-W" | "--watch-samples" => {
+                options.watch = true;
+                options.watch_samples = true;
+            }
+            "-O" | "--osc-port" => options.osc_port = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-l" | "--list-regions" => options.list_regions = true,
+            "-d" | "--verbose" => options.verbose = true,
+            "-h" | "--help" => options.help = true,
+            a if a.starts_with('-') => {
+                return Err(ArgumentError { msg: format!("Unknown option: {}", a) })
+            }
+            _ => {
+                if options.sfz_file.is_some() {
+                    return Err(ArgumentError { msg: "Only one SFZ file can be given".to_string() })
+                }
+                options.sfz_file = Some(arg);
+            }
+        }
+    }
+
+    if options.keyboard_map_file.is_some() && options.scala_file.is_none() {
+        return Err(ArgumentError { msg: "--kbm needs --scala".to_string() })
+    }
+
+    if options.list_regions && options.sfz_file.is_none() {
+        return Err(ArgumentError { msg: "--list-regions needs an SFZ file".to_string() })
+    }
+
+    Ok(options)
+}
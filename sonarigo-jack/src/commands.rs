@@ -0,0 +1,100 @@
+use std::fmt;
+
+pub enum UserCommand {
+    Load(String),
+    Gain(f32),
+    Panic,
+    Record(String, bool),
+    Stop,
+    Log,
+    Regions,
+    Mute(usize, bool),
+    /// `None` ends the solo
+    Solo(Option<usize>),
+    #[cfg(feature = "profiling")]
+    Stats,
+    Quit,
+    Help,
+}
+
+#[derive(Debug)]
+pub struct CommandError {
+    msg: String,
+}
+
+impl CommandError {
+    fn new(msg: &str) -> CommandError {
+        CommandError { msg: msg.to_string() }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+pub const HELP: &str = "\
+Commands:
+  load <path>   load the sfz file at <path>
+  gain <dB>     set the output gain in dB
+  panic         release all sounding notes
+  record <path> record the output into a dithered 16 bit WAV file at <path>
+  record-float <path>
+                record the output into a 32 bit float WAV file at <path>
+  stop          stop recording
+  log           show recent MIDI events and the regions they triggered or choked
+  regions       list the regions, the muted ones marked by M, the soloed one by S
+  mute <n>      keep region <n> from playing notes
+  unmute <n>    let region <n> play notes again
+  solo [<n>]    mute all regions but region <n>, end the solo without <n>
+  quit          quit sonarigo
+  help          show this help";
+
+#[cfg(feature = "profiling")]
+pub const PROFILING_HELP: &str = "\
+  stats         show the processing time per subsystem in folded stack format and reset it";
+
+pub fn parse_command(line: &str) -> Result<UserCommand, CommandError> {
+    let line = line.trim();
+    let (cmd, arg) = match line.find(char::is_whitespace) {
+        Some(n) => (&line[..n], line[n..].trim()),
+        None => (line, ""),
+    };
+
+    match cmd {
+        "load" => {
+            if arg.is_empty() {
+                Err(CommandError::new("load: missing path"))
+            } else {
+                Ok(UserCommand::Load(arg.to_string()))
+            }
+        }
+        "gain" => arg.parse::<f32>()
+            .map(UserCommand::Gain)
+            .map_err(|_| CommandError::new("gain: expecting a value in dB")),
+        "panic" => Ok(UserCommand::Panic),
+        "record" | "record-float" => {
+            if arg.is_empty() {
+                Err(CommandError::new(&format!("{}: missing path", cmd)))
+            } else {
+                Ok(UserCommand::Record(arg.to_string(), cmd == "record-float"))
+            }
+        }
+        "stop" => Ok(UserCommand::Stop),
+        "log" => Ok(UserCommand::Log),
+        "regions" => Ok(UserCommand::Regions),
+        "mute" | "unmute" => arg.parse::<usize>()
+            .map(|region| UserCommand::Mute(region, cmd == "mute"))
+            .map_err(|_| CommandError::new(&format!("{}: expecting a region number", cmd))),
+        "solo" if arg.is_empty() => Ok(UserCommand::Solo(None)),
+        "solo" => arg.parse::<usize>()
+            .map(|region| UserCommand::Solo(Some(region)))
+            .map_err(|_| CommandError::new("solo: expecting a region number")),
+        #[cfg(feature = "profiling")]
+        "stats" => Ok(UserCommand::Stats),
+        "quit" | "exit" => Ok(UserCommand::Quit),
+        "help" => Ok(UserCommand::Help),
+        c => Err(CommandError::new(&format!("Unknown command: {}", c))),
+    }
+}
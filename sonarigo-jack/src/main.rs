@@ -1,42 +1,304 @@
-
 use std::env;
-use std::io::Write;
 use std::convert::TryFrom;
 use std::io;
+use std::process;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
 extern crate jack;
 extern crate wmidi;
 
+use log::{error, info, warn};
+
 use soundfonts::engine::EngineTrait;
+use soundfonts::midi_filter::MidiFilter;
+use soundfonts::render::{Recorder, SampleFormat};
+use soundfonts::sfz::activity_log::ActivityLog;
+use soundfonts::sfz::diagnostics::{Diagnostic, Diagnostics, SharedDiagnostics};
 use soundfonts::sfz::engine;
 
+#[cfg(feature = "alsa-midi")]
+mod alsa_midi;
+mod cli;
+mod commands;
+mod nsm;
+mod osc;
+#[cfg(feature = "osc-input")]
+mod osc_input;
+mod watch;
+
+use commands::UserCommand;
+use nsm::{NsmClient, NsmEvent, SessionState};
+
+const ACTIVITY_LOG_LENGTH: usize = 64;
+const DIAGNOSTICS_LENGTH: usize = 256;
+#[cfg(feature = "osc-input")]
+const OSC_QUEUE_LENGTH: usize = 256;
+
+enum EngineCommand {
+    NewEngine(Box<engine::Engine>),
+    /// master gain in dB
+    Gain(f32),
+    Panic,
+    Record(Option<Recorder>),
+    Purged(engine::PurgedInstrument),
+    ReloadedSamples(engine::DeferredSamples),
+}
+
+enum ControlMessage {
+    User(UserCommand),
+    Nsm(NsmEvent),
+    /// the loaded SFZ file has been changed
+    Reload,
+    Purge(engine::PurgeRequest),
+    ReloadSamples(engine::ReloadRequest),
+    /// the instrument replaced by a purge, to be dropped outside the audio thread
+    PurgedInstrument(Arc<engine::InstrumentData>),
+    /// the engine replaced by a newly loaded one, to be dropped outside the
+    /// audio thread
+    ReplacedEngine(Box<engine::Engine>),
+    StdinClosed,
+}
+
+fn load_engine(filename: &str, samplerate: usize, max_block_length: u32, config: &engine::EngineConfig) -> Option<engine::Engine> {
+    match engine::Engine::new_with_config(filename.to_string(), samplerate as f64, max_block_length as usize, config) {
+        Err(e) => {
+            error!("Could not launch SFZ engine: {}", e);
+            None
+        }
+        Ok(e) => {
+            let stats = e.load_stats();
+            info!("decoded {} sample files with {} threads in {:.2} s, {:.0} frames/s",
+                  stats.files, stats.threads, stats.decode_time.as_secs_f64(), stats.frames_per_second());
+            for warning in e.warnings() {
+                warn!("skipped {}", warning);
+            }
+            for trimmed in e.trimmed_silence() {
+                info!("region {}: trimmed {} leading and {} trailing frames of silence",
+                      trimmed.region, trimmed.leading, trimmed.trailing);
+            }
+            Some(e)
+        }
+    }
+}
+
+// runs on the audio thread, so invalid events are only reported to the
+// diagnostics
+fn dispatch_midi(engine: &mut engine::Engine, diagnostics: &Diagnostics, frame: usize, bytes: &[u8]) {
+    match wmidi::MidiMessage::try_from(bytes) {
+        Ok(midi_msg) => engine.midi_event_at(frame, &midi_msg),
+        Err(_) => diagnostics.push(Diagnostic::InvalidMidiEvent),
+    }
+}
+
+fn apply_options(engine: &mut engine::Engine, options: &cli::Options) {
+    engine.set_max_voices(options.max_voices);
+    engine.set_transpose(options.transpose);
+    engine.set_note_off_velocity(options.note_off_velocity);
+    engine.set_click_guard(options.click_guard);
+    let fold = options.fold.and_then(|(lo, hi)| {
+        Some((wmidi::Note::try_from(lo).ok()?, wmidi::Note::try_from(hi).ok()?))
+    });
+    engine.set_note_fold(fold);
+    engine.set_mpe(options.mpe.map(|members| engine::MpeConfig {
+        lower_zone_members: members,
+        ..Default::default()
+    }));
+
+    let tuning = match &options.scala_file {
+        Some(scl) => soundfonts::tuning::Tuning::from_scala_files(scl, options.keyboard_map_file.as_deref()),
+        None => Ok(soundfonts::tuning::Tuning::default()),
+    };
+    match tuning {
+        Ok(mut tuning) => {
+            if let Some(hz) = options.reference_pitch {
+                tuning.set_reference_pitch(hz);
+            }
+            engine.set_tuning(&tuning);
+        }
+        Err(e) => error!("Could not load tuning: {}", e),
+    }
+}
+
+fn print_help() {
+    println!("{}", commands::HELP);
+    #[cfg(feature = "profiling")]
+    println!("{}", commands::PROFILING_HELP);
+}
+
+// prints what the engines report to their diagnostics once a second
+fn print_diagnostics(diagnostics: SharedDiagnostics) {
+    thread::spawn(move || {
+        let mut lost = 0;
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            for diagnostic in diagnostics.drain() {
+                eprintln!("{}", diagnostic);
+            }
+            if diagnostics.lost() > lost {
+                eprintln!("{} diagnostics lost", diagnostics.lost() - lost);
+                lost = diagnostics.lost();
+            }
+        }
+    });
+}
+
+fn absolute_path(path: &str) -> String {
+    std::fs::canonicalize(path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+// the regions of the engine with their ids
+fn describe_regions(engine: &engine::Engine) -> Vec<String> {
+    engine.region_data()
+        .enumerate()
+        .map(|(i, rd)| format!("[{}] {}", engine.region_id(i).unwrap(), rd))
+        .collect()
+}
+
+fn list_regions(filename: &str, config: &engine::EngineConfig) {
+    let engine = match load_engine(filename, 48000, 1024, config) {
+        Some(e) => e,
+        None => return
+    };
+    for (i, region) in describe_regions(&engine).iter().enumerate() {
+        println!("{}: {}", i, region);
+    }
+}
+
+fn connect_physical_ports(client: &jack::Client, midi_in: &str, out_left: &str, out_right: &str) {
+    let midi_sources = client.ports(None, Some("8 bit raw midi"), jack::PortFlags::IS_OUTPUT | jack::PortFlags::IS_PHYSICAL);
+    for source in midi_sources {
+        if let Err(e) = client.connect_ports_by_name(&source, midi_in) {
+            error!("Could not connect {}: {:?}", source, e);
+        }
+    }
+
+    let playback = client.ports(None, Some("32 bit float mono audio"), jack::PortFlags::IS_INPUT | jack::PortFlags::IS_PHYSICAL);
+    for (port, dest) in Iterator::zip([out_left, out_right].iter(), playback.iter()) {
+        if let Err(e) = client.connect_ports_by_name(port, dest) {
+            error!("Could not connect {}: {:?}", dest, e);
+        }
+    }
+}
+
 fn main() {
-    let (client, _status) = match jack::Client::new("Sonarigo", jack::ClientOptions::NO_START_SERVER) {
+    let options = match cli::parse_args(env::args().skip(1)) {
+        Ok(o) => o,
         Err(e) => {
-            println!("Failed to connecect to jack server: {:?}:", e);
+            println!("{}\n\n{}", e, cli::USAGE);
             return
         }
-        Ok(cs) => cs
     };
 
-    let samplerate = client.sample_rate();
-    let max_block_length = client.buffer_size();
-    println!("Samplerate: {}; maximum buffer size: {}", samplerate, max_block_length);
+    if options.help {
+        println!("{}", cli::USAGE);
+        return
+    }
+
+    soundfonts::logging::init(options.verbose);
+
+    let engine_config = engine::EngineConfig {
+        trim_silence_threshold: options.trim_silence,
+        strict: false,
+        load_threads: None,
+        sample_storage: if options.mapped_samples {
+            engine::SampleStorage::Mapped
+        } else if options.int16_samples {
+            engine::SampleStorage::Int16
+        } else {
+            engine::SampleStorage::Float32
+        },
+        memory_limit: options.memory_limit,
+        pitch_variants: options.pitch_variants,
+        random_seed: None,
+        midi_filter: MidiFilter {
+            channel_pressure_cc: options.pressure_cc,
+            cc_map: options.cc_map.clone(),
+            velocity_curve: options.velocity_curve.clone(),
+            release_velocity_curve: options.release_velocity_curve.clone(),
+        },
+        gain_table: options.gain_table,
+        load_tiers: None,
+    };
+
+    if options.list_regions {
+        if let Some(filename) = &options.sfz_file {
+            list_regions(filename, &engine_config);
+        }
+        return
+    }
+
+    let nsm_client = match NsmClient::from_env() {
+        Some(Ok(c)) => Some(c),
+        Some(Err(e)) => {
+            error!("Could not connect to session manager: {}", e);
+            None
+        }
+        None => None
+    };
 
-    let args: Vec<String> = env::args().collect();
-    let filename = &args[1];
+    let mut session = match &nsm_client {
+        Some(nsm) => match nsm.announce() {
+            Ok(session) => Some(session),
+            Err(e) => {
+                error!("{}", e);
+                return
+            }
+        },
+        None => None
+    };
+
+    let client_name = match &session {
+        Some(session) => session.client_id.as_str(),
+        None => options.client_name.as_deref().unwrap_or("Sonarigo")
+    };
 
-    let mut engine = match engine::Engine::new(filename.to_string(), samplerate as f64, max_block_length as usize) {
+    let (client, _status) = match jack::Client::new(client_name, jack::ClientOptions::NO_START_SERVER) {
         Err(e) => {
-            println!("Could not launch SFZ engine: {:?}", e);
+            error!("Failed to connecect to jack server: {:?}:", e);
             return
         }
-        Ok(e) => e
+        Ok(cs) => cs
     };
 
+    let samplerate = client.sample_rate();
+    let max_block_length = client.buffer_size();
+    info!("Samplerate: {}; maximum buffer size: {}", samplerate, max_block_length);
+
+    let mut state = match &session {
+        Some(session) => SessionState::load(session).unwrap_or(SessionState { sfz_file: None, gain_db: 0.0 }),
+        None => SessionState {
+            sfz_file: options.sfz_file.as_ref().map(|f| absolute_path(f)),
+            gain_db: options.gain_db.unwrap_or(0.0)
+        }
+    };
+
+    // boxed like the engines loaded later, so that replacing it on the audio
+    // thread only moves the box
+    let mut engine = Box::new(match &state.sfz_file {
+        Some(filename) => match load_engine(filename, samplerate, max_block_length, &engine_config) {
+            Some(e) => e,
+            None if session.is_none() => process::exit(1),
+            None => engine::Engine::dummy(samplerate as f64, max_block_length as usize)
+        },
+        None => engine::Engine::dummy(samplerate as f64, max_block_length as usize)
+    });
+
+    let activity_log = ActivityLog::shared(ACTIVITY_LOG_LENGTH);
+    engine.set_activity_log(Some(activity_log.clone()));
+    let diagnostics = Diagnostics::shared(DIAGNOSTICS_LENGTH);
+    engine.set_diagnostics(Some(diagnostics.clone()));
+    apply_options(&mut engine, &options);
+    engine.set_master_gain(state.gain_db);
+    let mut region_mutes = engine.region_mutes();
+    let mut regions = describe_regions(&engine);
+
     let midi_in = match client.register_port("MIDI input", jack::MidiIn::default()) {
         Err(e) => {
-            println!("MIDI input port registration failed: {:?}:", e);
+            error!("MIDI input port registration failed: {:?}:", e);
             return
         }
         Ok(p) => p
@@ -44,7 +306,7 @@ fn main() {
 
     let mut out_left = match client.register_port("out left", jack::AudioOut::default()) {
         Err(e) => {
-            println!("Audio output port registration failed: {:?}:", e);
+            error!("Audio output port registration failed: {:?}:", e);
             return
         }
         Ok(p) => p
@@ -52,44 +314,406 @@ fn main() {
 
     let mut out_right = match client.register_port("out right", jack::AudioOut::default()) {
         Err(e) => {
-            println!("Audio output port registration failed: {:?}:", e);
+            error!("Audio output port registration failed: {:?}:", e);
             return
         }
         Ok(p) => p
     };
 
-    let callback = move |_: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
-        for e in midi_in.iter(ps) {
-            let midi_msg = match wmidi::MidiMessage::try_from(e.bytes) {
-                Ok(m) => m,
-                Err(e) => {
-                    println!("midi event conversion failed: {:?}", e);
-                    continue
+    let port_names = [
+        format!("{}:MIDI input", client.name()),
+        format!("{}:out left", client.name()),
+        format!("{}:out right", client.name()),
+    ];
+
+    let (command_sender, command_receiver) = mpsc::channel();
+    let (control_sender, control_receiver) = mpsc::channel();
+
+    let mut new_engine: Option<Box<engine::Engine>> = None;
+    let mut gain_db = state.gain_db;
+    let limiter = options.limiter;
+    let crossfade = options.crossfade;
+    let purge_after = options.purge_after.map(|minutes| minutes * 60.0);
+    let purge_sender = control_sender.clone();
+    // the idle regions are purged once a second
+    let mut purge_countdown = samplerate;
+
+    #[cfg(feature = "alsa-midi")]
+    let (alsa_receiver, mut last_cycle_start) = {
+        let (alsa_sender, alsa_receiver) = mpsc::channel();
+        alsa_midi::spawn_input(client_name, alsa_sender);
+        (alsa_receiver, std::time::Instant::now())
+    };
+
+    #[cfg(feature = "osc-input")]
+    let mut osc_consumer = options.osc_port.and_then(|port| {
+        let (producer, consumer) = soundfonts::engine::midi_ring(OSC_QUEUE_LENGTH);
+        match osc_input::spawn_input(port, producer) {
+            Ok(()) => Some(consumer),
+            Err(e) => {
+                error!("Could not open OSC port {}: {}", port, e);
+                None
+            }
+        }
+    });
+    #[cfg(not(feature = "osc-input"))]
+    if options.osc_port.is_some() {
+        error!("OSC input needs sonarigo-jack to be built with --features osc-input");
+    }
+
+    let midi_diagnostics = diagnostics.clone();
+    let callback = move |client: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
+        while let Ok(command) = command_receiver.try_recv() {
+            match command {
+                EngineCommand::NewEngine(mut e) => {
+                    e.set_master_gain(gain_db);
+                    engine.crossfade_out(crossfade);
+                    e.set_recorder(engine.take_recorder());
+                    if let Some(pending) = new_engine.replace(e) {
+                        purge_sender.send(ControlMessage::ReplacedEngine(pending)).ok();
+                    }
+                }
+                EngineCommand::Record(r) => match &mut new_engine {
+                    Some(e) => e.set_recorder(r),
+                    None => engine.set_recorder(r),
+                }
+                EngineCommand::Gain(g) => {
+                    gain_db = g;
+                    engine.set_master_gain(g);
+                    if let Some(e) = &mut new_engine {
+                        e.set_master_gain(g);
+                    }
+                }
+                EngineCommand::Panic => {
+                    engine.fadeout();
+                    if let Some(e) = &mut new_engine {
+                        e.fadeout();
+                    }
+                }
+                EngineCommand::Purged(purged) => {
+                    let target: &mut engine::Engine = match &mut new_engine {
+                        Some(e) => e,
+                        None => &mut engine,
+                    };
+                    if let Some(instrument) = target.complete_purge(purged) {
+                        purge_sender.send(ControlMessage::PurgedInstrument(instrument)).ok();
+                    }
+                }
+                EngineCommand::ReloadedSamples(samples) => {
+                    let target: &mut engine::Engine = match &mut new_engine {
+                        Some(e) => e,
+                        None => &mut engine,
+                    };
+                    target.complete_loading(samples);
                 }
-            };
-            println!("{:?}", midi_msg);
-            engine.midi_event(&midi_msg);
-            io::stdout().flush();
+            }
         }
 
         let left = out_left.as_mut_slice(ps);
         let right = out_right.as_mut_slice(ps);
-        engine.process(left, right);
+
+        for (l, r) in Iterator::zip(left.iter_mut(), right.iter_mut()) {
+            *l = 0.0;
+            *r = 0.0;
+        }
+
+        let active_engine: &mut engine::Engine = if let Some(ne) = &mut new_engine {
+            if engine.fadeout_finished() {
+                let replaced = std::mem::replace(&mut engine, new_engine.take().unwrap());
+                purge_sender.send(ControlMessage::ReplacedEngine(replaced)).ok();
+                &mut engine
+            } else {
+                engine.process(left, right);
+                ne
+            }
+        } else {
+            &mut engine
+        };
+
+        if let Some(bbt) = client.transport().query().ok().and_then(|transport| transport.pos.bbt()) {
+            active_engine.set_tempo(bbt.bpm);
+        }
+
+        for e in midi_in.iter(ps) {
+            dispatch_midi(active_engine, &midi_diagnostics, e.time as usize, e.bytes);
+        }
+
+        #[cfg(feature = "alsa-midi")]
+        {
+            let cycle_start = std::time::Instant::now();
+            while let Ok(ev) = alsa_receiver.try_recv() {
+                let frame = ev.frame_in_cycle(last_cycle_start, samplerate, left.len());
+                dispatch_midi(active_engine, &midi_diagnostics, frame, ev.bytes());
+            }
+            last_cycle_start = cycle_start;
+        }
+
+        #[cfg(feature = "osc-input")]
+        if let Some(consumer) = &mut osc_consumer {
+            consumer.drain_into(active_engine);
+        }
+
+        active_engine.process(left, right);
+
+        if let Some(idle) = purge_after {
+            purge_countdown = purge_countdown.saturating_sub(left.len());
+            if purge_countdown == 0 {
+                purge_countdown = samplerate;
+                if let Some(request) = active_engine.purge(idle) {
+                    purge_sender.send(ControlMessage::Purge(request)).ok();
+                }
+            }
+            if let Some(request) = active_engine.reload_request() {
+                purge_sender.send(ControlMessage::ReloadSamples(request)).ok();
+            }
+        }
+
+        if limiter {
+            soundfonts::utils::soft_clip(left);
+            soundfonts::utils::soft_clip(right);
+        }
 
         jack::Control::Continue
     };
 
     let active_client = match client.activate_async((), jack::ClosureProcessHandler::new(callback)) {
         Err(e) => {
-            println!("Could not activate client: {:?}", e);
+            error!("Could not activate client: {:?}", e);
             return
         }
         Ok(a) => a,
     };
 
-    println!("Press any key to quit");
-    let mut user_input = String::new();
-    io::stdin().read_line(&mut user_input).ok();
+    if options.auto_connect {
+        connect_physical_ports(active_client.as_client(), &port_names[0], &port_names[1], &port_names[2]);
+    }
+
+    if let Some(nsm) = &nsm_client {
+        nsm.reply("/nsm/client/open", "Ready").ok();
+    }
+
+    print_diagnostics(diagnostics.clone());
+
+    let stdin_sender = control_sender.clone();
+    thread::spawn(move || {
+        loop {
+            let mut user_input = String::new();
+            match io::stdin().read_line(&mut user_input) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if user_input.trim().is_empty() {
+                continue;
+            }
+            match commands::parse_command(&user_input) {
+                Ok(c) => if stdin_sender.send(ControlMessage::User(c)).is_err() {
+                    return
+                },
+                Err(e) => println!("{}", e)
+            }
+        }
+        stdin_sender.send(ControlMessage::StdinClosed).ok();
+    });
+
+    let watch_sender = if options.watch {
+        let reload_sender = control_sender.clone();
+        let watch_sender = watch::spawn(options.watch_samples, move || reload_sender.send(ControlMessage::Reload).is_ok());
+        if let Some(filename) = &state.sfz_file {
+            watch_sender.send(filename.clone()).ok();
+        }
+        Some(watch_sender)
+    } else {
+        None
+    };
+
+    if let Some(nsm) = &nsm_client {
+        let nsm = match nsm.try_clone() {
+            Ok(n) => n,
+            Err(e) => {
+                error!("Could not listen to session manager: {}", e);
+                return
+            }
+        };
+        let nsm_sender = control_sender.clone();
+        thread::spawn(move || {
+            while let Ok(event) = nsm.next_event() {
+                if nsm_sender.send(ControlMessage::Nsm(event)).is_err() {
+                    break;
+                }
+            }
+        });
+    } else {
+        print_help();
+    }
+
+    let mut open_reply_pending = false;
+    while let Ok(message) = control_receiver.recv() {
+        let command = match message {
+            ControlMessage::User(c) => c,
+            ControlMessage::Nsm(NsmEvent::Save) => {
+                if let (Some(nsm), Some(session)) = (&nsm_client, &session) {
+                    match state.save(session) {
+                        Ok(()) => nsm.reply("/nsm/client/save", "Saved"),
+                        Err(e) => nsm.error("/nsm/client/save", &e.to_string()),
+                    }.ok();
+                }
+                continue
+            }
+            ControlMessage::Nsm(NsmEvent::Open(new_session)) => {
+                let new_state = SessionState::load(&new_session).unwrap_or(SessionState { sfz_file: None, gain_db: 0.0 });
+                session = Some(new_session);
+                state.gain_db = new_state.gain_db;
+                command_sender.send(EngineCommand::Gain(state.gain_db)).ok();
+                match new_state.sfz_file {
+                    // the open request is answered once the instrument is loaded
+                    Some(filename) => {
+                        open_reply_pending = true;
+                        UserCommand::Load(filename)
+                    }
+                    None => {
+                        let mut e = engine::Engine::dummy(samplerate as f64, max_block_length as usize);
+                        e.set_activity_log(Some(activity_log.clone()));
+                        e.set_diagnostics(Some(diagnostics.clone()));
+                        region_mutes = e.region_mutes();
+                        regions = describe_regions(&e);
+                        state.sfz_file = None;
+                        command_sender.send(EngineCommand::NewEngine(Box::new(e))).ok();
+                        if let Some(nsm) = &nsm_client {
+                            nsm.reply("/nsm/client/open", "Ready").ok();
+                        }
+                        continue
+                    }
+                }
+            }
+            ControlMessage::Reload => match &state.sfz_file {
+                Some(filename) => {
+                    println!("{} changed, reloading", filename);
+                    UserCommand::Load(filename.clone())
+                }
+                None => continue
+            },
+            ControlMessage::Purge(request) => {
+                command_sender.send(EngineCommand::Purged(request.release())).ok();
+                continue
+            }
+            ControlMessage::ReloadSamples(request) => {
+                match request.load() {
+                    Ok(samples) => {
+                        command_sender.send(EngineCommand::ReloadedSamples(samples)).ok();
+                    }
+                    Err(e) => error!("Could not reload samples: {}", e),
+                }
+                continue
+            }
+            ControlMessage::PurgedInstrument(instrument) => {
+                drop(instrument);
+                continue
+            }
+            ControlMessage::ReplacedEngine(replaced) => {
+                drop(replaced);
+                continue
+            }
+            ControlMessage::StdinClosed => {
+                if nsm_client.is_some() {
+                    continue
+                }
+                break
+            }
+        };
+
+        let engine_command = match command {
+            UserCommand::Load(path) => match load_engine(&path, samplerate, max_block_length, &engine_config) {
+                Some(mut e) => {
+                    if open_reply_pending {
+                        if let Some(nsm) = &nsm_client {
+                            nsm.reply("/nsm/client/open", "Ready").ok();
+                        }
+                        open_reply_pending = false;
+                    }
+                    e.set_activity_log(Some(activity_log.clone()));
+                    e.set_diagnostics(Some(diagnostics.clone()));
+                    apply_options(&mut e, &options);
+                    region_mutes = e.region_mutes();
+                    regions = describe_regions(&e);
+                    state.sfz_file = Some(absolute_path(&path));
+                    if let (Some(sender), Some(filename)) = (&watch_sender, &state.sfz_file) {
+                        sender.send(filename.clone()).ok();
+                    }
+                    EngineCommand::NewEngine(Box::new(e))
+                }
+                None => {
+                    if open_reply_pending {
+                        if let Some(nsm) = &nsm_client {
+                            nsm.error("/nsm/client/open", &format!("Could not load {}", path)).ok();
+                        }
+                        open_reply_pending = false;
+                    }
+                    continue
+                }
+            },
+            UserCommand::Gain(g) => {
+                state.gain_db = g;
+                EngineCommand::Gain(g)
+            }
+            UserCommand::Panic => EngineCommand::Panic,
+            UserCommand::Record(path, float) => {
+                let format = if float { SampleFormat::Float } else { SampleFormat::Pcm16 };
+                match Recorder::new(&path, samplerate as usize, format) {
+                    Ok(r) => EngineCommand::Record(Some(r)),
+                    Err(e) => {
+                        error!("Could not record to {}: {:?}", path, e);
+                        continue
+                    }
+                }
+            }
+            UserCommand::Stop => EngineCommand::Record(None),
+            UserCommand::Log => {
+                for entry in activity_log.lock().unwrap().entries() {
+                    println!("{}", entry);
+                }
+                continue
+            }
+            UserCommand::Regions => {
+                for (i, region) in regions.iter().enumerate() {
+                    let muted = if region_mutes.is_muted(i) { "M" } else { " " };
+                    let soloed = if region_mutes.soloed() == Some(i) { "S" } else { " " };
+                    println!("{}{} {}: {}", muted, soloed, i, region);
+                }
+                continue
+            }
+            UserCommand::Mute(region, muted) => {
+                if region < regions.len() {
+                    region_mutes.set_muted(region, muted);
+                } else {
+                    println!("There is no region {}", region);
+                }
+                continue
+            }
+            UserCommand::Solo(region) => {
+                match region {
+                    Some(r) if r >= regions.len() => println!("There is no region {}", r),
+                    _ => region_mutes.solo(region),
+                }
+                continue
+            }
+            #[cfg(feature = "profiling")]
+            UserCommand::Stats => {
+                print!("{}", soundfonts::profiling::breakdown().folded());
+                soundfonts::profiling::reset();
+                continue
+            }
+            UserCommand::Help => {
+                print_help();
+                continue
+            }
+            UserCommand::Quit => break,
+        };
+
+        if command_sender.send(engine_command).is_err() {
+            error!("Audio thread is not running anymore");
+            break;
+        }
+    }
 
     active_client.deactivate().unwrap();
 }
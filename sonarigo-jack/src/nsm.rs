@@ -0,0 +1,188 @@
+// Non Session Manager client
+//
+// See http://non.tuxfamily.org/nsm/API.html for the protocol.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+
+use crate::osc::{OscArg, OscMessage};
+
+const APP_NAME: &str = "Sonarigo";
+const CAPABILITIES: &str = ":";
+const API_VERSION_MAJOR: i32 = 1;
+const API_VERSION_MINOR: i32 = 2;
+
+#[derive(Debug)]
+pub enum NsmError {
+    IOError(io::Error),
+    InvalidUrl(String),
+    Refused(String),
+}
+
+impl fmt::Display for NsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NsmError::IOError(e) => e.fmt(f),
+            NsmError::InvalidUrl(url) => write!(f, "Invalid NSM_URL: {}", url),
+            NsmError::Refused(msg) => write!(f, "Session manager refused announce: {}", msg),
+        }
+    }
+}
+
+pub enum NsmEvent {
+    Open(SessionInfo),
+    Save,
+}
+
+pub struct SessionInfo {
+    pub path: String,
+    pub client_id: String,
+}
+
+impl SessionInfo {
+    fn state_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.sonarigo", self.path))
+    }
+}
+
+pub struct SessionState {
+    pub sfz_file: Option<String>,
+    pub gain_db: f32,
+}
+
+impl SessionState {
+    pub fn load(session: &SessionInfo) -> io::Result<SessionState> {
+        let text = fs::read_to_string(session.state_file())?;
+        let mut state = SessionState { sfz_file: None, gain_db: 0.0 };
+        for line in text.lines() {
+            match line.find('=') {
+                Some(n) => match (&line[..n], &line[n+1..]) {
+                    ("sfz_file", v) => state.sfz_file = Some(v.to_string()),
+                    ("gain", v) => state.gain_db = v.parse().unwrap_or(0.0),
+                    _ => {}
+                },
+                None => {}
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self, session: &SessionInfo) -> io::Result<()> {
+        let mut text = String::new();
+        if let Some(sfz_file) = &self.sfz_file {
+            text += &format!("sfz_file={}\n", sfz_file);
+        }
+        text += &format!("gain={}\n", self.gain_db);
+        fs::write(session.state_file(), text)
+    }
+}
+
+pub struct NsmClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+}
+
+impl NsmClient {
+    /// Connects to the session manager if sonarigo has been launched by one
+    pub fn from_env() -> Option<Result<NsmClient, NsmError>> {
+        env::var("NSM_URL").ok().map(|url| NsmClient::connect(&url))
+    }
+
+    fn connect(url: &str) -> Result<NsmClient, NsmError> {
+        let server = url.trim_start_matches("osc.udp://")
+            .trim_end_matches('/')
+            .to_socket_addrs()
+            .map_err(|_| NsmError::InvalidUrl(url.to_string()))?
+            .next()
+            .ok_or_else(|| NsmError::InvalidUrl(url.to_string()))?;
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(NsmError::IOError)?;
+        Ok(NsmClient { socket, server })
+    }
+
+    pub fn try_clone(&self) -> io::Result<NsmClient> {
+        Ok(NsmClient { socket: self.socket.try_clone()?, server: self.server })
+    }
+
+    fn send(&self, msg: OscMessage) -> io::Result<()> {
+        self.socket.send_to(&msg.encode(), self.server).map(|_| ())
+    }
+
+    /// Announces to the session manager and waits for the session to be opened
+    pub fn announce(&self) -> Result<SessionInfo, NsmError> {
+        let executable = env::args().next().unwrap_or_default();
+        self.send(OscMessage::new("/nsm/server/announce", vec![
+            OscArg::Str(APP_NAME.to_string()),
+            OscArg::Str(CAPABILITIES.to_string()),
+            OscArg::Str(executable),
+            OscArg::Int(API_VERSION_MAJOR),
+            OscArg::Int(API_VERSION_MINOR),
+            OscArg::Int(std::process::id() as i32),
+        ])).map_err(NsmError::IOError)?;
+
+        loop {
+            let msg = self.receive().map_err(NsmError::IOError)?;
+            if msg.address == "/error" && msg.str_arg(0) == Some("/nsm/server/announce") {
+                let reason = match msg.args.get(2) {
+                    Some(OscArg::Str(s)) => s.clone(),
+                    _ => String::new(),
+                };
+                return Err(NsmError::Refused(reason));
+            }
+            if let Some(NsmEvent::Open(session)) = self.parse_event(&msg) {
+                return Ok(session);
+            }
+        }
+    }
+
+    pub fn next_event(&self) -> io::Result<NsmEvent> {
+        loop {
+            let msg = self.receive()?;
+            if let Some(event) = self.parse_event(&msg) {
+                return Ok(event);
+            }
+        }
+    }
+
+    fn receive(&self) -> io::Result<OscMessage> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, _) = self.socket.recv_from(&mut buf)?;
+            if let Some(msg) = OscMessage::decode(&buf[..len]) {
+                return Ok(msg);
+            }
+        }
+    }
+
+    fn parse_event(&self, msg: &OscMessage) -> Option<NsmEvent> {
+        match msg.address.as_str() {
+            "/nsm/client/open" => match (msg.str_arg(0), msg.str_arg(2)) {
+                (Some(path), Some(client_id)) => Some(NsmEvent::Open(SessionInfo {
+                    path: path.to_string(),
+                    client_id: client_id.to_string(),
+                })),
+                _ => None,
+            },
+            "/nsm/client/save" => Some(NsmEvent::Save),
+            _ => None,
+        }
+    }
+
+    pub fn reply(&self, path: &str, message: &str) -> io::Result<()> {
+        self.send(OscMessage::new("/reply", vec![
+            OscArg::Str(path.to_string()),
+            OscArg::Str(message.to_string()),
+        ]))
+    }
+
+    pub fn error(&self, path: &str, message: &str) -> io::Result<()> {
+        self.send(OscMessage::new("/error", vec![
+            OscArg::Str(path.to_string()),
+            OscArg::Int(-1),
+            OscArg::Str(message.to_string()),
+        ]))
+    }
+}
@@ -0,0 +1,142 @@
+// Just enough of the OSC 1.0 wire format to talk to a session manager.
+// Bundles are not supported.
+
+use std::convert::TryInto;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage { address: address.to_string(), args }
+    }
+
+    pub fn str_arg(&self, n: usize) -> Option<&str> {
+        match self.args.get(n) {
+            Some(OscArg::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        push_string(&mut buf, &self.address);
+
+        let mut type_tags = String::from(",");
+        for arg in &self.args {
+            type_tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Str(_) => 's',
+            });
+        }
+        push_string(&mut buf, &type_tags);
+
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                OscArg::Float(v) => buf.extend_from_slice(&v.to_bits().to_be_bytes()),
+                OscArg::Str(s) => push_string(&mut buf, s),
+            }
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<OscMessage> {
+        let mut pos = 0;
+        let address = read_string(data, &mut pos)?;
+        if !address.starts_with('/') {
+            return None;
+        }
+
+        let type_tags = if pos < data.len() {
+            read_string(data, &mut pos)?
+        } else {
+            ",".to_string()
+        };
+        if !type_tags.starts_with(',') {
+            return None;
+        }
+
+        let mut args = Vec::new();
+        for tag in type_tags.chars().skip(1) {
+            let arg = match tag {
+                'i' => OscArg::Int(i32::from_be_bytes(read_word(data, &mut pos)?)),
+                'f' => OscArg::Float(f32::from_bits(u32::from_be_bytes(read_word(data, &mut pos)?))),
+                's' => OscArg::Str(read_string(data, &mut pos)?),
+                _ => return None,
+            };
+            args.push(arg);
+        }
+
+        Some(OscMessage { address, args })
+    }
+}
+
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    let padding = 4 - s.len() % 4;
+    buf.resize(buf.len() + padding, 0);
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> Option<String> {
+    let rest = data.get(*pos..)?;
+    let len = rest.iter().position(|b| *b == 0)?;
+    let s = std::str::from_utf8(&rest[..len]).ok()?.to_string();
+    *pos += (len / 4 + 1) * 4;
+    Some(s)
+}
+
+fn read_word(data: &[u8], pos: &mut usize) -> Option<[u8; 4]> {
+    let word = data.get(*pos..*pos + 4)?.try_into().ok()?;
+    *pos += 4;
+    Some(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let msg = OscMessage::new("/nsm/server/announce", vec![
+            OscArg::Str("Sonarigo".to_string()),
+            OscArg::Str(":".to_string()),
+            OscArg::Int(-1),
+            OscArg::Float(0.5),
+            OscArg::Str("four".to_string()),
+        ]);
+        let data = msg.encode();
+        assert_eq!(data.len() % 4, 0);
+        assert_eq!(OscMessage::decode(&data), Some(msg));
+
+        let empty = OscMessage::new("/nsm/client/save", Vec::new());
+        assert_eq!(OscMessage::decode(&empty.encode()), Some(empty));
+    }
+
+    #[test]
+    fn encode_wire_format() {
+        let msg = OscMessage::new("/ab", vec![OscArg::Int(1), OscArg::Str("abcd".to_string())]);
+        assert_eq!(msg.encode(), b"/ab\0,is\0\0\0\0\x01abcd\0\0\0\0".to_vec());
+    }
+
+    #[test]
+    fn decode_malformed() {
+        assert_eq!(OscMessage::decode(b"/ab\0"), Some(OscMessage::new("/ab", Vec::new())));
+        assert_eq!(OscMessage::decode(b"ab\0\0,\0\0\0"), None);
+        assert_eq!(OscMessage::decode(b"/ab\0i\0\0\0"), None);
+        assert_eq!(OscMessage::decode(b"/ab\0,i\0\0\0\0"), None);
+        assert_eq!(OscMessage::decode(b"/ab\0,b\0\0\0\0\0\0"), None);
+        assert_eq!(OscMessage::decode(b"/abc"), None);
+    }
+}
@@ -0,0 +1,86 @@
+// Virtual MIDI keyboard by OSC messages, for example from TouchOSC or scripts:
+//
+//   /noteon <note> <velocity> [channel]
+//   /noteoff <note> [velocity] [channel]
+//   /cc <controller> <value> [channel]
+//
+// The arguments are ints or floats, the channel is 1 to 16 and defaults to 1.
+// The messages are played at the start of the next jack cycle.
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::UdpSocket;
+use std::thread;
+
+use log::{debug, error, warn};
+use soundfonts::engine::MidiProducer;
+
+use crate::osc::{OscArg, OscMessage};
+
+fn int_arg(msg: &OscMessage, n: usize) -> Option<i32> {
+    match msg.args.get(n)? {
+        OscArg::Int(v) => Some(*v),
+        OscArg::Float(v) => Some(v.round() as i32),
+        OscArg::Str(_) => None,
+    }
+}
+
+fn u7_arg<T: TryFrom<u8>>(msg: &OscMessage, n: usize) -> Option<T> {
+    let v = u8::try_from(int_arg(msg, n)?).ok()?;
+    T::try_from(v).ok()
+}
+
+fn channel_arg(msg: &OscMessage, n: usize) -> Option<wmidi::Channel> {
+    match msg.args.get(n) {
+        None => Some(wmidi::Channel::Ch1),
+        Some(_) => {
+            let channel = u8::try_from(int_arg(msg, n)?).ok()?;
+            wmidi::Channel::from_index(channel.checked_sub(1)?).ok()
+        }
+    }
+}
+
+fn midi_message(msg: &OscMessage) -> Option<wmidi::MidiMessage<'static>> {
+    match msg.address.as_str() {
+        "/noteon" => Some(wmidi::MidiMessage::NoteOn(channel_arg(msg, 2)?, u7_arg(msg, 0)?, u7_arg(msg, 1)?)),
+        "/noteoff" => {
+            let velocity = match msg.args.get(1) {
+                Some(_) => u7_arg(msg, 1)?,
+                None => wmidi::Velocity::MIN,
+            };
+            Some(wmidi::MidiMessage::NoteOff(channel_arg(msg, 2)?, u7_arg(msg, 0)?, velocity))
+        }
+        "/cc" => Some(wmidi::MidiMessage::ControlChange(channel_arg(msg, 2)?, u7_arg(msg, 0)?, u7_arg(msg, 1)?)),
+        _ => None,
+    }
+}
+
+/// Listens on the UDP `port` and pushes the MIDI messages received to `producer`
+pub fn spawn_input(port: u16, mut producer: MidiProducer) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        loop {
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("OSC input failed: {}", e);
+                    return
+                }
+            };
+            let msg = match OscMessage::decode(&buf[..len]) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            match midi_message(&msg) {
+                Some(midi_msg) => {
+                    if !producer.push(0, &midi_msg) {
+                        warn!("OSC input queue full, dropped {}", msg.address);
+                    }
+                }
+                None => debug!("Ignored OSC message {} {:?}", msg.address, msg.args),
+            }
+        }
+    });
+    Ok(())
+}
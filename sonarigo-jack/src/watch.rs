@@ -0,0 +1,65 @@
+// Watch mode reloading the SFZ file when it or its samples are changed. With
+// the `watch` feature file system notifications wake up the watcher right
+// away, otherwise it polls the files.
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use soundfonts::sfz::SfzWatch;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(1000);
+#[cfg(feature = "watch")]
+const NOTIFY_DELAY: Duration = Duration::from_millis(200);
+
+/// Spawns the watcher thread. It watches the SFZ files sent to the returned
+/// sender, the one sent last, and calls `reload` when it has been changed.
+pub fn spawn<F: Fn() -> bool + Send + 'static>(samples: bool, reload: F) -> mpsc::Sender<String> {
+    let (file_sender, file_receiver) = mpsc::channel::<String>();
+
+    thread::spawn(move || {
+        #[cfg(feature = "watch")]
+        let (fs_receiver, mut watcher) = {
+            let (fs_sender, fs_receiver) = mpsc::channel();
+            match notify::watcher(fs_sender, NOTIFY_DELAY) {
+                Ok(w) => (fs_receiver, Some(w)),
+                Err(e) => {
+                    log::warn!("Could not watch files, polling them: {:?}", e);
+                    (fs_receiver, None)
+                }
+            }
+        };
+
+        let mut watch: Option<SfzWatch> = None;
+        loop {
+            loop {
+                match file_receiver.try_recv() {
+                    Ok(sfz_file) => {
+                        let w = SfzWatch::new(&sfz_file, samples);
+                        #[cfg(feature = "watch")]
+                        if let Some(watcher) = &mut watcher {
+                            use notify::Watcher;
+                            for dir in w.directories() {
+                                watcher.watch(dir, notify::RecursiveMode::NonRecursive).ok();
+                            }
+                        }
+                        watch = Some(w);
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                }
+            }
+
+            #[cfg(feature = "watch")]
+            fs_receiver.recv_timeout(POLL_INTERVAL).ok();
+            #[cfg(not(feature = "watch"))]
+            thread::sleep(POLL_INTERVAL);
+
+            if watch.as_mut().map_or(false, |w| w.changed()) && !reload() {
+                return;
+            }
+        }
+    });
+
+    file_sender
+}
@@ -1,6 +1,6 @@
 use std::any::Any;
-
-use std::f32::consts::PI;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
 
 extern crate lv2;
 extern crate lv2_worker;
@@ -8,7 +8,11 @@ extern crate lv2_worker;
 use lv2::prelude::*;
 use lv2::lv2_atom as atom;
 
+use log::{debug, error, warn};
+
 use soundfonts::engine::EngineTrait;
+use soundfonts::midi_filter::VelocityCurve;
+use soundfonts::sfz::diagnostics::{Diagnostic, Diagnostics, SharedDiagnostics};
 use soundfonts::sfz::engine;
 
 mod lv2_stuff;
@@ -19,6 +23,26 @@ struct StateChanged;
 #[uri("http://johannes-mueller.org/oss/lv2/sonarigo#sfzfile")]
 struct SampleFile;
 
+#[uri("http://johannes-mueller.org/oss/lv2/sonarigo#error")]
+struct LoadError;
+
+#[uri("http://johannes-mueller.org/oss/lv2/sonarigo#keyswitch")]
+struct Keyswitch;
+
+#[uri("http://johannes-mueller.org/oss/lv2/sonarigo#loaded")]
+struct Loaded;
+
+#[uri("http://johannes-mueller.org/oss/lv2/sonarigo#activeNotes")]
+struct ActiveNotes;
+
+const DIAGNOSTICS_LENGTH: usize = 256;
+
+// room for the path of the SFZ file, allocated up front so that the audio
+// thread can set it, longer paths cannot be opened anyway
+const SFZFILE_PATH_CAPACITY: usize = 4096;
+
+// set to log what the plugin is doing, like the sample files loaded
+const VERBOSE_VARIABLE: &str = "SONARIGO_VERBOSE";
 
 #[derive(PortCollection)]
 struct Ports {
@@ -27,11 +51,31 @@ struct Ports {
     out_left: OutputPort<Audio>,
     out_right: OutputPort<Audio>,
     gain: InputPort<Control>,
+    transpose: InputPort<Control>,
+    fold_low: InputPort<Control>,
+    fold_high: InputPort<Control>,
+    limiter: InputPort<Control>,
+    voices: OutputPort<Control>,
+    cpu_load: OutputPort<Control>,
+    crossfade: InputPort<Control>,
+    int16_samples: InputPort<Control>,
+    memory_limit: InputPort<Control>,
+    pan: InputPort<Control>,
+    tune: InputPort<Control>,
+    max_voices: InputPort<Control>,
+    midi_out: OutputPort<AtomPort>,
+    velocity_gamma: InputPort<Control>,
+    fixed_velocity: InputPort<Control>,
+    portamento: InputPort<Control>,
+    tiered_loading: InputPort<Control>,
+    fully_loaded: OutputPort<Control>,
+    purge_after: InputPort<Control>,
 }
 
 #[derive(FeatureCollection)]
 struct Features<'a> {
     map: LV2Map<'a>,
+    log: Option<lv2_stuff::Log<'a>>,
 }
 
 #[derive(FeatureCollection)]
@@ -45,27 +89,59 @@ struct URIDs {
     midi: MidiURIDCollection,
     unit: UnitURIDCollection,
     patch: lv2_stuff::PatchURIDCollection,
+    time: lv2_stuff::TimeURIDCollection,
     state_changed: URID<StateChanged>,
     atom_path: URID<lv2_stuff::AtomPath>,
 
     sfzfile: URID<SampleFile>,
+    error: URID<LoadError>,
+    keyswitch: URID<Keyswitch>,
+    loaded: URID<Loaded>,
+    active_notes: URID<ActiveNotes>,
+    log_warning: URID<lv2_stuff::LogWarning>,
 }
 
 
 #[uri("http://johannes-mueller.org/oss/lv2/sonarigo#lv2")]
 struct SonarigoLV2 {
-    engine: engine::Engine,
-    new_engine: Option<engine::Engine>,
+    engine: Box<engine::Engine>,
+    new_engine: Option<Box<engine::Engine>>,
     urids: URIDs,
 
-    sfzfile_path: Option<std::string::String>,
+    // shared with the worker, which copies it when loading
+    sfzfile_path: Arc<Mutex<std::string::String>>,
 
     samplerate: f64,
     max_block_length: usize,
+    crossfade_time: f64,
+    // only changed when the ports do, so that it is not cloned on the audio
+    // thread unless the worker is just copying it
+    engine_config: Arc<engine::EngineConfig>,
 
     state_notification_needed: bool,
-
-    current_gain: f32
+    // why the last SFZ file could not be loaded
+    load_error: std::string::String,
+    error_notification_needed: bool,
+    // the keyswitch last notified
+    keyswitch: Option<u8>,
+    keyswitch_notification_needed: bool,
+    // the samples of all regions of the SFZ file are loaded
+    loaded_notification_needed: bool,
+    // frames until the idle regions are purged next
+    purge_countdown: usize,
+
+    diagnostics: SharedDiagnostics,
+    log: Option<lv2_stuff::Log<'static>>,
+    // frames until the diagnostics are logged next
+    diagnostics_countdown: usize,
+    // the held keys last notified
+    active_notes: Vec<(wmidi::Note, wmidi::Velocity)>,
+    polled_notes: Vec<(wmidi::Note, wmidi::Velocity)>,
+    active_notes_notification_needed: bool,
+    // frames until the held keys are checked next
+    active_notes_countdown: usize,
+    // the tempo last sent by the host, kept for the engines loaded later
+    tempo: Option<f64>,
 }
 
 impl Plugin for SonarigoLV2 {
@@ -75,36 +151,78 @@ impl Plugin for SonarigoLV2 {
     type AudioFeatures = AudioFeatures<'static>;
 
     fn new(plugin_info: &PluginInfo, features: &mut Features<'static>) -> Option<Self> {
+        soundfonts::logging::init(std::env::var_os(VERBOSE_VARIABLE).is_some());
         let samplerate = plugin_info.sample_rate();
         let max_block_length = 8192; /*FIXME*/
-        let engine = engine::Engine::dummy(samplerate, max_block_length);
+        let mut engine = engine::Engine::dummy(samplerate, max_block_length);
+        let diagnostics = Diagnostics::shared(DIAGNOSTICS_LENGTH);
+        engine.set_diagnostics(Some(diagnostics.clone()));
         Some(Self {
-            engine,
+            engine: Box::new(engine),
             new_engine: None,
             urids: features.map.populate_collection()?,
 
-            sfzfile_path: None,
+            sfzfile_path: Arc::new(Mutex::new(std::string::String::with_capacity(SFZFILE_PATH_CAPACITY))),
 
             samplerate,
             max_block_length,
+            crossfade_time: 0.0,
+            engine_config: Default::default(),
 
             state_notification_needed: false,
-
-            current_gain: soundfonts::utils::dB_to_gain(-6.0)
+            load_error: std::string::String::new(),
+            error_notification_needed: false,
+            keyswitch: None,
+            keyswitch_notification_needed: false,
+            loaded_notification_needed: false,
+            purge_countdown: 0,
+
+            diagnostics,
+            log: features.log,
+            diagnostics_countdown: 0,
+            active_notes: Vec::with_capacity(128),
+            polled_notes: Vec::with_capacity(128),
+            active_notes_notification_needed: false,
+            active_notes_countdown: 0,
+            tempo: None,
         })
     }
 
     fn run(&mut self, ports: &mut Ports, features: &mut Self::AudioFeatures, _: u32) {
-        let mut offset: usize = 0;
-
         for (l, r) in Iterator::zip(ports.out_left.iter_mut(), ports.out_right.iter_mut()) {
             *l = 0.0;
             *r = 0.0;
         }
 
+        self.crossfade_time = *ports.crossfade as f64;
+        let sample_storage = if *ports.int16_samples > 0.5 {
+            engine::SampleStorage::Int16
+        } else {
+            engine::SampleStorage::Float32
+        };
+        let memory_limit = match ports.memory_limit.round() as usize {
+            0 => None,
+            megabytes => Some(megabytes << 20),
+        };
+        let load_tiers = if *ports.tiered_loading > 0.5 {
+            Some(Default::default())
+        } else {
+            None
+        };
+        let config = &self.engine_config;
+        if (config.sample_storage, config.memory_limit, config.load_tiers) != (sample_storage, memory_limit, load_tiers) {
+            let config = Arc::make_mut(&mut self.engine_config);
+            config.sample_storage = sample_storage;
+            config.memory_limit = memory_limit;
+            config.load_tiers = load_tiers;
+        }
+
         let active_engine = if let Some(new_engine) = &mut self.new_engine {
             if self.engine.fadeout_finished() {
-                self.engine = self.new_engine.take().unwrap();
+                let replaced = std::mem::replace(&mut self.engine, self.new_engine.take().unwrap());
+                if features.schedule.schedule_work(Work::ReplacedEngine(replaced)).is_err() {
+                    self.diagnostics.push(Diagnostic::WorkNotScheduled);
+                }
                 &mut self.engine
             } else {
                 self.engine.process(&mut ports.out_left, &mut ports.out_right);
@@ -114,77 +232,139 @@ impl Plugin for SonarigoLV2 {
             &mut self.engine
         };
 
+        active_engine.set_transpose(ports.transpose.round() as i32);
+        active_engine.set_note_fold(note_fold(*ports.fold_low, *ports.fold_high));
+        active_engine.set_master_gain(*ports.gain);
+        active_engine.set_master_pan(*ports.pan);
+        active_engine.set_master_tune(*ports.tune as f64);
+        active_engine.set_max_voices(match ports.max_voices.round() as usize {
+            0 => None,
+            voices => Some(voices),
+        });
+        active_engine.set_velocity_curve(velocity_curve(*ports.velocity_gamma, *ports.fixed_velocity));
+        active_engine.set_portamento(match *ports.portamento {
+            seconds if seconds > 0.0 => Some(seconds),
+            _ => None,
+        });
+        if let Some(bpm) = self.tempo {
+            active_engine.set_tempo(bpm);
+        }
+
         let control_sequence = ports
             .control
             .read(self.urids.atom.sequence, self.urids.unit.beat)
             .unwrap();
 
+        let mut midi_out = ports
+            .midi_out
+            .init(self.urids.atom.sequence, TimeStampURID::Frames(self.urids.unit.frame))
+            .unwrap();
+
         for (timestamp, message) in control_sequence {
-            match timestamp.as_frames() {
-                Some(ts) if ts > 0  => {
-                    let frame = ts as usize;
-                    active_engine.process(&mut ports.out_left[offset..frame],
-                                          &mut ports.out_right[offset..frame]);
-                    offset = frame;
-                }
-                _ => {}
-            };
+            let frame = timestamp.as_frames().map_or(0, |ts| ts.max(0) as usize);
 
             if let Some(msg) = message.read(self.urids.midi.wmidi, ()) {
-                active_engine.midi_event(&msg);
+                active_engine.midi_event_at(frame, &msg);
+                if let wmidi::MidiMessage::NoteOn(..) | wmidi::MidiMessage::NoteOff(..) = msg {
+                    midi_out.init(TimeStamp::Frames(frame as i64), self.urids.midi.wmidi, msg);
+                }
             };
 
             if let Some((header, mut object_reader)) = message.read(self.urids.atom.object, ()) {
-                println!("received message");
-                if header.otype == self.urids.patch.set {
+                if header.otype == self.urids.time.position {
+                    if let Some(bpm) = parse_tempo(&self.urids, object_reader) {
+                        self.tempo = Some(bpm);
+                        active_engine.set_tempo(bpm);
+                    }
+                } else if header.otype == self.urids.patch.set {
                     if let Some(path) = parse_sfzfile_path(&self.urids, &mut object_reader) {
-                        if let Err(e) = features.schedule.schedule_work(EngineParameters {
-                            sfzfile: path.to_string(),
+                        let stored = match self.sfzfile_path.try_lock() {
+                            Ok(mut sfzfile_path) if path.len() <= sfzfile_path.capacity() => {
+                                sfzfile_path.clear();
+                                sfzfile_path.push_str(path);
+                                true
+                            }
+                            _ => false,
+                        };
+                        if !stored || features.schedule.schedule_work(Work::Load(EngineParameters {
+                            sfzfile: self.sfzfile_path.clone(),
                             host_samplerate: self.samplerate,
-                            max_block_length: self.max_block_length
-                        }) {
-                            println!("can't schedule work {}", e);
-                        } else {
-                            println!("work scheduled");
+                            max_block_length: self.max_block_length,
+                            config: self.engine_config.clone()
+                        })).is_err() {
+                            self.diagnostics.push(Diagnostic::WorkNotScheduled);
                         }
-                        self.sfzfile_path = Some(path.to_string());
                     }
                 } else if header.otype == self.urids.patch.get {
-                    println!("recieved get request");
                     self.state_notification_needed = true;
                 }
             }
         }
 
-        let nsamples = ports.out_left.len();
-        if offset < nsamples {
-            active_engine.process(&mut ports.out_left[offset..nsamples],
-                                  &mut ports.out_right[offset..nsamples]);
-        }
+        active_engine.process(&mut ports.out_left, &mut ports.out_right);
 
-        let gain_target = match *ports.gain {
-            g if g < -80.0 => 0.0,
-            g if g >= 20.0 => soundfonts::utils::dB_to_gain(20.0),
-            g => soundfonts::utils::dB_to_gain(g)
-        };
+        if *ports.purge_after > 0.0 {
+            // the idle regions are purged once a second
+            self.purge_countdown = self.purge_countdown.saturating_sub(ports.out_left.len());
+            if self.purge_countdown == 0 {
+                self.purge_countdown = self.samplerate as usize;
+                if let Some(request) = active_engine.purge(*ports.purge_after as f64 * 60.0) {
+                    if features.schedule.schedule_work(Work::Purge(request)).is_err() {
+                        self.diagnostics.push(Diagnostic::WorkNotScheduled);
+                    }
+                }
+            }
+            if let Some(request) = active_engine.reload_request() {
+                if features.schedule.schedule_work(Work::ReloadSamples(request)).is_err() {
+                    self.diagnostics.push(Diagnostic::WorkNotScheduled);
+                }
+            }
+        }
 
-        let tau = 1.0 - (-2.0 * PI * 25.0 / self.samplerate as f32).exp();
-        let mut current_gain = self.current_gain;
+        // the diagnostics are logged once a second
+        self.diagnostics_countdown = self.diagnostics_countdown.saturating_sub(ports.out_left.len());
+        if self.diagnostics_countdown == 0 {
+            self.diagnostics_countdown = self.samplerate as usize;
+            if !self.diagnostics.is_empty() {
+                let work = Work::Diagnostics(self.diagnostics.clone(), self.log, self.urids.log_warning);
+                // the diagnostics are tried again a second later
+                features.schedule.schedule_work(work).ok();
+            }
+        }
 
-        for (l, r) in Iterator::zip(ports.out_left.iter_mut(), ports.out_right.iter_mut()) {
-            current_gain += tau * (gain_target - current_gain);
-            *l *= current_gain;
-            *r *= current_gain;
+        if active_engine.keyswitch() != self.keyswitch {
+            self.keyswitch = active_engine.keyswitch();
+            self.keyswitch_notification_needed = true;
+        }
+        let keyswitch_label = active_engine.keyswitch_label().unwrap_or("");
+
+        // the held keys are notified at most ten times a second
+        self.active_notes_countdown = self.active_notes_countdown.saturating_sub(ports.out_left.len());
+        if self.active_notes_countdown == 0 {
+            self.active_notes_countdown = self.samplerate as usize / 10;
+            active_engine.active_notes_into(&mut self.polled_notes);
+            if self.polled_notes != self.active_notes {
+                std::mem::swap(&mut self.polled_notes, &mut self.active_notes);
+                self.active_notes_notification_needed = true;
+            }
         }
 
-	if (tau * (current_gain - gain_target)).abs() < std::f32::EPSILON * current_gain {
-		current_gain = gain_target;
-	}
-        self.current_gain = current_gain;
+        **ports.voices = active_engine.voice_count() as f32;
+        **ports.cpu_load = 100.0 * active_engine.cpu_load_estimate();
+        **ports.fully_loaded = if active_engine.is_fully_loaded() { 1.0 } else { 0.0 };
 
-        if self.state_notification_needed {//&& self.sfzfile_path.is_some() {
-            println!("trying to notify");
+        if *ports.limiter > 0.5 {
+            soundfonts::utils::soft_clip(&mut ports.out_left);
+            soundfonts::utils::soft_clip(&mut ports.out_right);
+        }
 
+        let sfzfile_path = if self.state_notification_needed {
+            // tried again in the next cycle while the worker copies the path
+            self.sfzfile_path.try_lock().ok()
+        } else {
+            None
+        };
+        if let Some(sfzfile_path) = sfzfile_path {
             let mut object_writer = ports.notify.init(
                 self.urids.atom.object,
                 ObjectHeader {
@@ -199,11 +379,82 @@ impl Plugin for SonarigoLV2 {
 
             let mut prop_writer = object_writer.init(self.urids.patch.value,
                                                  self.urids.atom_path, ()).unwrap();
-            let test_string = prop_writer.append(self.sfzfile_path.as_ref().unwrap());
-
-            println!("wrote {:?}", test_string);
+            prop_writer.append(&sfzfile_path);
 
             self.state_notification_needed = false;
+        } else if self.error_notification_needed {
+            let mut object_writer = ports.notify.init(
+                self.urids.atom.object,
+                ObjectHeader {
+                    id: None,
+                    otype: self.urids.patch.set.into_general(),
+                }
+            ).unwrap();
+
+            object_writer.init(self.urids.patch.property,
+                               self.urids.atom.urid,
+                               self.urids.error.into_general());
+
+            let mut prop_writer = object_writer.init(self.urids.patch.value,
+                                                     self.urids.atom.string, ()).unwrap();
+            prop_writer.append(&self.load_error);
+
+            self.error_notification_needed = false;
+        } else if self.keyswitch_notification_needed {
+            let mut object_writer = ports.notify.init(
+                self.urids.atom.object,
+                ObjectHeader {
+                    id: None,
+                    otype: self.urids.patch.set.into_general(),
+                }
+            ).unwrap();
+
+            object_writer.init(self.urids.patch.property,
+                               self.urids.atom.urid,
+                               self.urids.keyswitch.into_general());
+
+            let mut prop_writer = object_writer.init(self.urids.patch.value,
+                                                     self.urids.atom.string, ()).unwrap();
+            prop_writer.append(keyswitch_label);
+
+            self.keyswitch_notification_needed = false;
+        } else if self.loaded_notification_needed {
+            let mut object_writer = ports.notify.init(
+                self.urids.atom.object,
+                ObjectHeader {
+                    id: None,
+                    otype: self.urids.patch.set.into_general(),
+                }
+            ).unwrap();
+
+            object_writer.init(self.urids.patch.property,
+                               self.urids.atom.urid,
+                               self.urids.loaded.into_general());
+
+            object_writer.init(self.urids.patch.value, self.urids.atom.bool, 1);
+
+            self.loaded_notification_needed = false;
+        } else if self.active_notes_notification_needed {
+            let mut object_writer = ports.notify.init(
+                self.urids.atom.object,
+                ObjectHeader {
+                    id: None,
+                    otype: self.urids.patch.set.into_general(),
+                }
+            ).unwrap();
+
+            object_writer.init(self.urids.patch.property,
+                               self.urids.atom.urid,
+                               self.urids.active_notes.into_general());
+
+            let mut vector_writer = object_writer.init(self.urids.patch.value,
+                                                       self.urids.atom.vector(),
+                                                       self.urids.atom.int).unwrap();
+            for (note, velocity) in &self.active_notes {
+                vector_writer.append(&[u8::from(*note) as i32, u8::from(*velocity) as i32]);
+            }
+
+            self.active_notes_notification_needed = false;
         }
 
     }
@@ -213,6 +464,23 @@ impl Plugin for SonarigoLV2 {
     }
 }
 
+fn note_fold(low: f32, high: f32) -> Option<(wmidi::Note, wmidi::Note)> {
+    let (low, high) = (low.round(), high.round());
+    if (low <= 0.0 && high >= 127.0) || low > high {
+        return None;
+    }
+    let note = |v: f32| wmidi::Note::try_from(v.max(0.0).min(127.0) as u8).ok();
+    Some((note(low)?, note(high)?))
+}
+
+fn velocity_curve(gamma: f32, fixed_velocity: f32) -> VelocityCurve {
+    match fixed_velocity.round().max(0.0).min(127.0) as u8 {
+        0 if gamma > 0.0 && gamma != 1.0 => VelocityCurve::Gamma(gamma),
+        0 => VelocityCurve::Linear,
+        v => VelocityCurve::Fixed(v),
+    }
+}
+
 fn parse_sfzfile_path<'a>(urids: &URIDs, object_reader:
                           &mut atom::object::ObjectReader<'a>) -> Option<&'a str> {
     if let Some((property_header, atom)) = object_reader.next() {
@@ -237,37 +505,147 @@ fn parse_sfzfile_path<'a>(urids: &URIDs, object_reader:
     None
 }
 
+fn parse_tempo(urids: &URIDs, object_reader: atom::object::ObjectReader) -> Option<f64> {
+    for (property_header, atom) in object_reader {
+        if property_header.key == urids.time.beats_per_minute {
+            return atom.read(urids.atom.float, ()).map(|bpm| bpm as f64);
+        }
+    }
+    None
+}
+
 struct EngineParameters {
-    sfzfile: std::string::String,
+    sfzfile: Arc<Mutex<std::string::String>>,
     host_samplerate: f64,
-    max_block_length: usize
+    max_block_length: usize,
+    config: Arc<engine::EngineConfig>
+}
+
+enum Work {
+    Load(EngineParameters),
+    Purge(engine::PurgeRequest),
+    ReloadSamples(engine::ReloadRequest),
+    /// the instrument replaced by a purge, dropped outside the audio thread
+    Release(Arc<engine::InstrumentData>),
+    /// the engine replaced by a newly loaded one, dropped outside the audio
+    /// thread
+    ReplacedEngine(Box<engine::Engine>),
+    /// drained and written to the host's log, to stderr without
+    Diagnostics(SharedDiagnostics, Option<lv2_stuff::Log<'static>>, URID<lv2_stuff::LogWarning>),
+}
+
+enum LoadResponse {
+    Engine(Result<Box<engine::Engine>, std::string::String>),
+    /// the samples loaded after the engine started playing
+    DeferredSamples(Result<engine::DeferredSamples, std::string::String>),
+    Purged(engine::PurgedInstrument),
 }
 
 impl lv2_worker::Worker for SonarigoLV2 {
-    type WorkData = EngineParameters;
+    type WorkData = Work;
 
-    type ResponseData = soundfonts::sfz::engine::Engine;
+    type ResponseData = LoadResponse;
 
     fn work(response_handler: &lv2_worker::ResponseHandler<Self>, data: Self::WorkData)
             -> Result<(), lv2_worker::WorkerError> {
-        println!("work {}", data.sfzfile);
-        let engine = soundfonts::sfz::engine::Engine::new(data.sfzfile,
-                                                          data.host_samplerate,
-                                                          data.max_block_length)
-            .map_err(|e| {
-                println!("failed {:?}", e);
-                lv2_worker::WorkerError::Unknown
-            })?;
-
-        response_handler.respond(engine).map_err(|_| lv2_worker::WorkerError::Unknown)
+        let data = match data {
+            Work::Load(parameters) => parameters,
+            Work::Purge(request) => {
+                return response_handler.respond(LoadResponse::Purged(request.release()))
+                    .map_err(|_| lv2_worker::WorkerError::Unknown);
+            }
+            Work::ReloadSamples(request) => {
+                let samples = request.load().map_err(|e| {
+                    error!("Could not reload samples: {}", e);
+                    e.to_string()
+                });
+                return response_handler.respond(LoadResponse::DeferredSamples(samples))
+                    .map_err(|_| lv2_worker::WorkerError::Unknown);
+            }
+            Work::Release(instrument) => {
+                drop(instrument);
+                return Ok(());
+            }
+            Work::ReplacedEngine(replaced) => {
+                drop(replaced);
+                return Ok(());
+            }
+            Work::Diagnostics(diagnostics, log, warning) => {
+                for diagnostic in diagnostics.drain() {
+                    match log {
+                        Some(log) => log.print(warning, &format!("sonarigo: {}", diagnostic)),
+                        None => warn!("{}", diagnostic),
+                    }
+                }
+                return Ok(());
+            }
+        };
+        let sfzfile = data.sfzfile.lock().unwrap().clone();
+        let config = engine::EngineConfig::clone(&data.config);
+        // released right away, so that the audio thread can change the config
+        // again without cloning it
+        drop(data.config);
+        debug!("loading {}", sfzfile);
+        let loaded = if config.load_tiers.is_some() {
+            engine::Engine::new_tiered(sfzfile, data.host_samplerate, data.max_block_length, &config)
+        } else {
+            engine::Engine::new_shared(sfzfile, data.host_samplerate, data.max_block_length, &config)
+                .map(|engine| (engine, None))
+        };
+        let error = |e: engine::EngineError| {
+            error!("Could not load SFZ file: {}", e);
+            e.to_string()
+        };
+        let (engine, deferred) = match loaded {
+            Ok((engine, deferred)) => (Ok(Box::new(engine)), deferred),
+            Err(e) => (Err(error(e)), None),
+        };
+
+        response_handler.respond(LoadResponse::Engine(engine)).map_err(|_| lv2_worker::WorkerError::Unknown)?;
+        if let Some(deferred) = deferred {
+            let samples = deferred.load().map_err(error);
+            response_handler.respond(LoadResponse::DeferredSamples(samples)).map_err(|_| lv2_worker::WorkerError::Unknown)?;
+        }
+        Ok(())
     }
 
-    fn work_response(&mut self, data: Self::ResponseData, _f: &mut Self::AudioFeatures)
+    fn work_response(&mut self, data: Self::ResponseData, features: &mut Self::AudioFeatures)
                      -> Result<(), lv2_worker::WorkerError> {
-        println!("work_response");
-        self.engine.fadeout();
-        self.new_engine = Some(data);
-        self.state_notification_needed = true;
+        match data {
+            LoadResponse::Engine(Ok(mut engine)) => {
+                engine.set_diagnostics(Some(self.diagnostics.clone()));
+                self.loaded_notification_needed = engine.is_fully_loaded();
+                self.engine.crossfade_out(self.crossfade_time);
+                if let Some(pending) = self.new_engine.replace(engine) {
+                    if features.schedule.schedule_work(Work::ReplacedEngine(pending)).is_err() {
+                        self.diagnostics.push(Diagnostic::WorkNotScheduled);
+                    }
+                }
+                self.state_notification_needed = true;
+            }
+            LoadResponse::DeferredSamples(Ok(samples)) => {
+                let engine = match &mut self.new_engine {
+                    Some(engine) => engine,
+                    None => &mut self.engine,
+                };
+                self.loaded_notification_needed = engine.complete_loading(samples) && engine.is_fully_loaded();
+            }
+            LoadResponse::Purged(purged) => {
+                let engine = match &mut self.new_engine {
+                    Some(engine) => engine,
+                    None => &mut self.engine,
+                };
+                if let Some(instrument) = engine.complete_purge(purged) {
+                    if features.schedule.schedule_work(Work::Release(instrument)).is_err() {
+                        self.diagnostics.push(Diagnostic::WorkNotScheduled);
+                    }
+                }
+            }
+            LoadResponse::Engine(Err(error)) | LoadResponse::DeferredSamples(Err(error)) => {
+                self.load_error = error;
+                self.error_notification_needed = true;
+            }
+        }
 
         Ok(())
     }
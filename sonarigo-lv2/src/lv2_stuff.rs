@@ -1,5 +1,9 @@
 
 
+use std::ffi::{c_void, CString};
+use std::os::raw::c_char;
+
+use lv2::lv2_core::feature::Feature;
 use lv2::prelude::*;
 
 #[uri("http://lv2plug.in/ns/ext/patch#Set")]
@@ -30,6 +34,18 @@ pub struct PatchURIDCollection {
     pub value: URID<PatchValue>
 }
 
+#[uri("http://lv2plug.in/ns/ext/time#Position")]
+pub struct TimePosition;
+
+#[uri("http://lv2plug.in/ns/ext/time#beatsPerMinute")]
+pub struct TimeBeatsPerMinute;
+
+#[derive(URIDCollection)]
+pub struct TimeURIDCollection {
+    pub position: URID<TimePosition>,
+    pub beats_per_minute: URID<TimeBeatsPerMinute>,
+}
+
 #[uri("http://lv2plug.in/ns/ext/atom#Path")]
 pub struct AtomPath;
 
@@ -64,3 +80,45 @@ impl<'a, 'b> AtomPathWriter<'a, 'b> {
         unsafe { Some(std::str::from_utf8_unchecked_mut(space)) }
     }
 }
+
+#[uri("http://lv2plug.in/ns/ext/log#Warning")]
+pub struct LogWarning;
+
+/// The log feature of the host, usable from any thread
+#[derive(Clone, Copy)]
+pub struct Log<'a> {
+    internal: &'a lv2_sys::LV2_Log_Log,
+}
+
+// SAFETY: the LV2 log specification requires the host's log functions to be
+// callable from any thread, including concurrently, and the handle is only
+// passed back to them
+unsafe impl<'a> Send for Log<'a> {}
+unsafe impl<'a> Sync for Log<'a> {}
+
+unsafe impl<'a> UriBound for Log<'a> {
+    const URI: &'static [u8] = lv2_sys::LV2_LOG__log;
+}
+
+unsafe impl<'a> Feature for Log<'a> {
+    unsafe fn from_feature_ptr(feature: *const c_void, _class: ThreadingClass) -> Option<Self> {
+        (feature as *const lv2_sys::LV2_Log_Log).as_ref().map(|internal| Self { internal })
+    }
+}
+
+impl<'a> Log<'a> {
+    pub fn print<T: ?Sized>(&self, entry_type: URID<T>, message: &str) {
+        let message = match CString::new(message) {
+            Ok(m) => m,
+            Err(_) => return,
+        };
+        if let Some(printf) = self.internal.printf {
+            unsafe {
+                printf(self.internal.handle,
+                       entry_type.get(),
+                       "%s\n\0".as_ptr() as *const c_char,
+                       message.as_ptr());
+            }
+        }
+    }
+}
@@ -0,0 +1,7 @@
+pub fn value_for<I: Iterator<Item = String>>(option: &str, args: &mut I) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{}: missing value", option))
+}
+
+pub fn parse_value<T: std::str::FromStr>(option: &str, value: String) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{}: invalid value {}", option, value))
+}
@@ -0,0 +1,57 @@
+use std::fs;
+use std::path::Path;
+
+use soundfonts::sfz;
+
+use crate::args::value_for;
+
+const USAGE: &str = "\
+Usage: sonarigo automap [OPTIONS] <DIR>
+
+Creates an SFZ file mapping the WAV and FLAC files in the directory and its
+subdirectories. The root keys are taken from note names in the file names like
+C#3 or Piano_A0v1, otherwise from the sampler chunks of WAV files. Velocity
+layers like v2 in the file names split the velocity range.
+
+Options:
+  -o, --output <FILE>    write the SFZ file into the directory under this
+                         name instead of printing it
+  -h, --help             show this help";
+
+pub fn run<I: Iterator<Item = String>>(mut args: I) -> Result<(), String> {
+    let mut dir = None;
+    let mut output = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value_for(&arg, &mut args)?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(())
+            }
+            a if a.starts_with('-') => return Err(format!("Unknown option: {}\n\n{}", a, USAGE)),
+            _ if dir.is_some() => return Err(USAGE.to_string()),
+            _ => dir = Some(arg),
+        }
+    }
+
+    let dir = dir.ok_or_else(|| USAGE.to_string())?;
+    let map = sfz::automap(Path::new(&dir)).map_err(|e| e.to_string())?;
+    for sample in map.skipped.iter() {
+        eprintln!("{}: no root key found, skipped", sample);
+    }
+    if map.regions.is_empty() {
+        return Err(format!("{}: no samples to map", dir));
+    }
+
+    let text = sfz::write_sfz(&map.regions);
+    match output {
+        Some(name) => {
+            let path = Path::new(&dir).join(name);
+            fs::write(&path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+            println!("{}: {} regions", path.display(), map.regions.len());
+        }
+        None => print!("{}", text),
+    }
+    Ok(())
+}
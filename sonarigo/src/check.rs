@@ -0,0 +1,55 @@
+use soundfonts::sfz;
+
+const USAGE: &str = "\
+Usage: sonarigo check [OPTIONS] <SFZ_FILE>...
+
+Reports unsupported opcodes, invalid values, missing sample files and
+overlapping regions of exclusive groups. Exits with 1 if problems other than
+ignored opcodes were found.
+
+Options:
+  -q, --quiet    only report the files with problems
+  -h, --help     show this help";
+
+pub fn run<I: Iterator<Item = String>>(args: I) -> Result<(), String> {
+    let mut files = Vec::new();
+    let mut quiet = false;
+
+    for arg in args {
+        match arg.as_str() {
+            "-q" | "--quiet" => quiet = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(())
+            }
+            a if a.starts_with('-') => return Err(format!("Unknown option: {}\n\n{}", a, USAGE)),
+            _ => files.push(arg),
+        }
+    }
+
+    if files.is_empty() {
+        return Err(USAGE.to_string());
+    }
+
+    let mut failed = 0;
+    for file in files.iter() {
+        let report = sfz::validate(file);
+        if !report.is_ok() {
+            failed += 1;
+        } else if quiet {
+            continue;
+        }
+        match report.issues.len() {
+            0 => println!("{}: {} regions, ok", report.path, report.regions),
+            n => println!("{}: {} regions, {} issues", report.path, report.regions, n),
+        }
+        for issue in report.issues.iter() {
+            println!("  {}", issue);
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} of {} files have problems", n, files.len())),
+    }
+}
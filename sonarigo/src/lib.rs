@@ -0,0 +1,34 @@
+//! The sonarigo SFZ sampler for Rust hosts embedding it. The items here are
+//! the stable API, the modules of the `soundfonts` crate behind them may
+//! change.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use sonarigo::{Engine, EngineConfig, EngineTrait, Instrument, MidiMessage};
+//!
+//! let config = EngineConfig::default();
+//! let instrument = Arc::new(Instrument::load("Piano.sfz", 48000.0, 256, &config).unwrap());
+//! let mut engine = Engine::from_instrument(instrument, 48000.0, 256);
+//!
+//! let note = sonarigo::wmidi::Note::C3;
+//! engine.midi_event(&MidiMessage::NoteOn(sonarigo::wmidi::Channel::Ch1, note, sonarigo::wmidi::Velocity::MAX));
+//! let (mut left, mut right) = (vec![0.0; 256], vec![0.0; 256]);
+//! engine.process(&mut left, &mut right);
+//! ```
+
+pub use soundfonts::engine::{midi_ring, EngineTrait, MidiConsumer, MidiProducer};
+pub use soundfonts::midi_filter::{MidiFilter, VelocityCurve, VelocityCurveError};
+pub use soundfonts::sfz::engine::{DeferredLoad, DeferredSamples, LoadTiers, PurgeRequest, PurgedInstrument, ReloadRequest};
+pub use soundfonts::sfz::engine::{Engine, EngineConfig, EngineError, MpeConfig, SampleStorage};
+pub use soundfonts::sfz::engine::InstrumentData as Instrument;
+pub use soundfonts::sfz::diagnostics::{Diagnostic, Diagnostics, SharedDiagnostics};
+pub use soundfonts::sfz::mutes::{RegionMutes, SharedRegionMutes};
+pub use soundfonts::sfz::observer::{EngineEvent, EngineObserver, EventQueue};
+pub use soundfonts::sfz::telemetry::{SharedTelemetry, Telemetry};
+pub use soundfonts::sfz::{LoadStats, ParserError, SampleData, SampleEncoding, SampleProvider};
+pub use soundfonts::tuning::Tuning;
+pub use soundfonts::{RangeError, SampleFileError};
+pub use soundfonts::wmidi;
+
+/// A MIDI message as passed to `Engine::midi_event()`
+pub use soundfonts::wmidi::MidiMessage;
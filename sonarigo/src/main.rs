@@ -0,0 +1,46 @@
+use std::env;
+use std::process;
+
+mod args;
+mod automap;
+mod check;
+mod map;
+mod render;
+
+const USAGE: &str = "\
+Usage: sonarigo <COMMAND> [ARGS]
+
+Commands:
+  render    render a MIDI file with an SFZ instrument into a WAV file
+  check     check SFZ files for problems
+  automap   create an SFZ file mapping a folder of samples
+  map       print the key, velocity and controller layout of an SFZ file as JSON
+  help      show this help
+
+Run `sonarigo <COMMAND> --help` for the options of a command. Set the
+environment variable SONARIGO_VERBOSE to log what is going on.";
+
+const VERBOSE_VARIABLE: &str = "SONARIGO_VERBOSE";
+
+fn main() {
+    soundfonts::logging::init(env::var_os(VERBOSE_VARIABLE).is_some());
+    let mut args = env::args().skip(1);
+
+    let result = match args.next().as_deref() {
+        Some("render") => render::run(args),
+        Some("check") => check::run(args),
+        Some("map") => map::run(args),
+        Some("automap") => automap::run(args),
+        Some("help") | Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(cmd) => Err(format!("Unknown command: {}\n\n{}", cmd, USAGE)),
+        None => Err(USAGE.to_string()),
+    };
+
+    if let Err(msg) = result {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+}
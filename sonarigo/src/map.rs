@@ -0,0 +1,31 @@
+use soundfonts::sfz;
+
+const USAGE: &str = "\
+Usage: sonarigo map <SFZ_FILE>
+
+Prints the key, velocity and controller layout of the regions of the SFZ file
+as JSON.
+
+Options:
+  -h, --help     show this help";
+
+pub fn run<I: Iterator<Item = String>>(args: I) -> Result<(), String> {
+    let mut file = None;
+
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(())
+            }
+            a if a.starts_with('-') => return Err(format!("Unknown option: {}\n\n{}", a, USAGE)),
+            _ if file.is_some() => return Err(USAGE.to_string()),
+            _ => file = Some(arg),
+        }
+    }
+
+    let file = file.ok_or_else(|| USAGE.to_string())?;
+    let map = sfz::map_file(&file).map_err(|e| e.to_string())?;
+    println!("{}", map);
+    Ok(())
+}
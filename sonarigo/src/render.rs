@@ -0,0 +1,116 @@
+use soundfonts::render::{Automation, Renderer, SampleFormat};
+use soundfonts::sfz::engine::{Engine, EngineConfig};
+use soundfonts::smf;
+
+use crate::args::{parse_value, value_for};
+
+const USAGE: &str = "\
+Usage: sonarigo render [OPTIONS] <SFZ_FILE> <MIDI_FILE> <WAV_FILE>
+
+Options:
+  -r, --samplerate <Hz>      samplerate of the output (default: 48000)
+  -b, --block-length <n>     frames processed at once (default: 1024)
+  -g, --gain <dB>            output gain in dB (default: 0)
+  -f, --format <format>      16, 24 or float (default: 24)
+  -t, --tail <seconds>       maximum time to let the notes ring out after the
+                             last MIDI event (default: 10)
+  -s, --seed <n>             seed of the random choice of regions by lorand
+                             and hirand, to render reproducibly
+  -h, --help                 show this help";
+
+struct Options {
+    sfz_file: String,
+    midi_file: String,
+    wav_file: String,
+    samplerate: usize,
+    block_length: usize,
+    gain_db: f32,
+    format: SampleFormat,
+    tail: f64,
+    seed: Option<u64>,
+}
+
+fn parse_format(value: String) -> Result<SampleFormat, String> {
+    match value.as_str() {
+        "16" => Ok(SampleFormat::Pcm16),
+        "24" => Ok(SampleFormat::Pcm24),
+        "float" => Ok(SampleFormat::Float),
+        _ => Err(format!("--format: invalid value {}", value)),
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut files = Vec::new();
+    let mut samplerate = 48000;
+    let mut block_length = 1024;
+    let mut gain_db = 0.0;
+    let mut format = SampleFormat::Pcm24;
+    let mut tail = 10.0;
+    let mut seed = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" | "--samplerate" => samplerate = parse_value(&arg, value_for(&arg, &mut args)?)?,
+            "-b" | "--block-length" => block_length = parse_value(&arg, value_for(&arg, &mut args)?)?,
+            "-g" | "--gain" => gain_db = parse_value(&arg, value_for(&arg, &mut args)?)?,
+            "-f" | "--format" => format = parse_format(value_for(&arg, &mut args)?)?,
+            "-t" | "--tail" => tail = parse_value(&arg, value_for(&arg, &mut args)?)?,
+            "-s" | "--seed" => seed = Some(parse_value(&arg, value_for(&arg, &mut args)?)?),
+            "-h" | "--help" => return Ok(None),
+            a if a.starts_with('-') => return Err(format!("Unknown option: {}\n\n{}", a, USAGE)),
+            _ => files.push(arg),
+        }
+    }
+
+    if block_length == 0 {
+        return Err("--block-length must not be 0".to_string());
+    }
+
+    match files.len() {
+        3 => {
+            let mut files = files.into_iter();
+            Ok(Some(Options {
+                sfz_file: files.next().unwrap(),
+                midi_file: files.next().unwrap(),
+                wav_file: files.next().unwrap(),
+                samplerate, block_length, gain_db, format, tail, seed,
+            }))
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+pub fn run<I: Iterator<Item = String>>(args: I) -> Result<(), String> {
+    let options = match parse_args(args)? {
+        Some(o) => o,
+        None => {
+            println!("{}", USAGE);
+            return Ok(())
+        }
+    };
+
+    let config = EngineConfig { random_seed: options.seed, ..Default::default() };
+    let engine = Engine::new_with_config(options.sfz_file.clone(), options.samplerate as f64, options.block_length, &config)
+        .map_err(|e| format!("Could not load {}: {}", options.sfz_file, e))?;
+    let events = smf::read_smf(&options.midi_file, options.samplerate as f64)
+        .map_err(|e| format!("Could not read {}: {}", options.midi_file, e))?;
+
+    let nframes = events.iter().map(|ev| ev.frame + 1).max().unwrap_or(0);
+
+    let mut renderer = Renderer::new(engine, options.block_length);
+    renderer.set_gain_automation(Automation::constant(options.gain_db));
+
+    let (mut left, mut right) = renderer.render(events, nframes);
+
+    let max_tail = (options.tail * options.samplerate as f64) as usize;
+    let mut tail = 0;
+    while tail < max_tail && !renderer.engine().fadeout_finished() {
+        let (l, r) = renderer.render(Vec::new(), options.block_length);
+        left.extend_from_slice(&l);
+        right.extend_from_slice(&r);
+        tail += options.block_length;
+    }
+
+    soundfonts::render::write_wav(&options.wav_file, &left, &right, options.samplerate, options.format)
+        .map_err(|e| format!("Could not write {}: {:?}", options.wav_file, e))
+}
@@ -0,0 +1,69 @@
+use std::convert::TryFrom;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use wmidi::{Channel, MidiMessage, Note, Velocity};
+
+use soundfonts::bench;
+use soundfonts::engine::EngineTrait;
+use soundfonts::sfz::engine::Engine;
+
+const SAMPLERATE: f64 = 48000.0;
+const BLOCK_LENGTH: usize = 256;
+const SAMPLE_FRAMES: usize = 10 * 48000;
+
+fn cubic_interpolation(c: &mut Criterion) {
+    let data = bench::sine_sample_data(4096, SAMPLERATE, 440.0);
+    c.bench_function("cubic", |b| b.iter(|| {
+        let mut sum = 0.0;
+        for pos in 1..4000 {
+            sum += bench::cubic(black_box(&data[0]), pos, black_box(0.3));
+        }
+        sum
+    }));
+    let frames: Vec<usize> = (1..4000).collect();
+    let remainders = vec![0.3; frames.len()];
+    let (mut left, mut right) = (vec![0.0; frames.len()], vec![0.0; frames.len()]);
+    c.bench_function("cubic_stereo", |b| b.iter(|| {
+        bench::cubic_stereo(black_box(&data[0]), black_box(&data[1]), &frames, black_box(&remainders), &mut left, &mut right);
+        left[0] + right[0]
+    }));
+}
+
+fn sample_process(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sample_process");
+    for voices in [1usize, 8, 32].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(voices), voices, |b, &voices| {
+            let mut sample = bench::sine_sample(SAMPLE_FRAMES, SAMPLERATE, 440.0, BLOCK_LENGTH);
+            for n in 0..voices {
+                let note = Note::try_from(40 + n as u8).unwrap();
+                sample.note_on(note, note.to_freq_f64(), 0.1);
+            }
+            let mut left = vec![0.0; BLOCK_LENGTH];
+            let mut right = vec![0.0; BLOCK_LENGTH];
+            b.iter(|| sample.process(&mut left, &mut right));
+        });
+    }
+    group.finish();
+}
+
+fn engine_process(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine_process");
+    for voices in [64usize, 128, 256].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(voices), voices, |b, &voices| {
+            let layers = (voices + 127) / 128;
+            let mut engine = Engine::synthetic(layers, SAMPLE_FRAMES, SAMPLERATE, BLOCK_LENGTH);
+            for n in 0..(voices / layers) {
+                let note = Note::try_from(n as u8).unwrap();
+                engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, note, Velocity::MAX));
+            }
+            let mut left = vec![0.0; BLOCK_LENGTH];
+            let mut right = vec![0.0; BLOCK_LENGTH];
+            b.iter(|| engine.process(&mut left, &mut right));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, cubic_interpolation, sample_process, engine_process);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+// Access to the DSP internals for the benchmarks, only available with the
+// feature "bench". Not meant as API for anything else.
+
+use crate::envelopes;
+use crate::sample;
+use crate::simd;
+
+pub use crate::sample::Sample;
+
+pub fn cubic(sample_data: &[f32], pos: usize, remainder: f64) -> f32 {
+    sample::cubic(sample_data, pos, remainder)
+}
+
+pub fn cubic_stereo(left: &[f32],
+                    right: &[f32],
+                    frames: &[usize],
+                    remainders: &[f64],
+                    out_left: &mut [f32],
+                    out_right: &mut [f32]) {
+    simd::cubic_stereo(left, right, frames, remainders, out_left, out_right)
+}
+
+/// Stereo sine wave, both channels in phase
+pub fn sine_sample_data(frames: usize, samplerate: f64, freq: f64) -> Vec<Vec<f32>> {
+    let omega = freq / samplerate * 2.0 * std::f64::consts::PI;
+    let channel: Vec<f32> = (0..frames)
+        .map(|t| (omega * t as f64).sin() as f32)
+        .collect();
+    vec![channel.clone(), channel]
+}
+
+/// Sample with the default envelope playing at `freq` when played at its native pitch
+pub fn sine_sample(frames: usize, samplerate: f64, freq: f64, max_block_length: usize) -> Sample {
+    let envelope = envelopes::ADSREnvelope::new(&envelopes::Generator::default(), samplerate as f32);
+    Sample::new(sine_sample_data(frames, samplerate, freq), max_block_length, freq, envelope)
+}
@@ -0,0 +1,181 @@
+// Built-in effect buses fed by the effect1 (reverb) and effect2 (chorus) sends
+
+use std::f32::consts::PI;
+
+// lengths of the reverb's delay lines in seconds, mutually prime at 48 kHz
+const REVERB_DELAYS: [f32; 4] = [0.0297, 0.0371, 0.0411, 0.0437];
+const REVERB_DECAY_TIME: f32 = 2.0;
+const REVERB_DAMPING_FREQUENCY: f32 = 6000.0;
+
+const CHORUS_DELAY: f32 = 0.02;
+const CHORUS_DEPTH: f32 = 0.003;
+const CHORUS_RATE: f32 = 0.8;
+
+struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(length: usize) -> DelayLine {
+        DelayLine { buffer: vec![0.0; length.max(1)], position: 0 }
+    }
+
+    fn output(&self) -> f32 {
+        self.buffer[self.position]
+    }
+
+    // the value pushed `delay` frames ago, linearly interpolated
+    fn tap(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let delay = delay.max(0.0).min(len.saturating_sub(2) as f32);
+        let whole = delay as usize;
+        let frac = delay - whole as f32;
+        let a = self.buffer[(self.position + 2 * len - 1 - whole) % len];
+        let b = self.buffer[(self.position + 2 * len - 2 - whole) % len];
+        a + frac * (b - a)
+    }
+
+    fn push(&mut self, v: f32) {
+        self.buffer[self.position] = v;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+}
+
+/// Feedback delay network reverb of four delay lines mixed by a Hadamard
+/// matrix, fed by the mono sum of its input
+pub struct Reverb {
+    lines: Vec<DelayLine>,
+    feedback: [f32; 4],
+    damping: f32,
+    damping_state: [f32; 4],
+}
+
+impl Reverb {
+    pub fn new(samplerate: f32) -> Reverb {
+        let mut feedback = [0.0; 4];
+        for (fb, delay) in Iterator::zip(feedback.iter_mut(), REVERB_DELAYS.iter()) {
+            // -60 dB after REVERB_DECAY_TIME
+            *fb = 10.0f32.powf(-3.0 * delay / REVERB_DECAY_TIME);
+        }
+        Reverb {
+            lines: REVERB_DELAYS.iter().map(|d| DelayLine::new((d * samplerate) as usize)).collect(),
+            feedback,
+            damping: 1.0 - (-2.0 * PI * REVERB_DAMPING_FREQUENCY / samplerate).exp(),
+            damping_state: [0.0; 4],
+        }
+    }
+
+    /// Adds the reverb of the input to the output
+    pub fn process(&mut self, in_left: &[f32], in_right: &[f32], out_left: &mut [f32], out_right: &mut [f32]) {
+        let frames = Iterator::zip(in_left.iter(), in_right.iter()).zip(Iterator::zip(out_left.iter_mut(), out_right.iter_mut()));
+        for ((il, ir), (ol, or)) in frames {
+            let mut d = [0.0; 4];
+            for (i, line) in self.lines.iter().enumerate() {
+                self.damping_state[i] += self.damping * (line.output() - self.damping_state[i]);
+                d[i] = self.damping_state[i];
+            }
+            *ol += 0.5 * (d[0] + d[2]);
+            *or += 0.5 * (d[1] + d[3]);
+
+            let input = 0.25 * (il + ir);
+            let mixed = [
+                0.5 * (d[0] + d[1] + d[2] + d[3]),
+                0.5 * (d[0] - d[1] + d[2] - d[3]),
+                0.5 * (d[0] + d[1] - d[2] - d[3]),
+                0.5 * (d[0] - d[1] - d[2] + d[3]),
+            ];
+            for (i, line) in self.lines.iter_mut().enumerate() {
+                line.push(input + self.feedback[i] * mixed[i]);
+            }
+        }
+    }
+}
+
+/// Stereo chorus, a delay line per channel modulated by sine LFOs in
+/// quadrature
+pub struct Chorus {
+    lines: [DelayLine; 2],
+    samplerate: f32,
+    phase: f32,
+}
+
+impl Chorus {
+    pub fn new(samplerate: f32) -> Chorus {
+        let length = ((CHORUS_DELAY + CHORUS_DEPTH) * samplerate) as usize + 2;
+        Chorus {
+            lines: [DelayLine::new(length), DelayLine::new(length)],
+            samplerate,
+            phase: 0.0,
+        }
+    }
+
+    /// Adds the chorused input to the output
+    pub fn process(&mut self, in_left: &[f32], in_right: &[f32], out_left: &mut [f32], out_right: &mut [f32]) {
+        let phase_step = 2.0 * PI * CHORUS_RATE / self.samplerate;
+        let frames = Iterator::zip(in_left.iter(), in_right.iter()).zip(Iterator::zip(out_left.iter_mut(), out_right.iter_mut()));
+        for ((il, ir), (ol, or)) in frames {
+            let [left, right] = &mut self.lines;
+            left.push(*il);
+            right.push(*ir);
+            *ol += left.tap((CHORUS_DELAY + CHORUS_DEPTH * self.phase.sin()) * self.samplerate);
+            *or += right.tap((CHORUS_DELAY + CHORUS_DEPTH * self.phase.cos()) * self.samplerate);
+
+            self.phase += phase_step;
+            if self.phase > 2.0 * PI {
+                self.phase -= 2.0 * PI;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse(length: usize) -> Vec<f32> {
+        let mut buffer = vec![0.0; length];
+        buffer[0] = 1.0;
+        buffer
+    }
+
+    #[test]
+    fn reverb_tail_decays() {
+        let samplerate = 48000.0;
+        let mut reverb = Reverb::new(samplerate);
+        let input = impulse(48000);
+        let silence = vec![0.0; 48000];
+        let mut out_left = vec![0.0; 48000];
+        let mut out_right = vec![0.0; 48000];
+        reverb.process(&input, &input, &mut out_left, &mut out_right);
+
+        let energy = |buffer: &[f32]| buffer.iter().map(|v| v * v).sum::<f32>();
+        let first = energy(&out_left[..24000]) + energy(&out_right[..24000]);
+        let second = energy(&out_left[24000..]) + energy(&out_right[24000..]);
+        assert!(first > 0.0);
+        assert!(second > 0.0 && second < first);
+
+        let mut out_left = vec![0.0; 48000];
+        let mut out_right = vec![0.0; 48000];
+        for _ in 0..4 {
+            out_left.iter_mut().chain(out_right.iter_mut()).for_each(|v| *v = 0.0);
+            reverb.process(&silence, &silence, &mut out_left, &mut out_right);
+        }
+        assert!(out_left.iter().chain(out_right.iter()).all(|v| v.abs() < 1e-3));
+    }
+
+    #[test]
+    fn chorus_delays_input() {
+        let samplerate = 1000.0;
+        let mut chorus = Chorus::new(samplerate);
+        let input = impulse(100);
+        let mut out_left = vec![0.0; 100];
+        let mut out_right = vec![0.0; 100];
+        chorus.process(&input, &input, &mut out_left, &mut out_right);
+
+        assert!(out_left[..15].iter().all(|v| *v == 0.0));
+        // the modulation stretches the impulse slightly
+        assert!((out_left.iter().sum::<f32>() - 1.0).abs() < 0.05);
+        assert!((out_right.iter().sum::<f32>() - 1.0).abs() < 0.05);
+    }
+}
@@ -1,8 +1,164 @@
 
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use wmidi;
 
 pub trait EngineTrait {
     fn midi_event(&mut self, midi_msg: &wmidi::MidiMessage);
 
+    /// Queues `midi_msg` to be dispatched `frame_offset` frames into the
+    /// next call of `process()`
+    fn midi_event_at(&mut self, frame_offset: usize, midi_msg: &wmidi::MidiMessage);
+
     fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]);
+
+    /// Tempo of the host in beats per minute, ignored by engines not
+    /// depending on it
+    fn set_tempo(&mut self, _bpm: f64) {}
+}
+
+// the channel messages, which are the only ones the engine reacts to, without
+// the lifetime of SysEx data
+pub(crate) fn owned_message(midi_msg: &wmidi::MidiMessage) -> Option<wmidi::MidiMessage<'static>> {
+    use wmidi::MidiMessage::*;
+    match *midi_msg {
+        NoteOff(ch, note, vel) => Some(NoteOff(ch, note, vel)),
+        NoteOn(ch, note, vel) => Some(NoteOn(ch, note, vel)),
+        PolyphonicKeyPressure(ch, note, pressure) => Some(PolyphonicKeyPressure(ch, note, pressure)),
+        ControlChange(ch, cc, value) => Some(ControlChange(ch, cc, value)),
+        ProgramChange(ch, program) => Some(ProgramChange(ch, program)),
+        ChannelPressure(ch, pressure) => Some(ChannelPressure(ch, pressure)),
+        PitchBendChange(ch, bend) => Some(PitchBendChange(ch, bend)),
+        _ => None,
+    }
+}
+
+type MidiSlot = UnsafeCell<Option<(usize, wmidi::MidiMessage<'static>)>>;
+
+// `head` and `tail` count the events popped and pushed, each slot is only
+// touched by the side whose turn it is
+struct MidiRing {
+    slots: Box<[MidiSlot]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for MidiRing {}
+
+/// Creates a lock free queue of at most `capacity` MIDI events to be pushed
+/// by one thread like a GUI or a network thread and dispatched to the engine
+/// in the realtime thread. Neither side allocates or blocks.
+pub fn midi_ring(capacity: usize) -> (MidiProducer, MidiConsumer) {
+    let ring = Arc::new(MidiRing {
+        slots: (0..capacity.max(1)).map(|_| UnsafeCell::new(None)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (MidiProducer { ring: ring.clone() }, MidiConsumer { ring })
+}
+
+/// The pushing end of a `midi_ring()`
+pub struct MidiProducer {
+    ring: Arc<MidiRing>,
+}
+
+impl MidiProducer {
+    /// Queues `midi_msg` to be dispatched `frame_offset` frames into the block
+    /// of the consumer's next `drain_into()`. Returns `false` if the queue is
+    /// full or `midi_msg` is not a channel message.
+    pub fn push(&mut self, frame_offset: usize, midi_msg: &wmidi::MidiMessage) -> bool {
+        let msg = match owned_message(midi_msg) {
+            Some(msg) => msg,
+            None => return false,
+        };
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let head = self.ring.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == self.ring.slots.len() {
+            return false;
+        }
+        let slot = &self.ring.slots[tail % self.ring.slots.len()];
+        unsafe { *slot.get() = Some((frame_offset, msg)) };
+        self.ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+}
+
+/// The realtime end of a `midi_ring()`
+pub struct MidiConsumer {
+    ring: Arc<MidiRing>,
+}
+
+impl MidiConsumer {
+    /// The oldest event with its frame offset
+    pub fn pop(&mut self) -> Option<(usize, wmidi::MidiMessage<'static>)> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let slot = &self.ring.slots[head % self.ring.slots.len()];
+        let event = unsafe { (*slot.get()).take() };
+        self.ring.head.store(head.wrapping_add(1), Ordering::Release);
+        event
+    }
+
+    /// Passes all queued events to `engine.midi_event_at()`, to be called
+    /// right before `engine.process()`
+    pub fn drain_into<E: EngineTrait + ?Sized>(&mut self, engine: &mut E) {
+        while let Some((frame_offset, midi_msg)) = self.pop() {
+            engine.midi_event_at(frame_offset, &midi_msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::thread;
+    use wmidi::{Channel, MidiMessage, Note, Velocity};
+
+    #[test]
+    fn midi_ring_full_and_empty() {
+        let (mut producer, mut consumer) = midi_ring(2);
+        assert_eq!(consumer.pop(), None);
+        assert!(producer.push(0, &MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX)));
+        assert!(producer.push(5, &MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX)));
+        assert!(!producer.push(7, &MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX)));
+        assert!(!producer.push(0, &MidiMessage::SysEx(&[])));
+
+        assert_eq!(consumer.pop(), Some((0, MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX))));
+        assert!(producer.push(7, &MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX)));
+        assert_eq!(consumer.pop(), Some((5, MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX))));
+        assert_eq!(consumer.pop(), Some((7, MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX))));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn midi_ring_across_threads() {
+        let (mut producer, mut consumer) = midi_ring(8);
+        let pusher = thread::spawn(move || {
+            for i in 0..1000usize {
+                let msg = MidiMessage::ControlChange(Channel::Ch1, wmidi::ControlNumber::try_from(1).unwrap(),
+                                                     wmidi::ControlValue::try_from((i % 128) as u8).unwrap());
+                while !producer.push(i, &msg) {
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut received = 0;
+        while received < 1000 {
+            match consumer.pop() {
+                Some((frame, _)) => {
+                    assert_eq!(frame, received);
+                    received += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        pusher.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
 }
@@ -1,36 +1,64 @@
-use log::error;
+use std::collections::HashMap;
 
 use crate::errors::*;
 
-use super::utils;
+// -160 dB, below which a releasing envelope ends
+const SILENCE: f32 = 1e-8;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub(crate) struct Generator {
+    delay: f32,
+    start: f32,
     attack: f32,
     hold: f32,
     decay: f32,
     sustain: f32,
     release: f32,
+
+    vel2attack: f32,
+    vel2hold: f32,
+    vel2decay: f32,
+    vel2sustain: f32,
+    vel2release: f32,
+
+    delay_oncc: HashMap<u8, f32>,
+    start_oncc: HashMap<u8, f32>,
 }
 
 impl Default for Generator {
     fn default() -> Self {
         Generator {
+            delay: 0.0,
+            start: 0.0,
             attack: 0.0,
             hold: 0.0,
             decay: 0.0,
             sustain: 1.0,
             release: 0.0,
+
+            vel2attack: 0.0,
+            vel2hold: 0.0,
+            vel2decay: 0.0,
+            vel2sustain: 0.0,
+            vel2release: 0.0,
+
+            delay_oncc: HashMap::new(),
+            start_oncc: HashMap::new(),
         }
     }
 }
 
-fn calc_needed_samples(length: f32, samplerate: f32, max_block_length: usize) -> usize {
-    let needed_samples = (length * samplerate).round() as usize;
-    ((needed_samples / max_block_length) + 2) * max_block_length
-}
-
 impl Generator {
+    pub(crate) fn set_delay(&mut self, v: f32) -> Result<(), RangeError> {
+        self.delay = range_check(v, 0.0, 100.0, "ampeg_delay")?;
+        Ok(())
+    }
+    pub(crate) fn set_start(&mut self, v: f32) -> Result<(), RangeError> {
+        self.start = range_check(v, 0.0, 100.0, "ampeg_start")? / 100.0;
+        Ok(())
+    }
     pub(crate) fn set_attack(&mut self, v: f32) -> Result<(), RangeError> {
         self.attack = range_check(v, 0.0, 100.0, "ampeg_attack")?;
         Ok(())
@@ -51,134 +79,256 @@ impl Generator {
         self.release = range_check(v, 0.0, 100.0, "ampeg_release")?;
         Ok(())
     }
+    pub(crate) fn set_vel2attack(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2attack = range_check(v, -100.0, 100.0, "ampeg_vel2attack")?;
+        Ok(())
+    }
+    pub(crate) fn set_vel2hold(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2hold = range_check(v, -100.0, 100.0, "ampeg_vel2hold")?;
+        Ok(())
+    }
+    pub(crate) fn set_vel2decay(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2decay = range_check(v, -100.0, 100.0, "ampeg_vel2decay")?;
+        Ok(())
+    }
+    pub(crate) fn set_vel2sustain(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2sustain = range_check(v, -100.0, 100.0, "ampeg_vel2sustain")? / 100.0;
+        Ok(())
+    }
+    pub(crate) fn set_vel2release(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2release = range_check(v, -100.0, 100.0, "ampeg_vel2release")?;
+        Ok(())
+    }
 
-    fn ads_envelope(&self, samplerate: f32, max_block_length: usize) -> Vec<f32> {
-        let length = calc_needed_samples(
-            self.attack + self.hold + 2.0 * self.decay,
-            samplerate,
-            max_block_length,
-        );
-
-        let mut env = Vec::with_capacity(length);
-        env.resize(length, 0.0);
-
-        let decay_step = (-8.0 / (samplerate * self.decay)).exp();
-        let mut time = 0;
-        let mut last = 1.0 - self.sustain;
-
-        for e in env.iter_mut() {
-            *e = match time as f32 / samplerate {
-                t if t < self.attack => t / self.attack,
-                t if t < self.attack + self.hold => 1.0,
-                t if t < self.attack + self.hold + 2.0 * self.decay => {
-                    last *= decay_step;
-                    self.sustain + last
-                }
-                _ => self.sustain,
-            };
-            time += 1;
-        }
-        env
+    pub(crate) fn set_delay_oncc(&mut self, cc: u8, v: f32) -> Result<(), RangeError> {
+        self.delay_oncc.insert(cc, range_check(v, -100.0, 100.0, "ampeg_delay_oncc")?);
+        Ok(())
+    }
+    pub(crate) fn set_start_oncc(&mut self, cc: u8, v: f32) -> Result<(), RangeError> {
+        self.start_oncc.insert(cc, range_check(v, -100.0, 100.0, "ampeg_start_oncc")? / 100.0);
+        Ok(())
     }
 
-    fn sustain_envelope(&self, nsamples: usize) -> Vec<f32> {
-        let mut sustain = Vec::new();
-        sustain.resize(nsamples, self.sustain);
-        sustain
+    /// The SFZ opcodes setting the generator with their values, the ones at
+    /// their defaults left out
+    pub(crate) fn opcodes(&self) -> Vec<(String, f32)> {
+        let default = Generator::default();
+        let mut opcodes: Vec<(String, f32)> = [
+            ("ampeg_delay", self.delay, default.delay),
+            ("ampeg_start", self.start * 100.0, default.start * 100.0),
+            ("ampeg_attack", self.attack, default.attack),
+            ("ampeg_hold", self.hold, default.hold),
+            ("ampeg_decay", self.decay, default.decay),
+            ("ampeg_sustain", self.sustain * 100.0, default.sustain * 100.0),
+            ("ampeg_release", self.release, default.release),
+            ("ampeg_vel2attack", self.vel2attack, default.vel2attack),
+            ("ampeg_vel2hold", self.vel2hold, default.vel2hold),
+            ("ampeg_vel2decay", self.vel2decay, default.vel2decay),
+            ("ampeg_vel2sustain", self.vel2sustain * 100.0, default.vel2sustain * 100.0),
+            ("ampeg_vel2release", self.vel2release, default.vel2release),
+        ].iter()
+            .filter(|(_, v, default)| v != default)
+            .map(|(name, v, _)| (name.to_string(), *v))
+            .collect();
+        let mut oncc = |name: &str, values: &HashMap<u8, f32>, scale: f32| {
+            let mut values: Vec<_> = values.iter().collect();
+            values.sort_by_key(|(cc, _)| **cc);
+            opcodes.extend(values.into_iter().map(|(cc, v)| (format!("{}_oncc{}", name, cc), v * scale)));
+        };
+        oncc("ampeg_delay", &self.delay_oncc, 1.0);
+        oncc("ampeg_start", &self.start_oncc, 100.0);
+        opcodes
     }
 
-    fn release_envelope(&self, samplerate: f32, max_block_length: usize) -> Vec<f32> {
-        let length = calc_needed_samples(2.0 * self.release, samplerate, max_block_length);
-        let mut env = Vec::new();
-        env.resize(length, 0.0);
+    pub(crate) fn has_velocity_modulation(&self) -> bool {
+        [self.vel2attack, self.vel2hold, self.vel2decay, self.vel2sustain, self.vel2release]
+            .iter()
+            .any(|v| *v != 0.0)
+    }
 
-        let release_step = (-8.0 / (samplerate * self.release)).exp();
-        let mut last = self.sustain;
+    pub(crate) fn has_cc_modulation(&self) -> bool {
+        !self.delay_oncc.is_empty() || !self.start_oncc.is_empty()
+    }
 
-        for e in env.iter_mut() {
-            last *= release_step;
-            *e = last;
+    /// The generator with the *_oncc modulations applied for the current
+    /// `controllers` values
+    pub(crate) fn for_controllers(&self, controllers: &[u8; 128]) -> Generator {
+        let modulation = |oncc: &HashMap<u8, f32>| -> f32 {
+            oncc.iter().map(|(cc, v)| v * controllers[*cc as usize] as f32 / 127.0).sum()
+        };
+        Generator {
+            delay: (self.delay + modulation(&self.delay_oncc)).max(0.0),
+            start: (self.start + modulation(&self.start_oncc)).max(0.0).min(1.0),
+            delay_oncc: HashMap::new(),
+            start_oncc: HashMap::new(),
+            ..self.clone()
         }
+    }
 
-        env
+    /// The generator with the vel2* modulations applied for `velocity`
+    pub(crate) fn for_velocity(&self, velocity: u8) -> Generator {
+        let v = velocity as f32 / 127.0;
+        Generator {
+            delay: self.delay,
+            start: self.start,
+            attack: (self.attack + v * self.vel2attack).max(0.0),
+            hold: (self.hold + v * self.vel2hold).max(0.0),
+            decay: (self.decay + v * self.vel2decay).max(0.0),
+            sustain: (self.sustain + v * self.vel2sustain).max(0.0).min(1.0),
+            release: (self.release + v * self.vel2release).max(0.0),
+            ..Generator::default()
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum State {
-    AttackDecay(usize),
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    AttackDecay,
     Sustain,
-    Release(usize),
+    Release,
     Inactive,
 }
 
+/// Position of a voice in its envelope
+#[derive(Debug, Clone, Copy)]
+pub struct State {
+    stage: Stage,
+    frame: usize,
+    level: f32,
+}
+
 impl State {
+    pub fn attack() -> State {
+        State { stage: Stage::AttackDecay, frame: 0, level: 0.0 }
+    }
+
+    pub fn release() -> State {
+        State { stage: Stage::Release, frame: 0, level: 0.0 }
+    }
+
+    pub fn inactive() -> State {
+        State { stage: Stage::Inactive, frame: 0, level: 0.0 }
+    }
+
     pub fn is_active(&self) -> bool {
-        match *self {
-            State::Inactive => false,
-            _ => true,
-        }
+        self.stage != Stage::Inactive
     }
 
     pub fn is_releasing(&self) -> bool {
-        match *self {
-            State::Inactive | State::Release(_) => true,
+        match self.stage {
+            Stage::Inactive | Stage::Release => true,
             _ => false,
         }
     }
+
+    /// Whether the envelope is past its attack and decay, so that it does
+    /// not rise anymore
+    pub fn is_past_attack(&self) -> bool {
+        self.stage != Stage::AttackDecay
+    }
 }
 
+/// Envelope generator computing the envelope values on the fly, the state
+/// of a voice's envelope is kept in its `State`.
+#[derive(Debug, Clone)]
 pub struct ADSREnvelope {
-    attack_decay_envelope: Vec<f32>,
-    sustain_envelope: Vec<f32>,
-    release_envelope: Vec<f32>,
+    delay: f32,
+    start: f32,
+    attack: f32,
+    hold: f32,
+    decay: f32,
+    sustain: f32,
 
-    max_block_length: usize,
+    samplerate: f32,
+    decay_step: f32,
+    release_step: f32,
+    release_frames: usize,
 }
 
 impl ADSREnvelope {
-    pub(crate) fn new(generator: &Generator, samplerate: f32, max_block_length: usize) -> Self {
+    pub(crate) fn new(generator: &Generator, samplerate: f32) -> Self {
         ADSREnvelope {
-            attack_decay_envelope: generator.ads_envelope(samplerate, max_block_length),
-            sustain_envelope: generator.sustain_envelope(max_block_length),
-            release_envelope: generator.release_envelope(samplerate, max_block_length),
+            delay: generator.delay,
+            start: generator.start,
+            attack: generator.attack,
+            hold: generator.hold,
+            decay: generator.decay,
+            sustain: generator.sustain,
 
-            max_block_length: max_block_length,
+            samplerate,
+            decay_step: (-8.0 / (samplerate * generator.decay)).exp(),
+            release_step: (-8.0 / (samplerate * generator.release)).exp(),
+            release_frames: (2.0 * generator.release * samplerate).round() as usize,
         }
     }
 
-    pub(crate) fn active_envelope(&self, state: State) -> (&Vec<f32>, usize) {
-        match state {
-            State::AttackDecay(pos) => (&self.attack_decay_envelope, pos),
-            State::Release(pos) => (&self.release_envelope, pos),
-            State::Sustain => (&self.sustain_envelope, 0),
-            State::Inactive => {
-                error!("Ordered envelope while inactive. This should not happen. Using sustain.");
-                (&self.sustain_envelope, 0)
-            }
-        }
+    /// Length of the release in frames
+    pub(crate) fn release_frames(&self) -> usize {
+        self.release_frames
     }
 
-    pub(crate) fn update_state(&self, state: &mut State, new_pos: usize) {
-        *state = match &state {
-            State::AttackDecay(_) => {
-                if new_pos < self.attack_decay_envelope.len() - self.max_block_length {
-                    State::AttackDecay(new_pos)
-                } else {
-                    State::Sustain
+    /// Writes the envelope values of the next `out.len()` frames to `out`
+    /// and advances `state`. Returns whether the envelope was inactive, which
+    /// should not happen, the frames are silent then.
+    pub(crate) fn process(&self, state: &mut State, out: &mut [f32]) -> bool {
+        let inactive = state.stage == Stage::Inactive;
+        for o in out.iter_mut() {
+            *o = self.next(state);
+        }
+
+        match state.stage {
+            Stage::AttackDecay => {
+                let t = state.frame as f32 / self.samplerate - self.delay;
+                if t >= self.attack + self.hold + 2.0 * self.decay {
+                    state.stage = Stage::Sustain;
                 }
             }
-            State::Release(_) => {
-                if new_pos < self.release_envelope.len() - self.max_block_length
-                    && self.release_envelope[new_pos] > utils::dB_to_gain(-160.0)
+            Stage::Release => {
+                if state.frame >= self.release_frames
+                    || self.next_value(state) <= SILENCE
                 {
-                    State::Release(new_pos)
-                } else {
-                    State::Inactive
+                    *state = State::inactive();
                 }
             }
-            s => **s,
+            _ => {}
         }
+        inactive
+    }
+
+    /// The envelope value of the next frame without advancing `state`
+    pub(crate) fn next_value(&self, state: &State) -> f32 {
+        self.next(&mut state.clone())
+    }
+
+    fn next(&self, state: &mut State) -> f32 {
+        let value = match state.stage {
+            Stage::AttackDecay => {
+                if state.frame == 0 {
+                    state.level = 1.0 - self.sustain;
+                }
+                match state.frame as f32 / self.samplerate - self.delay {
+                    t if t < 0.0 => 0.0,
+                    t if t < self.attack => self.start + (1.0 - self.start) * t / self.attack,
+                    t if t < self.attack + self.hold => 1.0,
+                    t if t < self.attack + self.hold + 2.0 * self.decay => {
+                        state.level *= self.decay_step;
+                        self.sustain + state.level
+                    }
+                    _ => self.sustain,
+                }
+            }
+            Stage::Sustain => self.sustain,
+            Stage::Release => {
+                if state.frame == 0 {
+                    state.level = self.sustain;
+                }
+                state.level *= self.release_step;
+                state.level
+            }
+            Stage::Inactive => 0.0,
+        };
+        state.frame += 1;
+        value
     }
 }
 
@@ -187,13 +337,28 @@ mod tests {
 
     use super::*;
 
+    fn render(envelope: &ADSREnvelope, state: &mut State, nframes: usize) -> Vec<f32> {
+        let mut out = vec![0.0; nframes];
+        envelope.process(state, &mut out);
+        out
+    }
+
     #[test]
-    fn adsr_envelope_length() {
-        let eg = Generator::default();
+    fn default_envelope() {
+        let env = ADSREnvelope::new(&Generator::default(), 1.0);
 
-        assert_eq!(eg.ads_envelope(1.0, 8).as_slice(), [1.0; 16]);
-        assert_eq!(eg.sustain_envelope(8).as_slice(), [1.0; 8]);
-        assert_eq!(eg.release_envelope(1.0, 8).as_slice(), [0.0; 16]);
+        let mut state = State::attack();
+        assert_eq!(render(&env, &mut state, 16).as_slice(), [1.0; 16]);
+        assert!(!state.is_releasing());
+        assert_eq!(render(&env, &mut state, 8).as_slice(), [1.0; 8]);
+
+        let mut state = State::release();
+        assert_eq!(render(&env, &mut state, 16).as_slice(), [0.0; 16]);
+        assert!(!state.is_active());
+
+        let mut out = [1.0; 4];
+        assert!(env.process(&mut state, &mut out));
+        assert_eq!(out, [0.0; 4]);
     }
 
     #[test]
@@ -204,11 +369,95 @@ mod tests {
         eg.set_decay(4.0).unwrap();
         eg.set_sustain(60.0).unwrap();
         eg.set_release(5.0).unwrap();
+        let env = ADSREnvelope::new(&eg, 1.0);
 
-        let ads: Vec<f32> = eg.ads_envelope(1.0, 12)[..12].iter().map(|v| (v*100.0).round()/100.0).collect();
+        let mut state = State::attack();
+        let mut ads = render(&env, &mut state, 5);
+        let next = env.next_value(&state);
+        ads.extend(render(&env, &mut state, 7));
+        assert_eq!(next, ads[5]);
+        let ads: Vec<f32> = ads.iter().map(|v| (v*100.0).round()/100.0).collect();
         assert_eq!(ads.as_slice(), [0.0, 0.5, 1.0, 1.0, 1.0, 0.65, 0.61, 0.6, 0.6, 0.6, 0.6, 0.6]);
 
-        let rel: Vec<f32> = eg.release_envelope(1.0, 8)[..8].iter().map(|v| (v*10000.0).round()/10000.0).collect();
+        let mut state = State::release();
+        let rel: Vec<f32> = render(&env, &mut state, 8).iter().map(|v| (v*10000.0).round()/10000.0).collect();
         assert_eq!(rel.as_slice(), [0.1211, 0.0245, 0.0049, 0.0010, 0.0002, 0.0, 0.0, 0.0]);
+        assert!(state.is_active());
+    }
+
+    #[test]
+    fn release_ends_after_twice_the_release_time() {
+        let mut eg = Generator::default();
+        eg.set_release(1.0).unwrap();
+        let env = ADSREnvelope::new(&eg, 4.0);
+
+        let mut state = State::release();
+        render(&env, &mut state, 4);
+        assert!(state.is_active());
+        render(&env, &mut state, 4);
+        assert!(!state.is_active());
+    }
+
+    #[test]
+    fn delay_and_start_level() {
+        let mut eg = Generator::default();
+        eg.set_delay(2.0).unwrap();
+        eg.set_start(20.0).unwrap();
+        eg.set_attack(2.0).unwrap();
+        let env = ADSREnvelope::new(&eg, 1.0);
+
+        let mut state = State::attack();
+        let values: Vec<f32> = render(&env, &mut state, 6).iter().map(|v| (v*100.0).round()/100.0).collect();
+        assert_eq!(values.as_slice(), [0.0, 0.0, 0.2, 0.6, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn cc_modulated_generator() {
+        let mut eg = Generator::default();
+        eg.set_delay(1.0).unwrap();
+        eg.set_start(50.0).unwrap();
+        assert!(!eg.has_cc_modulation());
+        assert!(eg.set_delay_oncc(1, 101.0).is_err());
+
+        eg.set_delay_oncc(1, 2.54).unwrap();
+        eg.set_start_oncc(11, -100.0).unwrap();
+        assert!(eg.has_cc_modulation());
+
+        let mut controllers = [0u8; 128];
+        let unmodulated = eg.for_controllers(&controllers);
+        assert_eq!(unmodulated.delay, 1.0);
+        assert_eq!(unmodulated.start, 0.5);
+        assert!(!unmodulated.has_cc_modulation());
+
+        controllers[1] = 127;
+        controllers[11] = 127;
+        let modulated = eg.for_controllers(&controllers);
+        assert!((modulated.delay - 3.54).abs() < 1e-6);
+        assert_eq!(modulated.start, 0.0);
+    }
+
+    #[test]
+    fn velocity_modulated_generator() {
+        let mut eg = Generator::default();
+        eg.set_attack(2.0).unwrap();
+        eg.set_sustain(60.0).unwrap();
+        eg.set_release(1.0).unwrap();
+        assert!(!eg.has_velocity_modulation());
+
+        eg.set_vel2attack(-2.0).unwrap();
+        eg.set_vel2sustain(40.0).unwrap();
+        eg.set_vel2release(1.27).unwrap();
+        assert!(eg.has_velocity_modulation());
+
+        let loud = eg.for_velocity(127);
+        assert_eq!(loud.attack, 0.0);
+        assert_eq!(loud.sustain, 1.0);
+        assert_eq!(loud.release, 2.27);
+        assert!(!loud.has_velocity_modulation());
+
+        let quiet = eg.for_velocity(0);
+        assert_eq!(quiet.attack, 2.0);
+        assert_eq!(quiet.sustain, 0.6);
+        assert_eq!(quiet.release, 1.0);
     }
 }
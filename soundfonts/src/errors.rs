@@ -1,6 +1,8 @@
 use std::error;
 use std::fmt;
 
+use crate::sndfile;
+
 #[derive(Debug)]
 pub struct FlippedRangeError {
     key: &'static str,
@@ -76,3 +78,30 @@ where T: PartialOrd + fmt::Display {
         _ => Err(RangeError::out_of_range(name, lo, hi, v))
     }
 }
+
+/// Error of sndfile, which does not implement `std::error::Error` itself
+#[derive(Debug)]
+pub struct SampleFileError(pub sndfile::SndFileError);
+
+impl fmt::Display for SampleFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            sndfile::SndFileError::InvalidParameter(s) => write!(f, "Invalid parameter: {}", s),
+            sndfile::SndFileError::UnrecognisedFormat(s) => write!(f, "Unrecognised format: {}", s),
+            sndfile::SndFileError::SystemError(s) => write!(f, "System error: {}", s),
+            sndfile::SndFileError::MalformedFile(s) => write!(f, "Malformed file: {}", s),
+            sndfile::SndFileError::UnsupportedEncoding(s) => write!(f, "Unsupported encoding: {}", s),
+            sndfile::SndFileError::InternalError(s) => write!(f, "Internal error: {}", s),
+            sndfile::SndFileError::IOError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for SampleFileError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.0 {
+            sndfile::SndFileError::IOError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// Filters applied to the output of a region
+
+use std::f32::consts::PI;
+
+/// Two pole lowpass (RBJ biquad with Q = 1/sqrt(2)) for both channels
+pub(crate) struct Lowpass {
+    samplerate: f32,
+    cutoff: f32,
+
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+
+    // x[n-1], x[n-2], y[n-1], y[n-2] of left and right
+    state: [[f32; 4]; 2],
+}
+
+impl Lowpass {
+    pub(crate) fn new(samplerate: f32, cutoff: f32) -> Lowpass {
+        let mut filter = Lowpass {
+            samplerate,
+            cutoff: 0.0,
+
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+
+            state: [[0.0; 4]; 2],
+        };
+        filter.set_cutoff(cutoff);
+        filter
+    }
+
+    /// Cutoff frequency in Hz, limited to the range the filter remains stable in
+    pub(crate) fn set_cutoff(&mut self, cutoff: f32) {
+        let cutoff = cutoff.max(10.0).min(0.45 * self.samplerate);
+        if cutoff == self.cutoff {
+            return;
+        }
+        self.cutoff = cutoff;
+
+        let omega = 2.0 * PI * cutoff / self.samplerate;
+        let alpha = omega.sin() / 2.0f32.sqrt();
+        let cos = omega.cos();
+        let a0 = 1.0 + alpha;
+
+        self.b1 = (1.0 - cos) / a0;
+        self.b0 = self.b1 / 2.0;
+        self.b2 = self.b0;
+        self.a1 = -2.0 * cos / a0;
+        self.a2 = (1.0 - alpha) / a0;
+    }
+
+    /// Returns whether the state decayed into denormal numbers, which are
+    /// flushed to zero at the end of the block as they are slow to compute.
+    pub(crate) fn process(&mut self, left: &mut [f32], right: &mut [f32]) -> bool {
+        let mut flushed = false;
+        for (channel, state) in Iterator::zip([left, right].iter_mut(), self.state.iter_mut()) {
+            let [mut x1, mut x2, mut y1, mut y2] = *state;
+            for v in channel.iter_mut() {
+                let x = *v;
+                let y = self.b0 * x + self.b1 * x1 + self.b2 * x2 - self.a1 * y1 - self.a2 * y2;
+                x2 = x1;
+                x1 = x;
+                y2 = y1;
+                y1 = y;
+                *v = y;
+            }
+            *state = [x1, x2, y1, y2];
+            for v in state.iter_mut().filter(|v| v.is_subnormal()) {
+                *v = 0.0;
+                flushed = true;
+            }
+        }
+        flushed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowpass_passes_dc() {
+        let mut filter = Lowpass::new(48000.0, 1000.0);
+        let mut left = vec![1.0; 4800];
+        let mut right = vec![0.5; 4800];
+        filter.process(&mut left, &mut right);
+        assert!((left[4799] - 1.0).abs() < 1e-4);
+        assert!((right[4799] - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn lowpass_damps_high_frequencies() {
+        let mut filter = Lowpass::new(48000.0, 1000.0);
+        let mut left: Vec<f32> = (0..4800).map(|i| if i % 2 == 0 { 1.0 } else { -1.0 }).collect();
+        let mut right = left.clone();
+        filter.process(&mut left, &mut right);
+        assert!(left[4000..].iter().all(|v| v.abs() < 0.01));
+    }
+
+    #[test]
+    fn lowpass_flushes_denormals() {
+        let mut filter = Lowpass::new(48000.0, 1000.0);
+        let (mut left, mut right) = (vec![1.0; 1], vec![1.0; 1]);
+        assert!(!filter.process(&mut left, &mut right));
+        let silence = |filter: &mut Lowpass| filter.process(&mut [0.0; 480], &mut [0.0; 480]);
+        let blocks = (0..100).take_while(|_| !silence(&mut filter)).count();
+        assert!(blocks < 100);
+        assert!(filter.state.iter().flatten().all(|v| *v == 0.0));
+        assert!(!silence(&mut filter));
+    }
+}
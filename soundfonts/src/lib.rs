@@ -1,12 +1,31 @@
 extern crate itertools;
-extern crate wmidi;
+pub extern crate wmidi;
 extern crate log;
 extern crate rand;
 extern crate sndfile;
 
 pub mod sfz;
 pub mod engine;
+pub mod midi_filter;
+pub mod render;
+pub mod smf;
+pub mod effects;
+mod mapped;
 mod sample;
+mod simd;
 mod envelopes;
+mod filters;
+mod modulation;
 mod errors;
+#[cfg(feature = "serde")]
+mod serde_midi;
 pub mod utils;
+pub mod logging;
+pub mod tuning;
+pub mod profiling;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "bench")]
+pub mod bench;
+
+pub use errors::{FlippedRangeError, OutOfRangeError, RangeError, SampleFileError};
@@ -0,0 +1,49 @@
+// A minimal logger for the frontends, printing the records of the `log`
+// crate to stderr
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("sonarigo {}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Prints the warnings and errors to stderr, with `verbose` also what is
+/// going on like the sample files loaded. A logger set before is kept, only
+/// the level is changed.
+pub fn init(verbose: bool) {
+    log::set_max_level(level(verbose));
+    let _ = log::set_logger(&LOGGER);
+}
+
+fn level(verbose: bool) -> LevelFilter {
+    if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Warn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbose_levels() {
+        assert_eq!(level(false), LevelFilter::Warn);
+        assert_eq!(level(true), LevelFilter::Debug);
+    }
+}
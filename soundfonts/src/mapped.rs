@@ -0,0 +1,290 @@
+// Sample frames of uncompressed WAV and AIFF files mapped into memory rather
+// than decoded at load time. The OS pages them in when they are played and
+// keeps them in its page cache, the frames are converted while interpolating.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::sfz::SampleEncoding;
+use crate::utils;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+}
+
+impl Encoding {
+    fn from_bits(bits: u16, float: bool) -> Option<Encoding> {
+        match (bits, float) {
+            (16, false) => Some(Encoding::Int16),
+            (24, false) => Some(Encoding::Int24),
+            (32, false) => Some(Encoding::Int32),
+            (32, true) => Some(Encoding::Float32),
+            _ => None,
+        }
+    }
+
+    fn sample_encoding(self) -> SampleEncoding {
+        match self {
+            Encoding::Int16 => SampleEncoding::Pcm(16),
+            Encoding::Int24 => SampleEncoding::Pcm(24),
+            Encoding::Int32 => SampleEncoding::Pcm(32),
+            Encoding::Float32 => SampleEncoding::Float(32),
+        }
+    }
+
+    fn bytes(self) -> usize {
+        match self {
+            Encoding::Int16 => 2,
+            Encoding::Int24 => 3,
+            Encoding::Int32 | Encoding::Float32 => 4,
+        }
+    }
+}
+
+// where and how the frames are stored in the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Layout {
+    offset: usize,
+    frames: usize,
+    channels: usize,
+    encoding: Encoding,
+    big_endian: bool,
+    samplerate: f64,
+}
+
+impl Layout {
+    // the layout of a WAV or AIFF file with one or two channels of 16, 24 or
+    // 32 bit integers or 32 bit floats
+    fn parse(bytes: &[u8]) -> Option<Layout> {
+        let layout = match bytes.get(0..12)? {
+            header if &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE" => Self::parse_wav(bytes)?,
+            header if &header[0..4] == b"FORM" && &header[8..12] == b"AIFF" => Self::parse_aiff(bytes)?,
+            _ => return None,
+        };
+        if layout.channels == 0 || layout.channels > 2 || layout.samplerate <= 0.0 {
+            return None;
+        }
+        let available = bytes.len().saturating_sub(layout.offset) / (layout.channels * layout.encoding.bytes());
+        Some(Layout { frames: layout.frames.min(available), ..layout })
+    }
+
+    fn parse_wav(bytes: &[u8]) -> Option<Layout> {
+        let u16_at = |at: usize| bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let u32_at = |at: usize| bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let mut format = None;
+        let mut at = 12;
+        while let (Some(id), Some(size)) = (bytes.get(at..at + 4), u32_at(at + 4)) {
+            let (data, size) = (at + 8, size as usize);
+            match id {
+                b"fmt " => {
+                    let tag = match u16_at(data)? {
+                        0xfffe => u16_at(data + 24)?,
+                        tag => tag,
+                    };
+                    let float = match tag {
+                        1 => false,
+                        3 => true,
+                        _ => return None,
+                    };
+                    let encoding = Encoding::from_bits(u16_at(data + 14)?, float)?;
+                    format = Some((u16_at(data + 2)? as usize, u32_at(data + 4)? as f64, encoding));
+                }
+                b"data" => {
+                    let (channels, samplerate, encoding) = format?;
+                    let frames = size / (channels * encoding.bytes()).max(1);
+                    return Some(Layout { offset: data, frames, channels, encoding, big_endian: false, samplerate });
+                }
+                _ => {}
+            }
+            at = data + size + size % 2;
+        }
+        None
+    }
+
+    fn parse_aiff(bytes: &[u8]) -> Option<Layout> {
+        let u16_at = |at: usize| bytes.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+        let u32_at = |at: usize| bytes.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+        let mut format = None;
+        let mut at = 12;
+        while let (Some(id), Some(size)) = (bytes.get(at..at + 4), u32_at(at + 4)) {
+            let (data, size) = (at + 8, size as usize);
+            match id {
+                b"COMM" => {
+                    let encoding = Encoding::from_bits(u16_at(data + 6)?, false)?;
+                    let samplerate = extended_float(bytes.get(data + 8..data + 18)?);
+                    format = Some((u16_at(data)? as usize, u32_at(data + 2)? as usize, samplerate, encoding));
+                }
+                b"SSND" => {
+                    let (channels, frames, samplerate, encoding) = format?;
+                    let offset = data + 8 + u32_at(data)? as usize;
+                    return Some(Layout { offset, frames, channels, encoding, big_endian: true, samplerate });
+                }
+                _ => {}
+            }
+            at = data + size + size % 2;
+        }
+        None
+    }
+
+    // the value of `channel` at `frame`, silence outside the sample
+    fn value(&self, bytes: &[u8], frame: usize, channel: usize) -> f32 {
+        if frame >= self.frames {
+            return 0.0;
+        }
+        let width = self.encoding.bytes();
+        let at = self.offset + (frame * self.channels + channel.min(self.channels - 1)) * width;
+        let mut b = [0u8; 4];
+        b[..width].copy_from_slice(&bytes[at..at + width]);
+        if self.big_endian {
+            b[..width].reverse();
+        }
+        match self.encoding {
+            Encoding::Int16 => utils::pcm_to_float(i32::from_le_bytes([0, 0, b[0], b[1]])),
+            Encoding::Int24 => utils::pcm_to_float(i32::from_le_bytes([0, b[0], b[1], b[2]])),
+            Encoding::Int32 => utils::pcm_to_float(i32::from_le_bytes(b)),
+            Encoding::Float32 => f32::from_le_bytes(b),
+        }
+    }
+}
+
+// the 80 bit extended precision float of the sample rate of AIFF files
+fn extended_float(bytes: &[u8]) -> f64 {
+    let exponent = (u16::from_be_bytes([bytes[0], bytes[1]]) & 0x7fff) as i32;
+    let mut mantissa = [0u8; 8];
+    mantissa.copy_from_slice(&bytes[2..10]);
+    u64::from_be_bytes(mantissa) as f64 * 2.0f64.powi(exponent - 16383 - 63)
+}
+
+/// The frames of an uncompressed sample file mapped into memory. The file
+/// must not be changed while it is mapped.
+pub struct MappedFrames {
+    map: Mmap,
+    layout: Layout,
+}
+
+impl MappedFrames {
+    /// Maps the frames of the file at `path`, `None` if it is not a WAV or
+    /// AIFF file of one or two channels of 16, 24 or 32 bit integers or 32
+    /// bit floats
+    pub fn open(path: &Path) -> io::Result<Option<MappedFrames>> {
+        let file = File::open(path)?;
+        // safe as long as the file is not truncated while mapped
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Layout::parse(&map).map(|layout| MappedFrames { map, layout }))
+    }
+
+    pub fn frames(&self) -> usize {
+        self.layout.frames
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.layout.channels
+    }
+
+    pub fn samplerate(&self) -> f64 {
+        self.layout.samplerate
+    }
+
+    pub fn encoding(&self) -> SampleEncoding {
+        self.layout.encoding.sample_encoding()
+    }
+
+    // the value of `channel` at `frame`, silence outside the sample
+    pub(crate) fn value(&self, frame: usize, channel: usize) -> f32 {
+        self.layout.value(&self.map, frame, channel)
+    }
+
+    /// The frames converted into one vector per channel
+    pub fn to_channels(&self) -> Vec<Vec<f32>> {
+        (0..self.layout.channels)
+            .map(|channel| (0..self.layout.frames).map(|frame| self.value(frame, channel)).collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(format_tag: u16, bits: u16, channels: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&format_tag.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&48000u32.to_le_bytes());
+        bytes.extend_from_slice(&(48000 * (bits / 8 * channels) as u32).to_le_bytes());
+        bytes.extend_from_slice(&(bits / 8 * channels).to_le_bytes());
+        bytes.extend_from_slice(&bits.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn wav_layouts() {
+        let data: Vec<u8> = [16384i16, -32768, 0, 8192].iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        let bytes = wav(1, 16, 2, &data);
+        let layout = Layout::parse(&bytes).unwrap();
+        assert_eq!((layout.offset, layout.frames, layout.channels, layout.samplerate), (44, 2, 2, 48000.0));
+        assert_eq!(layout.value(&bytes, 0, 0), 0.5);
+        assert_eq!(layout.value(&bytes, 0, 1), -1.0);
+        assert_eq!(layout.value(&bytes, 1, 1), 0.25);
+        assert_eq!(layout.value(&bytes, 2, 0), 0.0);
+
+        let data: Vec<u8> = vec![0x00, 0x00, 0x40, 0x00, 0x00, 0xc0];
+        let bytes = wav(1, 24, 1, &data);
+        let layout = Layout::parse(&bytes).unwrap();
+        assert_eq!(layout.value(&bytes, 0, 0), 0.5);
+        assert_eq!(layout.value(&bytes, 1, 1), -0.5);
+
+        let data: Vec<u8> = [i32::MIN, 0x4000_0001].iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        let bytes = wav(1, 32, 1, &data);
+        let layout = Layout::parse(&bytes).unwrap();
+        assert_eq!(layout.encoding.sample_encoding(), SampleEncoding::Pcm(32));
+        assert_eq!(layout.value(&bytes, 0, 0), -1.0);
+        assert_eq!(layout.value(&bytes, 1, 0), 0.5);
+
+        let data: Vec<u8> = [0.75f32, -0.125].iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        let bytes = wav(3, 32, 1, &data);
+        assert_eq!(Layout::parse(&bytes).unwrap().encoding.sample_encoding(), SampleEncoding::Float(32));
+        assert_eq!(Layout::parse(&bytes).unwrap().value(&bytes, 1, 0), -0.125);
+
+        assert_eq!(Layout::parse(&wav(1, 8, 1, &[0, 0])), None);
+        assert_eq!(Layout::parse(&wav(1, 16, 4, &data)), None);
+        assert_eq!(Layout::parse(&wav(2, 16, 1, &data)), None);
+        assert_eq!(Layout::parse(b"fLaC"), None);
+    }
+
+    #[test]
+    fn aiff_layout() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"FORM\0\0\0\x2eAIFFCOMM");
+        bytes.extend_from_slice(&18u32.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&2u32.to_be_bytes());
+        bytes.extend_from_slice(&16u16.to_be_bytes());
+        // 44100 as 80 bit extended float
+        bytes.extend_from_slice(&[0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(b"SSND");
+        bytes.extend_from_slice(&12u32.to_be_bytes());
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&16384i16.to_be_bytes());
+        bytes.extend_from_slice(&(-16384i16).to_be_bytes());
+
+        let layout = Layout::parse(&bytes).unwrap();
+        assert_eq!((layout.frames, layout.channels, layout.samplerate), (2, 1, 44100.0));
+        assert_eq!(layout.value(&bytes, 0, 0), 0.5);
+        assert_eq!(layout.value(&bytes, 1, 0), -0.5);
+    }
+}
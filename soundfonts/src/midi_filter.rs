@@ -0,0 +1,240 @@
+// Preprocessing of the incoming MIDI messages to adapt simple keyboards to
+// what an instrument expects
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Changes applied to the MIDI messages before they reach the regions
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct MidiFilter {
+    /// Controller the channel pressure is sent as instead, for example 1 for
+    /// the modulation wheel
+    pub channel_pressure_cc: Option<u8>,
+    /// Controllers sent as another controller, pairs of the incoming and the
+    /// outgoing controller number
+    pub cc_map: Vec<(u8, u8)>,
+    pub velocity_curve: VelocityCurve,
+    /// Curve of the note-off velocities, which release triggered regions play
+    /// with if `Engine::set_note_off_velocity()` is enabled
+    pub release_velocity_curve: VelocityCurve,
+}
+
+/// Mapping of the played velocities to the ones passed on
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VelocityCurve {
+    Linear,
+    /// `127 * (v / 127) ^ gamma`, values above 1.0 make the keyboard play
+    /// softer, below 1.0 louder
+    Gamma(f32),
+    /// every note is played with this velocity
+    Fixed(u8),
+    /// pairs of played and passed on velocity ordered by the played one,
+    /// interpolated linearly in between
+    Table(Vec<(u8, u8)>),
+}
+
+impl Default for VelocityCurve {
+    fn default() -> VelocityCurve {
+        VelocityCurve::Linear
+    }
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, velocity: wmidi::Velocity) -> wmidi::Velocity {
+        let v = u8::from(velocity);
+        match *self {
+            VelocityCurve::Linear => velocity,
+            _ if v == 0 => velocity,
+            VelocityCurve::Gamma(gamma) => {
+                let v = (127.0 * (v as f32 / 127.0).powf(gamma)).round().max(1.0).min(127.0);
+                wmidi::Velocity::try_from(v as u8).unwrap_or(velocity)
+            }
+            VelocityCurve::Fixed(fixed) => wmidi::Velocity::try_from(fixed.max(1)).unwrap_or(velocity),
+            VelocityCurve::Table(ref points) => {
+                let v = table_value(points, v).max(1);
+                wmidi::Velocity::try_from(v).unwrap_or(velocity)
+            }
+        }
+    }
+}
+
+fn table_value(points: &[(u8, u8)], v: u8) -> u8 {
+    let after = points.iter().position(|(played, _)| *played >= v);
+    match after {
+        None => points.last().map_or(v, |(_, out)| *out),
+        Some(0) => points[0].1,
+        Some(n) => {
+            let ((x0, y0), (x1, y1)) = (points[n-1], points[n]);
+            let t = (v - x0) as f32 / (x1 - x0) as f32;
+            (y0 as f32 + t * (y1 as f32 - y0 as f32)).round() as u8
+        }
+    }
+}
+
+/// Invalid text form of a `VelocityCurve`
+#[derive(Debug, PartialEq)]
+pub struct VelocityCurveError(String);
+
+impl fmt::Display for VelocityCurveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid velocity curve {}, expected linear, gamma:<g>, fixed:<v> or table:<in>=<out>,...", self.0)
+    }
+}
+
+impl std::error::Error for VelocityCurveError {}
+
+/// Parses `linear`, `gamma:<g>`, `fixed:<velocity>` or
+/// `table:<played>=<velocity>,...`
+impl FromStr for VelocityCurve {
+    type Err = VelocityCurveError;
+
+    fn from_str(s: &str) -> Result<VelocityCurve, VelocityCurveError> {
+        let error = || VelocityCurveError(s.to_string());
+        let velocity = |v: &str| v.trim().parse::<u8>().ok().filter(|v| *v <= 127);
+        let (kind, value) = match s.find(':') {
+            Some(n) => (&s[..n], &s[n+1..]),
+            None => (s, ""),
+        };
+        match kind {
+            "linear" if value.is_empty() => Ok(VelocityCurve::Linear),
+            "gamma" => match value.parse::<f32>() {
+                Ok(gamma) if gamma > 0.0 && gamma.is_finite() => Ok(VelocityCurve::Gamma(gamma)),
+                _ => Err(error()),
+            },
+            "fixed" => match velocity(value) {
+                Some(v) if v > 0 => Ok(VelocityCurve::Fixed(v)),
+                _ => Err(error()),
+            },
+            "table" => {
+                let mut points = Vec::new();
+                for point in value.split(',') {
+                    let n = point.find('=').ok_or_else(error)?;
+                    let point = (velocity(&point[..n]).ok_or_else(error)?, velocity(&point[n+1..]).ok_or_else(error)?);
+                    if points.last().map_or(false, |(played, _)| *played >= point.0) {
+                        return Err(error());
+                    }
+                    points.push(point);
+                }
+                Ok(VelocityCurve::Table(points))
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+impl MidiFilter {
+    /// Whether the filter passes all messages unchanged
+    pub fn is_transparent(&self) -> bool {
+        self.channel_pressure_cc.is_none() && self.cc_map.is_empty() && self.velocity_curve == VelocityCurve::Linear
+            && self.release_velocity_curve == VelocityCurve::Linear
+    }
+
+    pub fn apply<'a>(&self, midi_msg: wmidi::MidiMessage<'a>) -> wmidi::MidiMessage<'a> {
+        use wmidi::MidiMessage::*;
+        let control = |cc: u8| wmidi::ControlNumber::try_from(cc).ok();
+        match midi_msg {
+            NoteOn(ch, note, vel) => NoteOn(ch, note, self.velocity_curve.apply(vel)),
+            NoteOff(ch, note, vel) => NoteOff(ch, note, self.release_velocity_curve.apply(vel)),
+            ChannelPressure(ch, pressure) => match self.channel_pressure_cc.and_then(control) {
+                Some(cc) => ControlChange(ch, cc, pressure),
+                None => ChannelPressure(ch, pressure),
+            },
+            ControlChange(ch, cc, value) => {
+                let mapped = self.cc_map.iter().find(|(from, _)| *from == u8::from(cc));
+                ControlChange(ch, mapped.and_then(|(_, to)| control(*to)).unwrap_or(cc), value)
+            }
+            msg => msg,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wmidi::{Channel, ControlNumber, ControlValue, MidiMessage, Note, Velocity};
+
+    fn cc(cc: u8, value: u8) -> MidiMessage<'static> {
+        MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(cc).unwrap(), ControlValue::try_from(value).unwrap())
+    }
+
+    #[test]
+    fn transparent_by_default() {
+        let filter = MidiFilter::default();
+        assert!(filter.is_transparent());
+        let note_on = MidiMessage::NoteOn(Channel::Ch2, Note::C3, Velocity::try_from(64).unwrap());
+        assert_eq!(filter.apply(note_on.clone()), note_on);
+        assert_eq!(filter.apply(cc(1, 20)), cc(1, 20));
+    }
+
+    #[test]
+    fn channel_pressure_and_cc_map() {
+        let filter = MidiFilter {
+            channel_pressure_cc: Some(1),
+            cc_map: vec![(2, 11), (4, 64)],
+            ..Default::default()
+        };
+        let pressure = MidiMessage::ChannelPressure(Channel::Ch1, ControlValue::try_from(90).unwrap());
+        assert_eq!(filter.apply(pressure), cc(1, 90));
+        assert_eq!(filter.apply(cc(2, 30)), cc(11, 30));
+        assert_eq!(filter.apply(cc(4, 127)), cc(64, 127));
+        assert_eq!(filter.apply(cc(7, 100)), cc(7, 100));
+    }
+
+    #[test]
+    fn release_velocity_curve() {
+        let filter = MidiFilter {
+            release_velocity_curve: VelocityCurve::Gamma(2.0),
+            ..Default::default()
+        };
+        assert!(!filter.is_transparent());
+        let velocity = Velocity::try_from(64).unwrap();
+        let note_on = MidiMessage::NoteOn(Channel::Ch1, Note::C3, velocity);
+        assert_eq!(filter.apply(note_on.clone()), note_on);
+        let note_off = MidiMessage::NoteOff(Channel::Ch1, Note::C3, velocity);
+        assert_eq!(filter.apply(note_off), MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::try_from(32).unwrap()));
+    }
+
+    #[test]
+    fn velocity_gamma() {
+        let curve = VelocityCurve::Gamma(2.0);
+        let apply = |v: u8| u8::from(curve.apply(Velocity::try_from(v).unwrap()));
+        assert_eq!(apply(0), 0);
+        assert_eq!(apply(1), 1);
+        assert_eq!(apply(64), 32);
+        assert_eq!(apply(127), 127);
+        assert_eq!(u8::from(VelocityCurve::Gamma(0.5).apply(Velocity::try_from(32).unwrap())), 64);
+    }
+
+    #[test]
+    fn velocity_fixed_and_table() {
+        let apply = |curve: &VelocityCurve, v: u8| u8::from(curve.apply(Velocity::try_from(v).unwrap()));
+        let fixed = VelocityCurve::Fixed(100);
+        assert_eq!(apply(&fixed, 1), 100);
+        assert_eq!(apply(&fixed, 127), 100);
+        assert_eq!(apply(&fixed, 0), 0);
+
+        let table = VelocityCurve::Table(vec![(10, 20), (64, 100), (110, 127)]);
+        assert_eq!(apply(&table, 1), 20);
+        assert_eq!(apply(&table, 10), 20);
+        assert_eq!(apply(&table, 37), 60);
+        assert_eq!(apply(&table, 64), 100);
+        assert_eq!(apply(&table, 120), 127);
+        assert_eq!(apply(&VelocityCurve::Table(vec![(64, 0)]), 30), 1);
+    }
+
+    #[test]
+    fn parse_velocity_curve() {
+        assert_eq!("linear".parse(), Ok(VelocityCurve::Linear));
+        assert_eq!("gamma:1.5".parse(), Ok(VelocityCurve::Gamma(1.5)));
+        assert_eq!("fixed:90".parse(), Ok(VelocityCurve::Fixed(90)));
+        assert_eq!("table:1=40,127=127".parse(), Ok(VelocityCurve::Table(vec![(1, 40), (127, 127)])));
+        for invalid in &["", "cubic", "linear:1", "gamma:0", "gamma:x", "fixed:0", "fixed:128",
+                         "table:", "table:64=100,32=50", "table:1=128", "table:64"] {
+            assert!(invalid.parse::<VelocityCurve>().is_err(), "{}", invalid);
+        }
+    }
+}
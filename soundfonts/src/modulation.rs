@@ -0,0 +1,269 @@
+// Routing of modulation sources like MIDI controllers to region parameters
+
+use crate::errors::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Source {
+    Controller(u8),
+    ChannelAftertouch,
+    PolyAftertouch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Destination {
+    /// filter cutoff in cents
+    Cutoff,
+    /// pitch in cents
+    Pitch,
+    /// amplitude in percent
+    Amplitude,
+    /// volume in dB
+    Volume,
+    /// pan in percent, negative is left
+    Pan,
+}
+
+impl Destination {
+    // range of the depth and opcode name for error messages
+    fn depth_range(&self) -> (f32, f32, &'static str) {
+        match self {
+            Destination::Cutoff => (-9600.0, 9600.0, "cutoff modulation"),
+            Destination::Pitch => (-9600.0, 9600.0, "pitch modulation"),
+            Destination::Amplitude => (-100.0, 100.0, "amplitude modulation"),
+            Destination::Volume => (-144.0, 48.0, "gain modulation"),
+            Destination::Pan => (-200.0, 200.0, "pan modulation"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Route {
+    source: Source,
+    destination: Destination,
+    depth: f32,
+    // the `curve_index` of the curve shaping the source value
+    #[cfg_attr(feature = "serde", serde(default))]
+    curve: Option<u32>,
+}
+
+/// A `<curve>` shaping a source value, given by points at some of its 128
+/// values and linear in between. Without points at the ends it starts at 0.0
+/// and ends at 1.0.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Curve {
+    points: Vec<(u8, f32)>,
+}
+
+impl Curve {
+    pub(crate) fn set_point(&mut self, index: u8, value: f32) {
+        match self.points.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(n) => self.points[n].1 = value,
+            Err(n) => self.points.insert(n, (index, value)),
+        }
+    }
+
+    pub(crate) fn points(&self) -> &[(u8, f32)] {
+        &self.points
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The value of the curve at `x` in 0.0..=1.0
+    pub(crate) fn value(&self, x: f32) -> f32 {
+        let x = x.max(0.0).min(1.0) * 127.0;
+        let start = Some((0, 0.0)).filter(|_| self.points.first().map_or(true, |(i, _)| *i > 0));
+        let end = Some((127, 1.0)).filter(|_| self.points.last().map_or(true, |(i, _)| *i < 127));
+        let mut points = start.iter().chain(self.points.iter()).chain(end.iter());
+        let mut previous = points.next().copied().unwrap_or((0, 0.0));
+        for &(i, v) in points {
+            if x <= i as f32 {
+                let (pi, pv) = previous;
+                return pv + (x - pi as f32) / (i - pi) as f32 * (v - pv);
+            }
+            previous = (i, v);
+        }
+        previous.1
+    }
+}
+
+/// The curves of an instrument by their `curve_index`. The indices 0 to 6
+/// are predefined unless the instrument has curves of them.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Curves {
+    curves: Vec<(u32, Curve)>,
+}
+
+impl Curves {
+    pub(crate) fn insert(&mut self, index: u32, curve: Curve) {
+        self.curves.retain(|(i, _)| *i != index);
+        self.curves.push((index, curve));
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.curves.len()
+    }
+
+    /// Shapes `x` in 0.0..=1.0 by the curve `index`, unknown curves are linear
+    pub(crate) fn apply(&self, index: u32, x: f32) -> f32 {
+        if let Some((_, curve)) = self.curves.iter().find(|(i, _)| *i == index) {
+            return curve.value(x);
+        }
+        match index {
+            1 => 2.0 * x - 1.0,
+            2 => 1.0 - x,
+            3 => 1.0 - 2.0 * x,
+            4 => x * x,
+            5 => x.sqrt(),
+            6 => (1.0 - x).sqrt(),
+            _ => x,
+        }
+    }
+}
+
+/// The modulation routes of a region. The amount of a destination is the sum
+/// of the depths of its routes, each scaled by the source value in 0.0..=1.0.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Matrix {
+    routes: Vec<Route>,
+}
+
+impl Matrix {
+    /// Adds a route or replaces the depth of an existing one
+    pub(crate) fn set(&mut self, source: Source, destination: Destination, depth: f32) -> Result<(), RangeError> {
+        let (lo, hi, name) = destination.depth_range();
+        let depth = range_check(depth, lo, hi, name)?;
+        match self.routes.iter_mut().find(|r| r.source == source && r.destination == destination) {
+            Some(route) => route.depth = depth,
+            None => self.routes.push(Route { source, destination, depth, curve: None }),
+        }
+        Ok(())
+    }
+
+    /// Shapes the source of a route by the curve `curve_index`, the route is
+    /// added without depth if it does not exist yet
+    pub(crate) fn set_curve(&mut self, source: Source, destination: Destination, curve_index: u32) {
+        match self.routes.iter_mut().find(|r| r.source == source && r.destination == destination) {
+            Some(route) => route.curve = Some(curve_index),
+            None => self.routes.push(Route { source, destination, depth: 0.0, curve: Some(curve_index) }),
+        }
+    }
+
+    /// The SFZ opcodes of the routes with their depths
+    pub(crate) fn opcodes(&self) -> Vec<(String, f32)> {
+        self.routes.iter().flat_map(|r| {
+            let destination = match r.destination {
+                Destination::Cutoff => "cutoff",
+                Destination::Pitch => "pitch",
+                Destination::Amplitude => "amplitude",
+                Destination::Volume => "volume",
+                Destination::Pan => "pan",
+            };
+            let opcode = match r.source {
+                Source::Controller(cc) => format!("{}_oncc{}", destination, cc),
+                Source::ChannelAftertouch => format!("{}_chanaft", destination),
+                Source::PolyAftertouch => format!("{}_polyaft", destination),
+            };
+            let curve = match (r.source, r.curve) {
+                (Source::Controller(cc), Some(curve)) => Some((format!("{}_curvecc{}", destination, cc), curve as f32)),
+                _ => None,
+            };
+            std::iter::once((opcode, r.depth)).chain(curve)
+        }).collect()
+    }
+
+    /// The controllers used as sources
+    pub(crate) fn controllers(&self) -> Vec<u8> {
+        let mut controllers: Vec<u8> = self.routes.iter().filter_map(|r| match r.source {
+            Source::Controller(cc) => Some(cc),
+            _ => None,
+        }).collect();
+        controllers.sort_unstable();
+        controllers.dedup();
+        controllers
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub(crate) fn amount<F: Fn(Source) -> f32>(&self, destination: Destination, curves: &Curves, source_value: F) -> f32 {
+        self.routes.iter()
+            .filter(|r| r.destination == destination)
+            .map(|r| {
+                let value = source_value(r.source);
+                r.depth * r.curve.map_or(value, |curve| curves.apply(curve, value))
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_sum_up() {
+        let mut matrix = Matrix::default();
+        assert!(matrix.is_empty());
+        matrix.set(Source::Controller(1), Destination::Pitch, 100.0).unwrap();
+        matrix.set(Source::Controller(2), Destination::Pitch, -50.0).unwrap();
+        matrix.set(Source::Controller(1), Destination::Cutoff, 1200.0).unwrap();
+        matrix.set(Source::Controller(1), Destination::Pitch, 200.0).unwrap();
+
+        let value = |s| match s {
+            Source::Controller(1) => 0.5,
+            Source::Controller(2) => 1.0,
+            _ => 0.0,
+        };
+        let curves = Curves::default();
+        assert_eq!(matrix.amount(Destination::Pitch, &curves, value), 50.0);
+        assert_eq!(matrix.amount(Destination::Cutoff, &curves, value), 600.0);
+        assert_eq!(matrix.amount(Destination::Pan, &curves, value), 0.0);
+        assert_eq!(matrix.controllers(), vec![1, 2]);
+    }
+
+    #[test]
+    fn curves_shape_sources() {
+        let mut curve = Curve::default();
+        assert_eq!(curve.value(0.5), 0.5);
+        curve.set_point(127, 0.0);
+        curve.set_point(64, 1.0);
+        assert_eq!(curve.points(), &[(64, 1.0), (127, 0.0)]);
+        assert_eq!(curve.value(0.0), 0.0);
+        assert_eq!(curve.value(32.0 / 127.0), 0.5);
+        assert_eq!(curve.value(1.0), 0.0);
+
+        let mut curves = Curves::default();
+        curves.insert(7, curve);
+        assert_eq!(curves.apply(7, 64.0 / 127.0), 1.0);
+        assert_eq!(curves.apply(1, 0.25), -0.5);
+        assert_eq!(curves.apply(4, 0.5), 0.25);
+        assert_eq!(curves.apply(8, 0.3), 0.3);
+        curves.insert(1, Curve::default());
+        assert_eq!(curves.apply(1, 0.25), 0.25);
+
+        let mut matrix = Matrix::default();
+        matrix.set_curve(Source::Controller(1), Destination::Pan, 1);
+        matrix.set(Source::Controller(1), Destination::Pan, 100.0).unwrap();
+        assert_eq!(matrix.amount(Destination::Pan, &Curves::default(), |_| 0.0), -100.0);
+        assert_eq!(matrix.opcodes(), vec![("pan_oncc1".to_string(), 100.0), ("pan_curvecc1".to_string(), 1.0)]);
+    }
+
+    #[test]
+    fn depth_range_check() {
+        let mut matrix = Matrix::default();
+        match matrix.set(Source::Controller(7), Destination::Volume, 50.0) {
+            Err(e) => assert_eq!(format!("{}", e), "gain modulation out of range: -144 <= 50 <= 48"),
+            _ => panic!("Not seen expected error"),
+        }
+        assert!(matrix.is_empty());
+    }
+}
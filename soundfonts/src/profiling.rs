@@ -0,0 +1,91 @@
+// Time spent in the subsystems of the audio processing
+//
+// Only measured if the feature "profiling" is enabled. Otherwise the
+// stopwatches are zero sized and compile to nothing.
+
+#[cfg(feature = "profiling")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "profiling")]
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Subsystem {
+    Process,
+    Interpolation,
+    Envelope,
+    Mixing,
+    Filter,
+}
+
+#[cfg(feature = "profiling")]
+const SUBSYSTEMS: [(Subsystem, &str); 4] = [
+    (Subsystem::Interpolation, "interpolation"),
+    (Subsystem::Envelope, "envelope"),
+    (Subsystem::Mixing, "mixing"),
+    (Subsystem::Filter, "filter"),
+];
+
+#[cfg(feature = "profiling")]
+static NANOS: [AtomicU64; 5] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+                                AtomicU64::new(0)];
+
+pub(crate) struct Stopwatch {
+    #[cfg(feature = "profiling")]
+    start: Instant,
+}
+
+impl Stopwatch {
+    #[inline]
+    pub(crate) fn start() -> Stopwatch {
+        Stopwatch {
+            #[cfg(feature = "profiling")]
+            start: Instant::now(),
+        }
+    }
+
+    #[inline]
+    #[allow(unused_variables)]
+    pub(crate) fn stop(self, subsystem: Subsystem) {
+        #[cfg(feature = "profiling")]
+        NANOS[subsystem as usize].fetch_add(self.start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Accumulated processing time per subsystem
+#[cfg(feature = "profiling")]
+#[derive(Clone, Debug)]
+pub struct Breakdown {
+    pub process: Duration,
+    pub subsystems: Vec<(&'static str, Duration)>,
+}
+
+#[cfg(feature = "profiling")]
+impl Breakdown {
+    /// Folded stack format as taken by `flamegraph.pl` or `inferno-flamegraph`,
+    /// one line per subsystem with the time in microseconds
+    pub fn folded(&self) -> String {
+        let subsystems_total: Duration = self.subsystems.iter().map(|(_, d)| *d).sum();
+        let mut text = format!("process {}\n",
+                               self.process.checked_sub(subsystems_total).unwrap_or_default().as_micros());
+        for (name, duration) in &self.subsystems {
+            text += &format!("process;{} {}\n", name, duration.as_micros());
+        }
+        text
+    }
+}
+
+#[cfg(feature = "profiling")]
+pub fn breakdown() -> Breakdown {
+    let load = |s: Subsystem| Duration::from_nanos(NANOS[s as usize].load(Ordering::Relaxed));
+    Breakdown {
+        process: load(Subsystem::Process),
+        subsystems: SUBSYSTEMS.iter().map(|(s, name)| (*name, load(*s))).collect(),
+    }
+}
+
+#[cfg(feature = "profiling")]
+pub fn reset() {
+    for n in &NANOS {
+        n.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,470 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use log::warn;
+
+use crate::engine::EngineTrait;
+use crate::sndfile;
+use crate::sndfile::SndFileIO;
+use crate::utils;
+
+/// A MIDI message to be dispatched at a given frame of the rendering
+#[derive(Clone, Debug)]
+pub struct MidiEvent {
+    pub frame: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl MidiEvent {
+    pub fn new(frame: usize, bytes: &[u8]) -> MidiEvent {
+        MidiEvent { frame, bytes: bytes.to_vec() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub frame: usize,
+    pub value: f32,
+}
+
+/// Parameter curve like an automation lane of a DAW
+///
+/// The value is interpolated linearly between the breakpoints and held
+/// constant before the first and after the last one.
+#[derive(Clone, Debug)]
+pub struct Automation {
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl Automation {
+    pub fn new(mut breakpoints: Vec<Breakpoint>) -> Automation {
+        breakpoints.sort_by_key(|bp| bp.frame);
+        Automation { breakpoints }
+    }
+
+    pub fn constant(value: f32) -> Automation {
+        Automation { breakpoints: vec![Breakpoint { frame: 0, value }] }
+    }
+
+    pub fn value_at(&self, frame: usize) -> f32 {
+        // the index of the first breakpoint after `frame`
+        let next = self.breakpoints
+            .binary_search_by(|bp| if bp.frame > frame { cmp::Ordering::Greater } else { cmp::Ordering::Less })
+            .unwrap_or_else(|n| n);
+        match next {
+            n if n == self.breakpoints.len() => self.breakpoints.last().map_or(0.0, |bp| bp.value),
+            0 => self.breakpoints[0].value,
+            n => {
+                let (a, b) = (self.breakpoints[n-1], self.breakpoints[n]);
+                let t = (frame - a.frame) as f32 / (b.frame - a.frame) as f32;
+                a.value + t * (b.value - a.value)
+            }
+        }
+    }
+}
+
+fn gain_from_db(gain_db: f32) -> f32 {
+    match gain_db {
+        g if g < -80.0 => 0.0,
+        g if g >= 20.0 => utils::dB_to_gain(20.0),
+        g => utils::dB_to_gain(g)
+    }
+}
+
+/// Renders an engine offline into a pair of buffers
+///
+/// The engine is fed with blocks of `block_length` frames which are split
+/// at the frame stamps of the MIDI events, like a plugin host would do.
+/// Frame stamps of events and automation count from the start of the first
+/// `render()` call, so a rendering can be done in several chunks.
+pub struct Renderer<E: EngineTrait> {
+    engine: E,
+    block_length: usize,
+    gain: Automation,
+    position: usize,
+    // events stamped after the frames rendered so far
+    pending: Vec<MidiEvent>,
+}
+
+impl<E: EngineTrait> Renderer<E> {
+    pub fn new(engine: E, block_length: usize) -> Renderer<E> {
+        Renderer {
+            engine,
+            block_length,
+            gain: Automation::constant(0.0),
+            position: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Sets the master gain curve, values in dB
+    pub fn set_gain_automation(&mut self, gain: Automation) {
+        self.gain = gain;
+    }
+
+    pub fn engine(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    pub fn into_engine(self) -> E {
+        self.engine
+    }
+
+    /// Number of frames rendered so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Renders the next `nframes`. Events stamped before the current position
+    /// are dispatched right away, those stamped after the rendered frames are
+    /// kept for the following calls.
+    pub fn render(&mut self, events: Vec<MidiEvent>, nframes: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut events: Vec<MidiEvent> = self.pending.drain(..).chain(events).collect();
+        for ev in &mut events {
+            ev.frame = ev.frame.saturating_sub(self.position);
+        }
+        events.sort_by_key(|ev| ev.frame);
+        let later = events.iter().position(|ev| ev.frame >= nframes).unwrap_or(events.len());
+        self.pending = events.split_off(later);
+        for ev in &mut self.pending {
+            ev.frame += self.position;
+        }
+        let mut events = events.iter().peekable();
+
+        let mut out_left = vec![0.0; nframes];
+        let mut out_right = vec![0.0; nframes];
+
+        let mut block_start = 0;
+        while block_start < nframes {
+            let block_end = (block_start + self.block_length).min(nframes);
+            let mut offset = block_start;
+
+            while let Some(ev) = events.peek() {
+                if ev.frame >= block_end {
+                    break;
+                }
+                let frame = ev.frame.max(block_start);
+                if frame > offset {
+                    self.engine.process(&mut out_left[offset..frame], &mut out_right[offset..frame]);
+                    offset = frame;
+                }
+                if let Ok(msg) = wmidi::MidiMessage::try_from(ev.bytes.as_slice()) {
+                    self.engine.midi_event(&msg);
+                }
+                events.next();
+            }
+
+            if offset < block_end {
+                self.engine.process(&mut out_left[offset..block_end], &mut out_right[offset..block_end]);
+            }
+
+            block_start = block_end;
+        }
+
+        for (frame, (l, r)) in Iterator::zip(out_left.iter_mut(), out_right.iter_mut()).enumerate() {
+            let gain = gain_from_db(self.gain.value_at(self.position + frame));
+            *l *= gain;
+            *r *= gain;
+        }
+        self.position += nframes;
+
+        (out_left, out_right)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    Pcm16,
+    Pcm24,
+    Float,
+}
+
+fn open_wav<P: AsRef<Path>>(path: P,
+                            samplerate: usize,
+                            format: SampleFormat) -> Result<sndfile::SndFile, sndfile::SndFileError> {
+    let subtype = match format {
+        SampleFormat::Pcm16 => sndfile::SubtypeFormat::PCM_16,
+        SampleFormat::Pcm24 => sndfile::SubtypeFormat::PCM_24,
+        SampleFormat::Float => sndfile::SubtypeFormat::FLOAT,
+    };
+    let options = sndfile::WriteOptions::new(sndfile::MajorFormat::WAV, subtype,
+                                             sndfile::Endian::File, samplerate, 2);
+    sndfile::OpenOptions::WriteOnly(options).from_path(path)
+}
+
+fn interleave(left: &[f32], right: &[f32], interleaved: &mut Vec<f32>) {
+    for (l, r) in Iterator::zip(left.iter(), right.iter()) {
+        interleaved.push(*l);
+        interleaved.push(*r);
+    }
+}
+
+/// Writes a stereo WAV file
+pub fn write_wav<P: AsRef<Path>>(path: P,
+                                 left: &[f32],
+                                 right: &[f32],
+                                 samplerate: usize,
+                                 format: SampleFormat) -> Result<(), sndfile::SndFileError> {
+    let mut snd = open_wav(path, samplerate, format)?;
+
+    let mut interleaved = Vec::with_capacity(2 * left.len());
+    interleave(left, right, &mut interleaved);
+    snd.write_from_slice(&interleaved)
+        .map_err(|_| sndfile::SndFileError::InternalError("Could not write samples".to_string()))?;
+    Ok(())
+}
+
+// Quantizes the samples to `bits` with triangular dither and scales them to
+// the full range of i32
+fn dither(samples: &[f32], bits: u32, quantized: &mut Vec<i32>) {
+    let scale = (1i64 << (bits - 1)) as f32;
+    let (min, max) = (-scale, scale - 1.0);
+    quantized.clear();
+    for v in samples {
+        let noise = rand::random::<f32>() - rand::random::<f32>();
+        let q = (v * scale + noise).round().max(min).min(max) as i32;
+        quantized.push(q << (32 - bits));
+    }
+}
+
+// Number of frames a buffer of the recorder's pool holds
+const RECORD_BLOCK_FRAMES: usize = 1024;
+
+// Number of buffers circulating between the audio thread and the writer
+const RECORD_POOL_SIZE: usize = 64;
+
+/// Sink writing a stereo stream into a WAV file, PCM formats are dithered
+///
+/// The file is written by a thread of its own, so recording from the audio
+/// thread only costs copying the frames. The buffers are taken from a pool
+/// allocated up front; when the writer falls behind and the pool runs dry,
+/// blocks are dropped and counted rather than allocated.
+pub struct Recorder {
+    sender: mpsc::SyncSender<Vec<f32>>,
+    // behind a mutex so that the recorder is `Sync`, only locked by `record()`
+    recycled: Mutex<mpsc::Receiver<Vec<f32>>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Recorder {
+    pub fn new<P: AsRef<Path>>(path: P, samplerate: usize, format: SampleFormat) -> Result<Recorder, sndfile::SndFileError> {
+        let path = path.as_ref().to_path_buf();
+        let (sender, receiver) = mpsc::sync_channel::<Vec<f32>>(RECORD_POOL_SIZE);
+        let (recycle_sender, recycled) = mpsc::sync_channel(RECORD_POOL_SIZE);
+        let (open_sender, open_receiver) = mpsc::channel();
+        for _ in 0..RECORD_POOL_SIZE {
+            recycle_sender.send(Vec::with_capacity(2 * RECORD_BLOCK_FRAMES)).ok();
+        }
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let dropped_by_writer = dropped.clone();
+
+        thread::spawn(move || {
+            let mut snd = match open_wav(&path, samplerate, format) {
+                Ok(snd) => snd,
+                Err(e) => {
+                    open_sender.send(Err(e)).ok();
+                    return
+                }
+            };
+            open_sender.send(Ok(())).ok();
+
+            let mut quantized = Vec::new();
+            for buffer in receiver {
+                let written = match format {
+                    SampleFormat::Float => snd.write_from_slice(&buffer),
+                    SampleFormat::Pcm16 | SampleFormat::Pcm24 => {
+                        dither(&buffer, if format == SampleFormat::Pcm16 { 16 } else { 24 }, &mut quantized);
+                        snd.write_from_slice(&quantized)
+                    }
+                };
+                if written.is_err() {
+                    warn!("Could not write recording to {}", path.display());
+                    return
+                }
+                recycle_sender.try_send(buffer).ok();
+            }
+            let dropped = dropped_by_writer.load(Ordering::Relaxed);
+            if dropped > 0 {
+                warn!("Dropped {} blocks while recording to {}", dropped, path.display());
+            }
+        });
+
+        open_receiver.recv()
+            .unwrap_or_else(|_| Err(sndfile::SndFileError::InternalError("Recording thread died".to_string())))?;
+        Ok(Recorder { sender, recycled: Mutex::new(recycled), dropped })
+    }
+
+    /// Queues the frames for writing. The file is closed when the recorder
+    /// is dropped.
+    pub fn record(&self, left: &[f32], right: &[f32]) {
+        let recycled = match self.recycled.try_lock() {
+            Ok(recycled) => recycled,
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return
+            }
+        };
+        for (left, right) in Iterator::zip(left.chunks(RECORD_BLOCK_FRAMES), right.chunks(RECORD_BLOCK_FRAMES)) {
+            let mut buffer = match recycled.try_recv() {
+                Ok(buffer) => buffer,
+                Err(_) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    continue
+                }
+            };
+            buffer.clear();
+            interleave(left, right, &mut buffer);
+            if self.sender.try_send(buffer).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Number of blocks that were dropped because the writer fell behind
+    pub fn dropped_blocks(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample::tests::f32_eq;
+
+    struct CountingEngine {
+        frames: usize,
+        event_frames: Vec<usize>,
+        block_lengths: Vec<usize>,
+    }
+
+    impl CountingEngine {
+        fn new() -> CountingEngine {
+            CountingEngine { frames: 0, event_frames: Vec::new(), block_lengths: Vec::new() }
+        }
+    }
+
+    impl EngineTrait for CountingEngine {
+        fn midi_event(&mut self, _midi_msg: &wmidi::MidiMessage) {
+            self.event_frames.push(self.frames);
+        }
+
+        fn midi_event_at(&mut self, frame_offset: usize, _midi_msg: &wmidi::MidiMessage) {
+            self.event_frames.push(self.frames + frame_offset);
+        }
+
+        fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+            for (l, r) in Iterator::zip(out_left.iter_mut(), out_right.iter_mut()) {
+                *l += 1.0;
+                *r += 1.0;
+            }
+            self.frames += out_left.len();
+            self.block_lengths.push(out_left.len());
+        }
+    }
+
+    #[test]
+    fn automation_interpolation() {
+        let automation = Automation::new(vec![
+            Breakpoint { frame: 10, value: 2.0 },
+            Breakpoint { frame: 0, value: 0.0 },
+            Breakpoint { frame: 20, value: -2.0 },
+        ]);
+        assert!(f32_eq(automation.value_at(0), 0.0));
+        assert!(f32_eq(automation.value_at(5), 1.0));
+        assert!(f32_eq(automation.value_at(10), 2.0));
+        assert!(f32_eq(automation.value_at(15), 0.0));
+        assert!(f32_eq(automation.value_at(20), -2.0));
+        assert!(f32_eq(automation.value_at(100), -2.0));
+    }
+
+    #[test]
+    fn automation_before_first_breakpoint() {
+        let automation = Automation::new(vec![Breakpoint { frame: 10, value: 3.0 }]);
+        assert!(f32_eq(automation.value_at(0), 3.0));
+        assert!(f32_eq(automation.value_at(10), 3.0));
+    }
+
+    #[test]
+    fn render_splits_blocks_at_events() {
+        let mut renderer = Renderer::new(CountingEngine::new(), 8);
+        let note_on = [0x90, 60, 100];
+        let events = vec![
+            MidiEvent::new(11, &note_on),
+            MidiEvent::new(3, &note_on),
+            MidiEvent::new(16, &note_on),
+        ];
+        let (left, right) = renderer.render(events, 20);
+        assert_eq!(left.len(), 20);
+        assert_eq!(right.len(), 20);
+
+        let engine = renderer.into_engine();
+        assert_eq!(engine.event_frames, vec![3, 11, 16]);
+        assert_eq!(engine.block_lengths, vec![3, 5, 3, 5, 4]);
+    }
+
+    #[test]
+    fn render_in_chunks() {
+        let mut renderer = Renderer::new(CountingEngine::new(), 4);
+        renderer.set_gain_automation(Automation::new(vec![
+            Breakpoint { frame: 0, value: 0.0 },
+            Breakpoint { frame: 8, value: -20.0 },
+        ]));
+        let note_on = [0x90, 60, 100];
+        renderer.render(vec![MidiEvent::new(2, &note_on), MidiEvent::new(5, &note_on), MidiEvent::new(9, &note_on)], 4);
+        let (left, _) = renderer.render(vec![MidiEvent::new(6, &note_on), MidiEvent::new(1, &note_on)], 4);
+        assert_eq!(renderer.position(), 8);
+        assert!(f32_eq(left[0], utils::dB_to_gain(-10.0)));
+        renderer.render(Vec::new(), 4);
+
+        let engine = renderer.into_engine();
+        assert_eq!(engine.event_frames, vec![2, 4, 5, 6, 9]);
+    }
+
+    #[test]
+    fn render_applies_gain_automation() {
+        let mut renderer = Renderer::new(CountingEngine::new(), 4);
+        renderer.set_gain_automation(Automation::new(vec![
+            Breakpoint { frame: 0, value: 0.0 },
+            Breakpoint { frame: 4, value: -20.0 },
+            Breakpoint { frame: 6, value: -100.0 },
+        ]));
+        let (left, right) = renderer.render(Vec::new(), 8);
+        assert!(f32_eq(left[0], 1.0));
+        assert!(f32_eq(left[2], utils::dB_to_gain(-10.0)));
+        assert!(f32_eq(right[4], 0.1));
+        assert!(f32_eq(right[7], 0.0));
+    }
+
+    #[test]
+    fn dither_quantizes_to_bit_depth() {
+        let mut quantized = Vec::new();
+        dither(&[0.0, 0.5, -1.5, 2.0, -2.0], 16, &mut quantized);
+        let lsb = 1 << 16;
+        assert!(quantized.iter().all(|q| q % lsb == 0));
+        assert!(quantized[0].abs() <= lsb);
+        assert!((quantized[1] - (1 << 30)).abs() <= lsb);
+        assert_eq!(quantized[2], i32::MIN);
+        assert_eq!(quantized[3], i32::MAX - (lsb - 1));
+        assert_eq!(quantized[4], i32::MIN);
+    }
+
+    #[test]
+    fn recorder_drops_blocks_when_pool_is_empty() {
+        let (sender, receiver) = mpsc::sync_channel(RECORD_POOL_SIZE);
+        let (recycle_sender, recycled) = mpsc::sync_channel(RECORD_POOL_SIZE);
+        recycle_sender.send(Vec::with_capacity(2 * RECORD_BLOCK_FRAMES)).unwrap();
+        let recorder = Recorder { sender, recycled: Mutex::new(recycled), dropped: Arc::new(AtomicUsize::new(0)) };
+
+        let block = vec![0.5; RECORD_BLOCK_FRAMES + 1];
+        recorder.record(&block, &block);
+
+        assert_eq!(recorder.dropped_blocks(), 1);
+        let buffer = receiver.try_recv().unwrap();
+        assert_eq!(buffer.len(), 2 * RECORD_BLOCK_FRAMES);
+        assert!(receiver.try_recv().is_err());
+    }
+}
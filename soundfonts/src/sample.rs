@@ -1,6 +1,15 @@
+use std::sync::Arc;
+
 use wmidi;
 
 use super::envelopes;
+use super::mapped::MappedFrames;
+use super::profiling;
+use super::simd;
+
+/// Voices past their attack whose gain stays below -90 dB for a whole block
+/// are dropped before their sample ends
+const SILENCE_GAIN: f32 = 3.162_277_7e-5;
 
 struct Voice {
     position: f64,
@@ -8,9 +17,40 @@ struct Voice {
     frequency: f64,
     gain: f32,
 
+    // pitch bend of this voice only as frequency ratio, and the bend
+    // currently applied, which follows the one of the sample and the voice
+    note_bend: f64,
+    bend: f64,
+
+    // frequency ratio to the glide target, multiplied by `glide_step` per
+    // frame for `glide_frames` frames
+    glide: f64,
+    glide_step: f64,
+    glide_frames: usize,
+
+    // own envelope, if it differs from the one of the sample
+    envelope: Option<envelopes::ADSREnvelope>,
     envelope_state: envelopes::State,
     last_envelope_gain: f32,
     release_start_gain: f32,
+
+    // crossfade on retrigger, fade_step > 0 fading in, < 0 fading out
+    fade_gain: f32,
+    fade_step: f32,
+
+    age: usize,
+
+    // index of the pitch variant played instead of the original sample
+    variant: Option<usize>,
+
+    // stolen voices are faded out and not counted as playing anymore
+    stolen: bool,
+
+    // frames until the voice starts to play
+    delay: usize,
+
+    // the gain stayed below `SILENCE_GAIN` during the last block
+    silent: bool,
 }
 
 impl Voice {
@@ -21,49 +61,370 @@ impl Voice {
             gain: gain,
             position: 0.0,
 
-            envelope_state: envelopes::State::AttackDecay(0),
+            note_bend: 1.0,
+            bend: 1.0,
+
+            glide: 1.0,
+            glide_step: 1.0,
+            glide_frames: 0,
+
+            envelope: None,
+            envelope_state: envelopes::State::attack(),
             last_envelope_gain: 1.0,
             release_start_gain: 1.0,
+
+            fade_gain: 1.0,
+            fade_step: 0.0,
+
+            age: 0,
+
+            variant: None,
+
+            stolen: false,
+
+            delay: 0,
+
+            silent: false,
+        }
+    }
+
+    // releases the voice by its envelope, or fades it out over `click_guard`
+    // frames if the envelope would cut it off faster
+    fn release(&mut self, envelope: &envelopes::ADSREnvelope, click_guard: usize) {
+        let envelope = self.envelope.as_ref().unwrap_or(envelope);
+        if envelope.release_frames() < click_guard {
+            self.fade_step = -1.0 / click_guard as f32;
+        } else {
+            self.envelope_state = envelopes::State::release();
+            self.release_start_gain = self.last_envelope_gain;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Interpolation {
+    Cubic,
+    Linear,
+}
+
+/// Sample values as stored in memory, converted to floating point when
+/// interpolated
+pub(crate) trait SampleValue: Copy {
+    fn value(self) -> f64;
+}
+
+impl SampleValue for f32 {
+    fn value(self) -> f64 {
+        self as f64
+    }
+}
+
+impl SampleValue for i16 {
+    fn value(self) -> f64 {
+        self as f64 / 32768.0
+    }
+}
+
+enum Channels {
+    Float(Vec<Vec<f32>>),
+    Int16(Vec<Vec<i16>>),
+    Mapped(Arc<MappedFrames>),
+    Generator(Waveform),
+}
+
+/// The built-in oscillators played by `sample=*sine` and the like instead of
+/// sample files. One frame of a generator is one cycle of its waveform, so
+/// that it is tuned by the samplerate given to the region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Noise,
+    Silence,
+}
+
+impl Waveform {
+    /// The waveform of the sample name `name` starting with `*`
+    pub fn from_sample_name(name: &str) -> Option<Waveform> {
+        match name {
+            "*sine" => Some(Waveform::Sine),
+            "*saw" => Some(Waveform::Saw),
+            "*square" => Some(Waveform::Square),
+            "*triangle" | "*tri" => Some(Waveform::Triangle),
+            "*noise" => Some(Waveform::Noise),
+            "*silence" => Some(Waveform::Silence),
+            _ => None,
+        }
+    }
+
+    // the value at `phase` of the cycle starting at `frame`
+    fn value(self, frame: usize, phase: f64) -> f32 {
+        match self {
+            Waveform::Sine => (phase * 2.0 * std::f64::consts::PI).sin() as f32,
+            Waveform::Saw => (2.0 * phase - 1.0) as f32,
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => (1.0 - 4.0 * (phase - 0.5).abs()) as f32,
+            Waveform::Noise => {
+                // hash of the position, so that the noise needs no state
+                let mut x = (frame as u64) ^ phase.to_bits().rotate_left(32);
+                x = (x ^ (x >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+                x = (x ^ (x >> 33)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+                x ^= x >> 33;
+                ((x >> 40) as f64 / (1u64 << 23) as f64 - 1.0) as f32
+            }
+            Waveform::Silence => 0.0,
+        }
+    }
+}
+
+/// The frames of a sample padded with silence, so that the interpolation
+/// never reads beyond the data. Never changed after loading and shared by all
+/// engines playing the sample.
+pub struct SampleBuffer {
+    // one or two channels, mono samples are played on both outputs
+    channels: Channels,
+    frames: usize,
+}
+
+impl SampleBuffer {
+    pub fn new(channels: Vec<Vec<f32>>, max_block_length: usize) -> SampleBuffer {
+        let (channels, frames) = pad(channels, max_block_length);
+        SampleBuffer { channels: Channels::Float(channels), frames }
+    }
+
+    /// Buffer storing the frames as 16 bit integers, taking half the memory
+    /// at the cost of precision
+    pub fn new_16bit(channels: Vec<Vec<f32>>, max_block_length: usize) -> SampleBuffer {
+        let (channels, frames) = pad(channels, max_block_length);
+        let channels = channels.iter()
+            .map(|ch| ch.iter().map(|v| (v * 32768.0).round().max(-32768.0).min(32767.0) as i16).collect())
+            .collect();
+        SampleBuffer { channels: Channels::Int16(channels), frames }
+    }
+
+    /// Buffer reading the frames from a mapped file while playing, which
+    /// takes no memory of its own and needs no padding
+    pub fn mapped(frames: Arc<MappedFrames>) -> SampleBuffer {
+        SampleBuffer { frames: frames.frames(), channels: Channels::Mapped(frames) }
+    }
+
+    /// Buffer of the oscillator `waveform`, which never ends
+    pub fn generator(waveform: Waveform) -> SampleBuffer {
+        SampleBuffer { channels: Channels::Generator(waveform), frames: usize::MAX }
+    }
+
+    /// Number of frames without the padding
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn channel_count(&self) -> usize {
+        match &self.channels {
+            Channels::Float(channels) => channels.len(),
+            Channels::Int16(channels) => channels.len(),
+            Channels::Mapped(frames) => frames.channel_count(),
+            Channels::Generator(_) => 1,
+        }
+    }
+
+    /// Memory taken by the frames including the padding in bytes
+    pub fn memory_size(&self) -> usize {
+        match &self.channels {
+            Channels::Float(channels) => channels.iter().map(|ch| ch.len() * std::mem::size_of::<f32>()).sum(),
+            Channels::Int16(channels) => channels.iter().map(|ch| ch.len() * std::mem::size_of::<i16>()).sum(),
+            Channels::Mapped(_) | Channels::Generator(_) => 0,
+        }
+    }
+
+    #[cfg(test)]
+    fn channel_lengths(&self) -> Vec<usize> {
+        match &self.channels {
+            Channels::Float(channels) => channels.iter().map(|ch| ch.len()).collect(),
+            Channels::Int16(channels) => channels.iter().map(|ch| ch.len()).collect(),
+            Channels::Mapped(_) | Channels::Generator(_) => Vec::new(),
         }
     }
 }
 
+/// Frames of a sample played repeatedly, `end` is the first frame after the
+/// loop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loop {
+    pub start: usize,
+    pub end: usize,
+    /// only looped until the voice is released
+    pub sustain_only: bool,
+}
+
+/// Crossfades the `length` frames before the loop end with the ones before
+/// the loop start, so that the loop wraps around without a click. The
+/// crossfade is shortened if there are not enough frames before the start.
+pub fn crossfade_loop(channels: &mut [Vec<f32>], sample_loop: Loop, length: usize) {
+    let length = length.min(sample_loop.start).min(sample_loop.end - sample_loop.start);
+    for channel in channels.iter_mut() {
+        for i in 0..length {
+            let gain = (i + 1) as f32 / (length + 1) as f32;
+            let fade_out = sample_loop.end - length + i;
+            let fade_in = sample_loop.start - length + i;
+            channel[fade_out] = channel[fade_out] * (1.0 - gain) + channel[fade_in] * gain;
+        }
+    }
+}
+
+/// A sample rendered at another pitch at load time. Voices close to that
+/// pitch play it instead of the original, so that they are interpolated at
+/// a ratio close to 1.
+#[derive(Clone)]
+pub struct PitchVariant {
+    /// frequency ratio to the original sample
+    pub ratio: f64,
+    pub buffer: Arc<SampleBuffer>,
+}
+
+/// Renders `channels` pitched by the frequency ratio `ratio` using cubic
+/// interpolation
+pub fn pitched(channels: &[Vec<f32>], ratio: f64) -> Vec<Vec<f32>> {
+    channels.iter().map(|channel| {
+        let mut padded = channel.clone();
+        padded.resize(channel.len() + 3, 0.0);
+        let frames = (channel.len() as f64 / ratio).ceil() as usize;
+        (0..frames).map(|i| {
+            let position = i as f64 * ratio;
+            let pos = position.floor();
+            cubic(&padded, pos as usize, position - pos)
+        }).collect()
+    }).collect()
+}
+
+// downmixes more than two channels and appends the padding, returns the
+// number of frames without the padding
+fn pad(mut channels: Vec<Vec<f32>>, max_block_length: usize) -> (Vec<Vec<f32>>, usize) {
+    match channels.len() {
+        0 => channels.push(Vec::new()),
+        1 | 2 => {}
+        _ => channels = downmix(channels),
+    }
+    let frames = channels.iter().map(|ch| ch.len()).min().unwrap_or(0);
+
+    // at least three frames of padding for the cubic interpolation
+    // reading behind the last frame
+    let max_block_length = max_block_length.max(1);
+    let reserve_frames = (((frames / max_block_length) + 2) * max_block_length).max(frames + 3);
+    for channel in &mut channels {
+        channel.resize(reserve_frames, 0.0);
+    }
+    (channels, frames)
+}
+
 pub struct Sample {
-    sample_data: Vec<f32>,
+    buffer: Arc<SampleBuffer>,
+    variants: Vec<PitchVariant>,
+    sample_loop: Option<Loop>,
 
     voices: Vec<Voice>,
 
-    real_sample_length: f64,
     max_block_length: usize,
 
     native_frequency: f64,
 
     envelope: envelopes::ADSREnvelope,
+
+    interpolation: Interpolation,
+
+    retrigger_fade: usize,
+
+    // frames faded in at the start and out at the abrupt end of a voice
+    click_guard: usize,
+
+    // frame of the sample the next voices start at, and the frames they
+    // wait before they start
+    start_offset: usize,
+    start_delay: usize,
+
+    // gain applied to all voices, ramped to the target within a block
+    volume: f32,
+    target_volume: f32,
+
+    // playback speed factor applied to all voices
+    pitch_shift: f64,
+
+    // voices dropped for being silent so far
+    reclaimed_voices: usize,
+    // a voice was processed with an inactive envelope
+    inactive_envelope: bool,
+
+    // pitch bend of all voices as frequency ratio and the part of the
+    // remaining bend applied per frame
+    bend: f64,
+    bend_smoothing: f64,
+
+    scratch_frame: Vec<usize>,
+    scratch_remainder: Vec<f64>,
+    scratch_left: Vec<f32>,
+    scratch_right: Vec<f32>,
+    scratch_gain: Vec<f32>,
+    scratch_volume: Vec<f32>,
 }
 
 impl Sample {
+    #[cfg(any(test, feature = "bench"))]
     pub fn new(
-        mut sample_data: Vec<f32>,
+        channels: Vec<Vec<f32>>,
         max_block_length: usize,
         native_frequency: f64,
         envelope: envelopes::ADSREnvelope,
     ) -> Self {
-        let real_sample_length = sample_data.len();
-        let frames = real_sample_length / 2;
-
-        let reserve_frames = ((frames / max_block_length) + 2) * max_block_length;
-        sample_data.resize(reserve_frames * 2, 0.0);
+        Self::with_buffer(Arc::new(SampleBuffer::new(channels, max_block_length)), max_block_length, native_frequency, envelope)
+    }
 
+    /// Sample playing the frames of `buffer`, which may be shared with other
+    /// samples
+    pub fn with_buffer(
+        buffer: Arc<SampleBuffer>,
+        max_block_length: usize,
+        native_frequency: f64,
+        envelope: envelopes::ADSREnvelope,
+    ) -> Self {
         Sample {
-            sample_data: sample_data,
+            buffer,
+            variants: Vec::new(),
+            sample_loop: None,
 
             voices: Vec::new(),
-            real_sample_length: frames as f64,
             max_block_length: max_block_length,
 
             native_frequency: native_frequency,
 
             envelope: envelope,
+
+            interpolation: Interpolation::Cubic,
+
+            retrigger_fade: 0,
+
+            click_guard: 0,
+
+            start_offset: 0,
+            start_delay: 0,
+
+            volume: 1.0,
+            target_volume: 1.0,
+
+            pitch_shift: 1.0,
+
+            reclaimed_voices: 0,
+            inactive_envelope: false,
+
+            bend: 1.0,
+            bend_smoothing: 1.0,
+
+            scratch_frame: vec![0; max_block_length],
+            scratch_remainder: vec![0.0; max_block_length],
+            scratch_left: vec![0.0; max_block_length],
+            scratch_right: vec![0.0; max_block_length],
+            scratch_gain: vec![0.0; max_block_length],
+            scratch_volume: vec![0.0; max_block_length],
         }
     }
 
@@ -71,69 +432,489 @@ impl Sample {
         !self.voices.is_empty()
     }
 
+    pub fn voice_count(&self) -> usize {
+        self.voices.iter().filter(|v| !v.stolen).count()
+    }
+
+    /// Number of voices dropped so far because they became inaudible before
+    /// their sample ended
+    pub fn reclaimed_voices(&self) -> usize {
+        self.reclaimed_voices
+    }
+
+    /// Whether a voice was processed with an inactive envelope since the last
+    /// call, which is a bug reported by `Diagnostic::InactiveEnvelope`
+    pub(crate) fn take_inactive_envelope(&mut self) -> bool {
+        std::mem::take(&mut self.inactive_envelope)
+    }
+
+    /// Age in frames of the oldest voice either releasing or not
+    pub fn oldest_voice_age(&self, releasing: bool) -> Option<usize> {
+        self.voices.iter()
+            .filter(|v| !v.stolen && v.envelope_state.is_releasing() == releasing)
+            .map(|v| v.age)
+            .max()
+    }
+
+    pub fn kill_oldest_voice(&mut self, releasing: bool) {
+        let oldest = self.voices.iter()
+            .enumerate()
+            .filter(|(_, v)| !v.stolen && v.envelope_state.is_releasing() == releasing)
+            .max_by_key(|(_, v)| v.age)
+            .map(|(i, _)| i);
+        if let Some(i) = oldest {
+            if self.click_guard > 0 {
+                let voice = &mut self.voices[i];
+                voice.fade_step = -1.0 / self.click_guard as f32;
+                voice.stolen = true;
+            } else {
+                self.voices.remove(i);
+            }
+        }
+    }
+
+    pub(crate) fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    /// Length in frames of the crossfade from a still sounding voice to the
+    /// new one when a note is retriggered. With 0 the old voice is released.
+    pub fn set_retrigger_fade(&mut self, frames: usize) {
+        self.retrigger_fade = frames;
+    }
+
+    /// Length in frames of the fade in at the start of a voice and the fade
+    /// out when a voice ends before its envelope has faded it out, to avoid
+    /// clicks of samples not starting or ending at a zero crossing. 0 turns
+    /// it off.
+    pub fn set_click_guard(&mut self, frames: usize) {
+        self.click_guard = frames;
+    }
+
+    /// Frame the voices started from now on begin to play at, limited to the
+    /// length of the sample
+    pub fn set_start_offset(&mut self, frames: usize) {
+        self.start_offset = frames.min(self.buffer.frames().saturating_sub(1));
+    }
+
+    /// Frames the voices started from now on wait before they begin to play
+    pub fn set_start_delay(&mut self, frames: usize) {
+        self.start_delay = frames;
+    }
+
+    /// Gain applied to all voices. Changes are ramped over the next block
+    /// to avoid zipper noise.
+    pub fn set_volume(&mut self, volume: f32) {
+        self.target_volume = volume;
+        if self.voices.is_empty() {
+            self.volume = volume;
+        }
+    }
+
+    pub fn has_voice_for(&self, note: wmidi::Note) -> bool {
+        self.voices.iter().any(|v| v.note == note)
+    }
+
+    /// Bends the pitch of the voices of `note` by the frequency ratio
+    pub fn set_note_bend(&mut self, note: wmidi::Note, ratio: f64) {
+        for voice in self.voices.iter_mut().filter(|v| v.note == note) {
+            voice.note_bend = ratio;
+        }
+    }
+
+    /// Bends the pitch of all voices by the frequency ratio, on top of the
+    /// bends of single notes
+    pub fn set_bend(&mut self, ratio: f64) {
+        self.bend = ratio;
+    }
+
+    /// Bend changes reach about two thirds of the way within `frames`, 0
+    /// applies them at once
+    pub fn set_bend_smoothing(&mut self, frames: f64) {
+        self.bend_smoothing = if frames > 0.0 { 1.0 - (-1.0 / frames).exp() } else { 1.0 };
+    }
+
+    pub fn set_pitch_shift(&mut self, ratio: f64) {
+        self.pitch_shift = ratio;
+    }
+
+    /// Plays the frames of `buffer` recorded at `native_frequency` from now
+    /// on, meant to be called while no voice is playing
+    pub fn set_buffer(&mut self, buffer: Arc<SampleBuffer>, native_frequency: f64) {
+        self.buffer = buffer;
+        self.native_frequency = native_frequency;
+    }
+
+    pub fn set_loop(&mut self, sample_loop: Option<Loop>) {
+        self.sample_loop = sample_loop;
+    }
+
+    /// Pitch variants to play instead of the original sample for notes
+    /// closer to their pitch
+    pub fn set_pitch_variants(&mut self, variants: Vec<PitchVariant>) {
+        self.variants = variants;
+    }
+
+    // the variant with the playback ratio closest to 1, `None` for the original
+    fn closest_variant(&self, frequency: f64) -> Option<usize> {
+        let distance = |ratio: f64| (frequency / self.native_frequency / ratio).ln().abs();
+        let closest = self.variants.iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| distance(a.ratio).partial_cmp(&distance(b.ratio)).unwrap_or(std::cmp::Ordering::Equal))?;
+        if distance(closest.1.ratio) < distance(1.0) {
+            Some(closest.0)
+        } else {
+            None
+        }
+    }
+
     pub fn note_on(&mut self, note: wmidi::Note, frequency: f64, gain: f32) {
+        self.start_voice(Voice::new(note, frequency, gain));
+    }
+
+    /// Starts a voice with an envelope other than the one of the sample
+    pub(crate) fn note_on_with_envelope(&mut self,
+                                        note: wmidi::Note,
+                                        frequency: f64,
+                                        gain: f32,
+                                        envelope: envelopes::ADSREnvelope) {
+        let mut voice = Voice::new(note, frequency, gain);
+        voice.envelope = Some(envelope);
+        self.start_voice(voice);
+    }
+
+    fn start_voice(&mut self, mut voice: Voice) {
+        let note = voice.note;
+        voice.bend = self.bend;
+        voice.variant = self.closest_variant(voice.frequency);
+        let variant_ratio = voice.variant.map_or(1.0, |i| self.variants[i].ratio);
+        voice.position = self.start_offset as f64 / variant_ratio;
+        voice.delay = self.start_delay;
+        if self.retrigger_fade > 0 {
+            let step = 1.0 / self.retrigger_fade as f32;
+            for old in &mut self.voices {
+                if old.note == note && !old.envelope_state.is_releasing() && old.fade_step >= 0.0 {
+                    old.fade_step = -step;
+                    voice.fade_gain = 0.0;
+                    voice.fade_step = step;
+                }
+            }
+        }
+        if self.click_guard > 0 && voice.fade_step == 0.0 {
+            voice.fade_gain = 0.0;
+            voice.fade_step = 1.0 / self.click_guard as f32;
+        }
         self.note_off(note);
-        self.voices.push(Voice::new(note, frequency, gain))
+        self.voices.push(voice)
+    }
+
+    /// Moves the held voice of `from` to `note`, gliding from its pitch to
+    /// `frequency` over `frames`. Returns false if no voice of `from` is held.
+    pub fn glide(&mut self, from: wmidi::Note, note: wmidi::Note, frequency: f64, frames: usize) -> bool {
+        let voice = self.voices.iter_mut()
+            .rev()
+            .find(|v| v.note == from && !v.envelope_state.is_releasing() && v.fade_step >= 0.0);
+        let voice = match voice {
+            Some(voice) => voice,
+            None => return false,
+        };
+        voice.note = note;
+        voice.glide *= voice.frequency / frequency;
+        voice.frequency = frequency;
+        if frames > 0 {
+            voice.glide_step = (1.0 / voice.glide).powf(1.0 / frames as f64);
+            voice.glide_frames = frames;
+        } else {
+            voice.glide = 1.0;
+            voice.glide_frames = 0;
+        }
+        true
     }
 
     pub fn note_off(&mut self, note: wmidi::Note) {
         for voice in &mut self.voices {
-            if voice.note == note && !voice.envelope_state.is_releasing() {
-                voice.envelope_state = envelopes::State::Release(0);
-                voice.release_start_gain = voice.last_envelope_gain;
+            if voice.note == note && !voice.envelope_state.is_releasing() && voice.fade_step >= 0.0 {
+                voice.release(&self.envelope, self.click_guard);
             }
         }
     }
 
+    /// Fades all voices out over `frames`, at least over the click guard
+    pub fn fade_out(&mut self, frames: usize) {
+        let frames = frames.max(self.click_guard);
+        if frames == 0 {
+            self.voices.clear();
+            return;
+        }
+        for voice in &mut self.voices {
+            voice.fade_step = voice.fade_step.min(-1.0 / frames as f32);
+        }
+    }
+
     pub fn all_notes_off(&mut self) {
         for voice in &mut self.voices {
-            voice.envelope_state = envelopes::State::Release(0);
-            voice.release_start_gain = voice.last_envelope_gain;
+            voice.release(&self.envelope, self.click_guard);
         }
     }
 
+    /// Renders all voices into the buffers. Does not allocate, buffers longer
+    /// than `max_block_length` are processed in several blocks.
     pub fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
-        for voice in &mut self.voices {
-            let ratio = voice.frequency / self.native_frequency;
-            let needed_sample_length =
-                (voice.position + self.max_block_length as f64 * ratio).ceil() as usize + 5;
-            if needed_sample_length * 2 >= self.sample_data.len() {
-                self.sample_data.resize(needed_sample_length * 2, 0.0)
+        let block_length = self.max_block_length.max(1);
+        for (l, r) in Iterator::zip(out_left.chunks_mut(block_length), out_right.chunks_mut(block_length)) {
+            self.process_block(l, r);
+        }
+    }
+
+    fn process_block(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+        let nframes = out_left.len();
+        let scratch_frame = &mut self.scratch_frame[..nframes];
+        let scratch_remainder = &mut self.scratch_remainder[..nframes];
+        let scratch_left = &mut self.scratch_left[..nframes];
+        let scratch_right = &mut self.scratch_right[..nframes];
+        let scratch_gain = &mut self.scratch_gain[..nframes];
+        let scratch_volume = &mut self.scratch_volume[..nframes];
+
+        let apply_volume = self.volume != 1.0 || self.target_volume != 1.0;
+        if apply_volume {
+            let step = (self.target_volume - self.volume) / nframes as f32;
+            for (i, v) in scratch_volume.iter_mut().enumerate() {
+                *v = self.volume + step * (i + 1) as f32;
             }
+        }
 
-            let (envelope, mut env_position) = self.envelope.active_envelope(voice.envelope_state);
-            for (l, r) in Iterator::zip(out_left.iter_mut(), out_right.iter_mut()) {
-                let (remainder, sample_pos) = {
+        for voice in &mut self.voices {
+            // a delayed voice starts within the block or later
+            let start = voice.delay.min(nframes);
+            voice.delay -= start;
+            if start == nframes {
+                voice.age += nframes;
+                continue;
+            }
+            let scratch_frame = &mut scratch_frame[start..];
+            let scratch_remainder = &mut scratch_remainder[start..];
+            let scratch_left = &mut scratch_left[start..];
+            let scratch_right = &mut scratch_right[start..];
+            let scratch_gain = &mut scratch_gain[start..];
+            let scratch_volume = &scratch_volume[start..];
+            let out_left = &mut out_left[start..];
+            let out_right = &mut out_right[start..];
+
+            let (buffer, variant_ratio) = match voice.variant {
+                Some(i) => (&self.variants[i].buffer, self.variants[i].ratio),
+                None => (&self.buffer, 1.0),
+            };
+            let base_ratio = voice.frequency / (self.native_frequency * variant_ratio) * self.pitch_shift;
+            let target_bend = voice.note_bend * self.bend;
+            let sample_length = buffer.frames() as f64;
+            let last_frame = buffer.frames();
+            // loop points of the played variant
+            let loop_range = self.sample_loop
+                .filter(|l| !(l.sustain_only && voice.envelope_state.is_releasing()))
+                .map(|l| (l.start as f64 / variant_ratio, l.end as f64 / variant_ratio));
+
+            let stopwatch = profiling::Stopwatch::start();
+            for (frame, remainder) in Iterator::zip(scratch_frame.iter_mut(), scratch_remainder.iter_mut()) {
+                if voice.position < sample_length {
                     let sample_pos = voice.position.floor();
-                    ((voice.position - sample_pos), sample_pos as usize)
-                };
-                let gain = voice.gain * envelope[env_position] * voice.release_start_gain;
-                *l += gain * cubic(&self.sample_data, 2 * sample_pos, remainder);
-                *r += gain * cubic(&self.sample_data, 2 * sample_pos + 1, remainder);
-                voice.position += ratio;
-                env_position += 1;
+                    *frame = sample_pos as usize;
+                    *remainder = voice.position - sample_pos;
+                } else {
+                    // the voice has ended within the block, read silence from the padding
+                    *frame = last_frame;
+                    *remainder = 0.0;
+                }
+                if voice.bend != target_bend {
+                    voice.bend += (target_bend - voice.bend) * self.bend_smoothing;
+                    if (target_bend - voice.bend).abs() < 1e-9 {
+                        voice.bend = target_bend;
+                    }
+                }
+                if voice.glide_frames > 0 {
+                    voice.glide_frames -= 1;
+                    voice.glide = if voice.glide_frames > 0 { voice.glide * voice.glide_step } else { 1.0 };
+                }
+                voice.position += base_ratio * voice.bend * voice.glide;
+                if let Some((start, end)) = loop_range {
+                    if voice.position >= end {
+                        voice.position = start + (voice.position - start) % (end - start);
+                    }
+                }
+            }
+            match &buffer.channels {
+                Channels::Float(channels) => interpolate(channels, self.interpolation, scratch_frame, scratch_remainder, scratch_left, scratch_right),
+                Channels::Int16(channels) => interpolate(channels, self.interpolation, scratch_frame, scratch_remainder, scratch_left, scratch_right),
+                Channels::Mapped(frames) => interpolate_mapped(frames, self.interpolation, scratch_frame, scratch_remainder, scratch_left, scratch_right),
+                Channels::Generator(waveform) => {
+                    for ((frame, remainder), l) in scratch_frame.iter().zip(scratch_remainder.iter()).zip(scratch_left.iter_mut()) {
+                        *l = waveform.value(*frame, *remainder);
+                    }
+                }
+            }
+            stopwatch.stop(profiling::Subsystem::Interpolation);
+
+            let stopwatch = profiling::Stopwatch::start();
+            let adsr = voice.envelope.as_ref().unwrap_or(&self.envelope);
+            if adsr.process(&mut voice.envelope_state, scratch_gain) {
+                self.inactive_envelope = true;
+            }
+            simd::envelope_gains(scratch_gain, voice.gain, voice.release_start_gain);
+            voice.last_envelope_gain = adsr.next_value(&voice.envelope_state);
+            if voice.fade_step != 0.0 {
+                for g in scratch_gain.iter_mut() {
+                    voice.fade_gain = (voice.fade_gain + voice.fade_step).max(0.0).min(1.0);
+                    *g *= voice.fade_gain;
+                }
+                if voice.fade_gain <= 0.0 {
+                    voice.envelope_state = envelopes::State::inactive();
+                } else if voice.fade_gain >= 1.0 {
+                    voice.fade_step = 0.0;
+                }
             }
-            voice.last_envelope_gain = *envelope
-                .get(env_position)
-                .unwrap_or(&envelope[env_position - 1]);
-            self.envelope
-                .update_state(&mut voice.envelope_state, env_position);
+            // fade out before the end of the sample
+            let ratio = base_ratio * voice.bend * voice.glide;
+            let guard_frames = self.click_guard as f64 * ratio;
+            if self.click_guard > 0 && loop_range.is_none() && voice.position > sample_length - guard_frames {
+                let positions = Iterator::zip(scratch_frame.iter(), scratch_remainder.iter());
+                for (g, (frame, remainder)) in Iterator::zip(scratch_gain.iter_mut(), positions) {
+                    *g *= ((sample_length - (*frame as f64 + remainder)) / guard_frames).max(0.0).min(1.0) as f32;
+                }
+            }
+            if voice.envelope_state.is_past_attack() {
+                voice.silent = scratch_gain.iter().all(|g| g.abs() < SILENCE_GAIN);
+            }
+            if apply_volume {
+                for (g, v) in Iterator::zip(scratch_gain.iter_mut(), scratch_volume.iter()) {
+                    *g *= v;
+                }
+            }
+            stopwatch.stop(profiling::Subsystem::Envelope);
+
+            let stopwatch = profiling::Stopwatch::start();
+            let scratch_right: &[f32] = if buffer.channel_count() == 2 { scratch_right } else { scratch_left };
+            simd::mix(out_left, scratch_left, scratch_gain);
+            simd::mix(out_right, scratch_right, scratch_gain);
+            stopwatch.stop(profiling::Subsystem::Mixing);
+
+            voice.age += nframes;
         }
-        let real_sample_length = self.real_sample_length;
+        self.volume = self.target_volume;
+        let (buffer, variants) = (&self.buffer, &self.variants);
+        let mut reclaimed = 0;
         self.voices.retain(|voice| {
-            voice.position < real_sample_length && voice.envelope_state.is_active()
+            let frames = voice.variant.map_or(buffer.frames(), |i| variants[i].buffer.frames());
+            let playing = voice.position < frames as f64 && voice.envelope_state.is_active();
+            if playing && voice.silent {
+                reclaimed += 1;
+                return false;
+            }
+            playing
         });
+        self.reclaimed_voices += reclaimed;
     }
 }
 
-fn cubic(sample_data: &[f32], pos: usize, remainder: f64) -> f32 {
+// interpolates the channels at the frames and remainders into the outputs,
+// only into `out_left` for mono samples
+fn interpolate<T: SampleValue>(channels: &[Vec<T>],
+                               interpolation: Interpolation,
+                               frames: &[usize],
+                               remainders: &[f64],
+                               out_left: &mut [f32],
+                               out_right: &mut [f32]) {
+    let (interpolate_mono, interpolate_stereo): (fn(&[T], usize, f64) -> f32,
+                                                 fn(&[T], &[T], &[usize], &[f64], &mut [f32], &mut [f32])) =
+        match interpolation {
+            Interpolation::Cubic => (cubic, simd::cubic_stereo),
+            Interpolation::Linear => (linear, simd::linear_stereo),
+        };
+    match channels {
+        [left, right] => interpolate_stereo(left, right, frames, remainders, out_left, out_right),
+        channels => {
+            let positions = Iterator::zip(frames.iter(), remainders.iter());
+            for ((frame, remainder), l) in Iterator::zip(positions, out_left.iter_mut()) {
+                *l = interpolate_mono(&channels[0], *frame, *remainder);
+            }
+        }
+    }
+}
+
+// like `interpolate()` reading the frames from the mapped file
+fn interpolate_mapped(mapped: &MappedFrames,
+                      interpolation: Interpolation,
+                      frames: &[usize],
+                      remainders: &[f64],
+                      out_left: &mut [f32],
+                      out_right: &mut [f32]) {
+    let value = |frame: usize, channel| mapped.value(frame, channel) as f64;
+    let at = |frame: usize, remainder, channel| match interpolation {
+        Interpolation::Cubic => cubic_points(frame.checked_sub(1).map_or(0.0, |f| value(f, channel)),
+                                             value(frame, channel),
+                                             value(frame + 1, channel),
+                                             value(frame + 2, channel),
+                                             remainder),
+        Interpolation::Linear => {
+            let (p1, p2) = (value(frame, channel), value(frame + 1, channel));
+            (p1 + (p2 - p1) * remainder) as f32
+        }
+    };
+    let positions = Iterator::zip(frames.iter(), remainders.iter());
+    if mapped.channel_count() == 2 {
+        let outputs = Iterator::zip(out_left.iter_mut(), out_right.iter_mut());
+        for ((frame, remainder), (l, r)) in Iterator::zip(positions, outputs) {
+            *l = at(*frame, *remainder, 0);
+            *r = at(*frame, *remainder, 1);
+        }
+    } else {
+        for ((frame, remainder), l) in Iterator::zip(positions, out_left.iter_mut()) {
+            *l = at(*frame, *remainder, 0);
+        }
+    }
+}
+
+// Channels of multichannel samples are taken alternating as left and right,
+// a last channel without partner goes to both sides.
+fn downmix(channels: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+    let frames = channels.iter().map(|ch| ch.len()).min().unwrap_or(0);
+    let mut left = vec![0.0; frames];
+    let mut right = vec![0.0; frames];
+    let mut weights = [0.0f32; 2];
+    let pairs = channels.len() / 2;
+
+    for (i, channel) in channels.iter().enumerate() {
+        let sides: &[usize] = match i {
+            i if i >= 2 * pairs => &[0, 1],
+            i => &[i % 2],
+        };
+        for side in sides {
+            weights[*side] += 1.0;
+            let out = if *side == 0 { &mut left } else { &mut right };
+            for (o, v) in Iterator::zip(out.iter_mut(), channel.iter()) {
+                *o += v;
+            }
+        }
+    }
+
+    for (out, weight) in Iterator::zip([&mut left, &mut right].iter_mut(), weights.iter()) {
+        for o in out.iter_mut() {
+            *o /= weight;
+        }
+    }
+    vec![left, right]
+}
+
+// Scalar implementations, stereo samples are processed by the ones of `simd`
+pub(crate) fn cubic<T: SampleValue>(sample_data: &[T], pos: usize, remainder: f64) -> f32 {
     let len = sample_data.len();
 
-    let p0 = sample_data[((pos + len) - 2) % len] as f64;
-    let p1 = sample_data[pos] as f64;
-    let p2 = sample_data[pos + 2] as f64;
-    let p3 = sample_data[pos + 4] as f64;
+    cubic_points(sample_data[((pos + len) - 1) % len].value(),
+                 sample_data[pos].value(),
+                 sample_data[pos + 1].value(),
+                 sample_data[pos + 2].value(),
+                 remainder)
+}
 
+fn cubic_points(p0: f64, p1: f64, p2: f64, p3: f64, remainder: f64) -> f32 {
     let a = remainder;
     let b = 1.0 - a;
     let c = a * b;
@@ -141,10 +922,18 @@ fn cubic(sample_data: &[f32], pos: usize, remainder: f64) -> f32 {
     ((1.0 + 1.5 * c) * (p1 * b + p2 * a) - 0.5 * c * (p0 * b + p1 + p2 + p3 * a)) as f32
 }
 
+pub(crate) fn linear<T: SampleValue>(sample_data: &[T], pos: usize, remainder: f64) -> f32 {
+    let p1 = sample_data[pos].value();
+    let p2 = sample_data[pos + 1].value();
+
+    (p1 + (p2 - p1) * remainder) as f32
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
 
     use super::*;
+    use crate::utils;
 
     use std::convert::TryFrom;
     use std::f32::consts::SQRT_2;
@@ -168,11 +957,16 @@ pub(crate) mod tests {
         sample.voices.iter().any(|v| v.note == note && v.envelope_state.is_releasing())
     }
 
-    pub(crate) fn make_test_sample_data(nsamples: usize, samplerate: f64, freq: f64) -> Vec<f32> {
+    pub(crate) fn stereo(interleaved: Vec<f32>) -> Vec<Vec<f32>> {
+        utils::deinterleave(&interleaved, 2)
+    }
+
+    pub(crate) fn make_test_sample_data(nsamples: usize, samplerate: f64, freq: f64) -> Vec<Vec<f32>> {
         let omega = freq / samplerate * 2.0 * PI;
-        (0..nsamples * 2)
-            .map(|t| ((omega * (t / 2) as f64).sin() as f32))
-            .collect()
+        let channel: Vec<f32> = (0..nsamples)
+            .map(|t| ((omega * t as f64).sin() as f32))
+            .collect();
+        vec![channel.clone(), channel]
     }
 
     pub(crate) fn make_test_sample(nsamples: usize, samplerate: f64, freq: f64) -> Sample {
@@ -181,7 +975,7 @@ pub(crate) mod tests {
             sample_data,
             nsamples,
             freq,
-            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0, nsamples),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
         )
     }
 
@@ -191,7 +985,7 @@ pub(crate) mod tests {
         let mut last_l = 0.0;
         let mut last_r = 0.0;
 
-        let length = (sample.real_sample_length / 2.0).ceil() as usize;
+        let length = (sample.buffer.frames() as f64 / 2.0).ceil() as usize;
 
         let mut out_left = Vec::new();
         out_left.resize(length, 0.0);
@@ -212,7 +1006,7 @@ pub(crate) mod tests {
             last_r = *sl;
         }
 
-        let to_freq = samplerate / ((sample.real_sample_length / 2.0) as f64);
+        let to_freq = samplerate / (sample.buffer.frames() as f64 / 2.0);
 
         if halfw_l * to_freq > test_freq || (halfw_l + 1.0) * to_freq < test_freq {
             panic!(
@@ -277,12 +1071,12 @@ pub(crate) mod tests {
                           1.0, 0.5];
 
         let sample = Sample::new(
-            sample,
+            stereo(sample),
             16,
             440.0,
-            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0, 16),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
         );
-        assert_eq!(sample.sample_data.len(), 64);
+        assert_eq!(sample.buffer.channel_lengths(), vec![32, 32]);
     }
 
     #[test]
@@ -321,6 +1115,73 @@ pub(crate) mod tests {
         }
     }
 
+    fn max_jump_on_retrigger(retrigger_fade: usize) -> f32 {
+        let samplerate = 48000.0;
+        let mut sample = Sample::new(
+            make_test_sample_data(48000, samplerate, 100.0),
+            64,
+            100.0,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), samplerate as f32),
+        );
+        sample.set_retrigger_fade(retrigger_fade);
+        let note = wmidi::Note::A3;
+
+        let mut out = Vec::new();
+        sample.note_on(note, 100.0, 1.0);
+        for block in 0..12 {
+            if block == 2 {
+                sample.note_on(note, 100.0, 1.0);
+            }
+            let mut out_left = [0.0; 64];
+            let mut out_right = [0.0; 64];
+            sample.process(&mut out_left, &mut out_right);
+            out.extend_from_slice(&out_left);
+        }
+        out.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn retrigger_crossfade() {
+        // 128 frames in, a 100 Hz sine is close to its maximum
+        assert!(max_jump_on_retrigger(0) > 0.9);
+        assert!(max_jump_on_retrigger(240) < 0.02);
+    }
+
+    #[test]
+    fn retrigger_crossfade_voices() {
+        let mut sample = make_envelope_test_sample(1.0);
+        sample.set_retrigger_fade(4);
+        let note = wmidi::Note::C3;
+        sample.note_on(note, 1.0, 1.0);
+        sample.note_on(note, 1.0, 1.0);
+        assert_eq!(sample.voices.len(), 2);
+        assert!(is_playing_note(&sample, note));
+        assert!(!is_releasing_note(&sample, note));
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(sample.voices.len(), 1);
+        assert!(f32_eq(sample.voices[0].fade_gain, 1.0));
+        assert!(f32_eq(sample.voices[0].fade_step, 0.0));
+    }
+
+    #[test]
+    fn pitch_up_does_not_grow_sample_data() {
+        let mut sample = make_test_sample(1000, 48000.0, wmidi::Note::A3.to_freq_f64());
+        sample.max_block_length = 256;
+        let lengths = sample.buffer.channel_lengths();
+        sample.note_on(wmidi::Note::A3, 16.0 * 440.0, 1.0);
+
+        let mut out_left = [0.0; 600];
+        let mut out_right = [0.0; 600];
+        sample.process(&mut out_left, &mut out_right);
+
+        assert!(!sample.is_playing());
+        assert_eq!(sample.buffer.channel_lengths(), lengths);
+        assert_eq!(out_left[599], 0.0);
+    }
+
     #[test]
     fn test_pitch_up_late() {
         let mut sample = make_test_sample(36000, 48000.0, wmidi::Note::A3.to_freq_f64());
@@ -351,10 +1212,10 @@ pub(crate) mod tests {
         let frequency = note.to_freq_f64();
 
         let mut sample = Sample::new(
-            sample,
+            stereo(sample),
             max_block_length,
             frequency,
-            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0, max_block_length),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
         );
 
         sample.note_on(note, frequency, 1.0);
@@ -402,10 +1263,10 @@ pub(crate) mod tests {
         let frequency = note.to_freq_f64();
 
         let mut sample = Sample::new(
-            sample_data,
+            stereo(sample_data),
             max_block_length,
             frequency,
-            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0, max_block_length),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
         );
 
         sample.note_on(note, frequency, 1.0);
@@ -457,10 +1318,10 @@ pub(crate) mod tests {
         eg.set_release(5.0).unwrap();
 
         Sample::new(
-            sample,
+            stereo(sample),
             max_block_length,
             frequency,
-            envelopes::ADSREnvelope::new(&eg, 1.0, max_block_length),
+            envelopes::ADSREnvelope::new(&eg, 1.0),
         )
     }
 
@@ -749,10 +1610,10 @@ pub(crate) mod tests {
         let sample_dat = vec![1.0; 1 << 24];
         let eg = envelopes::Generator::default();
         let mut sample = Sample::new(
-            sample_dat,
+            stereo(sample_dat),
             4,
             1.0,
-            envelopes::ADSREnvelope::new(&eg, 1.0, 4),
+            envelopes::ADSREnvelope::new(&eg, 1.0),
         );
 
         for n in 0u8..127u8 {
@@ -773,24 +1634,298 @@ pub(crate) mod tests {
 
     #[test]
     fn test_cubic_interpolation() {
-        let d = [0.0, 0.0,
-                 1.0, -1.0,
-                 2.0, -2.0,
-                 3.0, -3.0,
-                 4.0, -4.0,
-                 0.0, 0.0];
-
-        assert_eq!(cubic(&d, 0, 0.0), 0.0);
-        assert_eq!(cubic(&d, 2, 0.0), 1.0);
-        assert_eq!(cubic(&d, 4, 0.0), 2.0);
-        assert_eq!(cubic(&d, 6, 0.0), 3.0);
-
-        assert_eq!(cubic(&d, 1, 0.0), -0.0);
-        assert_eq!(cubic(&d, 3, 0.0), -1.0);
-        assert_eq!(cubic(&d, 5, 0.0), -2.0);
-        assert_eq!(cubic(&d, 7, 0.0), -3.0);
-
-        assert_eq!(cubic(&d, 4, 0.5), 2.5);
-        assert_eq!(cubic(&d, 5, 0.5), -2.5);
+        let l = [0.0, 1.0, 2.0, 3.0, 4.0, 0.0];
+        let r = [0.0, -1.0, -2.0, -3.0, -4.0, 0.0];
+
+        assert_eq!(cubic(&l, 0, 0.0), 0.0);
+        assert_eq!(cubic(&l, 1, 0.0), 1.0);
+        assert_eq!(cubic(&l, 2, 0.0), 2.0);
+        assert_eq!(cubic(&l, 3, 0.0), 3.0);
+
+        assert_eq!(cubic(&r, 0, 0.0), -0.0);
+        assert_eq!(cubic(&r, 1, 0.0), -1.0);
+        assert_eq!(cubic(&r, 2, 0.0), -2.0);
+        assert_eq!(cubic(&r, 3, 0.0), -3.0);
+
+        assert_eq!(cubic(&l, 2, 0.5), 2.5);
+        assert_eq!(cubic(&r, 2, 0.5), -2.5);
+    }
+
+    #[test]
+    fn test_linear_interpolation() {
+        let l = [0.0, 1.0, 4.0];
+        let r = [0.0, -1.0, -4.0];
+
+        assert_eq!(linear(&l, 0, 0.0), 0.0);
+        assert_eq!(linear(&l, 1, 0.0), 1.0);
+        assert_eq!(linear(&r, 1, 0.0), -1.0);
+
+        assert_eq!(linear(&l, 0, 0.5), 0.5);
+        assert_eq!(linear(&l, 1, 0.25), 1.75);
+        assert_eq!(linear(&r, 1, 0.25), -1.75);
+    }
+
+    #[test]
+    fn multichannel_downmix() {
+        let quad = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.5, 0.0], vec![0.0, 0.5]];
+        assert_eq!(downmix(quad), vec![vec![0.75, 0.0], vec![0.0, 0.75]]);
+
+        let three = vec![vec![1.0], vec![-1.0], vec![0.5]];
+        assert_eq!(downmix(three), vec![vec![0.75], vec![-0.25]]);
+
+        let sample = Sample::new(
+            vec![vec![1.0; 4]; 6],
+            4,
+            440.0,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        assert_eq!(sample.buffer.channel_count(), 2);
+        assert_eq!(sample.buffer.frames(), 4);
+    }
+
+    #[test]
+    fn mono_sample_on_both_outputs() {
+        let max_block_length = 4;
+        let note = wmidi::Note::C3;
+        let frequency = note.to_freq_f64();
+        let mut sample = Sample::new(
+            vec![vec![1.0, 0.5, -0.5]],
+            max_block_length,
+            frequency,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        assert_eq!(sample.buffer.channel_count(), 1);
+
+        sample.note_on(note, frequency, 1.0);
+        let mut out_left = [0.0; 3];
+        let mut out_right = [0.0; 3];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [1.0, 0.5, -0.5]);
+        assert_eq!(out_right, [1.0, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn int16_sample_buffer() {
+        let sample_data = make_test_sample_data(64, 48000.0, 440.0);
+        let float_buffer = SampleBuffer::new(sample_data.clone(), 16);
+        let int_buffer = SampleBuffer::new_16bit(sample_data.clone(), 16);
+        assert_eq!(int_buffer.frames(), float_buffer.frames());
+        assert_eq!(int_buffer.channel_lengths(), float_buffer.channel_lengths());
+        assert_eq!(2 * int_buffer.memory_size(), float_buffer.memory_size());
+
+        let clipped = SampleBuffer::new_16bit(vec![vec![1.5, -1.5]], 4);
+        match &clipped.channels {
+            Channels::Int16(channels) => assert_eq!(channels[0][..2], [32767, -32768]),
+            _ => panic!("Not seen expected 16 bit channels"),
+        }
+
+        let note = wmidi::Note::A3;
+        let play = |buffer: SampleBuffer| {
+            let mut sample = Sample::with_buffer(Arc::new(buffer),
+                                                 16,
+                                                 note.to_freq_f64(),
+                                                 envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0));
+            sample.note_on(note, note.to_freq_f64() * 1.1, 1.0);
+            let mut out_left = [0.0; 16];
+            let mut out_right = [0.0; 16];
+            sample.process(&mut out_left, &mut out_right);
+            out_left
+        };
+        let float_out = play(float_buffer);
+        let int_out = play(int_buffer);
+        assert!(float_out.iter().any(|v| v.abs() > 0.1));
+        for (f, i) in float_out.iter().zip(int_out.iter()) {
+            assert!((f - i).abs() < 1e-4, "{} {}", f, i);
+        }
+    }
+
+    #[test]
+    fn render_pitched() {
+        let ramp = vec![vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]];
+        assert_eq!(pitched(&ramp, 2.0), vec![vec![0.0, 2.0, 4.0]]);
+        let down = pitched(&ramp, 0.5);
+        assert_eq!(down[0].len(), 12);
+        assert_eq!(down[0][2..4], [1.0, 1.5]);
+    }
+
+    #[test]
+    fn play_closest_pitch_variant() {
+        let samplerate = 48000.0;
+        let sample_data = make_test_sample_data(4800, samplerate, 100.0);
+        let note = wmidi::Note::A3;
+        let native_frequency = note.to_freq_f64();
+        let make_sample = |variants| {
+            let mut sample = Sample::new(sample_data.clone(),
+                                         1024,
+                                         native_frequency,
+                                         envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0));
+            sample.set_pitch_variants(variants);
+            sample
+        };
+        let variants: Vec<PitchVariant> = [2.0f64.powf(-1.0 / 12.0), 2.0f64.powf(1.0 / 12.0), 2.0f64.powf(2.0 / 12.0)].iter()
+            .map(|ratio| PitchVariant { ratio: *ratio, buffer: Arc::new(SampleBuffer::new(pitched(&sample_data, *ratio), 1024)) })
+            .collect();
+
+        let mut sample = make_sample(variants.clone());
+        for (frequency, variant) in [(native_frequency, None),
+                                     (native_frequency * 1.07, Some(1)),
+                                     (native_frequency * 1.3, Some(2)),
+                                     (native_frequency * 0.5, Some(0))].iter() {
+            assert_eq!(sample.closest_variant(*frequency), *variant);
+        }
+
+        let frequency = native_frequency * 2.0f64.powf(2.0 / 12.0);
+        let mut interpolated = make_sample(Vec::new());
+        interpolated.note_on(note, frequency, 1.0);
+        sample.note_on(note, frequency, 1.0);
+        assert_eq!(sample.voices[0].variant, Some(2));
+
+        let (mut left, mut right) = (vec![0.0; 1024], vec![0.0; 1024]);
+        let (mut expected_left, mut expected_right) = (vec![0.0; 1024], vec![0.0; 1024]);
+        sample.process(&mut left, &mut right);
+        interpolated.process(&mut expected_left, &mut expected_right);
+        for (v, e) in Iterator::zip(left.iter(), expected_left.iter()) {
+            assert!((v - e).abs() < 1e-3, "{} {}", v, e);
+        }
+    }
+
+    #[test]
+    fn play_loop() {
+        let note = wmidi::Note::C3;
+        let mut generator = envelopes::Generator::default();
+        generator.set_release(1.0).unwrap();
+        let make_sample = |sustain_only| {
+            let mut sample = Sample::new(vec![(0..8).map(|v| v as f32).collect()],
+                                         16,
+                                         note.to_freq_f64(),
+                                         envelopes::ADSREnvelope::new(&generator, 100.0));
+            sample.set_loop(Some(Loop { start: 2, end: 6, sustain_only }));
+            sample.note_on(note, note.to_freq_f64(), 1.0);
+            sample
+        };
+
+        let mut sample = make_sample(false);
+        let mut out_left = [0.0; 12];
+        let mut out_right = [0.0; 12];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 2.0, 3.0, 4.0, 5.0, 2.0, 3.0]);
+        assert!(sample.is_playing());
+
+        let mut sample = make_sample(true);
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 2.0, 3.0]);
+        sample.note_off(note);
+        assert!(sample.voices[0].position < 6.0);
+        let mut out_left = [0.0; 3];
+        let mut out_right = [0.0; 3];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(sample.voices[0].position, 7.0);
+    }
+
+    #[test]
+    fn crossfade_loop_seam() {
+        let mut channels = vec![(0..12).map(|v| v as f32).collect::<Vec<f32>>()];
+        crossfade_loop(&mut channels, Loop { start: 4, end: 10, sustain_only: false }, 3);
+        assert_eq!(channels[0], [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0 - 1.5, 8.0 - 3.0, 9.0 - 4.5, 10.0, 11.0]);
+
+        // not more than the frames before the loop start
+        let mut channels = vec![(0..12).map(|v| v as f32).collect::<Vec<f32>>()];
+        crossfade_loop(&mut channels, Loop { start: 1, end: 10, sustain_only: false }, 3);
+        assert_eq!(channels[0][8..], [8.0, 9.0 - 4.5, 10.0, 11.0]);
+    }
+
+    #[test]
+    fn click_guard() {
+        let note = wmidi::Note::C3;
+        let make_sample = |frames| {
+            let mut sample = Sample::new(vec![vec![1.0; frames]],
+                                         16,
+                                         note.to_freq_f64(),
+                                         envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0));
+            sample.set_click_guard(4);
+            sample.note_on(note, note.to_freq_f64(), 1.0);
+            sample
+        };
+
+        let mut sample = make_sample(12);
+        let mut out_left = [0.0; 14];
+        let mut out_right = [0.0; 14];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.25, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.75, 0.5, 0.25, 0.0, 0.0]);
+        assert!(!sample.is_playing());
+
+        let mut sample = make_sample(100);
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        sample.process(&mut out_left, &mut out_right);
+        sample.note_off(note);
+        let mut out_left = [0.0; 6];
+        let mut out_right = [0.0; 6];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.75, 0.5, 0.25, 0.0, 0.0, 0.0]);
+        assert!(!sample.is_playing());
+
+        let mut sample = make_sample(100);
+        sample.kill_oldest_voice(false);
+        assert_eq!(sample.voice_count(), 0);
+        assert_eq!(sample.oldest_voice_age(false), None);
+        assert!(sample.is_playing());
+        let mut out_left = [0.0; 6];
+        let mut out_right = [0.0; 6];
+        sample.process(&mut out_left, &mut out_right);
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn bend_smoothing() {
+        let mut sample = make_test_sample(100, 1.0, 1.0);
+        sample.note_on(wmidi::Note::C3, 1.0, 1.0);
+        sample.set_bend(2.0);
+        let mut out_left = [0.0; 1];
+        let mut out_right = [0.0; 1];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(sample.voices[0].bend, 2.0);
+
+        sample.set_bend_smoothing(10.0);
+        sample.set_note_bend(wmidi::Note::C3, 0.5);
+        let mut out_left = [0.0; 10];
+        let mut out_right = [0.0; 10];
+        sample.process(&mut out_left, &mut out_right);
+        let expected = 1.0 + (-1.0f64).exp();
+        assert!((sample.voices[0].bend - expected).abs() < 1e-9, "{}", sample.voices[0].bend);
+
+        // new voices start at the bend of the sample
+        sample.note_on(wmidi::Note::D3, 1.0, 1.0);
+        assert_eq!(sample.voices[1].bend, 2.0);
+    }
+
+    #[test]
+    fn drop_silent_voices() {
+        let make_sample = |sustain: f32| {
+            let mut generator = envelopes::Generator::default();
+            generator.set_decay(2.0).unwrap();
+            generator.set_sustain(sustain).unwrap();
+            Sample::new(stereo(vec![1.0; 2000]), 16, 1.0, envelopes::ADSREnvelope::new(&generator, 1.0))
+        };
+        let mut out_left = [0.0; 16];
+        let mut out_right = [0.0; 16];
+
+        let mut sample = make_sample(0.0);
+        sample.note_on(wmidi::Note::C3, 1.0, 1.0);
+        sample.process(&mut out_left, &mut out_right);
+        assert!(sample.is_playing());
+        sample.process(&mut out_left, &mut out_right);
+        assert!(!sample.is_playing());
+        assert_eq!(sample.reclaimed_voices(), 1);
+
+        let mut sample = make_sample(1.0);
+        sample.note_on(wmidi::Note::C3, 1.0, 1.0);
+        for _ in 0..10 {
+            sample.process(&mut out_left, &mut out_right);
+        }
+        assert!(sample.is_playing());
+        assert_eq!(sample.reclaimed_voices(), 0);
     }
 }
@@ -0,0 +1,74 @@
+// Serialization of the wmidi types as their MIDI numbers, to be used with
+// `#[serde(with = "...")]`
+
+use std::convert::TryFrom;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+fn from_u8<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u8>,
+{
+    let v = u8::deserialize(deserializer)?;
+    T::try_from(v).map_err(|_| D::Error::custom(format!("MIDI value {} out of range", v)))
+}
+
+pub(crate) mod note {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(note: &wmidi::Note, serializer: S) -> Result<S::Ok, S::Error> {
+        u8::from(*note).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<wmidi::Note, D::Error> {
+        from_u8(deserializer)
+    }
+}
+
+pub(crate) mod option_note {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(note: &Option<wmidi::Note>, serializer: S) -> Result<S::Ok, S::Error> {
+        note.map(u8::from).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<wmidi::Note>, D::Error> {
+        Option::<u8>::deserialize(deserializer)?
+            .map(|v| wmidi::Note::try_from(v).map_err(|_| D::Error::custom(format!("note {} out of range", v))))
+            .transpose()
+    }
+}
+
+pub(crate) mod velocity {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(vel: &wmidi::Velocity, serializer: S) -> Result<S::Ok, S::Error> {
+        u8::from(*vel).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<wmidi::Velocity, D::Error> {
+        from_u8(deserializer)
+    }
+}
+
+pub(crate) mod option_control_value {
+    use super::*;
+
+    pub(crate) fn serialize<S>(value: &Option<wmidi::ControlValue>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        value.map(u8::from).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<wmidi::ControlValue>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<u8>::deserialize(deserializer)?
+            .map(|v| wmidi::ControlValue::try_from(v).map_err(|_| D::Error::custom(format!("controller value {} out of range", v))))
+            .transpose()
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// How many region indices an entry keeps per list; further ones are only counted
+pub const MAX_LOGGED_REGIONS: usize = 16;
+
+/// Fixed size list of region indices, so that logging never allocates
+#[derive(Clone, Copy, Default)]
+pub struct RegionList {
+    indices: [usize; MAX_LOGGED_REGIONS],
+    len: usize,
+    overflow: usize,
+}
+
+impl RegionList {
+    pub(super) fn push(&mut self, index: usize) {
+        if self.len < MAX_LOGGED_REGIONS {
+            self.indices[self.len] = index;
+            self.len += 1;
+        } else {
+            self.overflow += 1;
+        }
+    }
+
+    pub fn indices(&self) -> &[usize] {
+        &self.indices[..self.len]
+    }
+
+    /// Number of regions that did not fit into the list
+    pub fn overflow(&self) -> usize {
+        self.overflow
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl fmt::Display for RegionList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.indices())?;
+        if self.overflow > 0 {
+            write!(f, " and {} more", self.overflow)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the engine made out of a single MIDI message
+///
+/// Entries are plain values and only formatted when the log is read.
+#[derive(Clone)]
+pub struct ActivityEntry {
+    pub message: Option<wmidi::MidiMessage<'static>>,
+    pub triggered: RegionList,
+    pub choked: RegionList,
+}
+
+impl fmt::Display for ActivityEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{:?}", message)?,
+            None => write!(f, "system message")?,
+        }
+        if !self.triggered.is_empty() {
+            write!(f, " triggered regions {}", self.triggered)?;
+        }
+        if !self.choked.is_empty() {
+            write!(f, " choked regions {}", self.choked)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ring of the most recent MIDI messages and the regions they triggered or choked
+///
+/// The ring is allocated up front, pushing an entry overwrites the oldest one.
+pub struct ActivityLog {
+    entries: Box<[Option<ActivityEntry>]>,
+    head: usize,
+    len: usize,
+}
+
+pub type SharedActivityLog = Arc<Mutex<ActivityLog>>;
+
+impl ActivityLog {
+    pub fn new(capacity: usize) -> ActivityLog {
+        ActivityLog {
+            entries: vec![None; capacity].into_boxed_slice(),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn shared(capacity: usize) -> SharedActivityLog {
+        Arc::new(Mutex::new(ActivityLog::new(capacity)))
+    }
+
+    pub(super) fn push(&mut self, entry: ActivityEntry) {
+        let capacity = self.entries.len();
+        if capacity == 0 {
+            return;
+        }
+        let index = (self.head + self.len) % capacity;
+        self.entries[index] = Some(entry);
+        if self.len == capacity {
+            self.head = (self.head + 1) % capacity;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// The logged entries, oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &ActivityEntry> {
+        let capacity = self.entries.len();
+        (0..self.len).filter_map(move |i| self.entries[(self.head + i) % capacity].as_ref())
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+        self.head = 0;
+        self.len = 0;
+    }
+}
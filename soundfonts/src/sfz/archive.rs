@@ -0,0 +1,242 @@
+// Instruments distributed as zip archives of the SFZ file and its samples,
+// addressed as `instrument.zip#Piano/Piano.sfz`. The entries are read from
+// the archive on demand without unpacking it.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use log::debug;
+
+use super::engine::EngineError;
+use super::loader::{self, SampleData, SampleProvider};
+use super::sample_path;
+
+const ARCHIVE_EXTENSIONS: [&str; 2] = [".zip", ".sfzpack"];
+
+// distinguishes the temporary files of samples decoded at the same time
+static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// attempts to find a name for a temporary file that is not yet taken
+const TEMP_FILE_ATTEMPTS: usize = 100;
+
+/// Splits `instrument.zip#Piano.sfz` into the archive and the SFZ entry in
+/// it, `None` if `sfz_file` does not point into an archive
+pub(super) fn split_path(sfz_file: &str) -> Option<(&str, &str)> {
+    let pos = sfz_file.rfind('#')?;
+    let (archive, entry) = (&sfz_file[..pos], &sfz_file[pos + 1..]);
+    let lowercase = archive.to_lowercase();
+    if entry.is_empty() || !ARCHIVE_EXTENSIONS.iter().any(|ext| lowercase.ends_with(ext)) {
+        return None;
+    }
+    Some((archive, entry))
+}
+
+/// Provides the samples from a zip archive relative to the SFZ entry
+///
+/// Temporary files that could not be removed after decoding are removed
+/// when the provider is dropped.
+pub struct ZipSamples {
+    archive: PathBuf,
+    dir: String,
+    temp_files: Mutex<Vec<PathBuf>>,
+}
+
+impl ZipSamples {
+    /// The samples of the SFZ entry `sfz_entry` of the zip file `archive`
+    pub fn new<P: AsRef<Path>>(archive: P, sfz_entry: &str) -> ZipSamples {
+        let sfz_entry = sfz_entry.replace("\\", "/");
+        let dir = match sfz_entry.rfind('/') {
+            Some(pos) => sfz_entry[..pos + 1].to_string(),
+            None => String::new(),
+        };
+        ZipSamples { archive: archive.as_ref().to_path_buf(), dir, temp_files: Mutex::new(Vec::new()) }
+    }
+
+    /// The text of the SFZ entry `sfz_entry`
+    pub fn read_sfz(archive: &Path, sfz_entry: &str) -> Result<String, EngineError> {
+        let mut zip = open(archive)?;
+        let name = find_entry(&zip, sfz_entry).ok_or_else(|| not_found(archive, &[sfz_entry.to_string()]))?;
+        let mut file = zip.by_name(&name).map_err(|e| io_error(archive, e.into()))?;
+        let mut text = String::new();
+        io::Read::read_to_string(&mut file, &mut text).map_err(|e| io_error(archive, e))?;
+        Ok(text)
+    }
+
+    fn entry_path(&self, name: &str) -> String {
+        format!("{}#{}", self.archive.display(), name)
+    }
+}
+
+impl SampleProvider for ZipSamples {
+    fn load(&self, sample: &str) -> Result<SampleData, EngineError> {
+        let sample = sample.trim().replace("\\", "/");
+        let mut candidates = vec![normalize(&format!("{}{}", self.dir, sample))];
+        if let Some(decoded) = sample_path::percent_decode(&sample) {
+            candidates.push(normalize(&format!("{}{}", self.dir, decoded)));
+        }
+
+        let mut zip = open(&self.archive)?;
+        let name = candidates.iter()
+            .find_map(|c| find_entry(&zip, c))
+            .ok_or_else(|| not_found(&self.archive, &candidates))?;
+        let entry_path = self.entry_path(&name);
+        debug!("loading {}", entry_path);
+
+        // sndfile only reads from files, so the entry is streamed into a
+        // temporary file with the same extension
+        let extension = Path::new(&name).extension().map_or(String::new(), |e| e.to_string_lossy().to_string());
+        let (temp_file, file) = create_temp_file(&extension)
+            .map_err(|error| EngineError::IOError { path: entry_path.clone(), error })?;
+        self.temp_files.lock().unwrap().push(temp_file.clone());
+        let result = extract(&mut zip, &name, file)
+            .map_err(|error| EngineError::IOError { path: entry_path.clone(), error })
+            .and_then(|_| loader::decode_file(&temp_file));
+        if fs::remove_file(&temp_file).is_ok() {
+            self.temp_files.lock().unwrap().retain(|f| *f != temp_file);
+        }
+        result.map_err(|error| error.with_path(entry_path))
+    }
+}
+
+impl Drop for ZipSamples {
+    fn drop(&mut self) {
+        if let Ok(temp_files) = self.temp_files.get_mut() {
+            for temp_file in temp_files.drain(..) {
+                let _ = fs::remove_file(&temp_file);
+            }
+        }
+    }
+}
+
+// Creates a new file with the extension `extension` in the temporary
+// directory, never opening a file that exists already
+fn create_temp_file(extension: &str) -> io::Result<(PathBuf, fs::File)> {
+    for _ in 0..TEMP_FILE_ATTEMPTS {
+        let path = std::env::temp_dir().join(format!("sonarigo-{}-{}.{}",
+                                                     std::process::id(),
+                                                     TEMP_FILE_COUNT.fetch_add(1, Ordering::Relaxed),
+                                                     extension));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "no unused name for a temporary file"))
+}
+
+fn open(archive: &Path) -> Result<zip::ZipArchive<fs::File>, EngineError> {
+    let file = fs::File::open(archive).map_err(|e| io_error(archive, e))?;
+    zip::ZipArchive::new(file).map_err(|e| io_error(archive, e.into()))
+}
+
+fn extract(zip: &mut zip::ZipArchive<fs::File>, name: &str, mut file: fs::File) -> io::Result<()> {
+    let mut entry = zip.by_name(name)?;
+    io::copy(&mut entry, &mut file).map(|_| ())
+}
+
+// the name of the entry `name`, looked up ignoring case if not found as is
+fn find_entry(zip: &zip::ZipArchive<fs::File>, name: &str) -> Option<String> {
+    let name = normalize(name);
+    let lowercase = name.to_lowercase();
+    zip.file_names().find(|n| *n == name)
+        .or_else(|| zip.file_names().find(|n| n.to_lowercase() == lowercase))
+        .map(|n| n.to_string())
+}
+
+// resolves `.` and `..` as zip entry names are always relative to the root
+fn normalize(name: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in name.split(|c| c == '/' || c == '\\') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            c => components.push(c),
+        }
+    }
+    components.join("/")
+}
+
+fn io_error(archive: &Path, error: io::Error) -> EngineError {
+    EngineError::IOError { path: archive.to_string_lossy().to_string(), error }
+}
+
+fn not_found(archive: &Path, tried: &[String]) -> EngineError {
+    EngineError::SampleNotFound {
+        path: archive.to_string_lossy().to_string(),
+        tried: tried.iter().map(|name| format!("{}#{}", archive.display(), name)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_archive(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, data) in entries {
+            zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn archive_paths() {
+        assert_eq!(split_path("instrument.zip#Piano/Piano.sfz"), Some(("instrument.zip", "Piano/Piano.sfz")));
+        assert_eq!(split_path("/home/me/Grand.SFZPACK#grand.sfz"), Some(("/home/me/Grand.SFZPACK", "grand.sfz")));
+        assert_eq!(split_path("instrument.zip#"), None);
+        assert_eq!(split_path("piano#1.sfz"), None);
+        assert_eq!(split_path("piano.sfz"), None);
+
+        assert_eq!(normalize("Piano/../samples\\./C4.wav"), "samples/C4.wav");
+        assert_eq!(ZipSamples::new("a.zip", "Piano\\Piano.sfz").dir, "Piano/");
+        assert_eq!(ZipSamples::new("a.zip", "Piano.sfz").dir, "");
+    }
+
+    #[test]
+    fn read_entries_from_archive() {
+        let archive = std::env::temp_dir().join(format!("sonarigo-archive-{}.zip", std::process::id()));
+        write_archive(&archive, &[("Piano/Piano.sfz", b"<region> sample=samples\\C4.wav"),
+                                  ("Piano/Samples/C4.wav", b"")]);
+
+        assert_eq!(ZipSamples::read_sfz(&archive, "piano/piano.SFZ").unwrap(), "<region> sample=samples\\C4.wav");
+
+        let provider = ZipSamples::new(&archive, "Piano/Piano.sfz");
+        let mut zip = open(&archive).unwrap();
+        assert_eq!(find_entry(&zip, &format!("{}{}", provider.dir, "samples/c4.wav")), Some("Piano/Samples/C4.wav".to_string()));
+        assert_eq!(find_entry(&zip, "Piano/Samples/D4.wav"), None);
+
+        let (temp_file, file) = create_temp_file("wav").unwrap();
+        extract(&mut zip, "Piano/Piano.sfz", file).unwrap();
+        assert_eq!(fs::read_to_string(&temp_file).unwrap(), "<region> sample=samples\\C4.wav");
+        fs::remove_file(&temp_file).unwrap();
+
+        match provider.load("samples/D4%20soft.wav") {
+            Err(e) => assert_eq!(format!("{}", e),
+                                 format!("{0}: sample file not found, tried {0}#Piano/samples/D4%20soft.wav, \
+                                          {0}#Piano/samples/D4 soft.wav (also ignoring case)", archive.display())),
+            _ => panic!("Not seen expected error"),
+        }
+
+        fs::remove_file(&archive).unwrap();
+    }
+
+    #[test]
+    fn temp_files_removed_on_drop() {
+        let provider = ZipSamples::new("a.zip", "Piano.sfz");
+        let (temp_file, _) = create_temp_file("wav").unwrap();
+        let (other_file, _) = create_temp_file("wav").unwrap();
+        assert_ne!(temp_file, other_file);
+        provider.temp_files.lock().unwrap().push(temp_file.clone());
+        drop(provider);
+        assert!(!temp_file.exists());
+        fs::remove_file(&other_file).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+// Creating the regions of an instrument from a folder of samples, a common
+// first step when making a sample library
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::engine::{EngineError, RegionData};
+use super::parser::{self, ParserError};
+use super::smpl;
+
+/// The result of `automap()`
+pub struct AutoMap {
+    /// One region per sample, ordered by velocity layer and key
+    pub regions: Vec<RegionData>,
+    /// The samples whose root key could not be found
+    pub skipped: Vec<String>,
+}
+
+struct MappedSample {
+    sample: String,
+    root_key: u8,
+    velocity_layer: Option<u32>,
+}
+
+/// Maps the WAV and FLAC files in `dir` and its subdirectories to regions
+/// for an SFZ file in `dir`. The root key of a sample is taken from a note
+/// name in its file name like `C#3` or `Piano_A0v1`, otherwise from the
+/// sampler chunk of a WAV file. The key ranges of the samples meet halfway
+/// between their root keys. Samples with a velocity layer like `v2` in their
+/// name split the velocity range by their layers, samples of the same key and
+/// layer alternate randomly.
+pub fn automap(dir: &Path) -> Result<AutoMap, EngineError> {
+    let mut files = Vec::new();
+    sample_files(dir, &mut files).map_err(|error| EngineError::IOError { path: dir.to_string_lossy().to_string(), error })?;
+    files.sort();
+
+    let mut samples = Vec::new();
+    let mut skipped = Vec::new();
+    for path in files {
+        let relative = path.strip_prefix(dir).unwrap_or(&path);
+        let sample = relative.iter().map(|c| encode_sample_name(&c.to_string_lossy())).collect::<Vec<_>>().join("\\");
+        let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let root_key = note_in_name(&stem).or_else(|| smpl::read(&path).and_then(|chunk| chunk.root_key));
+        match root_key {
+            Some(root_key) => samples.push(MappedSample { sample, root_key, velocity_layer: velocity_layer_in_name(&stem) }),
+            None => skipped.push(relative.to_string_lossy().to_string()),
+        }
+    }
+
+    let range_error = |error| EngineError::ParserError { path: dir.to_string_lossy().to_string(), error: ParserError::RangeError(error) };
+    let mut layers: Vec<Option<u32>> = samples.iter().map(|s| s.velocity_layer).collect();
+    layers.sort();
+    layers.dedup();
+    let mut regions = Vec::new();
+    for (l, layer) in layers.iter().enumerate() {
+        let mut layer_samples: Vec<&MappedSample> = samples.iter().filter(|s| s.velocity_layer == *layer).collect();
+        layer_samples.sort_by_key(|s| s.root_key);
+        let mut keys: Vec<u8> = layer_samples.iter().map(|s| s.root_key).collect();
+        keys.dedup();
+        for (k, key) in keys.iter().enumerate() {
+            let lokey = match k {
+                0 => 0,
+                k => key_range_end(keys[k - 1], *key) + 1,
+            };
+            let hikey = keys.get(k + 1).map_or(127, |next| key_range_end(*key, *next));
+            let round_robin: Vec<&&MappedSample> = layer_samples.iter().filter(|s| s.root_key == *key).collect();
+            for (r, sample) in round_robin.iter().enumerate() {
+                let mut rd = RegionData::default();
+                rd.set_sample(&sample.sample);
+                rd.key_range.set_lo(lokey as i32).map_err(range_error)?;
+                rd.key_range.set_hi(hikey as i32).map_err(range_error)?;
+                rd.set_pitch_keycenter(*key as u32).map_err(range_error)?;
+                rd.vel_range.set_lo((l * 128 / layers.len()) as i32).map_err(range_error)?;
+                rd.vel_range.set_hi(((l + 1) * 128 / layers.len() - 1) as i32).map_err(range_error)?;
+                if round_robin.len() > 1 {
+                    rd.random_range.set_lo(r as f32 / round_robin.len() as f32).map_err(range_error)?;
+                    rd.random_range.set_hi((r + 1) as f32 / round_robin.len() as f32).map_err(range_error)?;
+                }
+                regions.push(rd);
+            }
+        }
+    }
+
+    Ok(AutoMap { regions, skipped })
+}
+
+fn sample_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            sample_files(&path, files)?;
+            continue;
+        }
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        if let Some("wav") | Some("flac") = extension.as_deref() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// the highest key played by the sample at `key` when the next sample is at
+// `next_key`
+fn key_range_end(key: u8, next_key: u8) -> u8 {
+    key + (next_key - key - 1) / 2
+}
+
+// Whitespace ends the value of an opcode and `/` starts a comment, so they
+// are percent encoded and decoded again when the sample is looked up.
+fn encode_sample_name(name: &str) -> String {
+    let mut encoded = String::new();
+    for c in name.chars() {
+        match c {
+            ' ' | '\t' | '%' | '/' | '<' => encoded.push_str(&format!("%{:02X}", c as u32)),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+// The last note name like `C4`, `F#2` or `Bb0` in `name` not preceded by a
+// letter and not followed by another digit
+fn note_in_name(name: &str) -> Option<u8> {
+    let chars: Vec<char> = name.chars().collect();
+    let mut note = None;
+    for i in 0..chars.len() {
+        if !"ABCDEFGabcdefg".contains(chars[i]) || (i > 0 && chars[i - 1].is_alphabetic()) {
+            continue;
+        }
+        let octave = match chars.get(i + 1) {
+            Some('#') | Some('b') => i + 2,
+            _ => i + 1,
+        };
+        let is_digit = |j: usize| chars.get(j).map_or(false, |c| c.is_ascii_digit());
+        if !is_digit(octave) || is_digit(octave + 1) {
+            continue;
+        }
+        let token: String = chars[i..=octave].iter().collect();
+        if let Ok(key) = parser::parse_key(&token) {
+            if key >= 0 && key <= 127 {
+                note = Some(key as u8);
+            }
+        }
+    }
+    note
+}
+
+// a velocity layer like `v2`, preceded by a digit or a separator and followed
+// by digits only
+fn velocity_layer_in_name(name: &str) -> Option<u32> {
+    let chars: Vec<char> = name.chars().collect();
+    (0..chars.len())
+        .filter(|i| (chars[*i] == 'v' || chars[*i] == 'V') && (*i == 0 || !chars[*i - 1].is_alphabetic()))
+        .filter_map(|i| {
+            let digits: String = chars[i + 1..].iter().take_while(|c| c.is_ascii_digit()).collect();
+            match chars.get(i + 1 + digits.len()) {
+                Some(c) if c.is_alphanumeric() => None,
+                _ => digits.parse().ok(),
+            }
+        })
+        .last()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sfz::write_sfz;
+
+    #[test]
+    fn names() {
+        assert_eq!(note_in_name("A0v1"), Some(21));
+        assert_eq!(note_in_name("C#3"), Some(49));
+        assert_eq!(note_in_name("Piano_Bb2_rr1"), Some(46));
+        assert_eq!(note_in_name("Grand C4 loud"), Some(60));
+        assert_eq!(note_in_name("Bass"), None);
+        assert_eq!(note_in_name("Take 12"), None);
+        assert_eq!(velocity_layer_in_name("A0v1"), Some(1));
+        assert_eq!(velocity_layer_in_name("Piano_C4_v12"), Some(12));
+        assert_eq!(velocity_layer_in_name("C4_vel"), None);
+        assert_eq!(velocity_layer_in_name("Violin_C4"), None);
+    }
+
+    #[test]
+    fn map_folder() {
+        let dir = std::env::temp_dir().join(format!("sonarigo-automap-{}", std::process::id()));
+        fs::create_dir_all(dir.join("low notes")).unwrap();
+        for name in &["C4v1.wav", "C4v2.wav", "E4v1.wav", "G4v2.flac", "low notes/C2v1.wav", "noise.wav", "readme.txt"] {
+            fs::write(dir.join(name), b"RIFF").unwrap();
+        }
+        fs::write(dir.join("tom.wav"), smpl::wav_with_smpl(45, None)).unwrap();
+
+        let map = automap(&dir).unwrap();
+        assert_eq!(map.skipped, vec!["noise.wav".to_string()]);
+        assert_eq!(write_sfz(&map.regions), "\
+<region> sample=tom.wav pitch_keycenter=45 hivel=41
+<region> sample=low%20notes\\C2v1.wav hikey=47 pitch_keycenter=36 lovel=42 hivel=84
+<region> sample=C4v1.wav lokey=48 hikey=61 pitch_keycenter=60 lovel=42 hivel=84
+<region> sample=E4v1.wav lokey=62 pitch_keycenter=64 lovel=42 hivel=84
+<region> sample=C4v2.wav hikey=63 pitch_keycenter=60 lovel=85
+<region> sample=G4v2.flac lokey=64 pitch_keycenter=67 lovel=85
+");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+// Conformance of the parser to the opcodes of SFZ v1 as listed on
+// sfzformat.com. Every opcode must either change the region it is given in
+// or be listed as unsupported, which makes the lenient parser skip it with a
+// warning. An opcode becoming supported has to be moved to `SUPPORTED`.
+
+use super::engine::RegionData;
+use super::parser::parse_text;
+use super::write_sfz;
+
+// opcodes with an example value changing the region
+const SUPPORTED: &[(&str, &str)] = &[
+    // sample playback
+    ("sample", "a.wav"),
+    ("loop_mode", "loop_continuous"),
+    ("loop_start", "100"),
+    ("loop_end", "1000"),
+    ("offset_random", "100"),
+    ("delay_random", "0.1"),
+    // voice lifecycle
+    ("group", "1"),
+    ("off_by", "2"),
+    ("off_mode", "normal"),
+    ("off_time", "0.1"),
+    // input controls
+    ("lokey", "40"),
+    ("hikey", "50"),
+    ("key", "45"),
+    ("lovel", "10"),
+    ("hivel", "100"),
+    ("lochan", "2"),
+    ("hichan", "3"),
+    ("locc1", "20"),
+    ("hicc1", "100"),
+    ("lorand", "0.25"),
+    ("hirand", "0.75"),
+    ("lobpm", "60"),
+    ("hibpm", "120"),
+    ("trigger", "release"),
+    ("on_locc64", "64"),
+    ("on_hicc64", "127"),
+    ("sw_lokey", "24"),
+    ("sw_hikey", "30"),
+    ("sw_last", "25"),
+    ("sw_default", "25"),
+    ("sw_label", "Legato"),
+    ("sw_vel", "previous"),
+    // performance parameters
+    ("volume", "-6"),
+    ("amp_veltrack", "50"),
+    ("rt_decay", "3"),
+    ("rt_dead", "1"),
+    ("amp_velcurve_64", "0.5"),
+    ("amp_random", "3"),
+    ("tune", "10"),
+    ("pitch_keycenter", "62"),
+    ("pitch_keytrack", "50"),
+    ("pitch_random", "10"),
+    ("xfin_lokey", "40"),
+    ("xfin_hikey", "44"),
+    ("xfout_lokey", "56"),
+    ("xfout_hikey", "60"),
+    ("xf_keycurve", "gain"),
+    ("bend_up", "1200"),
+    ("bend_down", "-1200"),
+    ("bend_step", "100"),
+    ("bend_smooth", "10"),
+    // filter
+    ("cutoff", "1000"),
+    ("cutoff_cc1", "1200"),
+    ("cutoff_chanaft", "1200"),
+    ("cutoff_polyaft", "1200"),
+    // amplitude envelope
+    ("ampeg_delay", "0.1"),
+    ("ampeg_start", "10"),
+    ("ampeg_attack", "0.1"),
+    ("ampeg_hold", "0.1"),
+    ("ampeg_decay", "0.1"),
+    ("ampeg_sustain", "50"),
+    ("ampeg_release", "0.5"),
+    ("ampeg_vel2attack", "0.1"),
+    ("ampeg_vel2hold", "0.1"),
+    ("ampeg_vel2decay", "0.1"),
+    ("ampeg_vel2sustain", "10"),
+    ("ampeg_vel2release", "0.1"),
+    ("ampeg_delaycc1", "0.1"),
+    ("ampeg_startcc1", "10"),
+    // effects
+    ("effect1", "50"),
+    ("effect2", "50"),
+];
+
+// opcodes skipped with a warning
+const UNSUPPORTED: &[(&str, &str)] = &[
+    // sample playback
+    ("delay", "0.1"),
+    ("delay_cc1", "0.1"),
+    ("offset", "100"),
+    ("offset_cc1", "100"),
+    ("end", "1000"),
+    ("count", "2"),
+    ("sync_beats", "4"),
+    ("sync_offset", "1"),
+    // input controls
+    ("lobend", "-100"),
+    ("hibend", "100"),
+    ("lochanaft", "10"),
+    ("hichanaft", "100"),
+    ("lopolyaft", "10"),
+    ("hipolyaft", "100"),
+    ("seq_length", "2"),
+    ("seq_position", "2"),
+    ("sw_down", "25"),
+    ("sw_up", "25"),
+    ("sw_previous", "25"),
+    // performance parameters
+    ("pan", "-50"),
+    ("width", "50"),
+    ("position", "50"),
+    ("amp_keycenter", "60"),
+    ("amp_keytrack", "1"),
+    ("xf_cccurve", "power"),
+    ("xf_velcurve", "power"),
+    ("xfin_locc1", "20"),
+    ("xfin_hicc1", "40"),
+    ("xfout_locc1", "80"),
+    ("xfout_hicc1", "100"),
+    ("xfin_lovel", "20"),
+    ("xfin_hivel", "40"),
+    ("xfout_lovel", "80"),
+    ("xfout_hivel", "100"),
+    ("transpose", "12"),
+    ("pitch_veltrack", "100"),
+    // filter
+    ("fil_type", "hpf_2p"),
+    ("resonance", "3"),
+    ("fil_keytrack", "100"),
+    ("fil_keycenter", "60"),
+    ("fil_veltrack", "100"),
+    ("fil_random", "100"),
+    // amplitude envelope
+    ("ampeg_vel2delay", "0.1"),
+    ("ampeg_attackcc1", "0.1"),
+    ("ampeg_holdcc1", "0.1"),
+    ("ampeg_decaycc1", "0.1"),
+    ("ampeg_sustaincc1", "10"),
+    ("ampeg_releasecc1", "0.1"),
+];
+
+// unsupported opcodes made of each prefix followed by each suffix
+const UNSUPPORTED_FAMILIES: &[(&[&str], &[&str], &str)] = &[
+    (&["eq1_", "eq2_", "eq3_"],
+     &["freq", "bw", "gain", "vel2freq", "vel2gain", "freqcc1", "bwcc1", "gaincc1"],
+     "1"),
+    (&["pitcheg_", "fileg_"],
+     &["delay", "start", "attack", "hold", "decay", "sustain", "release", "depth",
+       "vel2delay", "vel2attack", "vel2hold", "vel2decay", "vel2sustain", "vel2release", "vel2depth",
+       "delaycc1", "startcc1", "attackcc1", "holdcc1", "decaycc1", "sustaincc1", "releasecc1"],
+     "1"),
+    (&["amplfo_", "pitchlfo_", "fillfo_"],
+     &["delay", "fade", "freq", "depth", "depthcc1", "depthchanaft", "depthpolyaft",
+       "freqcc1", "freqchanaft", "freqpolyaft"],
+     "1"),
+];
+
+fn unsupported() -> Vec<(String, &'static str)> {
+    let mut opcodes: Vec<(String, &str)> = UNSUPPORTED.iter().map(|(o, v)| (o.to_string(), *v)).collect();
+    for (prefixes, suffixes, value) in UNSUPPORTED_FAMILIES {
+        for prefix in prefixes.iter() {
+            opcodes.extend(suffixes.iter().map(|suffix| (format!("{}{}", prefix, suffix), *value)));
+        }
+    }
+    opcodes
+}
+
+#[test]
+fn supported_opcodes_change_the_region() {
+    let default = write_sfz(&[RegionData::default()]);
+    for (opcode, value) in SUPPORTED {
+        let text = format!("<region> {}={}", opcode, value);
+        let (regions, _, warnings) = parse_text(&text, false).unwrap();
+        assert!(warnings.is_empty(), "{}: {}", text, warnings[0]);
+        assert_ne!(write_sfz(&regions), default, "{} does not change the region", text);
+        assert!(parse_text(&text, true).is_ok(), "{}", text);
+    }
+}
+
+#[test]
+fn unsupported_opcodes_warn() {
+    for (opcode, value) in unsupported() {
+        let text = format!("<region> {}={}", opcode, value);
+        let (regions, _, warnings) = parse_text(&text, false).unwrap();
+        assert_eq!(warnings.len(), 1, "{} is listed as unsupported but has no warning", text);
+        assert_eq!(warnings[0].opcode(), Some(opcode.as_str()));
+        assert_eq!(regions.len(), 1);
+    }
+}
+
+#[test]
+fn opcodes_listed_once() {
+    let mut opcodes: Vec<String> = SUPPORTED.iter().map(|(o, _)| o.to_string())
+        .chain(unsupported().into_iter().map(|(o, _)| o))
+        .collect();
+    let count = opcodes.len();
+    opcodes.sort();
+    opcodes.dedup();
+    assert_eq!(opcodes.len(), count);
+}
@@ -0,0 +1,188 @@
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Something that went wrong or was worked around while processing, see
+/// `Engine::set_diagnostics()`. Regions are given by their index in the SFZ
+/// file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Diagnostic {
+    /// A voice of the region was stopped to stay within the voice limit
+    VoiceStolen { region: usize },
+    /// The filter state of the region decayed into denormal numbers, which
+    /// are slow to compute, and was flushed to zero
+    DenormalsFlushed { region: usize },
+    /// The region was triggered before its sample was loaded and stayed
+    /// silent
+    SampleNotLoaded { region: usize },
+    /// More MIDI events were queued than there was room for, so the event
+    /// was dropped
+    EventQueueOverflow,
+    /// A voice of the region was played with an inactive envelope, which is
+    /// a bug, and stayed silent
+    InactiveEnvelope { region: usize },
+    /// The frontend received MIDI data it could not read
+    InvalidMidiEvent,
+    /// The frontend could not hand work like loading a file over to its
+    /// worker thread
+    WorkNotScheduled,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Diagnostic::VoiceStolen { region } => write!(f, "region {}: voice stolen", region),
+            Diagnostic::DenormalsFlushed { region } => write!(f, "region {}: denormals flushed", region),
+            Diagnostic::SampleNotLoaded { region } => write!(f, "region {}: triggered before its sample was loaded", region),
+            Diagnostic::EventQueueOverflow => write!(f, "MIDI event queue overflow"),
+            Diagnostic::InactiveEnvelope { region } => write!(f, "region {}: envelope processed while inactive", region),
+            Diagnostic::InvalidMidiEvent => write!(f, "invalid MIDI event skipped"),
+            Diagnostic::WorkNotScheduled => write!(f, "work could not be scheduled"),
+        }
+    }
+}
+
+/// Fixed size ring of the diagnostics of engines. The engines and frontends
+/// write it from any thread without allocating or locking, another thread
+/// drains it. When the ring is full new diagnostics are lost.
+pub struct Diagnostics {
+    slots: Box<[Slot]>,
+    // `head` and `tail` count the diagnostics drained and the slots claimed
+    // for writing
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    lost: AtomicUsize,
+    // only one thread drains at a time
+    draining: Mutex<()>,
+}
+
+// The sequence of a slot tells whose turn it is: it equals the position of the
+// next write into the slot while it is free, the position plus one once it is
+// written and it moves on by the length of the ring when it is drained.
+struct Slot {
+    sequence: AtomicUsize,
+    diagnostic: UnsafeCell<Option<Diagnostic>>,
+}
+
+unsafe impl Sync for Diagnostics {}
+
+pub type SharedDiagnostics = Arc<Diagnostics>;
+
+impl Diagnostics {
+    pub fn shared(capacity: usize) -> SharedDiagnostics {
+        Arc::new(Diagnostics {
+            slots: (0..capacity.max(1)).map(|i| Slot {
+                sequence: AtomicUsize::new(i),
+                diagnostic: UnsafeCell::new(None),
+            }).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            lost: AtomicUsize::new(0),
+            draining: Mutex::new(()),
+        })
+    }
+
+    /// Reports `diagnostic` without allocating or locking. Frontends report
+    /// the problems of their audio threads here as well, pushing from several
+    /// threads at once is safe.
+    pub fn push(&self, diagnostic: Diagnostic) {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[tail % self.slots.len()];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            if sequence == tail {
+                match self.tail.compare_exchange_weak(tail, tail.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // the slot is claimed, no other writer touches it
+                        unsafe { *slot.diagnostic.get() = Some(diagnostic) };
+                        slot.sequence.store(tail.wrapping_add(1), Ordering::Release);
+                        return;
+                    }
+                    Err(current) => tail = current,
+                }
+            } else if (sequence.wrapping_sub(tail) as isize) < 0 {
+                // the slot still holds a diagnostic not drained
+                self.lost.fetch_add(1, Ordering::Relaxed);
+                return;
+            } else {
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Takes the diagnostics written so far, oldest first
+    pub fn drain(&self) -> Vec<Diagnostic> {
+        let _draining = self.draining.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut diagnostics = Vec::new();
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[head % self.slots.len()];
+            if slot.sequence.load(Ordering::Acquire) != head.wrapping_add(1) {
+                break;
+            }
+            diagnostics.extend(unsafe { (*slot.diagnostic.get()).take() });
+            slot.sequence.store(head.wrapping_add(self.slots.len()), Ordering::Release);
+            head = head.wrapping_add(1);
+            self.head.store(head, Ordering::Release);
+        }
+        diagnostics
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    /// Number of diagnostics lost so far because the ring was full
+    pub fn lost(&self) -> usize {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostics_ring_full_and_drained() {
+        let diagnostics = Diagnostics::shared(2);
+        assert!(diagnostics.is_empty());
+        diagnostics.push(Diagnostic::VoiceStolen { region: 1 });
+        diagnostics.push(Diagnostic::EventQueueOverflow);
+        diagnostics.push(Diagnostic::DenormalsFlushed { region: 2 });
+        assert_eq!(diagnostics.lost(), 1);
+        assert_eq!(diagnostics.drain(), vec![Diagnostic::VoiceStolen { region: 1 }, Diagnostic::EventQueueOverflow]);
+        assert!(diagnostics.is_empty());
+
+        diagnostics.push(Diagnostic::SampleNotLoaded { region: 3 });
+        assert_eq!(diagnostics.drain(), vec![Diagnostic::SampleNotLoaded { region: 3 }]);
+        assert_eq!(format!("{}", Diagnostic::SampleNotLoaded { region: 3 }), "region 3: triggered before its sample was loaded");
+    }
+
+    #[test]
+    fn diagnostics_pushed_from_threads() {
+        let diagnostics = Diagnostics::shared(64);
+        let finished = Arc::new(AtomicUsize::new(0));
+        let writers: Vec<_> = (0..4).map(|region| {
+            let diagnostics = diagnostics.clone();
+            let finished = finished.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    diagnostics.push(Diagnostic::VoiceStolen { region });
+                }
+                finished.fetch_add(1, Ordering::Release);
+            })
+        }).collect();
+        let mut drained = Vec::new();
+        while finished.load(Ordering::Acquire) < 4 {
+            drained.extend(diagnostics.drain());
+        }
+        drained.extend(diagnostics.drain());
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(drained.len() + diagnostics.lost(), 4000);
+        assert!(drained.iter().all(|d| matches!(d, Diagnostic::VoiceStolen { region } if *region < 4)));
+        assert!(diagnostics.is_empty());
+    }
+}
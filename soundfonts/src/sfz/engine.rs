@@ -1,31 +1,77 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::error;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
-use log::warn;
+use log::{debug, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use crate::engine;
+use crate::effects;
 use crate::envelopes;
 use crate::errors::*;
+use crate::filters;
+use crate::mapped::MappedFrames;
+use crate::modulation;
+use crate::profiling;
+use crate::render;
 use crate::sample;
 use crate::sndfile;
-use crate::sndfile::SndFileIO;
+use crate::tuning;
 use crate::utils;
 
+use super::activity_log::{ActivityEntry, RegionList, SharedActivityLog};
+use super::diagnostics::{Diagnostic, SharedDiagnostics};
+use super::mutes::{RegionMutes, SharedRegionMutes};
+use super::observer::EngineObserver;
+use crate::midi_filter::{MidiFilter, VelocityCurve};
+use super::archive::{self, ZipSamples};
+use super::instrument_cache;
+use super::telemetry::{SharedTelemetry, Telemetry};
+use super::loader::{self, LoadStats, SampleEncoding, SampleFiles, SampleProvider};
 use super::parser;
+use super::sample_path;
+
+// the channel of the messages the regions handle
+fn message_channel(midi_msg: &wmidi::MidiMessage) -> Option<wmidi::Channel> {
+    match midi_msg {
+        wmidi::MidiMessage::NoteOn(ch, ..)
+        | wmidi::MidiMessage::NoteOff(ch, ..)
+        | wmidi::MidiMessage::PolyphonicKeyPressure(ch, ..)
+        | wmidi::MidiMessage::ControlChange(ch, ..)
+        | wmidi::MidiMessage::ChannelPressure(ch, _)
+        | wmidi::MidiMessage::PitchBendChange(ch, _) => Some(*ch),
+        _ => None,
+    }
+}
+
+// tempo assumed until the host tells one
+const DEFAULT_TEMPO: f64 = 120.0;
+
+// values out of the u8 range become 255, so that the MIDI conversions reject
+// them instead of taking a truncated value
+fn saturating_u8(v: i32) -> u8 {
+    u8::try_from(v).unwrap_or(u8::MAX)
+}
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(super) struct VelRange {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_midi::velocity"))]
     lo: wmidi::Velocity,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_midi::velocity"))]
     hi: wmidi::Velocity,
 }
 
 impl VelRange {
     pub(super) fn set_hi(&mut self, v: i32) -> Result<(), RangeError> {
-        let vel = wmidi::Velocity::try_from(v as u8)
+        let vel = wmidi::Velocity::try_from(saturating_u8(v))
             .map_err(|_| RangeError::out_of_range("hivel", 0, 127, v))?;
         if  vel < self.lo {
             return Err(RangeError::flipped_range("hivel", v, u8::from(self.lo) as i32));
@@ -35,7 +81,7 @@ impl VelRange {
     }
 
     pub(super) fn set_lo(&mut self, v: i32) -> Result<(), RangeError> {
-        let vel = wmidi::Velocity::try_from(v as u8)
+        let vel = wmidi::Velocity::try_from(saturating_u8(v))
             .map_err(|_| RangeError::out_of_range("lovel", 0, 127, v))?;
         if  vel > self.hi {
             return Err(RangeError::flipped_range("lovel", v, u8::from(self.hi) as i32));
@@ -59,8 +105,11 @@ impl Default for VelRange {
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(super) struct NoteRange {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_midi::option_note"))]
     lo: Option<wmidi::Note>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_midi::option_note"))]
     hi: Option<wmidi::Note>,
 }
 
@@ -71,7 +120,7 @@ impl NoteRange {
             return Ok(());
         }
 
-        let note = wmidi::Note::try_from(v as u8)
+        let note = wmidi::Note::try_from(saturating_u8(v))
             .map_err(|_| RangeError::out_of_range("hikey", -1, 127, v))?;
         if self.lo.map_or(false, |n| note < n) {
             return Err(RangeError::flipped_range("hikey", v, u8::from(note) as i32));
@@ -86,7 +135,7 @@ impl NoteRange {
             return Ok(());
         }
 
-        let note = wmidi::Note::try_from(v as u8)
+        let note = wmidi::Note::try_from(saturating_u8(v))
             .map_err(|_| RangeError::out_of_range("lokey", -1, 127, v))?;
         if self.hi.map_or(false, |n| note > n) {
             return Err(RangeError::flipped_range("lokey", v, u8::from(note) as i32));
@@ -112,7 +161,52 @@ impl Default for NoteRange {
     }
 }
 
-#[derive(Default, Clone)]
+/// The MIDI channels from 1 to 16 the region listens to
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(super) struct ChannelRange {
+    lo: u8,
+    hi: u8,
+}
+
+impl ChannelRange {
+    pub(super) fn set_hi(&mut self, v: i32) -> Result<(), RangeError> {
+        let channel = range_check(v, 1, 16, "hichan")?;
+        if channel < self.lo as i32 {
+            return Err(RangeError::flipped_range("hichan", v, self.lo as i32));
+        }
+        self.hi = channel as u8;
+        Ok(())
+    }
+
+    pub(super) fn set_lo(&mut self, v: i32) -> Result<(), RangeError> {
+        let channel = range_check(v, 1, 16, "lochan")?;
+        if channel > self.hi as i32 {
+            return Err(RangeError::flipped_range("lochan", v, self.hi as i32));
+        }
+        self.lo = channel as u8;
+        Ok(())
+    }
+
+    fn covering(&self, channel: wmidi::Channel) -> bool {
+        (self.lo..=self.hi).contains(&channel.number())
+    }
+
+    fn is_full(&self) -> bool {
+        self.lo == 1 && self.hi == 16
+    }
+}
+
+impl Default for ChannelRange {
+    fn default() -> Self {
+        ChannelRange { lo: 1, hi: 16 }
+    }
+}
+
+/// The region is triggered when the random number of the note, between 0.0
+/// and 1.0, is at least `lo` and less than `hi`
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(super) struct RandomRange {
     hi: f32,
     lo: f32,
@@ -121,10 +215,8 @@ pub(super) struct RandomRange {
 impl RandomRange {
     pub(super) fn set_hi(&mut self, v: f32) -> Result<(), RangeError> {
         match v {
-            v if v < 0.0 && v > 1.0 => Err(RangeError::out_of_range("hirand", 0.0, 1.0, v)),
-            v if v < self.lo && self.lo > 0.0 => {
-                Err(RangeError::flipped_range("hirand", v, self.lo))
-            }
+            v if !(0.0..=1.0).contains(&v) => Err(RangeError::out_of_range("hirand", 0.0, 1.0, v)),
+            v if v < self.lo => Err(RangeError::flipped_range("hirand", v, self.lo)),
             _ => {
                 self.hi = v;
                 Ok(())
@@ -134,10 +226,8 @@ impl RandomRange {
 
     pub(super) fn set_lo(&mut self, v: f32) -> Result<(), RangeError> {
         match v {
-            v if v < 0.0 && v > 1.0 => Err(RangeError::out_of_range("lorand", 0.0, 1.0, v)),
-            v if v > self.hi && self.hi > 0.0 => {
-                Err(RangeError::flipped_range("lorand", v, self.hi))
-            }
+            v if !(0.0..=1.0).contains(&v) => Err(RangeError::out_of_range("lorand", 0.0, 1.0, v)),
+            v if v > self.hi => Err(RangeError::flipped_range("lorand", v, self.hi)),
             _ => {
                 self.lo = v;
                 Ok(())
@@ -146,27 +236,48 @@ impl RandomRange {
     }
 
     fn covering(&self, v: f32) -> bool {
-        self.hi == self.lo || (v >= self.lo && v < self.hi)
+        v >= self.lo && v < self.hi
+    }
+
+    fn is_full(&self) -> bool {
+        self.lo == 0.0 && self.hi == 1.0
+    }
+}
+
+impl Default for RandomRange {
+    fn default() -> Self {
+        RandomRange { hi: 1.0, lo: 0.0 }
     }
 }
 
 #[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(super) struct ControlValRange {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_midi::option_control_value"))]
     hi: Option<wmidi::ControlValue>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_midi::option_control_value"))]
     lo: Option<wmidi::ControlValue>,
 }
 
 impl ControlValRange {
     pub(super) fn set_hi(&mut self, v: i32) -> Result<(), RangeError> {
+        self.set_hi_named(v, "on_hiccXX")
+    }
+
+    pub(super) fn set_lo(&mut self, v: i32) -> Result<(), RangeError> {
+        self.set_lo_named(v, "on_loccXX")
+    }
+
+    fn set_hi_named(&mut self, v: i32, name: &'static str) -> Result<(), RangeError> {
         if v < 0 {
             self.hi = None;
             return Ok(());
         }
-        let val = wmidi::ControlValue::try_from(v as u8)
-            .map_err(|_| RangeError::out_of_range("on_hiccXX", 0, 127, v))?;
+        let val = wmidi::ControlValue::try_from(saturating_u8(v))
+            .map_err(|_| RangeError::out_of_range(name, 0, 127, v))?;
         match self.lo {
             Some(lo) if val < lo => {
-                return Err(RangeError::flipped_range("on_hiccXX", v, u8::from(lo) as i32));
+                return Err(RangeError::flipped_range(name, v, u8::from(lo) as i32));
             }
             _ => {}
         };
@@ -174,16 +285,16 @@ impl ControlValRange {
         Ok(())
     }
 
-    pub(super) fn set_lo(&mut self, v: i32) -> Result<(), RangeError> {
+    fn set_lo_named(&mut self, v: i32, name: &'static str) -> Result<(), RangeError> {
         if v < 0 {
             self.lo = None;
             return Ok(());
         }
-        let val = wmidi::ControlValue::try_from(v as u8)
-            .map_err(|_| RangeError::out_of_range("on_loccXX", 0, 127, v))?;
+        let val = wmidi::ControlValue::try_from(saturating_u8(v))
+            .map_err(|_| RangeError::out_of_range(name, 0, 127, v))?;
         match self.hi {
             Some(hi) if val > hi => {
-                return Err(RangeError::flipped_range("on_loccXX", v, u8::from(hi) as i32));
+                return Err(RangeError::flipped_range(name, v, u8::from(hi) as i32));
             }
             _ => {}
         };
@@ -197,10 +308,63 @@ impl ControlValRange {
             _ => false,
         }
     }
+
+    // unset bounds default to 0 and 127, unlike for `covering()`
+    fn contains(&self, v: u8) -> bool {
+        self.lo.map_or(true, |lo| v >= u8::from(lo)) && self.hi.map_or(true, |hi| v <= u8::from(hi))
+    }
+}
+
+// a value of an opcode with six significant digits, so that the rounding
+// errors of the unit conversions do not show up
+fn opcode_number(v: f64) -> String {
+    let integer_digits = if v == 0.0 { 1 } else { v.abs().log10().floor() as i32 + 1 };
+    let decimals = (6 - integer_digits).max(0).min(12) as usize;
+    let text = format!("{:.*}", decimals, v);
+    let text = match text.contains('.') {
+        true => text.trim_end_matches('0').trim_end_matches('.'),
+        false => &text,
+    };
+    match text {
+        "-0" => "0".to_string(),
+        t => t.to_string(),
+    }
+}
+
+fn cc_bounds(ranges: &HashMap<u8, ControlValRange>) -> Vec<(u8, Option<u8>, Option<u8>)> {
+    let mut bounds: Vec<_> = ranges.iter()
+        .map(|(cc, range)| (*cc, range.lo.map(u8::from), range.hi.map(u8::from)))
+        .collect();
+    bounds.sort_by_key(|(cc, _, _)| *cc);
+    bounds
+}
+
+// FNV-1a, used for the region ids rather than std's hasher, as that one is
+// not guaranteed to be stable across compiler versions
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub(super) enum Trigger {
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Trigger {
     Attack,
     Release,
     First,
@@ -214,32 +378,197 @@ impl Default for Trigger {
     }
 }
 
+// hashed by the position of the variant, so that region ids stay stable
+// across compiler versions
+impl Hash for Trigger {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u8(*self as u8);
+    }
+}
+
+impl Trigger {
+    fn is_release(&self) -> bool {
+        matches!(self, Trigger::Release | Trigger::ReleaseKey)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LoopMode {
+    NoLoop,
+    /// played to the end ignoring note-offs
+    OneShot,
+    LoopContinuous,
+    /// looped until the note is released, then played to the end
+    LoopSustain,
+}
+
+impl Default for LoopMode {
+    fn default() -> Self {
+        LoopMode::NoLoop
+    }
+}
+
+/// How the voices of a region are ended when it is choked by another one
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OffMode {
+    /// faded out within a few milliseconds
+    Fast,
+    /// released by the amplitude envelope
+    Normal,
+    /// faded out over `off_time`
+    Time,
+}
+
+impl Default for OffMode {
+    fn default() -> Self {
+        OffMode::Fast
+    }
+}
+
+/// Seconds choked voices of `OffMode::Fast` are faded out in
+const FAST_OFF_TIME: f32 = 0.006;
+
+/// Shape of the crossfades, `Power` keeps the sum of the powers of the
+/// crossfaded regions constant, `Gain` the sum of their amplitudes
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum XfCurve {
+    Gain,
+    Power,
+}
+
+impl Default for XfCurve {
+    fn default() -> Self {
+        XfCurve::Power
+    }
+}
+
+impl XfCurve {
+    fn gain(&self, position: f32) -> f32 {
+        match self {
+            XfCurve::Gain => position,
+            XfCurve::Power => position.sqrt(),
+        }
+    }
+}
+
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct RegionData {
     pub(super) key_range: NoteRange,
     pub(super) vel_range: VelRange,
+    pub(super) channel_range: ChannelRange,
 
     pub(super) ampeg: envelopes::Generator,
 
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_midi::note"))]
     pitch_keycenter: wmidi::Note,
+    // whether `pitch_keycenter` is given in the SFZ file, otherwise the root
+    // key of the sample file is used if it has one
+    pitch_keycenter_set: bool,
 
     pitch_keytrack: f64,
 
     amp_veltrack: f32,
+    // frames the start of the sample is randomly delayed by at most
+    offset_random: u32,
+    // maximal random variations of a note's gain in dB, its pitch in cents
+    // up and down and its start in seconds
+    amp_random: f32,
+    pitch_random: f32,
+    delay_random: f32,
+
+    // the region only plays at host tempos from lobpm up to below hibpm
+    lobpm: f32,
+    hibpm: f32,
+
+    // gain by velocity given by `amp_velcurve_N` points, replaces the
+    // quadratic curve unless empty
+    amp_velcurve: modulation::Curve,
 
     volume: f32,
+    group_volume: f32,
+    master_volume: f32,
+    global_volume: f32,
 
     sample: String,
+    // the directory of the sample given at the group or region level, which
+    // replaces the `default_path` of the `<control>` header
+    default_path: Option<String>,
     rt_decay: f32,
+    // release triggered notes play even if the notes of the attack regions
+    // of their key have already died away
+    rt_dead: bool,
 
     tune: f64,
 
+    // pitch bend range in cents, the bend is quantized to `bend_step` cents
+    // and follows the wheel within `bend_smooth` milliseconds
+    bend_up: f32,
+    bend_down: f32,
+    bend_step: f32,
+    bend_smooth: f32,
+
+    // seconds a note played while another one is held glides from its
+    // pitch, the region is played monophonic then (sonarigo extension)
+    portamento_time: f32,
+
     trigger: Trigger,
 
+    // `None` if not given in the SFZ file, the loop mode and points of the
+    // sample file are used then
+    loop_mode: Option<LoopMode>,
+    // in frames of the sample, `loop_end` is the last frame of the loop,
+    // the loop spans the whole sample if neither are given
+    loop_start: Option<usize>,
+    loop_end: Option<usize>,
+    // seconds crossfaded before the loop end
+    loop_crossfade: f32,
+
+    // seconds of the click guard overriding the one of the engine
+    click_guard: Option<f32>,
+
     group: u32,
     off_by: u32,
+    off_mode: OffMode,
+    off_time: f32,
+    // the region is also choked by notes of the keys `off_lokey` to
+    // `off_hikey`, like an open hi-hat by the pedal (sonarigo extension)
+    off_lokey: Option<u8>,
+    off_hikey: Option<u8>,
+
+    // the region only plays while `sw_last` is the last key pressed of the
+    // keyswitches `sw_lokey` to `sw_hikey`, `sw_default` is the keyswitch
+    // before any is pressed and `sw_label` the name of the articulation
+    sw_lokey: Option<u8>,
+    sw_hikey: Option<u8>,
+    sw_last: Option<u8>,
+    sw_default: Option<u8>,
+    sw_label: Option<String>,
+    // sw_vel=previous: play with the velocity of the previous note
+    sw_vel_previous: bool,
+
+    // keys the region fades in from `xfin_lokey` to `xfin_hikey` and out
+    // from `xfout_lokey` to `xfout_hikey`
+    xfin_lokey: u8,
+    xfin_hikey: u8,
+    xfout_lokey: u8,
+    xfout_hikey: u8,
+    xf_keycurve: XfCurve,
 
     on_ccs: HashMap<u8, ControlValRange>,
+    cc_conditions: HashMap<u8, ControlValRange>,
+
+    cutoff: Option<f32>,
+
+    modulation: modulation::Matrix,
+
+    // send levels to the reverb and chorus buses, 0.0 to 1.0
+    effect1: f32,
+    effect2: f32,
 
     pub(super) random_range: RandomRange,
 }
@@ -249,40 +578,435 @@ impl Default for RegionData {
         RegionData {
             key_range: Default::default(),
             vel_range: Default::default(),
+            channel_range: Default::default(),
 
             pitch_keycenter: wmidi::Note::C3,
+            pitch_keycenter_set: false,
 
             pitch_keytrack: 1.0,
 
             amp_veltrack: 1.0,
+            amp_velcurve: Default::default(),
+            offset_random: 0,
+            amp_random: 0.0,
+            pitch_random: 0.0,
+            delay_random: 0.0,
+
+            lobpm: 0.0,
+            hibpm: 500.0,
 
             ampeg: Default::default(),
 
             volume: Default::default(),
+            group_volume: Default::default(),
+            master_volume: Default::default(),
+            global_volume: Default::default(),
             sample: Default::default(),
+            default_path: None,
             rt_decay: Default::default(),
+            rt_dead: false,
             tune: Default::default(),
+            bend_up: 200.0,
+            bend_down: -200.0,
+            bend_step: 1.0,
+            bend_smooth: 0.0,
+            portamento_time: 0.0,
             trigger: Default::default(),
 
+            loop_mode: None,
+            loop_start: None,
+            loop_end: None,
+            loop_crossfade: 0.0,
+
+            click_guard: None,
+
             group: Default::default(),
             off_by: Default::default(),
+            off_mode: Default::default(),
+            off_time: FAST_OFF_TIME,
+            off_lokey: None,
+            off_hikey: None,
+
+            sw_lokey: None,
+            sw_hikey: None,
+            sw_last: None,
+            sw_default: None,
+            sw_vel_previous: false,
+            sw_label: None,
+
+            xfin_lokey: 0,
+            xfin_hikey: 0,
+            xfout_lokey: 127,
+            xfout_hikey: 127,
+            xf_keycurve: Default::default(),
 
             on_ccs: HashMap::new(),
+            cc_conditions: HashMap::new(),
+
+            cutoff: None,
+
+            modulation: Default::default(),
+
+            effect1: 0.0,
+            effect2: 0.0,
 
             random_range: Default::default(),
         }
     }
 }
 
+impl fmt::Display for RegionData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.key_range.lo, self.key_range.hi) {
+            (Some(lo), Some(hi)) => write!(f, "key {}-{}", u8::from(lo), u8::from(hi))?,
+            _ => write!(f, "key none")?,
+        }
+        write!(f, " vel {}-{}", u8::from(self.vel_range.lo), u8::from(self.vel_range.hi))?;
+        if self.trigger != Trigger::Attack {
+            write!(f, " trigger {:?}", self.trigger)?;
+        }
+        if let Some(loop_mode) = self.loop_mode.filter(|m| *m != LoopMode::NoLoop) {
+            write!(f, " loop_mode {:?}", loop_mode)?;
+        }
+        if self.group > 0 {
+            write!(f, " group {}", self.group)?;
+        }
+        if self.off_by > 0 {
+            write!(f, " off_by {}", self.off_by)?;
+        }
+        write!(f, " sample {}", self.sample)
+    }
+}
+
 impl RegionData {
+    // FNV-1a over the parameters identifying a region, see `Fnv1a`
+    fn identity_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        hasher.write(&self.group.to_le_bytes());
+        hasher.write(self.sample.as_bytes());
+        hasher.write(&[0]);
+        let note = |n: Option<wmidi::Note>| n.map_or(0xff, u8::from);
+        hasher.write(&[note(self.key_range.lo), note(self.key_range.hi),
+                     u8::from(self.vel_range.lo), u8::from(self.vel_range.hi)]);
+        if !self.channel_range.is_full() {
+            hasher.write(&[b'h', self.channel_range.lo, self.channel_range.hi]);
+        }
+        self.trigger.hash(&mut hasher);
+        hasher.write(&self.random_range.lo.to_bits().to_le_bytes());
+        hasher.write(&self.random_range.hi.to_bits().to_le_bytes());
+        let mut on_ccs: Vec<_> = self.on_ccs.iter().collect();
+        on_ccs.sort_by_key(|(cc, _)| **cc);
+        let cc_val = |v: Option<wmidi::ControlValue>| v.map_or(0xff, u8::from);
+        for (cc, range) in on_ccs {
+            hasher.write(&[*cc, cc_val(range.lo), cc_val(range.hi)]);
+        }
+        let mut cc_conditions: Vec<_> = self.cc_conditions.iter().collect();
+        cc_conditions.sort_by_key(|(cc, _)| **cc);
+        for (cc, range) in cc_conditions {
+            hasher.write(&[b'c', *cc, cc_val(range.lo), cc_val(range.hi)]);
+        }
+        hasher.finish()
+    }
+
+    // the semitones off the keycenter to render pitch variants for, none if
+    // the region is not played more than `threshold` semitones off
+    fn pitch_variant_semitones(&self, threshold: u32) -> Vec<i32> {
+        let (lo, hi) = match (self.key_range.lo, self.key_range.hi) {
+            (Some(lo), Some(hi)) => (lo, hi),
+            _ => return Vec::new(),
+        };
+        let offset = |note: wmidi::Note| {
+            (u8::from(note) as f64 - u8::from(self.pitch_keycenter) as f64) * self.pitch_keytrack
+        };
+        let (min, max) = (offset(lo).min(offset(hi)), offset(lo).max(offset(hi)));
+        if min.abs().max(max.abs()) <= threshold as f64 {
+            return Vec::new();
+        }
+        let min = (min.round() as i32).max(-MAX_PITCH_VARIANT_SEMITONES);
+        let max = (max.round() as i32).min(MAX_PITCH_VARIANT_SEMITONES);
+        (min..=max).filter(|s| *s != 0).collect()
+    }
+
+    pub(super) fn sample(&self) -> &str {
+        &self.sample
+    }
+
+    pub(super) fn group(&self) -> u32 {
+        self.group
+    }
+
+    pub(super) fn off_by(&self) -> u32 {
+        self.off_by
+    }
+
+    pub(super) fn trigger(&self) -> Trigger {
+        self.trigger
+    }
+
+    pub(super) fn pitch_keycenter(&self) -> u8 {
+        u8::from(self.pitch_keycenter)
+    }
+
+    /// Lowest and highest key, `None` if the region is not triggered by keys
+    pub(super) fn key_bounds(&self) -> Option<(u8, u8)> {
+        match (self.key_range.lo, self.key_range.hi) {
+            (Some(lo), Some(hi)) => Some((u8::from(lo), u8::from(hi))),
+            _ => None,
+        }
+    }
+
+    pub(super) fn velocity_bounds(&self) -> (u8, u8) {
+        (u8::from(self.vel_range.lo), u8::from(self.vel_range.hi))
+    }
+
+    /// The range of the random value the region is played in
+    pub(super) fn random_bounds(&self) -> (f32, f32) {
+        (self.random_range.lo, self.random_range.hi)
+    }
+
+    /// The controllers triggering the region with the bounds of their
+    /// ranges, ordered by controller
+    pub(super) fn on_cc_bounds(&self) -> Vec<(u8, Option<u8>, Option<u8>)> {
+        cc_bounds(&self.on_ccs)
+    }
+
+    /// The controllers that must be in range to play the region with the
+    /// bounds of their ranges, ordered by controller
+    pub(super) fn cc_condition_bounds(&self) -> Vec<(u8, Option<u8>, Option<u8>)> {
+        cc_bounds(&self.cc_conditions)
+    }
+
+    /// The SFZ opcodes setting the region with their values, the ones at
+    /// their defaults left out
+    pub(super) fn opcodes(&self) -> Vec<(String, String)> {
+        let mut opcodes: Vec<(String, String)> = vec![("sample".to_string(), self.sample.clone())];
+        let mut push = |name: &str, value: String| opcodes.push((name.to_string(), value));
+        let note = |n: Option<wmidi::Note>| n.map_or(-1, |n| u8::from(n) as i32).to_string();
+        if self.key_range.lo != Some(wmidi::Note::LOWEST_NOTE) {
+            push("lokey", note(self.key_range.lo));
+        }
+        if self.key_range.hi != Some(wmidi::Note::HIGHEST_NOTE) {
+            push("hikey", note(self.key_range.hi));
+        }
+        if self.pitch_keycenter_set {
+            push("pitch_keycenter", u8::from(self.pitch_keycenter).to_string());
+        }
+        if self.vel_range.lo != wmidi::Velocity::MIN {
+            push("lovel", u8::from(self.vel_range.lo).to_string());
+        }
+        if self.vel_range.hi != wmidi::Velocity::MAX {
+            push("hivel", u8::from(self.vel_range.hi).to_string());
+        }
+        if self.channel_range.lo != 1 {
+            push("lochan", self.channel_range.lo.to_string());
+        }
+        if self.channel_range.hi != 16 {
+            push("hichan", self.channel_range.hi.to_string());
+        }
+        if !self.random_range.is_full() {
+            push("lorand", opcode_number(self.random_range.lo as f64));
+            push("hirand", opcode_number(self.random_range.hi as f64));
+        }
+        let trigger = match self.trigger {
+            Trigger::Attack => None,
+            Trigger::Release => Some("release"),
+            Trigger::First => Some("first"),
+            Trigger::Legato => Some("legato"),
+            Trigger::ReleaseKey => Some("release_key"),
+        };
+        if let Some(trigger) = trigger {
+            push("trigger", trigger.to_string());
+        }
+        if self.rt_dead {
+            push("rt_dead", "1".to_string());
+        }
+        if self.group > 0 {
+            push("group", self.group.to_string());
+        }
+        if self.off_by > 0 {
+            push("off_by", self.off_by.to_string());
+        }
+        match self.off_mode {
+            OffMode::Fast => {}
+            OffMode::Normal => push("off_mode", "normal".to_string()),
+            OffMode::Time => push("off_mode", "time".to_string()),
+        }
+        if self.off_time != FAST_OFF_TIME {
+            push("off_time", opcode_number(self.off_time as f64));
+        }
+        if let Some(key) = self.off_lokey {
+            push("off_lokey", key.to_string());
+        }
+        if let Some(key) = self.off_hikey {
+            push("off_hikey", key.to_string());
+        }
+        let keyswitches = [
+            ("sw_lokey", self.sw_lokey),
+            ("sw_hikey", self.sw_hikey),
+            ("sw_last", self.sw_last),
+            ("sw_default", self.sw_default),
+        ];
+        for (name, key) in keyswitches.iter() {
+            if let Some(key) = key {
+                push(name, key.to_string());
+            }
+        }
+        if let Some(label) = &self.sw_label {
+            push("sw_label", label.clone());
+        }
+        if self.sw_vel_previous {
+            push("sw_vel", "previous".to_string());
+        }
+        let crossfades = [
+            ("xfin_lokey", self.xfin_lokey, 0),
+            ("xfin_hikey", self.xfin_hikey, 0),
+            ("xfout_lokey", self.xfout_lokey, 127),
+            ("xfout_hikey", self.xfout_hikey, 127),
+        ];
+        for (name, key, _) in crossfades.iter().filter(|(_, key, default)| key != default) {
+            push(name, key.to_string());
+        }
+        if self.xf_keycurve == XfCurve::Gain {
+            push("xf_keycurve", "gain".to_string());
+        }
+        let cc_value = |v: Option<wmidi::ControlValue>| v.map_or(-1, |v| u8::from(v) as i32).to_string();
+        let mut on_ccs: Vec<_> = self.on_ccs.iter().collect();
+        on_ccs.sort_by_key(|(cc, _)| **cc);
+        for (cc, range) in on_ccs {
+            push(&format!("on_locc{}", cc), cc_value(range.lo));
+            push(&format!("on_hicc{}", cc), cc_value(range.hi));
+        }
+        let mut cc_conditions: Vec<_> = self.cc_conditions.iter().collect();
+        cc_conditions.sort_by_key(|(cc, _)| **cc);
+        for (cc, range) in cc_conditions {
+            push(&format!("locc{}", cc), cc_value(range.lo));
+            push(&format!("hicc{}", cc), cc_value(range.hi));
+        }
+        let numbers = [
+            ("tune", self.tune * 100.0, 0.0),
+            ("pitch_keytrack", self.pitch_keytrack * 100.0, 100.0),
+            ("amp_veltrack", self.amp_veltrack as f64 * 100.0, 100.0),
+            ("volume", self.volume as f64, 0.0),
+            ("group_volume", self.group_volume as f64, 0.0),
+            ("master_volume", self.master_volume as f64, 0.0),
+            ("global_volume", self.global_volume as f64, 0.0),
+            ("rt_decay", self.rt_decay as f64, 0.0),
+            ("bend_up", self.bend_up as f64, 200.0),
+            ("bend_down", self.bend_down as f64, -200.0),
+            ("bend_step", self.bend_step as f64, 1.0),
+            ("bend_smooth", self.bend_smooth as f64, 0.0),
+            ("portamento_time", self.portamento_time as f64, 0.0),
+            ("effect1", self.effect1 as f64 * 100.0, 0.0),
+            ("effect2", self.effect2 as f64 * 100.0, 0.0),
+            ("amp_random", self.amp_random as f64, 0.0),
+            ("pitch_random", self.pitch_random as f64, 0.0),
+            ("delay_random", self.delay_random as f64, 0.0),
+            ("lobpm", self.lobpm as f64, 0.0),
+            ("hibpm", self.hibpm as f64, 500.0),
+        ];
+        for (name, v, _) in numbers.iter().filter(|(_, v, default)| v != default) {
+            push(name, opcode_number(*v));
+        }
+        if self.offset_random > 0 {
+            push("offset_random", self.offset_random.to_string());
+        }
+        for (velocity, gain) in self.amp_velcurve.points() {
+            push(&format!("amp_velcurve_{}", velocity), opcode_number(*gain as f64));
+        }
+        if let Some(cutoff) = self.cutoff {
+            push("cutoff", opcode_number(cutoff as f64));
+        }
+        let loop_mode = self.loop_mode.map(|m| match m {
+            LoopMode::NoLoop => "no_loop",
+            LoopMode::OneShot => "one_shot",
+            LoopMode::LoopContinuous => "loop_continuous",
+            LoopMode::LoopSustain => "loop_sustain",
+        });
+        if let Some(loop_mode) = loop_mode {
+            push("loop_mode", loop_mode.to_string());
+        }
+        if let Some(loop_start) = self.loop_start {
+            push("loop_start", loop_start.to_string());
+        }
+        if let Some(loop_end) = self.loop_end {
+            push("loop_end", loop_end.to_string());
+        }
+        if self.loop_crossfade > 0.0 {
+            push("loop_crossfade", opcode_number(self.loop_crossfade as f64));
+        }
+        if let Some(click_guard) = self.click_guard {
+            push("click_guard", opcode_number(click_guard as f64));
+        }
+        for (name, v) in self.ampeg.opcodes().into_iter().chain(self.modulation.opcodes()) {
+            push(&name, opcode_number(v as f64));
+        }
+        opcodes
+    }
+
+    /// Whether both regions can be triggered by the same note
+    pub(super) fn overlaps(&self, other: &RegionData) -> bool {
+        let keys = match (self.key_range.lo, self.key_range.hi, other.key_range.lo, other.key_range.hi) {
+            (Some(lo), Some(hi), Some(other_lo), Some(other_hi)) => lo <= other_hi && other_lo <= hi,
+            _ => false,
+        };
+        let velocities = self.vel_range.lo <= other.vel_range.hi && other.vel_range.lo <= self.vel_range.hi;
+        let channels = self.channel_range.lo <= other.channel_range.hi && other.channel_range.lo <= self.channel_range.hi;
+        let ccs = self.cc_conditions.iter().all(|(cc, range)| match other.cc_conditions.get(cc) {
+            Some(other_range) => (0..128).any(|v| range.contains(v) && other_range.contains(v)),
+            None => true,
+        });
+        keys && velocities && channels && ccs && self.trigger == other.trigger
+    }
+
     pub(super) fn set_amp_veltrack(&mut self, v: f32) -> Result<(), RangeError> {
         self.amp_veltrack = range_check(v, -100.0, 100.0, "amp_veltrack")? / 100.0;
         Ok(())
     }
 
+    pub(super) fn set_offset_random(&mut self, v: u32) {
+        self.offset_random = v;
+    }
+
+    pub(super) fn set_lobpm(&mut self, v: f32) -> Result<(), RangeError> {
+        self.lobpm = range_check(v, 0.0, 500.0, "lobpm")?;
+        Ok(())
+    }
+
+    pub(super) fn set_hibpm(&mut self, v: f32) -> Result<(), RangeError> {
+        self.hibpm = range_check(v, 0.0, 500.0, "hibpm")?;
+        Ok(())
+    }
+
+    fn covers_tempo(&self, bpm: f64) -> bool {
+        bpm >= self.lobpm as f64 && bpm < self.hibpm as f64
+    }
+
+    pub(super) fn set_amp_random(&mut self, v: f32) -> Result<(), RangeError> {
+        self.amp_random = range_check(v, 0.0, 24.0, "amp_random")?;
+        Ok(())
+    }
+
+    pub(super) fn set_pitch_random(&mut self, v: f32) -> Result<(), RangeError> {
+        self.pitch_random = range_check(v, 0.0, 9600.0, "pitch_random")?;
+        Ok(())
+    }
+
+    pub(super) fn set_delay_random(&mut self, v: f32) -> Result<(), RangeError> {
+        self.delay_random = range_check(v, 0.0, 100.0, "delay_random")?;
+        Ok(())
+    }
+
+    pub(super) fn set_amp_velcurve_point(&mut self, velocity: u32, gain: f32) -> Result<(), RangeError> {
+        let velocity = range_check(velocity, 0, 127, "amp_velcurve velocity")?;
+        let gain = range_check(gain, 0.0, 1.0, "amp_velcurve")?;
+        self.amp_velcurve.set_point(velocity as u8, gain);
+        Ok(())
+    }
+
     pub(super) fn set_pitch_keycenter(&mut self, v: u32) -> Result<(), RangeError> {
         let v = range_check(v, 0, 127, "pich_keycenter")? as u8;
         self.pitch_keycenter = unsafe { wmidi::Note::from_u8_unchecked(v as u8) };
+        self.pitch_keycenter_set = true;
         Ok(())
     }
 
@@ -295,11 +1019,65 @@ impl RegionData {
         self.sample = v.to_string();
     }
 
+    pub(super) fn set_default_path(&mut self, v: &str) {
+        self.default_path = Some(v.to_string());
+    }
+
+    /// Prefixes the sample with the `default_path` of the region, or the one
+    /// of the `<control>` header if the region has none
+    pub(super) fn apply_default_path(&mut self, control: &ControlData) {
+        let default_path = self.default_path.take().unwrap_or_else(|| control.default_path.clone());
+        if default_path.is_empty() || self.sample.is_empty() || self.sample.starts_with(&['\\', '/', '*'][..]) {
+            return;
+        }
+        let separator = match default_path.ends_with(&['\\', '/'][..]) {
+            true => "",
+            false => "\\",
+        };
+        self.sample = format!("{}{}{}", default_path, separator, self.sample);
+    }
+
+    pub(super) fn set_bend_up(&mut self, v: f32) -> Result<(), RangeError> {
+        self.bend_up = range_check(v, -9600.0, 9600.0, "bend_up")?;
+        Ok(())
+    }
+
+    pub(super) fn set_bend_down(&mut self, v: f32) -> Result<(), RangeError> {
+        self.bend_down = range_check(v, -9600.0, 9600.0, "bend_down")?;
+        Ok(())
+    }
+
+    pub(super) fn set_bend_step(&mut self, v: f32) -> Result<(), RangeError> {
+        self.bend_step = range_check(v, 1.0, 1200.0, "bend_step")?;
+        Ok(())
+    }
+
+    pub(super) fn set_bend_smooth(&mut self, v: f32) -> Result<(), RangeError> {
+        self.bend_smooth = range_check(v, 0.0, 100.0, "bend_smooth")?;
+        Ok(())
+    }
+
+    pub(super) fn set_portamento_time(&mut self, v: f32) -> Result<(), RangeError> {
+        self.portamento_time = range_check(v, 0.0, 10.0, "portamento_time")?;
+        Ok(())
+    }
+
+    // frequency ratio of a bend by `cents`, quantized to `bend_step`
+    fn bend_ratio(&self, cents: f64) -> f64 {
+        let step = self.bend_step as f64;
+        let cents = if step > 1.0 { (cents / step).round() * step } else { cents };
+        2.0f64.powf(cents / 1200.0)
+    }
+
     pub(super) fn set_rt_decay(&mut self, v: f32) -> Result<(), RangeError> {
         self.rt_decay = range_check(v, 0.0, 200.0, "rt_decay")?;
         Ok(())
     }
 
+    pub(super) fn set_rt_dead(&mut self, dead: bool) {
+        self.rt_dead = dead;
+    }
+
     pub(super) fn set_tune(&mut self, v: i32) -> Result<(), RangeError> {
         self.tune = range_check(v, -100, 100, "tune")? as f64 / 100.0;
         Ok(())
@@ -310,34 +1088,229 @@ impl RegionData {
         Ok(())
     }
 
-    pub(super) fn set_trigger(&mut self, t: Trigger) {
-        self.trigger = t;
+    pub(super) fn set_group_volume(&mut self, v: f32) -> Result<(), RangeError> {
+        self.group_volume = range_check(v, -144.0, 6.0, "group_volume")?;
+        Ok(())
     }
 
-    pub(super) fn set_group(&mut self, v: u32) {
-        self.group = v;
+    pub(super) fn set_master_volume(&mut self, v: f32) -> Result<(), RangeError> {
+        self.master_volume = range_check(v, -144.0, 6.0, "master_volume")?;
+        Ok(())
     }
 
-    pub(super) fn set_off_by(&mut self, v: u32) {
-        self.off_by = v;
+    pub(super) fn set_global_volume(&mut self, v: f32) -> Result<(), RangeError> {
+        self.global_volume = range_check(v, -144.0, 6.0, "global_volume")?;
+        Ok(())
     }
 
-    pub(super) fn push_on_lo_cc(&mut self, channel: u32, v: i32) -> Result<(), RangeError> {
-        let channel = channel as u8;
-        match self.on_ccs.get_mut(&channel) {
-            Some(ref mut range) => range.set_lo(v),
-            None => {
-                let mut range = ControlValRange { hi: None, lo: None };
-                range.set_lo(v)?;
-                self.on_ccs.insert(channel, range);
-                Ok(())
-            }
-        }
+    // the volume of the region and the levels of the header hierarchy in dB
+    fn total_volume(&self) -> f32 {
+        self.volume + self.group_volume + self.master_volume + self.global_volume
     }
 
-    pub(super) fn push_on_hi_cc(&mut self, channel: u32, v: i32) -> Result<(), RangeError> {
-        let channel = channel as u8;
-        match self.on_ccs.get_mut(&channel) {
+    pub(super) fn set_trigger(&mut self, t: Trigger) {
+        self.trigger = t;
+    }
+
+    pub(super) fn set_loop_mode(&mut self, m: LoopMode) {
+        self.loop_mode = Some(m);
+    }
+
+    pub(super) fn set_loop_start(&mut self, v: u32) {
+        self.loop_start = Some(v as usize);
+    }
+
+    pub(super) fn set_loop_end(&mut self, v: u32) {
+        self.loop_end = Some(v as usize);
+    }
+
+    pub(super) fn set_loop_crossfade(&mut self, v: f32) -> Result<(), RangeError> {
+        self.loop_crossfade = range_check(v, 0.0, 10.0, "loop_crossfade")?;
+        Ok(())
+    }
+
+    pub(super) fn set_click_guard(&mut self, v: f32) -> Result<(), RangeError> {
+        self.click_guard = Some(range_check(v, 0.0, 0.1, "click_guard")?);
+        Ok(())
+    }
+
+    // the loop in a sample of `frames` frames, `None` if the region does not
+    // loop or the loop is empty
+    fn sample_loop(&self, frames: usize) -> Option<sample::Loop> {
+        let sustain_only = match self.loop_mode.unwrap_or_default() {
+            LoopMode::LoopContinuous => false,
+            LoopMode::LoopSustain => true,
+            LoopMode::NoLoop | LoopMode::OneShot => return None,
+        };
+        let start = self.loop_start.unwrap_or(0);
+        let end = self.loop_end.map_or(frames, |e| (e + 1).min(frames));
+        if start >= end {
+            return None;
+        }
+        Some(sample::Loop { start, end, sustain_only })
+    }
+
+    // Takes the root key and the loop of the sample file for the ones not
+    // given in the SFZ file. A sample with a loop is looped continuously
+    // unless the loop mode is given.
+    fn apply_sample_defaults(&mut self, sample: &loader::SampleData) {
+        if let Some(key) = sample.root_key.filter(|_| !self.pitch_keycenter_set) {
+            self.pitch_keycenter = unsafe { wmidi::Note::from_u8_unchecked(key) };
+        }
+        if let Some((start, end)) = sample.loop_points {
+            self.loop_mode = self.loop_mode.or(Some(LoopMode::LoopContinuous));
+            self.loop_start = self.loop_start.or(Some(start));
+            self.loop_end = self.loop_end.or(Some(end));
+        }
+    }
+
+    // moves the loop points when `frames` frames are trimmed off the start
+    // of the sample
+    fn trim_loop(&mut self, frames: usize) {
+        self.loop_start = self.loop_start.map(|s| s.saturating_sub(frames));
+        self.loop_end = self.loop_end.map(|e| e.saturating_sub(frames));
+    }
+
+    pub(super) fn set_group(&mut self, v: u32) {
+        self.group = v;
+    }
+
+    pub(super) fn set_off_by(&mut self, v: u32) {
+        self.off_by = v;
+    }
+
+    pub(super) fn set_off_mode(&mut self, mode: OffMode) {
+        self.off_mode = mode;
+    }
+
+    pub(super) fn set_off_time(&mut self, v: f32) -> Result<(), RangeError> {
+        self.off_time = range_check(v, 0.0, 100.0, "off_time")?;
+        Ok(())
+    }
+
+    pub(super) fn set_off_lokey(&mut self, v: i32) -> Result<(), RangeError> {
+        self.off_lokey = Some(range_check(v, 0, 127, "off_lokey")? as u8);
+        Ok(())
+    }
+
+    pub(super) fn set_off_hikey(&mut self, v: i32) -> Result<(), RangeError> {
+        self.off_hikey = Some(range_check(v, 0, 127, "off_hikey")? as u8);
+        Ok(())
+    }
+
+    // whether notes of the key choke the region
+    fn off_by_key(&self, note: wmidi::Note) -> bool {
+        let key = u8::from(note);
+        match (self.off_lokey, self.off_hikey) {
+            (None, None) => false,
+            (lo, hi) => key >= lo.unwrap_or(0) && key <= hi.unwrap_or(127),
+        }
+    }
+
+    // seconds choked voices are faded out in, `None` to release them
+    fn off_seconds(&self) -> Option<f32> {
+        match self.off_mode {
+            OffMode::Fast => Some(FAST_OFF_TIME),
+            OffMode::Normal => None,
+            OffMode::Time => Some(self.off_time),
+        }
+    }
+
+    pub(super) fn set_sw_lokey(&mut self, v: i32) -> Result<(), RangeError> {
+        self.sw_lokey = Some(range_check(v, 0, 127, "sw_lokey")? as u8);
+        Ok(())
+    }
+
+    pub(super) fn set_sw_hikey(&mut self, v: i32) -> Result<(), RangeError> {
+        self.sw_hikey = Some(range_check(v, 0, 127, "sw_hikey")? as u8);
+        Ok(())
+    }
+
+    pub(super) fn set_sw_last(&mut self, v: i32) -> Result<(), RangeError> {
+        self.sw_last = Some(range_check(v, 0, 127, "sw_last")? as u8);
+        Ok(())
+    }
+
+    pub(super) fn set_sw_default(&mut self, v: i32) -> Result<(), RangeError> {
+        self.sw_default = Some(range_check(v, 0, 127, "sw_default")? as u8);
+        Ok(())
+    }
+
+    pub(super) fn set_sw_label(&mut self, v: &str) {
+        self.sw_label = Some(v.to_string());
+    }
+
+    pub(super) fn set_sw_vel_previous(&mut self, previous: bool) {
+        self.sw_vel_previous = previous;
+    }
+
+    pub(super) fn set_xfin_lokey(&mut self, v: i32) -> Result<(), RangeError> {
+        self.xfin_lokey = range_check(v, 0, 127, "xfin_lokey")? as u8;
+        Ok(())
+    }
+
+    pub(super) fn set_xfin_hikey(&mut self, v: i32) -> Result<(), RangeError> {
+        self.xfin_hikey = range_check(v, 0, 127, "xfin_hikey")? as u8;
+        Ok(())
+    }
+
+    pub(super) fn set_xfout_lokey(&mut self, v: i32) -> Result<(), RangeError> {
+        self.xfout_lokey = range_check(v, 0, 127, "xfout_lokey")? as u8;
+        Ok(())
+    }
+
+    pub(super) fn set_xfout_hikey(&mut self, v: i32) -> Result<(), RangeError> {
+        self.xfout_hikey = range_check(v, 0, 127, "xfout_hikey")? as u8;
+        Ok(())
+    }
+
+    pub(super) fn set_xf_keycurve(&mut self, curve: XfCurve) {
+        self.xf_keycurve = curve;
+    }
+
+    // gain factor of the key crossfades for `key`
+    fn key_crossfade(&self, key: u8) -> f32 {
+        let fade_in = if key >= self.xfin_hikey {
+            1.0
+        } else if key <= self.xfin_lokey {
+            0.0
+        } else {
+            (key - self.xfin_lokey) as f32 / (self.xfin_hikey - self.xfin_lokey) as f32
+        };
+        let fade_out = if key <= self.xfout_lokey {
+            1.0
+        } else if key >= self.xfout_hikey {
+            0.0
+        } else {
+            (self.xfout_hikey - key) as f32 / (self.xfout_hikey - self.xfout_lokey) as f32
+        };
+        self.xf_keycurve.gain(fade_in) * self.xf_keycurve.gain(fade_out)
+    }
+
+    // whether pressing `key` selects a keyswitch
+    fn is_keyswitch(&self, key: u8) -> bool {
+        match (self.sw_lokey, self.sw_hikey) {
+            (Some(lo), Some(hi)) => lo <= key && key <= hi,
+            _ => self.sw_last == Some(key),
+        }
+    }
+
+    pub(super) fn push_on_lo_cc(&mut self, channel: u32, v: i32) -> Result<(), RangeError> {
+        let channel = channel as u8;
+        match self.on_ccs.get_mut(&channel) {
+            Some(ref mut range) => range.set_lo(v),
+            None => {
+                let mut range = ControlValRange { hi: None, lo: None };
+                range.set_lo(v)?;
+                self.on_ccs.insert(channel, range);
+                Ok(())
+            }
+        }
+    }
+
+    pub(super) fn push_on_hi_cc(&mut self, channel: u32, v: i32) -> Result<(), RangeError> {
+        let channel = channel as u8;
+        match self.on_ccs.get_mut(&channel) {
             Some(ref mut range) => range.set_hi(v),
             None => {
                 let mut range = ControlValRange { hi: None, lo: None };
@@ -347,9 +1320,211 @@ impl RegionData {
             }
         }
     }
+
+    pub(super) fn push_lo_cc(&mut self, channel: u32, v: i32) -> Result<(), RangeError> {
+        let mut range = self.cc_conditions.get(&(channel as u8)).cloned().unwrap_or_default();
+        range.set_lo_named(v, "loccXX")?;
+        self.cc_conditions.insert(channel as u8, range);
+        Ok(())
+    }
+
+    pub(super) fn push_hi_cc(&mut self, channel: u32, v: i32) -> Result<(), RangeError> {
+        let mut range = self.cc_conditions.get(&(channel as u8)).cloned().unwrap_or_default();
+        range.set_hi_named(v, "hiccXX")?;
+        self.cc_conditions.insert(channel as u8, range);
+        Ok(())
+    }
+
+    pub(super) fn set_cutoff(&mut self, v: f32) -> Result<(), RangeError> {
+        self.cutoff = Some(range_check(v, 0.0, 96000.0, "cutoff")?);
+        Ok(())
+    }
+
+    pub(super) fn set_effect1(&mut self, v: f32) -> Result<(), RangeError> {
+        self.effect1 = range_check(v, 0.0, 100.0, "effect1")? / 100.0;
+        Ok(())
+    }
+
+    pub(super) fn set_effect2(&mut self, v: f32) -> Result<(), RangeError> {
+        self.effect2 = range_check(v, 0.0, 100.0, "effect2")? / 100.0;
+        Ok(())
+    }
+
+    fn has_effect_sends(&self) -> bool {
+        self.effect1 > 0.0 || self.effect2 > 0.0
+    }
+
+    pub(super) fn push_modulation(&mut self,
+                                  source: modulation::Source,
+                                  destination: modulation::Destination,
+                                  v: f32) -> Result<(), RangeError> {
+        self.modulation.set(source, destination, v)
+    }
+
+    pub(super) fn set_modulation_curve(&mut self,
+                                       source: modulation::Source,
+                                       destination: modulation::Destination,
+                                       curve_index: u32) {
+        self.modulation.set_curve(source, destination, curve_index)
+    }
+
+    /// Whether the controller values fulfill the locc/hicc conditions
+    fn cc_conditions_met(&self, controllers: &[u8; 128]) -> bool {
+        self.cc_conditions.iter().all(|(cc, range)| range.contains(controllers[*cc as usize]))
+    }
+
+    /// Whether the controller values are in all the on_locc/on_hicc ranges
+    fn on_cc_ranges_met(&self, controllers: &[u8; 128]) -> bool {
+        self.on_ccs.iter().all(|(cc, range)| range.contains(controllers[*cc as usize]))
+    }
+}
+
+/// Identifier of a region that stays the same when the SFZ file is parsed
+/// again, as long as the region's group, sample, key, velocity and random
+/// ranges, trigger and controller conditions are unchanged. Regions identical in
+/// all of these are told apart by their order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RegionId(u64);
+
+/// The opcodes of the `<control>` header applying to the whole instrument
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ControlData {
+    note_offset: i32,
+    octave_offset: i32,
+    default_path: String,
+    // the `<curve>` headers
+    curves: modulation::Curves,
+}
+
+impl ControlData {
+    pub(super) fn set_note_offset(&mut self, v: i32) -> Result<(), RangeError> {
+        self.note_offset = range_check(v, -127, 127, "note_offset")?;
+        Ok(())
+    }
+
+    pub(super) fn set_octave_offset(&mut self, v: i32) -> Result<(), RangeError> {
+        self.octave_offset = range_check(v, -10, 10, "octave_offset")?;
+        Ok(())
+    }
+
+    pub(super) fn set_default_path(&mut self, v: &str) {
+        self.default_path = v.to_string();
+    }
+
+    pub(super) fn push_curve(&mut self, index: u32, curve: modulation::Curve) {
+        self.curves.insert(index, curve);
+    }
+
+    /// Number of the `<curve>` headers
+    pub fn curve_count(&self) -> usize {
+        self.curves.len()
+    }
+
+    /// The directory the sample paths of the following regions are relative
+    /// to, within the directory of the SFZ file
+    pub fn default_path(&self) -> &str {
+        &self.default_path
+    }
+
+    /// Semitones the incoming notes are transposed by before they are
+    /// matched against the regions
+    pub fn note_shift(&self) -> i32 {
+        self.note_offset + 12 * self.octave_offset
+    }
+}
+
+impl fmt::Display for RegionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl std::str::FromStr for RegionId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(RegionId)
+    }
+}
+
+fn region_ids<'a, I: Iterator<Item = &'a RegionData>>(region_data: I) -> Vec<RegionId> {
+    let mut seen: HashMap<u64, u64> = HashMap::new();
+    region_data.map(|rd| {
+        let hash = rd.identity_hash();
+        let count = seen.entry(hash).or_insert(0);
+        let id = RegionId(hash ^ count.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        *count += 1;
+        id
+    }).collect()
+}
+
+/// Crossfade time in seconds when a still sounding note is retriggered
+const DEFAULT_RETRIGGER_FADE: f64 = 0.005;
+
+/// Weight of the latest block in the smoothed CPU load estimate
+const CPU_LOAD_SMOOTHING: f32 = 0.1;
+
+/// Cutoff frequency in Hz of the low pass smoothing master gain changes
+const MASTER_GAIN_SMOOTHING_FREQUENCY: f64 = 25.0;
+
+// events queued by `midi_event_at()`, further ones are dropped
+const EVENT_QUEUE_CAPACITY: usize = 1024;
+
+/// Pitch variants are rendered up to this many semitones off the keycenter,
+/// notes further off are interpolated from the outermost variant
+const MAX_PITCH_VARIANT_SEMITONES: i32 = 24;
+
+// a pressed key of a release triggered region, played when it is released
+#[derive(Clone, Copy)]
+struct HeldNote {
+    velocity: wmidi::Velocity,
+    pressed_at: u64,
+}
+
+// frames between the updates of the modulation while controllers are interpolated
+const MODULATION_INTERVAL: usize = 16;
+
+// a controller routed to a modulation destination, ramping from its previous
+// to its latest value over the time between the two control changes
+#[derive(Clone, Copy)]
+struct ControllerRamp {
+    controller: u8,
+    value: f32,
+    target: f32,
+    step: f32,
+    remaining: usize,
+    changed_at: u64,
+}
+
+impl ControllerRamp {
+    fn new(controller: u8) -> ControllerRamp {
+        ControllerRamp { controller, value: 0.0, target: 0.0, step: 0.0, remaining: 0, changed_at: 0 }
+    }
+
+    fn set_target(&mut self, target: f32, frames: usize) {
+        self.target = target;
+        self.remaining = frames;
+        if frames == 0 {
+            self.value = target;
+        } else {
+            self.step = (target - self.value) / frames as f32;
+        }
+    }
+
+    fn advance(&mut self, frames: usize) {
+        let frames = frames.min(self.remaining);
+        self.value += self.step * frames as f32;
+        self.remaining -= frames;
+        if self.remaining == 0 {
+            self.value = self.target;
+        }
+    }
 }
 
 pub(super) struct Region {
+    id: RegionId,
     params: RegionData,
 
     sample: sample::Sample,
@@ -358,63 +1533,303 @@ pub(super) struct Region {
 
     host_samplerate: f64,
 
-    last_note_on: Option<(wmidi::Note, wmidi::Velocity)>,
-    notes_for_release_trigger: HashSet<wmidi::Note>,
+    note_frequencies: Arc<[f64; 128]>,
+    // frequency factor of the master tune
+    master_tune: f64,
+
+    // approximates the note and modulation gains if set
+    gain_table: Option<Arc<utils::GainTable>>,
+    // the curves of the instrument shaping the modulation sources
+    curves: Arc<modulation::Curves>,
+
+    // the held keys of release triggered regions
+    held_keys: [Option<HeldNote>; 128],
+    // keys released while the sustain pedal is down: with the note to play
+    // trigger=release regions with when the pedal is lifted, or `None` for
+    // notes of other regions to be released then
+    held_by_pedal: [Option<Option<HeldNote>>; 128],
+    // release triggered notes use the note-off velocity instead of the note-on velocity
+    note_off_velocity: bool,
 
     other_notes_on: HashSet<u8>,
-    time_since_note_on: f64,
+
+    // frames processed so far, to attenuate release triggered notes by rt_decay
+    frames_processed: u64,
+
+    controllers: [u8; 128],
+    // the controllers of the modulation routes, interpolated between control changes
+    controller_ramps: Vec<ControllerRamp>,
+    channel_pressure: u8,
+    key_pressure: [u8; 128],
+    latest_note: Option<wmidi::Note>,
+
+    filter: Option<filters::Lowpass>,
+    pan: f32,
+
+    // the region's output before filter and pan, only allocated when needed
+    max_block_length: usize,
+    scratch_left: Vec<f32>,
+    scratch_right: Vec<f32>,
 
     sustain_pedal_pushed: bool,
 
+    // portamento time overriding the one of the SFZ file, and the keys held
+    // while gliding, the last one sounding
+    portamento: Option<f32>,
+    mono_notes: Vec<wmidi::Note>,
+
     once_immune_against_group_events: bool,
+
+    // frames the next note starts into the sample, its gain in dB, pitch in
+    // cents and delay in seconds, drawn by `randomize()`
+    random_offset: usize,
+    random_gain_db: f32,
+    random_cents: f32,
+    random_delay: f64,
+
+    // silent until the sample is loaded, see `DeferredLoad`
+    pending: bool,
+    // the sample was purged, it is loaded again when the region is triggered
+    purged: bool,
+    reload_requested: bool,
+    // `frames_processed` when the region was last triggered
+    triggered_at: u64,
+
+    // reported to the diagnostics after the block
+    denormals_flushed: bool,
+    missed_trigger: bool,
 }
 
 impl Region {
+    #[cfg(test)]
     fn new(params: RegionData,
-           sample_data: Vec<f32>,
+           sample_data: Vec<Vec<f32>>,
            host_samplerate: f64,
            sample_samplerate: f64,
            max_block_length: usize) -> Region {
+        let buffer = Arc::new(sample::SampleBuffer::new(sample_data, max_block_length));
+        Self::with_buffer(params, buffer, host_samplerate, sample_samplerate, max_block_length)
+    }
+
+    fn with_buffer(params: RegionData,
+                   buffer: Arc<sample::SampleBuffer>,
+                   host_samplerate: f64,
+                   sample_samplerate: f64,
+                   max_block_length: usize) -> Region {
 
-        let amp_envelope = envelopes::ADSREnvelope::new(&params.ampeg,
-                                                        host_samplerate as f32,
-                                                        max_block_length);
+        let amp_envelope = envelopes::ADSREnvelope::new(&params.ampeg, host_samplerate as f32);
         let freq_shift = host_samplerate / sample_samplerate;
-        let sample = sample::Sample::new(sample_data,
-                                         max_block_length,
-                                         params.pitch_keycenter.to_freq_f64() * freq_shift,
-                                         amp_envelope);
+        let mut sample = sample::Sample::with_buffer(buffer,
+                                                     max_block_length,
+                                                     params.pitch_keycenter.to_freq_f64() * freq_shift,
+                                                     amp_envelope);
+        sample.set_retrigger_fade((DEFAULT_RETRIGGER_FADE * host_samplerate) as usize);
+        sample.set_bend_smoothing(params.bend_smooth as f64 / 1000.0 * host_samplerate);
+        if let Some(seconds) = params.click_guard {
+            sample.set_click_guard((seconds as f64 * host_samplerate) as usize);
+        }
+
+        let filter = params.cutoff.map(|cutoff| filters::Lowpass::new(host_samplerate as f32, cutoff));
+        let scratch_length = if filter.is_some() || !params.modulation.is_empty() { max_block_length } else { 0 };
+        let controller_ramps = params.modulation.controllers().into_iter().map(ControllerRamp::new).collect();
 
         Region {
+            id: RegionId(params.identity_hash()),
             params: params,
 
             sample: sample,
 
             gain: 1.0,
 
-            host_samplerate: host_samplerate,
+            host_samplerate,
+
+            note_frequencies: Arc::new(tuning::Tuning::default().frequency_table()),
+            master_tune: 1.0,
+
+            gain_table: None,
+            curves: Arc::new(modulation::Curves::default()),
 
-            last_note_on: None,
-            notes_for_release_trigger: HashSet::new(),
+            held_keys: [None; 128],
+            held_by_pedal: [None; 128],
+            note_off_velocity: false,
             other_notes_on: HashSet::new(),
-            time_since_note_on: 0.0,
+
+            frames_processed: 0,
+
+            controllers: [0; 128],
+            controller_ramps,
+            channel_pressure: 0,
+            key_pressure: [0; 128],
+            latest_note: None,
+
+            filter: filter,
+            pan: 0.0,
+
+            max_block_length: max_block_length,
+            scratch_left: vec![0.0; scratch_length],
+            scratch_right: vec![0.0; scratch_length],
 
             sustain_pedal_pushed: false,
 
+            portamento: None,
+            mono_notes: Vec::with_capacity(128),
+
             once_immune_against_group_events: false,
+
+            random_offset: 0,
+            random_gain_db: 0.0,
+            random_cents: 0.0,
+            random_delay: 0.0,
+
+            pending: false,
+            purged: false,
+            reload_requested: false,
+            triggered_at: 0,
+
+            denormals_flushed: false,
+            missed_trigger: false,
         }
     }
 
+    // replaces the placeholder of a pending region by the loaded sample
+    fn set_sample(&mut self,
+                  params: RegionData,
+                  buffer: Arc<sample::SampleBuffer>,
+                  variants: Vec<sample::PitchVariant>,
+                  sample_loop: Option<sample::Loop>,
+                  sample_samplerate: f64) {
+        let freq_shift = self.host_samplerate / sample_samplerate;
+        self.sample.set_buffer(buffer, params.pitch_keycenter.to_freq_f64() * freq_shift);
+        self.sample.set_pitch_variants(variants);
+        self.sample.set_loop(sample_loop);
+        self.params = params;
+        self.pending = false;
+        self.purged = false;
+        self.reload_requested = false;
+    }
+
+    // replaces the sample by the placeholder until it is triggered again
+    fn purge_sample(&mut self, placeholder: Arc<sample::SampleBuffer>) {
+        self.sample.set_buffer(placeholder, self.params.pitch_keycenter.to_freq_f64());
+        self.sample.set_pitch_variants(Vec::new());
+        self.pending = true;
+        self.purged = true;
+    }
+
     fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
-        self.time_since_note_on += out_left.len() as f64 / self.host_samplerate;
+        self.frames_processed += out_left.len() as u64;
+
+        if self.sample.is_playing() && self.controller_ramps.iter().any(|r| r.remaining > 0) {
+            for (l, r) in Iterator::zip(out_left.chunks_mut(MODULATION_INTERVAL), out_right.chunks_mut(MODULATION_INTERVAL)) {
+                // the volume ramps to the modulation at the end of the chunk
+                self.controller_ramps.iter_mut().for_each(|ramp| ramp.advance(l.len()));
+                self.apply_modulation();
+                self.render(l, r);
+            }
+            return;
+        }
+
+        self.controller_ramps.iter_mut().for_each(|ramp| ramp.advance(out_left.len()));
+        if !self.params.modulation.is_empty() {
+            self.apply_modulation();
+        }
+        self.render(out_left, out_right);
+    }
 
+    fn render(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
         if !self.sample.is_playing() {
             return;
         }
-        self.sample.process(out_left, out_right);
+        if self.filter.is_none() && self.pan == 0.0 {
+            self.sample.process(out_left, out_right);
+            return;
+        }
+
+        let (pan_left, pan_right) = ((1.0 - self.pan / 100.0).min(1.0), (1.0 + self.pan / 100.0).min(1.0));
+        let block_length = self.max_block_length.max(1);
+        for (l, r) in Iterator::zip(out_left.chunks_mut(block_length), out_right.chunks_mut(block_length)) {
+            let scratch_left = &mut self.scratch_left[..l.len()];
+            let scratch_right = &mut self.scratch_right[..r.len()];
+            scratch_left.iter_mut().for_each(|v| *v = 0.0);
+            scratch_right.iter_mut().for_each(|v| *v = 0.0);
+            self.sample.process(scratch_left, scratch_right);
+
+            if let Some(filter) = &mut self.filter {
+                let stopwatch = profiling::Stopwatch::start();
+                self.denormals_flushed |= filter.process(scratch_left, scratch_right);
+                stopwatch.stop(profiling::Subsystem::Filter);
+            }
+            for (o, v) in Iterator::zip(l.iter_mut(), scratch_left.iter()) {
+                *o += pan_left * v;
+            }
+            for (o, v) in Iterator::zip(r.iter_mut(), scratch_right.iter()) {
+                *o += pan_right * v;
+            }
+        }
+    }
+
+    fn source_value(&self, source: modulation::Source) -> f32 {
+        match source {
+            modulation::Source::Controller(cc) => self.controller_ramps.iter()
+                .find(|r| r.controller == cc)
+                .map_or(self.controllers[cc as usize] as f32 / 127.0, |r| r.value),
+            modulation::Source::ChannelAftertouch => self.channel_pressure as f32 / 127.0,
+            // regions are modulated as a whole, so the pressure of the latest note counts
+            modulation::Source::PolyAftertouch => self.latest_note
+                .map_or(0.0, |note| self.key_pressure[u8::from(note) as usize] as f32 / 127.0),
+        }
+    }
+
+    fn apply_modulation(&mut self) {
+        let amount = |destination| self.params.modulation.amount(destination, &self.curves, |s| self.source_value(s));
+        let volume = self.dB_to_gain(amount(modulation::Destination::Volume))
+            * (1.0 + amount(modulation::Destination::Amplitude) / 100.0).max(0.0);
+        let pitch = amount(modulation::Destination::Pitch);
+        let cutoff = amount(modulation::Destination::Cutoff);
+        let pan = amount(modulation::Destination::Pan);
+
+        self.sample.set_volume(volume);
+        self.sample.set_pitch_shift(2.0f64.powf(pitch as f64 / 1200.0));
+        self.pan = pan.max(-100.0).min(100.0);
+        if let (Some(filter), Some(base)) = (&mut self.filter, self.params.cutoff) {
+            filter.set_cutoff(base * 2.0f32.powf(cutoff / 1200.0));
+        }
     }
 
     fn note_on(&mut self, note: wmidi::Note, velocity: wmidi::Velocity) {
+        self.play_note(note, velocity, 0.0);
+    }
+
+    // draws the random variations of the next note from the engine's random
+    // number generator, only if the region has any
+    fn randomize<R: Rng>(&mut self, rng: &mut R) {
+        if self.params.offset_random > 0 {
+            self.random_offset = rng.gen_range(0, self.params.offset_random as u64 + 1) as usize;
+        }
+        if self.params.amp_random > 0.0 {
+            self.random_gain_db = rng.gen::<f32>() * self.params.amp_random;
+        }
+        if self.params.pitch_random > 0.0 {
+            self.random_cents = (rng.gen::<f32>() * 2.0 - 1.0) * self.params.pitch_random;
+        }
+        if self.params.delay_random > 0.0 {
+            self.random_delay = rng.gen::<f64>() * self.params.delay_random as f64;
+        }
+    }
+
+    // plays a release triggered note attenuated by rt_decay for the time the key was held
+    fn release_note_on(&mut self, note: wmidi::Note, held: HeldNote) {
+        let held_seconds = (self.frames_processed - held.pressed_at) as f64 / self.host_samplerate;
+        self.play_note(note, held.velocity, held_seconds as f32 * (-self.params.rt_decay));
+    }
+
+    fn play_note(&mut self, note: wmidi::Note, velocity: wmidi::Velocity, rt_decay: f32) {
+        self.triggered_at = self.frames_processed;
+        if self.pending {
+            self.reload_requested = self.purged;
+            self.missed_trigger = true;
+            return;
+        }
         let velocity = u8::from(velocity);
         let vel = if self.params.amp_veltrack < 0.0 {
             127 - velocity
@@ -422,52 +1837,110 @@ impl Region {
             velocity
         };
 
-        let velocity_db = if vel == 0 {
+        let velocity_db = if !self.params.amp_velcurve.is_empty() {
+            let gain = self.params.amp_velcurve.value(vel as f32 / 127.0);
+            if gain > 0.0 { 20.0 * gain.log10() } else { -160.0 }
+        } else if vel == 0 {
             -160.0
         } else {
             let vel = vel as f32;
             -20.0 * ((127.0 * 127.0) / (vel * vel)).log10()
         };
 
-        let rt_decay = match self.params.trigger {
-            Trigger::Release | Trigger::ReleaseKey => {
-                self.time_since_note_on as f32 * (-self.params.rt_decay)
-            }
-            _ => 0.0,
-        };
+        self.gain = self.dB_to_gain(
+            self.params.total_volume() + velocity_db * self.params.amp_veltrack.abs() + rt_decay + self.random_gain_db,
+        ) * self.params.key_crossfade(u8::from(note));
 
-        self.gain = utils::dB_to_gain(
-            self.params.volume + velocity_db * self.params.amp_veltrack.abs() + rt_decay,
-        );
+        let current_note_frequency = self.note_frequency(note) * 2.0f64.powf(self.random_cents as f64 / 1200.0);
+
+        self.latest_note = Some(note);
+        self.key_pressure[u8::from(note) as usize] = 0;
+        self.sample.set_start_offset(self.random_offset);
+        self.sample.set_start_delay((self.random_delay * self.host_samplerate) as usize);
+        if self.params.ampeg.has_velocity_modulation() || self.params.ampeg.has_cc_modulation() {
+            let generator = self.params.ampeg.for_controllers(&self.controllers).for_velocity(velocity);
+            let envelope = envelopes::ADSREnvelope::new(&generator, self.host_samplerate as f32);
+            self.sample.note_on_with_envelope(note, current_note_frequency, self.gain, envelope);
+        } else {
+            self.sample.note_on(note, current_note_frequency, self.gain);
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn dB_to_gain(&self, db: f32) -> f32 {
+        match &self.gain_table {
+            Some(table) => table.gain(db),
+            None => utils::dB_to_gain(db),
+        }
+    }
 
+    fn note_frequency(&self, note: wmidi::Note) -> f64 {
         let native_freq = self.params.pitch_keycenter.to_freq_f64();
-        let key_pitchshift = (note.to_freq_f64() / native_freq).powf(self.params.pitch_keytrack);
+        let note_freq = self.note_frequencies[u8::from(note) as usize];
+        let key_pitchshift = (note_freq / native_freq).powf(self.params.pitch_keytrack);
         let tune_pitchshift = 2.0f64.powf(1.0 / 12.0 * self.params.tune);
-        let current_note_frequency = native_freq * key_pitchshift * tune_pitchshift;
+        native_freq * key_pitchshift * tune_pitchshift * self.master_tune
+    }
 
-        self.time_since_note_on = 0.0;
-        self.sample.note_on(note, current_note_frequency, self.gain);
+    fn portamento_time(&self) -> Option<f32> {
+        match self.portamento.unwrap_or(self.params.portamento_time) {
+            seconds if seconds > 0.0 => Some(seconds),
+            _ => None,
+        }
     }
 
-    fn note_off(&mut self, note: wmidi::Note) {
-        self.sample.note_off(note);
+    // glides the voice of the key `from` to `note`, false if it is not held
+    fn glide(&mut self, from: wmidi::Note, note: wmidi::Note) -> bool {
+        let seconds = self.portamento_time().unwrap_or(0.0);
+        let frequency = self.note_frequency(note);
+        self.sample.glide(from, note, frequency, (seconds as f64 * self.host_samplerate) as usize)
     }
 
-    fn sustain_pedal(&mut self, pushed: bool) {
-        self.sustain_pedal_pushed = pushed;
+    fn set_click_guard(&mut self, seconds: f64) {
+        let seconds = self.params.click_guard.map_or(seconds, |s| s as f64);
+        self.sample.set_click_guard((seconds * self.host_samplerate) as usize);
+    }
 
-        if !pushed {
-            match self.params.trigger {
-                Trigger::Release => self.last_note_on
-                    .map_or((), |(note, vel)| self.note_on(note, vel)),
-                _ => {
-                    for note in self.notes_for_release_trigger.clone() {
-                        self.note_off(note);
-                    }
-                    self.notes_for_release_trigger.clear();
+    fn note_off(&mut self, note: wmidi::Note) {
+        if self.params.loop_mode != Some(LoopMode::OneShot) {
+            self.sample.note_off(note);
+        }
+    }
+
+    fn note_bend(&mut self, note: wmidi::Note, ratio: f64) {
+        let ratio = self.params.bend_ratio(1200.0 * ratio.log2());
+        self.sample.set_note_bend(note, ratio);
+    }
+
+    fn pitch_bend(&mut self, bend: wmidi::U14) {
+        let wheel = (u16::from(bend) as f64 - 8192.0) / 8192.0;
+        let cents = if wheel >= 0.0 {
+            wheel * self.params.bend_up as f64
+        } else {
+            -wheel * self.params.bend_down as f64
+        };
+        self.sample.set_bend(self.params.bend_ratio(cents));
+    }
+
+    fn sustain_pedal(&mut self, pushed: bool) -> bool {
+        self.sustain_pedal_pushed = pushed;
+        if pushed {
+            return false;
+        }
+
+        let mut triggered = false;
+        for n in 0..128 {
+            let note = unsafe { wmidi::Note::from_u8_unchecked(n as u8) };
+            match self.held_by_pedal[n].take() {
+                Some(Some(held)) => {
+                    self.release_note_on(note, held);
+                    triggered = true;
                 }
+                Some(None) => self.note_off(note),
+                None => {}
             }
         }
+        triggered
     }
 
     fn handle_note_on(&mut self, note: wmidi::Note, velocity: wmidi::Velocity) -> bool {
@@ -480,9 +1953,14 @@ impl Region {
             return false;
         }
 
+        if !self.params.cc_conditions_met(&self.controllers) {
+            return false;
+        }
+
         match self.params.trigger {
             Trigger::Release | Trigger::ReleaseKey => {
-                self.last_note_on = Some((note, velocity));
+                self.held_keys[u8::from(note) as usize] = Some(HeldNote { velocity, pressed_at: self.frames_processed });
+                self.held_by_pedal[u8::from(note) as usize] = None;
                 return false;
             }
             Trigger::First => {
@@ -497,56 +1975,107 @@ impl Region {
             }
             _ => {}
         }
+        if self.portamento_time().is_some() {
+            let glided = match self.mono_notes.last() {
+                Some(&from) => self.glide(from, note),
+                None => false,
+            };
+            self.mono_notes.retain(|n| *n != note);
+            self.mono_notes.push(note);
+            if glided {
+                self.held_by_pedal[u8::from(note) as usize] = None;
+                return true;
+            }
+        }
         self.note_on(note, velocity);
-        self.notes_for_release_trigger.remove(&note);
+        self.held_by_pedal[u8::from(note) as usize] = None;
         true
     }
 
-    fn handle_note_off(&mut self, note: wmidi::Note) -> bool {
+    fn handle_note_off(&mut self, note: wmidi::Note, velocity: wmidi::Velocity) -> bool {
         if !self.params.key_range.covering(note) {
             self.other_notes_on.remove(&u8::from(note));
             return false;
         }
+        let key = u8::from(note) as usize;
         match self.params.trigger {
-            Trigger::Release | Trigger::ReleaseKey => match self.last_note_on {
-                Some((note, velocity)) => {
-                    self.note_on(note, velocity);
-                    true
+            Trigger::Release | Trigger::ReleaseKey => match self.held_keys[key].take() {
+                Some(held) => {
+                    let held = if self.note_off_velocity { HeldNote { velocity, ..held } } else { held };
+                    if self.params.trigger == Trigger::Release && self.sustain_pedal_pushed {
+                        self.held_by_pedal[key] = Some(Some(held));
+                        false
+                    } else {
+                        self.release_note_on(note, held);
+                        true
+                    }
                 }
                 None => false,
             },
             _ => {
+                if self.portamento_time().is_some() {
+                    let sounding = self.mono_notes.last() == Some(&note);
+                    self.mono_notes.retain(|n| *n != note);
+                    if sounding && !self.sustain_pedal_pushed {
+                        if let Some(&previous) = self.mono_notes.last() {
+                            if self.glide(note, previous) {
+                                return false;
+                            }
+                        }
+                    }
+                }
                 if !self.sustain_pedal_pushed {
                     self.note_off(note);
                 } else {
-                    self.notes_for_release_trigger.insert(note);
+                    self.held_by_pedal[key] = Some(None);
                 }
                 false
             }
         }
     }
 
+    // The region is triggered by a controller entering its on_locc/on_hicc
+    // range if the last values of its other on_locc/on_hicc controllers are
+    // in their ranges as well.
     fn handle_control_event(&mut self,
                             control_number: wmidi::ControlNumber,
-                            control_value: wmidi::ControlValue) -> bool {
+                            control_value: wmidi::ControlValue,
+                            random_value: f32) -> bool {
         let (cnum, cval) = (u8::from(control_number), u8::from(control_value));
-
-        match cnum {
-            64 => self.sustain_pedal(cval >= 64),
-            _ => {}
+        self.controllers[cnum as usize] = cval;
+
+        // a new note starts at the latest value, otherwise the ramp ends at
+        // most a block after the control change
+        let playing = self.sample.is_playing();
+        for ramp in self.controller_ramps.iter_mut().filter(|r| r.controller == cnum) {
+            let frames = if playing {
+                ((self.frames_processed - ramp.changed_at) as usize).min(self.max_block_length)
+            } else {
+                0
+            };
+            ramp.set_target(cval as f32 / 127.0, frames);
+            ramp.changed_at = self.frames_processed;
         }
 
+        let released_by_pedal = cnum == 64 && self.sustain_pedal(cval >= 64);
+
         match self.params.on_ccs.get(&cnum) {
-            Some(cvrange) if cvrange.covering(control_value) => {
+            Some(cvrange) if cvrange.covering(control_value)
+                && self.params.on_cc_ranges_met(&self.controllers)
+                && self.params.cc_conditions_met(&self.controllers)
+                && self.params.random_range.covering(random_value) => {
                 self.note_on(self.params.pitch_keycenter, wmidi::Velocity::MAX);
                 true
             }
-            _ => false,
+            _ => released_by_pedal,
         }
     }
 
     fn pass_midi_msg(&mut self, midi_msg: &wmidi::MidiMessage, random_value: f32) -> bool {
         self.once_immune_against_group_events = false;
+        if message_channel(midi_msg).map_or(false, |ch| !self.params.channel_range.covering(ch)) {
+            return false;
+        }
         match midi_msg {
             wmidi::MidiMessage::NoteOn(_ch, note, vel) => {
                 if self.params.random_range.covering(random_value) {
@@ -555,9 +2084,21 @@ impl Region {
                     false
                 }
             }
-            wmidi::MidiMessage::NoteOff(_ch, note, _vel) => self.handle_note_off(*note),
+            wmidi::MidiMessage::NoteOff(_ch, note, vel) => self.handle_note_off(*note, *vel),
             wmidi::MidiMessage::ControlChange(_ch, cnum, cval) => {
-                self.handle_control_event(*cnum, *cval)
+                self.handle_control_event(*cnum, *cval, random_value)
+            }
+            wmidi::MidiMessage::ChannelPressure(_ch, pressure) => {
+                self.channel_pressure = u8::from(*pressure);
+                false
+            }
+            wmidi::MidiMessage::PolyphonicKeyPressure(_ch, note, pressure) => {
+                self.key_pressure[u8::from(*note) as usize] = u8::from(*pressure);
+                false
+            }
+            wmidi::MidiMessage::PitchBendChange(_ch, bend) => {
+                self.pitch_bend(*bend);
+                false
             }
             _ => false,
         }
@@ -568,13 +2109,30 @@ impl Region {
         self.params.group
     }
 
-    fn group_activated(&mut self, group: u32) {
+    fn group_activated(&mut self, group: u32) -> bool {
         if self.once_immune_against_group_events {
-            return;
+            return false;
         }
         if group == self.params.group || group == self.params.off_by {
-            self.sample.all_notes_off();
+            return self.choke();
+        }
+        false
+    }
+
+    fn key_played(&mut self, note: wmidi::Note) -> bool {
+        if self.once_immune_against_group_events || !self.params.off_by_key(note) {
+            return false;
+        }
+        self.choke()
+    }
+
+    fn choke(&mut self) -> bool {
+        let was_playing = self.sample.is_playing();
+        match self.params.off_seconds() {
+            Some(seconds) => self.sample.fade_out((seconds as f64 * self.host_samplerate) as usize),
+            None => self.sample.all_notes_off(),
         }
+        was_playing
     }
 
     fn all_notes_off(&mut self) {
@@ -584,20 +2142,63 @@ impl Region {
 
 #[derive(Debug)]
 pub enum EngineError {
-    ParserError(parser::ParserError),
-    SndFileError(sndfile::SndFileError),
-    IOError(io::Error),
-    UnspecifiedSndFileError(String),
+    /// The SFZ file could not be read
+    IOError { path: String, error: io::Error },
+    /// The SFZ file is invalid, the error tells the line and the opcode
+    ParserError { path: String, error: parser::ParserError },
+    /// The sample file of a region was not found at any of the paths `tried`
+    SampleNotFound { path: String, tried: Vec<String> },
+    /// The sample file of a region is in a format not supported
+    UnsupportedSampleFormat { path: String, format: String },
+    /// The sample file of a region could not be opened
+    SampleFileError { path: String, error: SampleFileError },
+    /// The samples could not be read from the sample file
+    SampleReadError { path: String },
+    /// The samples of the SFZ file need more memory than allowed
+    MemoryLimitExceeded { path: String, required: usize, limit: usize },
+}
+
+impl EngineError {
+    /// The file that caused the error
+    pub fn path(&self) -> &str {
+        match self {
+            EngineError::IOError { path, .. } => path,
+            EngineError::ParserError { path, .. } => path,
+            EngineError::SampleNotFound { path, .. } => path,
+            EngineError::UnsupportedSampleFormat { path, .. } => path,
+            EngineError::SampleFileError { path, .. } => path,
+            EngineError::SampleReadError { path } => path,
+            EngineError::MemoryLimitExceeded { path, .. } => path,
+        }
+    }
+
+    /// The same error caused by the file `path`
+    pub(super) fn with_path(self, path: String) -> EngineError {
+        match self {
+            EngineError::IOError { error, .. } => EngineError::IOError { path, error },
+            EngineError::ParserError { error, .. } => EngineError::ParserError { path, error },
+            EngineError::SampleNotFound { tried, .. } => EngineError::SampleNotFound { path, tried },
+            EngineError::UnsupportedSampleFormat { format, .. } => EngineError::UnsupportedSampleFormat { path, format },
+            EngineError::SampleFileError { error, .. } => EngineError::SampleFileError { path, error },
+            EngineError::SampleReadError { .. } => EngineError::SampleReadError { path },
+            EngineError::MemoryLimitExceeded { required, limit, .. } => EngineError::MemoryLimitExceeded { path, required, limit },
+        }
+    }
 }
 
 impl fmt::Display for EngineError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &*self {
-            EngineError::ParserError(pe) => std::fmt::Display::fmt(&pe, f),
-            EngineError::SndFileError(sfe) => fmt::Debug::fmt(&sfe, f),
-            EngineError::IOError(ioe) => fmt::Display::fmt(&ioe, f),
-            EngineError::UnspecifiedSndFileError(sf) => {
-                write!(f, "Unspecified error from sndfile while reading {}", sf)
+        match self {
+            EngineError::IOError { path, error } => write!(f, "{}: {}", path, error),
+            EngineError::ParserError { path, error } => write!(f, "{}: {}", path, error),
+            EngineError::SampleNotFound { path, tried } => {
+                write!(f, "{}: sample file not found, tried {} (also ignoring case)", path, tried.join(", "))
+            }
+            EngineError::UnsupportedSampleFormat { path, format } => write!(f, "{}: {} samples are not supported", path, format),
+            EngineError::SampleFileError { path, error } => write!(f, "{}: {}", path, error),
+            EngineError::SampleReadError { path } => write!(f, "{}: Could not read the samples", path),
+            EngineError::MemoryLimitExceeded { path, required, limit } => {
+                write!(f, "{}: the samples need {} MB, more than the limit of {} MB", path, required >> 20, limit >> 20)
             }
         }
     }
@@ -605,434 +2206,2315 @@ impl fmt::Display for EngineError {
 
 impl error::Error for EngineError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match *self {
-            EngineError::ParserError(ref e) => Some(e),
-            EngineError::SndFileError(_) => None, // SndFileError should implement std::errer::Error
-            EngineError::IOError(ref e) => Some(e),
-            _ => None,
+        match self {
+            EngineError::IOError { error, .. } => Some(error),
+            EngineError::ParserError { error, .. } => Some(error),
+            EngineError::SampleNotFound { .. } => None,
+            EngineError::UnsupportedSampleFormat { .. } => None,
+            EngineError::SampleFileError { error, .. } => Some(error),
+            EngineError::SampleReadError { .. } => None,
+            EngineError::MemoryLimitExceeded { .. } => None,
         }
     }
 }
 
-pub struct Engine {
-    pub(super) regions: Vec<Region>,
+/// Layout of a region as shown to the user
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegionInfo {
+    pub id: RegionId,
+    /// lowest and highest key, `None` if the region is not triggered by keys
+    pub key_range: Option<(u8, u8)>,
+    pub velocity_range: (u8, u8),
+    pub sample: String,
+    pub trigger: Trigger,
+    pub group: u32,
+    /// whether the region currently has sounding voices
+    pub playing: bool,
+    /// samplerate of the sample file
+    pub sample_samplerate: f64,
+    /// encoding of the sample file, `None` if not known, see `SampleData`
+    pub sample_encoding: Option<SampleEncoding>,
 }
 
-impl Engine {
-    pub fn new(sfz_file: String, host_samplerate: f64, max_block_length: usize) -> Result<Engine, EngineError> {
-        let mut fh = std::fs::File::open(&sfz_file).map_err(|e| EngineError::IOError(e))?;
-        let mut sfz_text = String::new();
-        io::Read::read_to_string(&mut fh, &mut sfz_text)
-            .map_err(|e| EngineError::IOError(e))?;
-
-        let region_data = parser::parse_sfz_text(sfz_text)
-            .map_err(|pe| EngineError::ParserError(pe))?;
-
-        let sample_path = Path::new(&sfz_file).parent().unwrap();
-
-        let regions: Result<Vec<(RegionData, Vec<f32>, f64)>, _> = region_data.iter()
-            .map( |rd| {
-                let sample_file = rd.sample.replace("\\", &std::path::MAIN_SEPARATOR.to_string());
-                println!("{}", sample_file);
-                let mut snd = sndfile::OpenOptions::ReadOnly(sndfile::ReadOptions::Auto)
-                    .from_path(sample_path.join(&sample_file))
-                    .map_err(|sfe| EngineError::SndFileError(sfe))?;
-                let sample = snd.read_all_to_vec()
-                    .map_err(|_| EngineError::UnspecifiedSndFileError(sample_file))?;
-                let sample_samplerate = snd.get_samplerate() as f64;
-                if host_samplerate != sample_samplerate {
-                    warn!("Sample rate of file {} differs from host sample rate. Reccomend resampling or using other host sample rate", rd.sample);
-                }
-                Ok((rd.clone(), sample, sample_samplerate))
-        }).collect();
-        println!("loaded");
-        regions.map(|data| Self::from_region_array(data, host_samplerate, max_block_length))
-    }
+/// Options applied when loading an instrument
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct EngineConfig {
+    /// Trim leading and trailing sample frames quieter than this level in dB
+    pub trim_silence_threshold: Option<f32>,
+    /// Fail on any invalid opcode instead of skipping it with a warning
+    pub strict: bool,
+    /// Number of threads decoding the sample files, all CPUs if `None`
+    pub load_threads: Option<usize>,
+    /// How the sample frames are kept in memory
+    pub sample_storage: SampleStorage,
+    /// Fail if the sample frames would take more memory in bytes
+    pub memory_limit: Option<usize>,
+    /// Render the samples of regions played more than this many semitones
+    /// off their keycenter at every semitone of their key range, trading
+    /// memory for less CPU load and interpolation artifacts while playing
+    pub pitch_variants: Option<u32>,
+    /// Seed of the random numbers choosing the regions by `lorand` and
+    /// `hirand` and varying their notes like `offset_random` or
+    /// `amp_random`, so that rendering is reproducible, seeded from the
+    /// system if `None`
+    pub random_seed: Option<u64>,
+    /// Changes applied to the incoming MIDI messages
+    pub midi_filter: MidiFilter,
+    /// Compute the gains of the notes and of the volume modulation by a
+    /// lookup table with this many steps per dB instead of exactly
+    pub gain_table: Option<u32>,
+    /// Load the samples of rarely played regions after the others, see
+    /// `Engine::new_tiered()`
+    pub load_tiers: Option<LoadTiers>,
+}
 
-    fn from_region_array(reg_data_sample: Vec<(RegionData, Vec<f32>, f64)>,
-                         host_samplerate: f64,
-                         max_block_length: usize) -> Engine {
-        Engine {
-            regions: reg_data_sample.iter()
-                .map(|(rd, sample, s_samplerate)| Region::new(rd.clone(),
-                                                              sample.to_vec(),
-                                                              host_samplerate, *s_samplerate,
-                                                              max_block_length))
-                .collect(),
+/// Which regions `Engine::new_tiered()` loads first. The others are loaded
+/// later, unless they share a sample with one loaded first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoadTiers {
+    /// regions whose velocity range lies outside are loaded later
+    pub velocities: (u8, u8),
+    /// regions whose key range lies outside are loaded later
+    pub keys: (u8, u8),
+    /// release triggered regions are loaded later
+    pub defer_release: bool,
+}
+
+impl Default for LoadTiers {
+    fn default() -> Self {
+        LoadTiers {
+            velocities: (24, 120),
+            keys: (21, 108),
+            defer_release: true,
         }
     }
+}
 
-    pub fn fadeout(&mut self) {
-        for r in &mut self.regions {
-            r.all_notes_off();
+impl LoadTiers {
+    fn defers(&self, rd: &RegionData) -> bool {
+        if sample::Waveform::from_sample_name(&rd.sample).is_some() {
+            return false;
         }
+        let (lovel, hivel) = (u8::from(rd.vel_range.lo), u8::from(rd.vel_range.hi));
+        let outside_keys = match (rd.key_range.lo, rd.key_range.hi) {
+            (Some(lo), Some(hi)) => u8::from(hi) < self.keys.0 || u8::from(lo) > self.keys.1,
+            _ => false,
+        };
+        let release = rd.trigger.is_release();
+        hivel < self.velocities.0 || lovel > self.velocities.1 || outside_keys || (release && self.defer_release)
     }
 
-    pub fn fadeout_finished(&self) -> bool {
-        !self.regions.iter().any(|r| r.sample.is_playing())
+    // the regions to be loaded later
+    fn pending(&self, region_data: &[RegionData]) -> Vec<bool> {
+        let mut pending: Vec<bool> = region_data.iter().map(|rd| self.defers(rd)).collect();
+        let first: HashSet<&str> = Iterator::zip(region_data.iter(), pending.iter())
+            .filter(|(_, p)| !**p)
+            .map(|(rd, _)| rd.sample.as_str())
+            .collect();
+        for (p, rd) in pending.iter_mut().zip(region_data.iter()) {
+            *p = *p && !first.contains(rd.sample.as_str());
+        }
+        pending
     }
+}
 
-    pub fn dummy(host_samplerate: f64, max_block_length: usize) -> Engine {
-        Engine::from_region_array(Vec::new(), host_samplerate, max_block_length)
+/// Format of the sample frames in memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SampleStorage {
+    Float32,
+    /// half the memory of `Float32`, converted while playing
+    Int16,
+    /// uncompressed WAV and AIFF files are mapped into memory and read while
+    /// playing, taking no memory of their own, the others are stored as
+    /// `Float32`. Not used for samples whose silence is trimmed, whose loops
+    /// are crossfaded or that have pitch variants.
+    Mapped,
+}
+
+impl Default for SampleStorage {
+    fn default() -> SampleStorage {
+        SampleStorage::Float32
     }
 }
 
-impl engine::EngineTrait for Engine {
-    fn midi_event(&mut self, midi_msg: &wmidi::MidiMessage) {
-        let mut activated_groups = HashSet::new();
-        let random_value = rand::random();
-        for r in &mut self.regions {
-            if r.pass_midi_msg(midi_msg, random_value) {
-                let group = r.group();
-                if group > 0 {
-                    activated_groups.insert(group);
-                }
-            }
-        }
-        for group in activated_groups {
-            for r in &mut self.regions {
-                r.group_activated(group);
-            }
+/// Number of frames cut off the sample of a region
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrimmedSilence {
+    pub region: usize,
+    pub leading: usize,
+    pub trailing: usize,
+}
+
+/// Switches to linear interpolation when more than `high_watermark` voices
+/// are playing and back to cubic when the count drops to `low_watermark`
+#[derive(Clone, Copy, Debug)]
+pub struct QualityScaling {
+    pub high_watermark: usize,
+    pub low_watermark: usize,
+}
+
+/// Layout of the MPE zones. The lower zone has its master channel on channel
+/// 1 and the member channels above it, the upper zone its master on channel 16
+/// and the members below. Pitch bend, pressure and controllers sent on a
+/// member channel only affect the notes played on that channel. Messages on
+/// the master channels are handled as without MPE.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MpeConfig {
+    pub lower_zone_members: u8,
+    pub upper_zone_members: u8,
+    /// in semitones
+    pub pitch_bend_range: f64,
+}
+
+impl Default for MpeConfig {
+    fn default() -> Self {
+        MpeConfig {
+            lower_zone_members: 15,
+            upper_zone_members: 0,
+            pitch_bend_range: 48.0,
         }
     }
+}
 
-    fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
-        if out_left.len() * out_right.len() == 0 {
-            return;
+impl MpeConfig {
+    fn is_member_channel(&self, channel: wmidi::Channel) -> bool {
+        let index = channel.index();
+        (index >= 1 && index <= self.lower_zone_members)
+            || (index < 15 && index >= 15 - self.upper_zone_members.min(15))
+    }
+}
+
+// The reverb and chorus buses fed by the effect1 and effect2 sends of the
+// regions. Only present if a region has an effect send.
+struct EffectBuses {
+    reverb: effects::Reverb,
+    chorus: effects::Chorus,
+
+    dry_left: Vec<f32>,
+    dry_right: Vec<f32>,
+    reverb_left: Vec<f32>,
+    reverb_right: Vec<f32>,
+    chorus_left: Vec<f32>,
+    chorus_right: Vec<f32>,
+}
+
+impl EffectBuses {
+    fn new(host_samplerate: f64, max_block_length: usize) -> EffectBuses {
+        EffectBuses {
+            reverb: effects::Reverb::new(host_samplerate as f32),
+            chorus: effects::Chorus::new(host_samplerate as f32),
+
+            dry_left: vec![0.0; max_block_length],
+            dry_right: vec![0.0; max_block_length],
+            reverb_left: vec![0.0; max_block_length],
+            reverb_right: vec![0.0; max_block_length],
+            chorus_left: vec![0.0; max_block_length],
+            chorus_right: vec![0.0; max_block_length],
         }
-        for r in &mut self.regions {
-            r.process(out_left, out_right);
+    }
+
+    fn process(&mut self, regions: &mut [Region], out_left: &mut [f32], out_right: &mut [f32]) {
+        let block_length = self.dry_left.len();
+        for (out_left, out_right) in Iterator::zip(out_left.chunks_mut(block_length), out_right.chunks_mut(block_length)) {
+            let nframes = out_left.len();
+            for bus in [&mut self.reverb_left, &mut self.reverb_right, &mut self.chorus_left, &mut self.chorus_right].iter_mut() {
+                bus[..nframes].iter_mut().for_each(|v| *v = 0.0);
+            }
+
+            for r in regions.iter_mut() {
+                if !r.params.has_effect_sends() {
+                    r.process(out_left, out_right);
+                    continue;
+                }
+                let dry_left = &mut self.dry_left[..nframes];
+                let dry_right = &mut self.dry_right[..nframes];
+                dry_left.iter_mut().chain(dry_right.iter_mut()).for_each(|v| *v = 0.0);
+                r.process(dry_left, dry_right);
+
+                let (reverb, chorus) = (r.params.effect1, r.params.effect2);
+                for i in 0..nframes {
+                    out_left[i] += dry_left[i];
+                    out_right[i] += dry_right[i];
+                    self.reverb_left[i] += reverb * dry_left[i];
+                    self.reverb_right[i] += reverb * dry_right[i];
+                    self.chorus_left[i] += chorus * dry_left[i];
+                    self.chorus_right[i] += chorus * dry_right[i];
+                }
+            }
+
+            self.reverb.process(&self.reverb_left[..nframes], &self.reverb_right[..nframes], out_left, out_right);
+            self.chorus.process(&self.chorus_left[..nframes], &self.chorus_right[..nframes], out_left, out_right);
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
+// One frame of the built-in oscillators is one cycle, so that they play the
+// pitch of the note with the default `pitch_keycenter`
+pub(super) fn generator_samplerate() -> f64 {
+    wmidi::Note::C3.to_freq_f64()
+}
 
-    use super::super::parser::parse_sfz_text;
-    use super::*;
-    use crate::engine::EngineTrait;
+/// The path of the sample file `sample` referenced in an SFZ file in `sfz_dir`
+pub(super) fn sample_path(sfz_dir: &Path, sample: &str) -> Result<PathBuf, EngineError> {
+    sample_path::resolve(sfz_dir, sample).map_err(|tried| EngineError::SampleNotFound {
+        path: sample.to_string(),
+        tried: tried.iter().map(|p| p.to_string_lossy().to_string()).collect(),
+    })
+}
 
-    use crate::sndfile;
-    use crate::sndfile::SndFileIO;
+#[derive(Clone)]
+struct InstrumentRegion {
+    id: RegionId,
+    params: RegionData,
+    buffer: Arc<sample::SampleBuffer>,
+    variants: Vec<sample::PitchVariant>,
+    sample_loop: Option<sample::Loop>,
+    samplerate: f64,
+    encoding: Option<SampleEncoding>,
+    // the sample is not loaded yet, see `DeferredLoad`
+    pending: bool,
+    // the first region playing the same sample file
+    sample_group: usize,
+}
 
-    use crate::sample::tests as sampletests;
-    use crate::sample::tests::f32_eq;
+// the first region of each sample file, the regions of a sample file are
+// purged and reloaded together
+fn assign_sample_groups(regions: &mut [InstrumentRegion]) {
+    let mut first: HashMap<String, usize> = HashMap::new();
+    for (i, region) in regions.iter_mut().enumerate() {
+        region.sample_group = *first.entry(region.params.sample.clone()).or_insert(i);
+    }
+}
 
-    use wmidi::*;
+// Where the samples of an instrument come from, to load them after the
+// instrument is playing
+struct SampleSource {
+    name: String,
+    provider: Box<dyn SampleProvider + Send + Sync>,
+    host_samplerate: f64,
+    max_block_length: usize,
+    config: EngineConfig,
+}
 
-    #[test]
-    fn region_data_default() {
-        let rd: RegionData = Default::default();
+impl SampleSource {
+    // loads the samples of the regions `indices` of `instrument`, which are
+    // pending
+    fn load(&self, instrument: Arc<InstrumentData>, indices: Vec<usize>) -> Result<DeferredSamples, EngineError> {
+        let region_data: Vec<RegionData> = indices.iter().map(|i| instrument.regions[*i].params.clone()).collect();
+        let config = EngineConfig { memory_limit: None, ..self.config.clone() };
+        let loaded = Engine::decode_regions(&self.name, &region_data, &[], self.provider.as_ref(),
+                                            self.host_samplerate, self.max_block_length, &config)?;
+
+        let mut regions = instrument.regions.clone();
+        for (i, mut region) in indices.iter().zip(loaded.regions.into_iter()) {
+            region.id = regions[*i].id;
+            regions[*i] = region;
+        }
+        assign_sample_groups(&mut regions);
+        let mut trimmed_silence = instrument.trimmed_silence.clone();
+        trimmed_silence.retain(|t| !indices.contains(&t.region));
+        trimmed_silence.extend(loaded.trimmed_silence.iter().map(|t| TrimmedSilence { region: indices[t.region], ..*t }));
+        trimmed_silence.sort_by_key(|t| t.region);
+        let (first, later) = (instrument.load_stats, loaded.load_stats);
+        let loaded = InstrumentData {
+            regions,
+            control: instrument.control.clone(),
+            trimmed_silence,
+            warnings: instrument.warnings.clone(),
+            load_stats: LoadStats {
+                files: first.files + later.files,
+                frames: first.frames + later.frames,
+                threads: first.threads.max(later.threads),
+                decode_time: first.decode_time + later.decode_time,
+            },
+            random_seed: instrument.random_seed,
+            source: instrument.source.clone(),
+        };
+        let required = loaded.memory_size();
+        match self.config.memory_limit {
+            Some(limit) if required > limit => {
+                return Err(EngineError::MemoryLimitExceeded { path: self.name.clone(), required, limit });
+            }
+            _ => {}
+        }
 
-        assert_eq!(rd.key_range.hi, Some(Note::HIGHEST_NOTE));
-        assert_eq!(rd.key_range.lo, Some(Note::LOWEST_NOTE));
-        assert_eq!(rd.vel_range.hi, Velocity::MAX);
-        assert_eq!(rd.vel_range.lo, Velocity::MIN);
+        let regions = indices.iter()
+            .map(|i| (*i, loaded.regions[*i].params.clone(), loaded.regions[*i].variants.clone()))
+            .collect();
+        Ok(DeferredSamples {
+            partial: instrument,
+            instrument: Arc::new(loaded),
+            regions,
+        })
+    }
+}
 
-        assert_eq!(rd.amp_veltrack, 1.0);
-/* FIXME: How to test this?
-        let mut env = envelopes::ADSREnvelope::new(&rd.ampeg, 1.0, 4);
-        let (sustain_env, _) = env.active_envelope();
-        assert_eq!(*sustain_env.as_slice(), [1.0; 4]);
-*/
-        assert_eq!(rd.tune, 0.0)
+/// The regions and decoded samples of an instrument. They are never changed
+/// after loading, so any number of engines on any threads can share them,
+/// see `Engine::from_instrument()`.
+pub struct InstrumentData {
+    regions: Vec<InstrumentRegion>,
+    control: ControlData,
+    trimmed_silence: Vec<TrimmedSilence>,
+    warnings: Arc<Vec<parser::ParserError>>,
+    load_stats: LoadStats,
+    random_seed: Option<u64>,
+    // `None` if the instrument was loaded from a borrowed sample provider
+    source: Option<Arc<SampleSource>>,
+}
+
+impl InstrumentData {
+    /// Loads the SFZ file and decodes its samples, `sfz_file` may point into
+    /// a zip archive as in `instrument.zip#Piano.sfz`
+    pub fn load(sfz_file: &str,
+                host_samplerate: f64,
+                max_block_length: usize,
+                config: &EngineConfig) -> Result<InstrumentData, EngineError> {
+        Engine::read_instrument(sfz_file, host_samplerate, max_block_length, config)
     }
 
-    #[test]
-    fn parse_empty_text() {
-        match parse_sfz_text("".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "General parser error: Expecting <> tag in sfz file"
-            ),
-            _ => panic!("Expected error message"),
-        }
+    // regions referring to the same sample data share its buffer and its
+    // pitch variants
+    pub(super) fn new(reg_data_sample: Vec<(RegionData, Arc<Vec<Vec<f32>>>, f64)>,
+                      max_block_length: usize,
+                      config: &EngineConfig) -> InstrumentData {
+        Self::with_buffers(reg_data_sample, Vec::new(), max_block_length, config)
     }
 
-    #[test]
-    fn parse_sfz_hikey_lokey_region_line() {
-        let regions = parse_sfz_text("<region> hikey=42 lokey=23".to_string()).unwrap();
-        assert_eq!(regions.len(), 1);
-        match &regions.get(0) {
-            Some(rd) => {
-                assert_eq!(rd.key_range.hi, Some(Note::FSharp1));
-                assert_eq!(rd.key_range.lo, Some(Note::BMinus1));
-                assert_eq!(rd.vel_range.hi, Velocity::MAX);
-                assert_eq!(rd.vel_range.lo, Velocity::MIN);
-            }
-            _ => panic!("Expected region, got somthing different."),
+    // like `new()` with the buffers of some sample data already made, as the
+    // ones of mapped files
+    fn with_buffers(reg_data_sample: Vec<(RegionData, Arc<Vec<Vec<f32>>>, f64)>,
+                    mut buffers: Vec<(Arc<Vec<Vec<f32>>>, Arc<sample::SampleBuffer>)>,
+                    max_block_length: usize,
+                    config: &EngineConfig) -> InstrumentData {
+        let make_buffer = |channels: Vec<Vec<f32>>| Arc::new(match config.sample_storage {
+            SampleStorage::Float32 | SampleStorage::Mapped => sample::SampleBuffer::new(channels, max_block_length),
+            SampleStorage::Int16 => sample::SampleBuffer::new_16bit(channels, max_block_length),
+        });
+        let ids = region_ids(reg_data_sample.iter().map(|(rd, _, _)| rd));
+        let mut crossfaded: Vec<(Arc<Vec<Vec<f32>>>, sample::Loop, usize, Arc<Vec<Vec<f32>>>)> = Vec::new();
+        let mut pitched: Vec<(Arc<Vec<Vec<f32>>>, i32, Arc<sample::SampleBuffer>)> = Vec::new();
+        let mut regions = Iterator::zip(ids.into_iter(), reg_data_sample.into_iter())
+            .map(|(id, (params, sample, samplerate))| {
+                let frames = match buffers.iter().find(|(s, _)| Arc::ptr_eq(s, &sample)) {
+                    Some((_, buffer)) => buffer.frames(),
+                    None => sample.iter().map(|ch| ch.len()).min().unwrap_or(0),
+                };
+                let sample_loop = params.sample_loop(frames);
+                let crossfade = (params.loop_crossfade as f64 * samplerate) as usize;
+                // the loop seam is crossfaded in a copy of the sample data
+                let sample = match sample_loop {
+                    Some(l) if crossfade > 0 => {
+                        match crossfaded.iter().find(|(s, sl, c, _)| Arc::ptr_eq(s, &sample) && *sl == l && *c == crossfade) {
+                            Some((_, _, _, channels)) => channels.clone(),
+                            None => {
+                                let mut channels = sample.as_ref().clone();
+                                sample::crossfade_loop(&mut channels, l, crossfade);
+                                let channels = Arc::new(channels);
+                                crossfaded.push((sample, l, crossfade, channels.clone()));
+                                channels
+                            }
+                        }
+                    }
+                    _ => sample,
+                };
+                let generator = sample::Waveform::from_sample_name(&params.sample);
+                let buffer = match buffers.iter().find(|(s, _)| Arc::ptr_eq(s, &sample)) {
+                    Some((_, buffer)) => buffer.clone(),
+                    None => {
+                        let buffer = match generator {
+                            Some(waveform) => Arc::new(sample::SampleBuffer::generator(waveform)),
+                            None => make_buffer(sample.as_ref().clone()),
+                        };
+                        buffers.push((sample.clone(), buffer.clone()));
+                        buffer
+                    }
+                };
+                let semitones = match config.pitch_variants {
+                    Some(threshold) if generator.is_none() => params.pitch_variant_semitones(threshold),
+                    _ => Vec::new(),
+                };
+                let variants = semitones.into_iter()
+                    .map(|semitones| {
+                        let ratio = 2.0f64.powf(semitones as f64 / 12.0);
+                        let buffer = match pitched.iter().find(|(s, st, _)| Arc::ptr_eq(s, &sample) && *st == semitones) {
+                            Some((_, _, buffer)) => buffer.clone(),
+                            None => {
+                                let buffer = make_buffer(sample::pitched(&sample, ratio));
+                                pitched.push((sample.clone(), semitones, buffer.clone()));
+                                buffer
+                            }
+                        };
+                        sample::PitchVariant { ratio, buffer }
+                    })
+                    .collect();
+                InstrumentRegion { id, params, buffer, variants, sample_loop, samplerate, encoding: None, pending: false, sample_group: 0 }
+            })
+            .collect::<Vec<_>>();
+        assign_sample_groups(&mut regions);
+        InstrumentData {
+            regions,
+            control: ControlData::default(),
+            trimmed_silence: Vec::new(),
+            warnings: Arc::new(Vec::new()),
+            load_stats: LoadStats::default(),
+            random_seed: config.random_seed,
+            source: None,
         }
     }
 
-    #[test]
-    fn parse_sfz_key_region_line() {
-        let regions = parse_sfz_text("<region> key=42".to_string()).unwrap();
-        assert_eq!(regions.len(), 1);
-        match &regions.get(0) {
-            Some(rd) => {
-                assert_eq!(rd.key_range.hi, Some(Note::FSharp1));
-                assert_eq!(rd.key_range.lo, Some(Note::FSharp1));
-            }
-            _ => panic!("Expected region, got somthing different."),
-        }
+    pub fn region_count(&self) -> usize {
+        self.regions.len()
     }
 
-    #[test]
-    fn parse_sfz_hikey_lokey_notefmt_region_line() {
-        let regions =
-            parse_sfz_text("<region> hikey=c#3 lokey=ab2 <region> hikey=c3 lokey=a2".to_string())
-                .unwrap();
-        assert_eq!(regions.len(), 2);
-        match &regions.get(0) {
-            Some(rd) => {
-                assert_eq!(rd.key_range.hi, Some(Note::Db2));
-                assert_eq!(rd.key_range.lo, Some(Note::GSharp1));
-                assert_eq!(rd.vel_range.hi, Velocity::MAX);
-                assert_eq!(rd.vel_range.lo, Velocity::MIN);
-            }
-            _ => panic!("Expected region, got somthing different."),
-        }
-        match &regions.get(1) {
-            Some(rd) => {
-                assert_eq!(rd.key_range.hi, Some(Note::C2));
-                assert_eq!(rd.key_range.lo, Some(Note::A1));
-                assert_eq!(rd.vel_range.hi, Velocity::MAX);
-                assert_eq!(rd.vel_range.lo, Velocity::MIN);
+    pub fn control(&self) -> &ControlData {
+        &self.control
+    }
+
+    /// Whether the samples of all regions are loaded
+    pub fn is_complete(&self) -> bool {
+        self.regions.iter().all(|r| !r.pending)
+    }
+
+    /// Memory taken by the sample frames in bytes
+    pub fn memory_size(&self) -> usize {
+        let mut buffers: Vec<&Arc<sample::SampleBuffer>> = Vec::new();
+        for buffer in self.regions.iter().flat_map(|r| std::iter::once(&r.buffer).chain(r.variants.iter().map(|v| &v.buffer))) {
+            if !buffers.iter().any(|b| Arc::ptr_eq(b, buffer)) {
+                buffers.push(buffer);
             }
-            _ => panic!("Expected region, got somthing different."),
         }
+        buffers.iter().map(|b| b.memory_size()).sum()
     }
+}
 
-    #[test]
-    fn parse_sfz_hikey_lokey_group_line() {
-        let regions = parse_sfz_text("<group> hivel=42 lovel=23".to_string()).unwrap();
-        assert_eq!(regions.len(), 0);
+/// The samples left to be loaded by `Engine::new_tiered()`
+pub struct DeferredLoad {
+    source: Arc<SampleSource>,
+    // the instrument with the placeholders
+    instrument: Arc<InstrumentData>,
+}
+
+impl DeferredLoad {
+    /// Number of regions whose samples are still to be loaded
+    pub fn region_count(&self) -> usize {
+        self.instrument.regions.iter().filter(|r| r.pending).count()
     }
 
-    #[test]
-    fn parse_sfz_invalid_header_line() {
-        match parse_sfz_text("<foo> hikey=42 lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "Unknown key: foo"),
-            _ => panic!("Not seen expected error"),
-        }
+    /// Decodes the remaining samples. As it reads files and allocates, it is
+    /// meant to be called on a non realtime thread.
+    pub fn load(self) -> Result<DeferredSamples, EngineError> {
+        let indices = self.instrument.regions.iter()
+            .enumerate()
+            .filter(|(_, r)| r.pending)
+            .map(|(i, _)| i)
+            .collect();
+        self.source.load(self.instrument, indices)
     }
+}
 
-    #[test]
-    fn parse_sfz_invalid_opcode_line() {
-        match parse_sfz_text("<region> foo=42 lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "Unknown key: foo"),
-            _ => panic!("Not seen expected error"),
-        }
+/// The samples of the regions not triggered for long, see `Engine::purge()`
+pub struct PurgeRequest {
+    instrument: Arc<InstrumentData>,
+    regions: Vec<usize>,
+}
+
+impl PurgeRequest {
+    pub fn region_count(&self) -> usize {
+        self.regions.len()
     }
 
-    #[test]
-    fn parse_sfz_invalid_non_int_value_line() {
-        match parse_sfz_text("<region> hikey=aa lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "Invalid key: aa"),
-            _ => panic!("Not seen expected error"),
+    /// The instrument without the samples to be purged, to be passed to
+    /// `Engine::complete_purge()`. As it allocates, it is meant to be called
+    /// on a non realtime thread.
+    pub fn release(self) -> PurgedInstrument {
+        let placeholder = Arc::new(sample::SampleBuffer::new(Vec::new(), 0));
+        let mut regions = self.instrument.regions.clone();
+        for i in self.regions.iter() {
+            let region = &mut regions[*i];
+            region.buffer = placeholder.clone();
+            region.variants = Vec::new();
+            region.pending = true;
+        }
+        let instrument = InstrumentData {
+            regions,
+            control: self.instrument.control.clone(),
+            trimmed_silence: self.instrument.trimmed_silence.clone(),
+            warnings: self.instrument.warnings.clone(),
+            load_stats: self.instrument.load_stats,
+            random_seed: self.instrument.random_seed,
+            source: self.instrument.source.clone(),
+        };
+        PurgedInstrument {
+            purged: self.instrument,
+            instrument: Arc::new(instrument),
+            regions: self.regions,
         }
     }
+}
 
-    /* FIXME: How to test this?
-    #[test]
-    fn parse_ampeg() {
-        let regions = parse_sfz_text("<region> ampeg_attack=23 ampeg_hold=42 ampeg_decay=47 ampeg_sustain=11 ampeg_release=0.2342".to_string()).unwrap();
-        match regions.get(0) {
-            Some(rd) => {
-                assert_eq!(rd.ampeg.attack, 23.0);
-                assert_eq!(rd.ampeg.hold, 42.0);
-                assert_eq!(rd.ampeg.decay, 47.0);
-                assert_eq!(rd.ampeg.sustain, 0.11);
-                assert_eq!(rd.ampeg.release, 0.2342);
-            }
-            None => panic!("expeted region with ampeg")
-        }
+/// The instrument made by `PurgeRequest::release()`
+pub struct PurgedInstrument {
+    purged: Arc<InstrumentData>,
+    instrument: Arc<InstrumentData>,
+    regions: Vec<usize>,
+}
+
+/// The purged samples of regions triggered again, see
+/// `Engine::reload_request()`
+pub struct ReloadRequest {
+    source: Arc<SampleSource>,
+    instrument: Arc<InstrumentData>,
+    regions: Vec<usize>,
+}
+
+impl ReloadRequest {
+    /// Decodes the samples again, to be passed to
+    /// `Engine::complete_loading()`. As it reads files and allocates, it is
+    /// meant to be called on a non realtime thread.
+    pub fn load(self) -> Result<DeferredSamples, EngineError> {
+        self.source.load(self.instrument, self.regions)
     }
-     */
+}
 
-    #[test]
-    fn parse_out_of_range_amp_veltrack() {
-        match parse_sfz_text("<region> amp_veltrack=105 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "amp_veltrack out of range: -100 <= 105 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
+/// The samples loaded by `DeferredLoad::load()` to be passed to
+/// `Engine::complete_loading()`
+pub struct DeferredSamples {
+    partial: Arc<InstrumentData>,
+    instrument: Arc<InstrumentData>,
+    // prepared so that the realtime thread doesn't need to clone them
+    regions: Vec<(usize, RegionData, Vec<sample::PitchVariant>)>,
+}
+
+static_assertions::assert_impl_all!(InstrumentData: Send, Sync);
+static_assertions::assert_impl_all!(DeferredLoad: Send);
+static_assertions::assert_impl_all!(DeferredSamples: Send);
+static_assertions::assert_impl_all!(PurgeRequest: Send);
+static_assertions::assert_impl_all!(PurgedInstrument: Send);
+static_assertions::assert_impl_all!(ReloadRequest: Send);
+static_assertions::assert_impl_all!(Engine: Send, Sync);
+
+/// The regions of an engine are kept in the order they appear in the SFZ
+/// file, so region indices are the same whenever the same file is loaded.
+/// Use `RegionId` to refer to regions across edits of the file.
+///
+/// The engine is `Send`: it is meant to be built on a non realtime thread,
+/// as loading allocates and reads files, and then moved to the audio thread.
+/// There `process()` and the MIDI and parameter methods do not allocate,
+/// block or do I/O, except for `set_tuning()`, `set_recorder()`, `purge()`
+/// and `reload_request()` when they return a request. The immutable
+/// `InstrumentData` is shared, all other state belongs to the engine.
+///
+/// It is also `Sync`, as plugin hosts like LV2 require of a plugin instance,
+/// though all methods that change its state take `&mut self`.
+pub struct Engine {
+    regions: Vec<Region>,
+    instrument: Arc<InstrumentData>,
+
+    activity_log: Option<SharedActivityLog>,
+    diagnostics: Option<SharedDiagnostics>,
+    observer: Option<Box<dyn EngineObserver>>,
+
+    midi_filter: MidiFilter,
+
+    // the last keyswitch pressed and the keys that are keyswitches
+    keyswitch: Option<u8>,
+    keyswitches: [bool; 128],
+    // velocity of the last note-on for sw_vel=previous regions
+    previous_velocity: Option<wmidi::Velocity>,
+    // tempo of the host for lobpm and hibpm
+    tempo: f64,
+
+    quality_scaling: Option<QualityScaling>,
+    interpolation: sample::Interpolation,
+
+    max_voices: Option<usize>,
+
+    transpose: i32,
+    note_fold: Option<(wmidi::Note, wmidi::Note)>,
+    held_note_map: [Option<wmidi::Note>; 128],
+    // velocity of each held key as played, 0 if released
+    held_velocities: [u8; 128],
+
+    mpe: Option<MpeConfig>,
+    note_channels: [Option<wmidi::Channel>; 128],
+    channel_bends: [f64; 16],
+
+    effects: Option<EffectBuses>,
+
+    // the random numbers of the notes for `lorand` and `hirand`
+    rng: StdRng,
+
+    host_samplerate: f64,
+    recorder: Option<render::Recorder>,
+
+    telemetry: SharedTelemetry,
+    mutes: SharedRegionMutes,
+    cpu_load: f32,
+
+    master_gain: utils::Smoother,
+    // -1.0 (left) to 1.0 (right)
+    master_pan: utils::Smoother,
+    // the initial master gain and pan are set without fading them in
+    processing_started: bool,
+
+    // events to be dispatched in the next process call, ordered by their
+    // frame offset
+    event_queue: VecDeque<(usize, wmidi::MidiMessage<'static>)>,
+
+    // remaining and total frames of fading out the output when crossfading
+    // to another engine
+    crossfade: Option<(usize, usize)>,
+
+    // the output of the regions before the master gain is applied
+    mix_left: Vec<f32>,
+    mix_right: Vec<f32>,
+
+    // the buffer of purged regions, and a flag for each sample group used by
+    // `purge()` and `reload_request()`
+    purged_buffer: Arc<sample::SampleBuffer>,
+    group_flags: Vec<bool>,
+    // a purge is in progress
+    purging: bool,
+}
+
+impl Engine {
+    /// `sfz_file` may point into a zip archive as in `instrument.zip#Piano.sfz`
+    pub fn new(sfz_file: String, host_samplerate: f64, max_block_length: usize) -> Result<Engine, EngineError> {
+        Self::new_with_config(sfz_file, host_samplerate, max_block_length, &EngineConfig::default())
+    }
+
+    pub fn new_with_config(sfz_file: String,
+                           host_samplerate: f64,
+                           max_block_length: usize,
+                           config: &EngineConfig) -> Result<Engine, EngineError> {
+        let instrument = Self::read_instrument(&sfz_file, host_samplerate, max_block_length, config)?;
+        Ok(Self::from_instrument(Arc::new(instrument), host_samplerate, max_block_length).with_config(config))
+    }
+
+    /// Like `new_with_config()` but reuses the instrument if another engine
+    /// in the process plays the same unchanged SFZ file at the same sample
+    /// rate, so that the samples are in memory only once.
+    pub fn new_shared(sfz_file: String,
+                      host_samplerate: f64,
+                      max_block_length: usize,
+                      config: &EngineConfig) -> Result<Engine, EngineError> {
+        let instrument = instrument_cache::instrument(&sfz_file, host_samplerate, config, || {
+            Self::read_instrument(&sfz_file, host_samplerate, max_block_length, config)
+        })?;
+        Ok(Self::from_instrument(instrument, host_samplerate, max_block_length).with_config(config))
+    }
+
+    /// Engine of the SFZ text `sfz_text` taking the samples from
+    /// `sample_provider`
+    pub fn from_sfz_text(sfz_text: String,
+                         sample_provider: &dyn SampleProvider,
+                         host_samplerate: f64,
+                         max_block_length: usize) -> Result<Engine, EngineError> {
+        Self::from_sfz_text_with_config(sfz_text, sample_provider, host_samplerate, max_block_length, &EngineConfig::default())
+    }
+
+    pub fn from_sfz_text_with_config(sfz_text: String,
+                                     sample_provider: &dyn SampleProvider,
+                                     host_samplerate: f64,
+                                     max_block_length: usize,
+                                     config: &EngineConfig) -> Result<Engine, EngineError> {
+        let instrument = Self::load_instrument("<sfz text>", sfz_text, sample_provider, host_samplerate, max_block_length, config)?;
+        Ok(Self::from_instrument(Arc::new(instrument), host_samplerate, max_block_length).with_config(config))
+    }
+
+    // applies the settings of `config` not concerning the instrument data
+    fn with_config(mut self, config: &EngineConfig) -> Engine {
+        self.midi_filter = config.midi_filter.clone();
+        self.set_gain_table(config.gain_table);
+        self
+    }
+
+    fn read_instrument(sfz_file: &str,
+                       host_samplerate: f64,
+                       max_block_length: usize,
+                       config: &EngineConfig) -> Result<InstrumentData, EngineError> {
+        let (sfz_text, provider) = Self::read_sfz(sfz_file)?;
+        let mut instrument = Self::load_instrument(sfz_file, sfz_text, provider.as_ref(), host_samplerate, max_block_length, config)?;
+        instrument.source = Some(Arc::new(SampleSource {
+            name: sfz_file.to_string(),
+            provider,
+            host_samplerate,
+            max_block_length,
+            config: config.clone(),
+        }));
+        Ok(instrument)
+    }
+
+    // the SFZ text and the provider of its samples
+    fn read_sfz(sfz_file: &str) -> Result<(String, Box<dyn SampleProvider + Send + Sync>), EngineError> {
+        if let Some((archive, entry)) = archive::split_path(sfz_file) {
+            let sfz_text = ZipSamples::read_sfz(Path::new(archive), entry)?;
+            return Ok((sfz_text, Box::new(ZipSamples::new(archive, entry))));
         }
-        match parse_sfz_text("<region> amp_veltrack=-105 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "amp_veltrack out of range: -100 <= -105 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
+
+        let io_error = |error| EngineError::IOError { path: sfz_file.to_string(), error };
+        let mut fh = std::fs::File::open(sfz_file).map_err(io_error)?;
+        let mut sfz_text = String::new();
+        io::Read::read_to_string(&mut fh, &mut sfz_text).map_err(io_error)?;
+
+        Ok((sfz_text, Box::new(SampleFiles::new(Path::new(sfz_file).parent().unwrap()))))
+    }
+
+    /// Loads the SFZ file like `new_with_config()`, but with
+    /// `config.load_tiers` at first only the samples of the regions likely
+    /// played most. The engine can play right away, the other regions stay
+    /// silent until `DeferredLoad::load()` has decoded their samples on a non
+    /// realtime thread and they are passed to `complete_loading()`. The
+    /// `DeferredLoad` is `None` if all samples are loaded.
+    pub fn new_tiered(sfz_file: String,
+                      host_samplerate: f64,
+                      max_block_length: usize,
+                      config: &EngineConfig) -> Result<(Engine, Option<DeferredLoad>), EngineError> {
+        let (sfz_text, provider) = Self::read_sfz(&sfz_file)?;
+        Self::load_tiered(sfz_file, sfz_text, provider, host_samplerate, max_block_length, config)
+    }
+
+    /// Like `new_tiered()` for the SFZ text `sfz_text` taking the samples
+    /// from `sample_provider`
+    pub fn from_sfz_text_tiered(sfz_text: String,
+                                sample_provider: Box<dyn SampleProvider + Send + Sync>,
+                                host_samplerate: f64,
+                                max_block_length: usize,
+                                config: &EngineConfig) -> Result<(Engine, Option<DeferredLoad>), EngineError> {
+        Self::load_tiered("<sfz text>".to_string(), sfz_text, sample_provider, host_samplerate, max_block_length, config)
+    }
+
+    fn load_tiered(name: String,
+                   sfz_text: String,
+                   provider: Box<dyn SampleProvider + Send + Sync>,
+                   host_samplerate: f64,
+                   max_block_length: usize,
+                   config: &EngineConfig) -> Result<(Engine, Option<DeferredLoad>), EngineError> {
+        let (region_data, control, warnings) = Self::parse_instrument(&name, sfz_text, config)?;
+        let pending = match config.load_tiers {
+            Some(tiers) => tiers.pending(&region_data),
+            None => Vec::new(),
+        };
+        let mut instrument = Self::decode_regions(&name, &region_data, &pending,
+                                                  provider.as_ref(), host_samplerate, max_block_length, config)?;
+        let source = Arc::new(SampleSource { name, provider, host_samplerate, max_block_length, config: config.clone() });
+        instrument.control = control;
+        instrument.warnings = Arc::new(warnings);
+        instrument.source = Some(source.clone());
+        let instrument = Arc::new(instrument);
+
+        let deferred = if instrument.is_complete() {
+            None
+        } else {
+            Some(DeferredLoad { source, instrument: instrument.clone() })
+        };
+        let engine = Self::from_instrument(instrument, host_samplerate, max_block_length).with_config(config);
+        Ok((engine, deferred))
+    }
+
+    // `name` is the SFZ file in error messages
+    fn load_instrument(name: &str,
+                       sfz_text: String,
+                       sample_provider: &dyn SampleProvider,
+                       host_samplerate: f64,
+                       max_block_length: usize,
+                       config: &EngineConfig) -> Result<InstrumentData, EngineError> {
+        let (region_data, control, warnings) = Self::parse_instrument(name, sfz_text, config)?;
+        let mut instrument = Self::decode_regions(name, &region_data, &[],
+                                                  sample_provider, host_samplerate, max_block_length, config)?;
+        instrument.control = control;
+        instrument.warnings = Arc::new(warnings);
+        Ok(instrument)
+    }
+
+    fn parse_instrument(name: &str,
+                        sfz_text: String,
+                        config: &EngineConfig) -> Result<(Vec<RegionData>, ControlData, Vec<parser::ParserError>), EngineError> {
+        let parser_error = |error| EngineError::ParserError { path: name.to_string(), error };
+        let (region_data, control, warnings) = parser::parse_instrument(sfz_text, config.strict).map_err(parser_error)?;
+        for warning in warnings.iter() {
+            warn!("{}: {}", name, warning);
         }
+        Ok((region_data, control, warnings))
     }
 
-    #[test]
-    fn parse_out_of_range_ampeg_attack() {
-        match parse_sfz_text("<region> ampeg_attack=105 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "ampeg_attack out of range: 0 <= 105 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
+    // the regions flagged in `pending` get an empty placeholder instead of
+    // their sample
+    fn decode_regions(name: &str,
+                      region_data: &[RegionData],
+                      pending: &[bool],
+                      sample_provider: &dyn SampleProvider,
+                      host_samplerate: f64,
+                      max_block_length: usize,
+                      config: &EngineConfig) -> Result<InstrumentData, EngineError> {
+        let is_pending = |i: usize| pending.get(i).copied().unwrap_or(false);
+        let samples: Vec<&str> = region_data.iter()
+            .enumerate()
+            .filter(|(i, _)| !is_pending(*i))
+            .map(|(_, rd)| rd.sample.as_str())
+            .collect();
+        let map = config.sample_storage == SampleStorage::Mapped && config.trim_silence_threshold.is_none();
+        let ((decoded, mapped), load_stats) = loader::load_samples(sample_provider, &samples, config.load_threads, map)?;
+        let placeholder = Arc::new(loader::SampleData {
+            channels: Vec::new(),
+            samplerate: host_samplerate,
+            encoding: None,
+            root_key: None,
+            loop_points: None,
+        });
+        let mut decoded = decoded.into_iter().zip(mapped);
+        let (decoded, mapped): (Vec<Arc<loader::SampleData>>, Vec<Option<Arc<MappedFrames>>>) = (0..region_data.len())
+            .map(|i| if is_pending(i) {
+                (placeholder.clone(), None)
+            } else {
+                decoded.next().expect("a sample for every region loaded")
+            })
+            .unzip();
+
+        // mapped files are decoded after all if a region needs their frames
+        // in memory
+        let needs_frames = |rd: &RegionData, mapped: &MappedFrames| {
+            let crossfaded = rd.loop_crossfade > 0.0 && rd.sample_loop(mapped.frames()).is_some();
+            let pitched = config.pitch_variants.map_or(false, |threshold| !rd.pitch_variant_semitones(threshold).is_empty());
+            crossfaded || pitched
+        };
+        let mut buffers: Vec<(Arc<Vec<Vec<f32>>>, Arc<sample::SampleBuffer>)> = Vec::new();
+        let mut mapped_samples: Vec<(Arc<loader::SampleData>, Arc<loader::SampleData>)> = Vec::new();
+        let decoded: Vec<Arc<loader::SampleData>> = decoded.iter().zip(mapped.iter())
+            .map(|(data, frames)| {
+                let frames = match frames {
+                    Some(frames) => frames,
+                    None => return data.clone(),
+                };
+                if let Some((_, sample)) = mapped_samples.iter().find(|(d, _)| Arc::ptr_eq(d, data)) {
+                    return sample.clone();
+                }
+                let users = Iterator::zip(region_data.iter(), decoded.iter()).filter(|(_, d)| Arc::ptr_eq(d, data));
+                let sample = if users.clone().any(|(rd, _)| needs_frames(rd, frames)) {
+                    Arc::new(loader::SampleData { channels: frames.to_channels(), ..data.as_ref().clone() })
+                } else {
+                    data.clone()
+                };
+                mapped_samples.push((data.clone(), sample.clone()));
+                sample
+            })
+            .collect();
+
+        let mut trimmed_silence = Vec::new();
+        let mut trimmed: Vec<(Arc<loader::SampleData>, Arc<Vec<Vec<f32>>>, (usize, usize))> = Vec::new();
+        let regions: Vec<(RegionData, Arc<Vec<Vec<f32>>>, f64)> = Iterator::zip(region_data.iter(), decoded.iter())
+            .enumerate()
+            .map( |(i, (rd, decoded))| {
+                if host_samplerate != decoded.samplerate && sample::Waveform::from_sample_name(&rd.sample).is_none() {
+                    warn!("Sample rate of file {} differs from host sample rate. Reccomend resampling or using other host sample rate", rd.sample);
+                }
+                let (sample, (leading, trailing)) = match trimmed.iter().find(|(d, _, _)| Arc::ptr_eq(d, decoded)) {
+                    Some((_, sample, cut)) => (sample.clone(), *cut),
+                    None => {
+                        let mut sample = decoded.channels.clone();
+                        let cut = match config.trim_silence_threshold {
+                            Some(threshold) => utils::trim_silence(&mut sample, utils::dB_to_gain(threshold)),
+                            None => (0, 0),
+                        };
+                        let sample = Arc::new(sample);
+                        if let Some(frames) = mapped[i].as_ref().filter(|_| decoded.channels.is_empty()) {
+                            buffers.push((sample.clone(), Arc::new(sample::SampleBuffer::mapped(frames.clone()))));
+                        }
+                        trimmed.push((decoded.clone(), sample.clone(), cut));
+                        (sample, cut)
+                    }
+                };
+                if leading + trailing > 0 {
+                    trimmed_silence.push(TrimmedSilence { region: i, leading, trailing });
+                }
+                let mut rd = rd.clone();
+                rd.apply_sample_defaults(decoded);
+                rd.trim_loop(leading);
+                (rd, sample, decoded.samplerate)
+        }).collect();
+        debug!("decoded the samples of {} regions", regions.len());
+        let mut instrument = InstrumentData::with_buffers(regions, buffers, max_block_length, config);
+        for (region, pending) in instrument.regions.iter_mut().zip(pending.iter()) {
+            region.pending = *pending;
         }
-        match parse_sfz_text("<region> ampeg_attack=-20 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "ampeg_attack out of range: 0 <= -20 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
+        for (region, decoded) in instrument.regions.iter_mut().zip(decoded.iter()) {
+            region.encoding = decoded.encoding;
         }
-        match parse_sfz_text("<region> ampeg_attack=aa lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "invalid float literal"),
-            _ => panic!("Not seen expected error"),
+        let required = instrument.memory_size();
+        match config.memory_limit {
+            Some(limit) if required > limit => {
+                return Err(EngineError::MemoryLimitExceeded { path: name.to_string(), required, limit });
+            }
+            _ => {}
         }
+        instrument.trimmed_silence = trimmed_silence;
+        instrument.load_stats = load_stats;
+        Ok(instrument)
     }
 
-    #[test]
-    fn parse_out_of_range_ampeg_hold() {
-        match parse_sfz_text("<region> ampeg_hold=105 lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "ampeg_hold out of range: 0 <= 105 <= 100"),
-            _ => panic!("Not seen expected error"),
-        }
-        match parse_sfz_text("<region> ampeg_hold=-20 lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "ampeg_hold out of range: 0 <= -20 <= 100"),
-            _ => panic!("Not seen expected error"),
+    fn from_region_array(reg_data_sample: Vec<(RegionData, Vec<Vec<f32>>, f64)>,
+                         host_samplerate: f64,
+                         max_block_length: usize) -> Engine {
+        let reg_data_sample = reg_data_sample.into_iter()
+            .map(|(rd, sample, samplerate)| (rd, Arc::new(sample), samplerate))
+            .collect();
+        let instrument = InstrumentData::new(reg_data_sample, max_block_length, &EngineConfig::default());
+        Self::from_instrument(Arc::new(instrument), host_samplerate, max_block_length)
+    }
+
+    /// Engine playing `instrument`, whose sample data is shared with the
+    /// other engines playing it. The engine's playing state, controllers and
+    /// settings are its own.
+    pub fn from_instrument(instrument: Arc<InstrumentData>, host_samplerate: f64, max_block_length: usize) -> Engine {
+        let effects = if instrument.regions.iter().any(|r| r.params.has_effect_sends()) {
+            Some(EffectBuses::new(host_samplerate, max_block_length))
+        } else {
+            None
+        };
+        let rng = match instrument.random_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let region_count = instrument.regions.len();
+        let mut keyswitches = [false; 128];
+        for (key, is_keyswitch) in keyswitches.iter_mut().enumerate() {
+            *is_keyswitch = instrument.regions.iter().any(|r| r.params.is_keyswitch(key as u8));
         }
-        match parse_sfz_text("<region> ampeg_hold=aa lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "invalid float literal"),
-            _ => panic!("Not seen expected error"),
+        let keyswitch = instrument.regions.iter().find_map(|r| r.params.sw_default);
+        let curves = Arc::new(instrument.control.curves.clone());
+        Engine {
+            regions: instrument.regions.iter()
+                .map(|r| {
+                    let mut region = Region::with_buffer(r.params.clone(),
+                                                         r.buffer.clone(),
+                                                         host_samplerate, r.samplerate,
+                                                         max_block_length);
+                    region.id = r.id;
+                    region.pending = r.pending;
+                    region.sample.set_pitch_variants(r.variants.clone());
+                    region.sample.set_loop(r.sample_loop);
+                    region.curves = curves.clone();
+                    region
+                })
+                .collect(),
+            telemetry: Telemetry::shared(region_count),
+            mutes: RegionMutes::shared(region_count),
+            instrument,
+            activity_log: None,
+            diagnostics: None,
+            observer: None,
+
+            midi_filter: MidiFilter::default(),
+
+            keyswitch,
+            keyswitches,
+            previous_velocity: None,
+            tempo: DEFAULT_TEMPO,
+
+            quality_scaling: None,
+            interpolation: sample::Interpolation::Cubic,
+
+            max_voices: None,
+
+            transpose: 0,
+            note_fold: None,
+            held_note_map: [None; 128],
+            held_velocities: [0; 128],
+
+            mpe: None,
+            note_channels: [None; 128],
+            channel_bends: [1.0; 16],
+
+            effects,
+
+            rng,
+
+            host_samplerate,
+            recorder: None,
+
+            cpu_load: 0.0,
+
+            master_gain: utils::Smoother::new(host_samplerate, MASTER_GAIN_SMOOTHING_FREQUENCY, 1.0),
+            master_pan: utils::Smoother::new(host_samplerate, MASTER_GAIN_SMOOTHING_FREQUENCY, 0.0),
+            processing_started: false,
+
+            event_queue: VecDeque::with_capacity(EVENT_QUEUE_CAPACITY),
+
+            crossfade: None,
+
+            mix_left: vec![0.0; max_block_length.max(1)],
+            mix_right: vec![0.0; max_block_length.max(1)],
+
+            purged_buffer: Arc::new(sample::SampleBuffer::new(Vec::new(), max_block_length)),
+            group_flags: vec![false; region_count],
+            purging: false,
         }
     }
 
-    #[test]
-    fn parse_out_of_range_ampeg_decay() {
-        match parse_sfz_text("<region> ampeg_decay=105 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "ampeg_decay out of range: 0 <= 105 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
-        }
-        match parse_sfz_text("<region> ampeg_decay=-20 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "ampeg_decay out of range: 0 <= -20 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
+    /// The loaded instrument, to create more engines playing it with
+    /// `from_instrument()`
+    pub fn instrument(&self) -> &Arc<InstrumentData> {
+        &self.instrument
+    }
+
+    /// Whether the samples of all regions are loaded, see `new_tiered()`
+    pub fn is_fully_loaded(&self) -> bool {
+        self.instrument.is_complete()
+    }
+
+    /// Plays the regions whose samples `samples` has loaded from now on. If
+    /// they were loaded for another instrument, the engine is left unchanged
+    /// and `false` returned. Doesn't allocate but frees the placeholders.
+    pub fn complete_loading(&mut self, samples: DeferredSamples) -> bool {
+        if !Arc::ptr_eq(&self.instrument, &samples.partial) {
+            return false;
         }
-        match parse_sfz_text("<region> ampeg_decay=aa lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "invalid float literal"),
-            _ => panic!("Not seen expected error"),
+        for (i, params, variants) in samples.regions {
+            let loaded = &samples.instrument.regions[i];
+            self.regions[i].set_sample(params, loaded.buffer.clone(), variants, loaded.sample_loop, loaded.samplerate);
         }
+        self.instrument = samples.instrument;
+        true
     }
 
-    #[test]
-    fn parse_out_of_range_ampeg_sustain() {
-        match parse_sfz_text("<region> ampeg_sustain=105 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "ampeg_sustain out of range: 0 <= 105 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
+    /// Requests to purge the samples of the regions that have not been
+    /// triggered for `idle` seconds, so that their memory is freed. The
+    /// samples of a file are purged once all regions playing it are idle.
+    /// `None` if there is nothing to purge, another purge is in progress or
+    /// the instrument was loaded from a borrowed `SampleProvider` and could
+    /// not be reloaded. Allocates only if there are samples to purge.
+    pub fn purge(&mut self, idle: f64) -> Option<PurgeRequest> {
+        if self.purging || self.instrument.source.is_none() {
+            return None;
         }
-        match parse_sfz_text("<region> ampeg_sustain=-20 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "ampeg_sustain out of range: 0 <= -20 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
+        let idle = (idle * self.host_samplerate) as u64;
+        self.group_flags.iter_mut().for_each(|busy| *busy = false);
+        for (region, data) in Iterator::zip(self.regions.iter(), self.instrument.regions.iter()) {
+            let busy = region.sample.is_playing()
+                || region.frames_processed.saturating_sub(region.triggered_at) < idle
+                || sample::Waveform::from_sample_name(&region.params.sample).is_some();
+            if busy && !region.pending {
+                self.group_flags[data.sample_group] = true;
+            }
         }
-        match parse_sfz_text("<region> ampeg_sustain=aa lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "invalid float literal"),
-            _ => panic!("Not seen expected error"),
+        let purged = |(region, data): &(&Region, &InstrumentRegion)| !region.pending && !self.group_flags[data.sample_group];
+        if !Iterator::zip(self.regions.iter(), self.instrument.regions.iter()).any(|r| purged(&r)) {
+            return None;
         }
+        let regions = Iterator::zip(self.regions.iter(), self.instrument.regions.iter())
+            .enumerate()
+            .filter(|(_, r)| purged(r))
+            .map(|(i, _)| i)
+            .collect();
+        self.purging = true;
+        Some(PurgeRequest { instrument: self.instrument.clone(), regions })
     }
 
-    #[test]
-    fn parse_out_of_range_ampeg_release() {
-        match parse_sfz_text("<region> ampeg_release=105 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "ampeg_release out of range: 0 <= 105 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
-        }
-        match parse_sfz_text("<region> ampeg_release=-20 lokey=23".to_string()) {
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "ampeg_release out of range: 0 <= -20 <= 100"
-            ),
-            _ => panic!("Not seen expected error"),
+    /// Replaces the samples released by `PurgeRequest::release()` by silent
+    /// placeholders, except for regions triggered again and still playing.
+    /// Returns the replaced instrument, which frees the samples when it is
+    /// dropped, so better on a non realtime thread. `None` if the engine's
+    /// instrument has changed since `purge()`.
+    pub fn complete_purge(&mut self, purged: PurgedInstrument) -> Option<Arc<InstrumentData>> {
+        self.purging = false;
+        if !Arc::ptr_eq(&self.instrument, &purged.purged) {
+            return None;
         }
-        match parse_sfz_text("<region> ampeg_release=aa lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "invalid float literal"),
-            _ => panic!("Not seen expected error"),
+        for i in purged.regions {
+            if !self.regions[i].sample.is_playing() {
+                self.regions[i].purge_sample(self.purged_buffer.clone());
+            }
         }
+        Some(std::mem::replace(&mut self.instrument, purged.instrument))
     }
 
-    #[test]
-    fn parse_sfz_comment_in_line() {
-        let regions = parse_sfz_text("<region> hivel=42 lovel=23 // foo".to_string()).unwrap();
-        assert_eq!(regions.len(), 1);
-        match &regions.get(0) {
-            Some(rd) => {
-                assert_eq!(rd.key_range.hi, Some(Note::HIGHEST_NOTE));
-                assert_eq!(rd.key_range.lo, Some(Note::LOWEST_NOTE));
-                assert_eq!(u8::from(rd.vel_range.hi), 42);
-                assert_eq!(u8::from(rd.vel_range.lo), 23);
+    /// Requests to load the purged samples of the regions triggered since
+    /// they have been purged or since the last request. The regions stay
+    /// silent until the samples are passed to `complete_loading()`, if that
+    /// fails they are requested again when they are triggered again.
+    /// Allocates only if there are samples to load.
+    pub fn reload_request(&mut self) -> Option<ReloadRequest> {
+        if !self.regions.iter().any(|r| r.reload_requested) {
+            return None;
+        }
+        let source = self.instrument.source.clone()?;
+        self.group_flags.iter_mut().for_each(|requested| *requested = false);
+        for (region, data) in Iterator::zip(self.regions.iter_mut(), self.instrument.regions.iter()) {
+            if region.reload_requested {
+                self.group_flags[data.sample_group] = true;
+                region.reload_requested = false;
             }
-            _ => panic!("Expected region, got somthing different."),
         }
+        let regions = Iterator::zip(self.regions.iter(), self.instrument.regions.iter())
+            .enumerate()
+            .filter(|(_, (region, data))| region.purged && self.group_flags[data.sample_group])
+            .map(|(i, _)| i)
+            .collect();
+        Some(ReloadRequest { source, instrument: self.instrument.clone(), regions })
     }
 
-    #[test]
-    fn parse_region_line_span() {
-        let regions =
-            parse_sfz_text("<region> hivel=42 lovel=23 \n hikey=43 lokey=24".to_string()).unwrap();
-        assert_eq!(regions.len(), 1);
-        match &regions.get(0) {
-            Some(rd) => {
-                assert_eq!(rd.key_range.hi, Some(Note::G1));
-                assert_eq!(rd.key_range.lo, Some(Note::C0));
-                assert_eq!(u8::from(rd.vel_range.hi), 42);
-                assert_eq!(u8::from(rd.vel_range.lo), 23);
+    /// The regions whose samples had silence trimmed at load time
+    pub fn trimmed_silence(&self) -> &[TrimmedSilence] {
+        &self.instrument.trimmed_silence
+    }
+
+    /// How long decoding the sample files took
+    pub fn load_stats(&self) -> LoadStats {
+        self.instrument.load_stats
+    }
+
+    /// The opcodes and headers skipped when loading the SFZ file leniently
+    pub fn warnings(&self) -> &[parser::ParserError] {
+        &self.instrument.warnings
+    }
+
+    pub fn region_data(&self) -> impl Iterator<Item = &RegionData> {
+        self.regions.iter().map(|r| &r.params)
+    }
+
+    /// The regions in the order of the SFZ file
+    pub fn regions_info(&self) -> Vec<RegionInfo> {
+        Iterator::zip(self.regions.iter(), self.instrument.regions.iter()).map(|(r, ir)| {
+            let rd = &r.params;
+            RegionInfo {
+                id: r.id,
+                key_range: match (rd.key_range.lo, rd.key_range.hi) {
+                    (Some(lo), Some(hi)) => Some((u8::from(lo), u8::from(hi))),
+                    _ => None,
+                },
+                velocity_range: (u8::from(rd.vel_range.lo), u8::from(rd.vel_range.hi)),
+                sample: rd.sample.clone(),
+                trigger: rd.trigger,
+                group: rd.group,
+                playing: r.sample.is_playing(),
+                sample_samplerate: ir.samplerate,
+                sample_encoding: ir.encoding,
             }
-            _ => panic!("Expected region, got somthing different."),
-        }
+        }).collect()
     }
 
-    #[test]
-    fn parse_region_line_span_with_coment() {
-        let regions = parse_sfz_text(
-            "<region> hivel=42 lovel=23 // foo bar foo\nhikey=43 lokey=24".to_string(),
-        )
-        .unwrap();
-        assert_eq!(regions.len(), 1);
-        match &regions.get(0) {
-            Some(rd) => {
-                assert_eq!(rd.key_range.hi, Some(Note::G1));
-                assert_eq!(rd.key_range.lo, Some(Note::C0));
-                assert_eq!(u8::from(rd.vel_range.hi), 42);
-                assert_eq!(u8::from(rd.vel_range.lo), 23);
+    pub fn region_id(&self, index: usize) -> Option<RegionId> {
+        self.regions.get(index).map(|r| r.id)
+    }
+
+    pub fn region_index(&self, id: RegionId) -> Option<usize> {
+        self.regions.iter().position(|r| r.id == id)
+    }
+
+    /// Shifts all incoming notes by `semitones`
+    pub fn set_transpose(&mut self, semitones: i32) {
+        self.transpose = semitones;
+    }
+
+    /// Transposes incoming notes outside of `range` by octaves into it.
+    /// Notes that don't fit, because the range is narrower than an octave,
+    /// are dropped.
+    pub fn set_note_fold(&mut self, range: Option<(wmidi::Note, wmidi::Note)>) {
+        self.note_fold = range;
+    }
+
+    fn map_note(&self, note: wmidi::Note) -> Option<wmidi::Note> {
+        let mut n = u8::from(note) as i32 + self.instrument.control.note_shift() + self.transpose;
+        if let Some((lo, hi)) = self.note_fold {
+            let (lo, hi) = (u8::from(lo) as i32, u8::from(hi) as i32);
+            while n < lo {
+                n += 12;
+            }
+            while n > hi {
+                n -= 12;
+            }
+            if n < lo {
+                return None;
             }
-            _ => panic!("Expected region, got somthing different."),
         }
+        if n < 0 || n > 127 {
+            return None;
+        }
+        wmidi::Note::try_from(n as u8).ok()
     }
 
-    #[test]
-    fn parse_two_region_line() {
-        let s = "<region> hivel=41 lovel=22 <region> hikey=42 lokey=23";
-
-        let regions = parse_sfz_text(s.to_string()).unwrap();
-        assert_eq!(regions.len(), 2)
+    /// Limits the number of simultaneously playing voices. When exceeded,
+    /// the oldest releasing voice is stopped, or if there is none, the oldest
+    /// voice at all.
+    pub fn set_max_voices(&mut self, max_voices: Option<usize>) {
+        self.max_voices = max_voices;
+        self.steal_voices();
     }
 
-    #[test]
-    fn parse_regions_inheriting_group_data() {
-        let s = "
+    fn steal_voices(&mut self) {
+        let max_voices = match self.max_voices {
+            Some(m) => m,
+            None => return,
+        };
+        let mut voices: usize = self.regions.iter().map(|r| r.sample.voice_count()).sum();
+        while voices > max_voices {
+            let oldest = |regions: &Vec<Region>, releasing| regions.iter()
+                .enumerate()
+                .filter_map(|(i, r)| r.sample.oldest_voice_age(releasing).map(|age| (i, age)))
+                .max_by_key(|(_, age)| *age)
+                .map(|(i, _)| (i, releasing));
+            match oldest(&self.regions, true).or_else(|| oldest(&self.regions, false)) {
+                Some((i, releasing)) => {
+                    self.regions[i].sample.kill_oldest_voice(releasing);
+                    if let Some(observer) = self.observer.as_mut() {
+                        observer.voice_stolen(i);
+                    }
+                    if let Some(diagnostics) = &self.diagnostics {
+                        diagnostics.push(Diagnostic::VoiceStolen { region: i });
+                    }
+                }
+                None => break,
+            }
+            voices -= 1;
+        }
+    }
+
+    pub fn set_quality_scaling(&mut self, quality_scaling: Option<QualityScaling>) {
+        self.quality_scaling = quality_scaling;
+        if quality_scaling.is_none() {
+            self.set_interpolation(sample::Interpolation::Cubic);
+        }
+    }
+
+    fn set_interpolation(&mut self, interpolation: sample::Interpolation) {
+        if interpolation == self.interpolation {
+            return;
+        }
+        self.interpolation = interpolation;
+        for r in &mut self.regions {
+            r.sample.set_interpolation(interpolation);
+        }
+    }
+
+    fn scale_quality(&mut self) {
+        let qs = match self.quality_scaling {
+            Some(qs) => qs,
+            None => return,
+        };
+        let voices: usize = self.regions.iter().map(|r| r.sample.voice_count()).sum();
+        let interpolation = match self.interpolation {
+            sample::Interpolation::Cubic if voices > qs.high_watermark => sample::Interpolation::Linear,
+            sample::Interpolation::Linear if voices <= qs.low_watermark => sample::Interpolation::Cubic,
+            i => i,
+        };
+        self.set_interpolation(interpolation);
+    }
+
+    /// Sets the crossfade time in seconds from a still sounding voice to the
+    /// new one when a note is retriggered, 5 ms by default.
+    pub fn set_retrigger_fade(&mut self, seconds: f64) {
+        for r in &mut self.regions {
+            r.sample.set_retrigger_fade((seconds * r.host_samplerate) as usize);
+        }
+    }
+
+    /// Sets the time in seconds voices are faded in when they start and
+    /// faded out when they are cut off by the end of the sample, a release
+    /// shorter than that or voice stealing. Avoids clicks of samples not
+    /// starting or ending at a zero crossing. Off (0) by default, the
+    /// `click_guard` opcode overrides it for a region.
+    pub fn set_click_guard(&mut self, seconds: f64) {
+        for r in &mut self.regions {
+            r.set_click_guard(seconds);
+        }
+    }
+
+    /// Sets the gain in dB applied to the output, smoothly faded to avoid
+    /// zipper noise. Below -80 dB the output is muted, the maximum is 20 dB.
+    pub fn set_master_gain(&mut self, gain_db: f32) {
+        let gain = match gain_db {
+            g if g < -80.0 => 0.0,
+            g => utils::dB_to_gain(g.min(20.0)),
+        };
+        if self.processing_started {
+            self.master_gain.set_target(gain);
+        } else {
+            self.master_gain.reset(gain);
+        }
+    }
+
+    /// Balance of the output from -100 (left only) to 100 (right only),
+    /// smoothly faded like the master gain
+    pub fn set_master_pan(&mut self, pan: f32) {
+        let pan = pan.max(-100.0).min(100.0) / 100.0;
+        if self.processing_started {
+            self.master_pan.set_target(pan);
+        } else {
+            self.master_pan.reset(pan);
+        }
+    }
+
+    /// Approximates the gains of notes triggered from now on and of the
+    /// volume modulation by a lookup table with `steps_per_db`, `None` to
+    /// compute them exactly
+    pub fn set_gain_table(&mut self, steps_per_db: Option<u32>) {
+        let table = steps_per_db.map(|steps| Arc::new(utils::GainTable::new(-160.0, 24.0, steps.max(1))));
+        for r in &mut self.regions {
+            r.gain_table = table.clone();
+        }
+    }
+
+    /// Detunes notes triggered from now on by `cents` on top of the tuning
+    pub fn set_master_tune(&mut self, cents: f64) {
+        let factor = 2.0f64.powf(cents / 1200.0);
+        for r in &mut self.regions {
+            r.master_tune = factor;
+        }
+    }
+
+    /// Glide time in seconds of notes played legato for all regions, which
+    /// are played monophonic then, `None` for the `portamento_time` of the SFZ
+    /// file
+    pub fn set_portamento(&mut self, seconds: Option<f32>) {
+        for r in &mut self.regions {
+            r.portamento = seconds;
+        }
+    }
+
+    /// Tuning applied to notes triggered from now on
+    pub fn set_tuning(&mut self, tuning: &tuning::Tuning) {
+        let note_frequencies = Arc::new(tuning.frequency_table());
+        for r in &mut self.regions {
+            r.note_frequencies = note_frequencies.clone();
+        }
+    }
+
+    /// Play release triggered regions with the note-off velocity rather than
+    /// the velocity of the note-on
+    pub fn set_note_off_velocity(&mut self, enabled: bool) {
+        for r in &mut self.regions {
+            r.note_off_velocity = enabled;
+        }
+    }
+
+    /// Restarts the random numbers choosing the regions by `lorand` and
+    /// `hirand` at `seed`, so that the same notes trigger the same regions
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_mpe(&mut self, mpe: Option<MpeConfig>) {
+        self.mpe = mpe;
+        self.note_channels = [None; 128];
+        self.channel_bends = [1.0; 16];
+    }
+
+    fn mpe_member_channel(&self, midi_msg: &wmidi::MidiMessage) -> Option<wmidi::Channel> {
+        let mpe = self.mpe.as_ref()?;
+        let channel = message_channel(midi_msg)?;
+        if mpe.is_member_channel(channel) {
+            Some(channel)
+        } else {
+            None
+        }
+    }
+
+    // Expression on an MPE member channel, only passed to the notes of the channel
+    fn mpe_expression(&mut self, channel: wmidi::Channel, midi_msg: &wmidi::MidiMessage) {
+        let notes = self.note_channels.iter()
+            .enumerate()
+            .filter(|(_, ch)| **ch == Some(channel))
+            .filter_map(|(note, _)| wmidi::Note::try_from(note as u8).ok());
+        match midi_msg {
+            wmidi::MidiMessage::PitchBendChange(_ch, bend) => {
+                let semitones = (u16::from(*bend) as f64 - 8192.0) / 8192.0
+                    * self.mpe.map_or(0.0, |mpe| mpe.pitch_bend_range);
+                let ratio = 2.0f64.powf(semitones / 12.0);
+                self.channel_bends[channel.index() as usize] = ratio;
+                for note in notes {
+                    for r in &mut self.regions {
+                        r.note_bend(note, ratio);
+                    }
+                }
+            }
+            msg => {
+                for r in &mut self.regions {
+                    if notes.clone().any(|note| r.sample.has_voice_for(note)) {
+                        r.pass_midi_msg(msg, 0.0);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of voices currently sounding
+    pub fn voice_count(&self) -> usize {
+        self.regions.iter().map(|r| r.sample.voice_count()).sum()
+    }
+
+    /// Number of voices dropped so far because their envelope had faded
+    /// below audibility before their sample ended
+    pub fn reclaimed_voices(&self) -> usize {
+        self.regions.iter().map(|r| r.sample.reclaimed_voices()).sum()
+    }
+
+    /// Time spent in `process()` relative to the duration of the processed
+    /// frames, smoothed over the recent blocks
+    pub fn cpu_load_estimate(&self) -> f32 {
+        self.cpu_load
+    }
+
+    /// Handle to the voice statistics for threads other than the audio thread
+    pub fn telemetry(&self) -> SharedTelemetry {
+        self.telemetry.clone()
+    }
+
+    /// Handle to the mute and solo state of the regions for threads other
+    /// than the audio thread
+    pub fn region_mutes(&self) -> SharedRegionMutes {
+        self.mutes.clone()
+    }
+
+    /// Keeps the region at `index` from playing notes until it is unmuted
+    pub fn set_region_muted(&self, index: usize, muted: bool) {
+        self.mutes.set_muted(index, muted);
+    }
+
+    /// Mutes all regions but the one at `index`, `None` ends the solo
+    pub fn solo_region(&self, index: Option<usize>) {
+        self.mutes.solo(index);
+    }
+
+    pub fn set_activity_log(&mut self, log: Option<SharedActivityLog>) {
+        self.activity_log = log;
+    }
+
+    /// Ring the engine reports voice steals, flushed denormals, regions
+    /// triggered before their sample was loaded and overflows of the event
+    /// queue to while processing
+    pub fn set_diagnostics(&mut self, diagnostics: Option<SharedDiagnostics>) {
+        self.diagnostics = diagnostics;
+    }
+
+    /// Takes the diagnostics reported so far, empty if no ring is set. Meant
+    /// for threads other than the audio thread, which drain the ring of
+    /// `set_diagnostics()` directly.
+    pub fn drain_diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.as_ref().map_or_else(Vec::new, |diagnostics| diagnostics.drain())
+    }
+
+    /// Changes applied to the MIDI messages from now on
+    pub fn set_midi_filter(&mut self, filter: MidiFilter) {
+        self.midi_filter = filter;
+    }
+
+    /// Sets the velocity curve of the MIDI filter, the notes are matched to
+    /// the regions by the velocities it gives
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        if self.midi_filter.velocity_curve != curve {
+            self.midi_filter.velocity_curve = curve;
+        }
+    }
+
+    /// The last keyswitch pressed, or the default one of the SFZ file
+    pub fn keyswitch(&self) -> Option<u8> {
+        self.keyswitch
+    }
+
+    /// The `sw_label` of the regions played by the current keyswitch
+    pub fn keyswitch_label(&self) -> Option<&str> {
+        let keyswitch = self.keyswitch?;
+        self.regions.iter()
+            .filter(|r| r.params.sw_last == Some(keyswitch))
+            .find_map(|r| r.params.sw_label.as_deref())
+    }
+
+    /// The keyswitches with the `sw_label` of their regions, ordered by key
+    pub fn keyswitch_labels(&self) -> Vec<(u8, String)> {
+        let mut labels: Vec<(u8, String)> = self.regions.iter()
+            .filter_map(|r| Some((r.params.sw_last?, r.params.sw_label.clone()?)))
+            .collect();
+        labels.sort_by_key(|(key, _)| *key);
+        labels.dedup_by_key(|(key, _)| *key);
+        labels
+    }
+
+    /// Tells `observer` about the notes, the triggered regions and the stolen
+    /// voices from now on
+    pub fn set_observer(&mut self, observer: Option<Box<dyn EngineObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Records the output of the engine into the WAV file at `path` until
+    /// `set_recorder(None)` is called or the engine is dropped
+    pub fn record_to<P: AsRef<Path>>(&mut self, path: P, format: render::SampleFormat) -> Result<(), sndfile::SndFileError> {
+        self.recorder = Some(render::Recorder::new(path, self.host_samplerate as usize, format)?);
+        Ok(())
+    }
+
+    pub fn set_recorder(&mut self, recorder: Option<render::Recorder>) {
+        self.recorder = recorder;
+    }
+
+    pub fn take_recorder(&mut self) -> Option<render::Recorder> {
+        self.recorder.take()
+    }
+
+    pub fn fadeout(&mut self) {
+        for r in &mut self.regions {
+            r.all_notes_off();
+        }
+        self.held_velocities = [0; 128];
+    }
+
+    /// Fills `out` with the keys held and the velocities they were played
+    /// with, after the velocity curve and before transposing. Does not
+    /// allocate if `out` has a capacity of 128. Use
+    /// `Telemetry::active_notes()` on threads other than the audio thread.
+    pub fn active_notes_into(&self, out: &mut Vec<(wmidi::Note, wmidi::Velocity)>) {
+        out.clear();
+        out.extend(self.held_velocities.iter()
+                   .enumerate()
+                   .filter(|(_, velocity)| **velocity > 0)
+                   .filter_map(|(key, velocity)| Some((wmidi::Note::try_from(key as u8).ok()?, wmidi::Velocity::try_from(*velocity).ok()?))));
+    }
+
+    /// Fades out the output linearly within `seconds` while the voices keep
+    /// playing, to crossfade to another engine rendering into the same
+    /// buffers. Without a crossfade time the notes are released like by
+    /// `fadeout()`.
+    pub fn crossfade_out(&mut self, seconds: f64) {
+        let length = (seconds.max(0.0) * self.host_samplerate) as usize;
+        if length == 0 {
+            self.fadeout();
+            return;
+        }
+        self.crossfade = Some((length, length));
+    }
+
+    pub fn fadeout_finished(&self) -> bool {
+        self.crossfade.map_or(false, |(remaining, _)| remaining == 0)
+            || !self.regions.iter().any(|r| r.sample.is_playing())
+    }
+
+    pub fn dummy(host_samplerate: f64, max_block_length: usize) -> Engine {
+        Engine::from_region_array(Vec::new(), host_samplerate, max_block_length)
+    }
+
+    /// Engine with `layers` regions on every key, each playing a sine wave
+    /// sample of `sample_frames` length at the key's pitch.
+    #[cfg(feature = "bench")]
+    pub fn synthetic(layers: usize, sample_frames: usize, host_samplerate: f64, max_block_length: usize) -> Engine {
+        let mut regions = Vec::new();
+        for _ in 0..layers {
+            for key in 0..128 {
+                let mut rd = RegionData::default();
+                rd.key_range.set_lo(key).unwrap();
+                rd.key_range.set_hi(key).unwrap();
+                rd.set_pitch_keycenter(key as u32).unwrap();
+                let freq = 440.0 * 2f64.powf((key - 69) as f64 / 12.0);
+                let sample = crate::bench::sine_sample_data(sample_frames, host_samplerate, freq);
+                regions.push((rd, sample, host_samplerate));
+            }
+        }
+        Engine::from_region_array(regions, host_samplerate, max_block_length)
+    }
+}
+
+impl engine::EngineTrait for Engine {
+    fn midi_event(&mut self, midi_msg: &wmidi::MidiMessage) {
+        let filtered_msg;
+        let midi_msg = if self.midi_filter.is_transparent() {
+            midi_msg
+        } else {
+            filtered_msg = self.midi_filter.apply(midi_msg.clone());
+            &filtered_msg
+        };
+
+        let mpe_channel = self.mpe_member_channel(midi_msg);
+        if let Some(channel) = mpe_channel {
+            match midi_msg {
+                wmidi::MidiMessage::NoteOn(..) | wmidi::MidiMessage::NoteOff(..) => {}
+                msg => return self.mpe_expression(channel, msg),
+            }
+        }
+
+        match midi_msg {
+            wmidi::MidiMessage::NoteOn(_ch, note, vel) => self.held_velocities[u8::from(*note) as usize] = u8::from(*vel),
+            wmidi::MidiMessage::NoteOff(_ch, note, _vel) => self.held_velocities[u8::from(*note) as usize] = 0,
+            _ => {}
+        }
+
+        let mapped_msg;
+        let midi_msg = match midi_msg {
+            wmidi::MidiMessage::NoteOn(ch, note, vel) => {
+                let mapped = self.map_note(*note);
+                self.held_note_map[u8::from(*note) as usize] = mapped;
+                match mapped {
+                    Some(n) => {
+                        mapped_msg = wmidi::MidiMessage::NoteOn(*ch, n, *vel);
+                        &mapped_msg
+                    }
+                    None => return,
+                }
+            }
+            wmidi::MidiMessage::NoteOff(ch, note, vel) => {
+                match self.held_note_map[u8::from(*note) as usize].take().or_else(|| self.map_note(*note)) {
+                    Some(n) => {
+                        mapped_msg = wmidi::MidiMessage::NoteOff(*ch, n, *vel);
+                        &mapped_msg
+                    }
+                    None => return,
+                }
+            }
+            wmidi::MidiMessage::PolyphonicKeyPressure(ch, note, pressure) => {
+                match self.held_note_map[u8::from(*note) as usize].or_else(|| self.map_note(*note)) {
+                    Some(n) => {
+                        mapped_msg = wmidi::MidiMessage::PolyphonicKeyPressure(*ch, n, *pressure);
+                        &mapped_msg
+                    }
+                    None => return,
+                }
+            }
+            m => m,
+        };
+
+        let mut activated_groups = HashSet::new();
+        let mut note_played = false;
+        let random_value = self.rng.gen();
+        let logging = self.activity_log.is_some();
+        let mut triggered = RegionList::default();
+        let mut choked = RegionList::default();
+        match (midi_msg, self.observer.as_mut()) {
+            (wmidi::MidiMessage::NoteOn(_ch, note, vel), Some(observer)) => observer.note_on(*note, *vel),
+            (wmidi::MidiMessage::NoteOff(_ch, note, vel), Some(observer)) => observer.note_off(*note, *vel),
+            _ => {}
+        }
+        if let wmidi::MidiMessage::NoteOn(_ch, note, _vel) = midi_msg {
+            if self.keyswitches[u8::from(*note) as usize] {
+                self.keyswitch = Some(u8::from(*note));
+            }
+        }
+        let keyswitch = self.keyswitch;
+        // the notes of the attack regions of a released key died away, its
+        // release triggered regions only play if they have rt_dead set
+        let dead_note = match midi_msg {
+            wmidi::MidiMessage::NoteOff(_ch, note, _vel) => {
+                let mut attack_regions = self.regions.iter()
+                    .filter(|r| !r.params.trigger.is_release() && r.params.key_range.covering(*note))
+                    .peekable();
+                attack_regions.peek().is_some() && !attack_regions.any(|r| r.sample.has_voice_for(*note))
+            }
+            _ => false,
+        };
+        let previous_velocity_msg = match (midi_msg, self.previous_velocity) {
+            (wmidi::MidiMessage::NoteOn(ch, note, vel), Some(previous)) if u8::from(*vel) > 0 =>
+                Some(wmidi::MidiMessage::NoteOn(*ch, *note, previous)),
+            _ => None,
+        };
+        for (i, r) in self.regions.iter_mut().enumerate() {
+            let switched_off = r.params.sw_last.map_or(false, |key| Some(key) != keyswitch)
+                || !r.params.covers_tempo(self.tempo);
+            if (switched_off || self.mutes.is_silenced(i)) && matches!(midi_msg, wmidi::MidiMessage::NoteOn(..)) {
+                continue;
+            }
+            let region_msg = match &previous_velocity_msg {
+                Some(msg) if r.params.sw_vel_previous => msg,
+                _ => midi_msg,
+            };
+            if let wmidi::MidiMessage::NoteOff(_ch, note, _vel) = region_msg {
+                if dead_note && r.params.trigger.is_release() && !r.params.rt_dead {
+                    r.held_keys[u8::from(*note) as usize] = None;
+                }
+            }
+            if matches!(region_msg, wmidi::MidiMessage::NoteOn(..) | wmidi::MidiMessage::NoteOff(..) | wmidi::MidiMessage::ControlChange(..)) {
+                r.randomize(&mut self.rng);
+            }
+            if r.pass_midi_msg(region_msg, random_value) {
+                note_played = true;
+                if logging {
+                    triggered.push(i);
+                }
+                if let Some(observer) = self.observer.as_mut() {
+                    observer.region_triggered(i);
+                }
+                let group = r.group();
+                if group > 0 {
+                    activated_groups.insert(group);
+                }
+            }
+        }
+        if let wmidi::MidiMessage::NoteOn(_ch, _note, vel) = midi_msg {
+            if u8::from(*vel) > 0 {
+                self.previous_velocity = Some(*vel);
+            }
+        }
+        for group in activated_groups {
+            for (i, r) in self.regions.iter_mut().enumerate() {
+                if r.group_activated(group) && logging {
+                    choked.push(i);
+                }
+            }
+        }
+        if let wmidi::MidiMessage::NoteOn(_ch, note, _vel) = midi_msg {
+            if note_played {
+                for (i, r) in self.regions.iter_mut().enumerate() {
+                    if r.key_played(*note) && logging {
+                        choked.push(i);
+                    }
+                }
+            }
+        }
+
+        if let Some(channel) = mpe_channel {
+            match midi_msg {
+                wmidi::MidiMessage::NoteOn(_ch, note, _vel) => {
+                    self.note_channels[u8::from(*note) as usize] = Some(channel);
+                    let bend = self.channel_bends[channel.index() as usize];
+                    for r in &mut self.regions {
+                        r.note_bend(*note, bend);
+                    }
+                }
+                wmidi::MidiMessage::NoteOff(_ch, note, _vel) => {
+                    self.note_channels[u8::from(*note) as usize] = None;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(log) = &self.activity_log {
+            if let Ok(mut log) = log.try_lock() {
+                log.push(ActivityEntry {
+                    message: engine::owned_message(midi_msg),
+                    triggered,
+                    choked,
+                });
+            }
+        }
+
+        self.steal_voices();
+    }
+
+    fn midi_event_at(&mut self, frame_offset: usize, midi_msg: &wmidi::MidiMessage) {
+        if let Some(msg) = engine::owned_message(midi_msg) {
+            if self.event_queue.len() == self.event_queue.capacity() {
+                if let Some(diagnostics) = &self.diagnostics {
+                    diagnostics.push(Diagnostic::EventQueueOverflow);
+                }
+                return;
+            }
+            let position = self.event_queue.iter().position(|(frame, _)| *frame > frame_offset).unwrap_or(self.event_queue.len());
+            self.event_queue.insert(position, (frame_offset, msg));
+        }
+    }
+
+    /// Events queued by `midi_event_at()` are dispatched at their frame
+    /// offsets, those beyond the block remain queued for the next call.
+    fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+        if out_left.len() * out_right.len() == 0 {
+            return;
+        }
+        let stopwatch = profiling::Stopwatch::start();
+        let start = Instant::now();
+        self.scale_quality();
+        self.processing_started = true;
+
+        let nframes = out_left.len();
+        let mut offset = 0;
+        while let Some(frame) = self.event_queue.front().map(|(frame, _)| *frame) {
+            if frame >= nframes {
+                break;
+            }
+            if frame > offset {
+                self.render(&mut out_left[offset..frame], &mut out_right[offset..frame]);
+                offset = frame;
+            }
+            if let Some((_, msg)) = self.event_queue.pop_front() {
+                engine::EngineTrait::midi_event(self, &msg);
+            }
+        }
+        if offset < nframes {
+            self.render(&mut out_left[offset..], &mut out_right[offset..]);
+        }
+        for (frame, _) in self.event_queue.iter_mut() {
+            *frame -= nframes;
+        }
+        self.report_diagnostics();
+
+        let block_duration = out_left.len() as f64 / self.host_samplerate;
+        let load = (start.elapsed().as_secs_f64() / block_duration) as f32;
+        self.cpu_load += CPU_LOAD_SMOOTHING * (load - self.cpu_load);
+        self.telemetry.publish(self.voice_count(),
+                               self.reclaimed_voices(),
+                               self.cpu_load,
+                               self.regions.iter().map(|r| r.sample.is_playing()),
+                               &self.held_velocities);
+        stopwatch.stop(profiling::Subsystem::Process);
+    }
+
+    /// Notes are played by the regions whose `lobpm` and `hibpm` cover the
+    /// tempo, 120 bpm until the host tells one
+    fn set_tempo(&mut self, bpm: f64) {
+        self.tempo = bpm;
+    }
+}
+
+impl Engine {
+    fn report_diagnostics(&mut self) {
+        let diagnostics = match &self.diagnostics {
+            Some(diagnostics) => diagnostics,
+            None => return,
+        };
+        for (i, region) in self.regions.iter_mut().enumerate() {
+            if std::mem::take(&mut region.denormals_flushed) {
+                diagnostics.push(Diagnostic::DenormalsFlushed { region: i });
+            }
+            if std::mem::take(&mut region.missed_trigger) {
+                diagnostics.push(Diagnostic::SampleNotLoaded { region: i });
+            }
+            if region.sample.take_inactive_envelope() {
+                diagnostics.push(Diagnostic::InactiveEnvelope { region: i });
+            }
+        }
+    }
+
+    // renders the regions into the mix buffers and adds the mix to the output
+    fn render(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+        let block_length = self.mix_left.len();
+        for (out_left, out_right) in Iterator::zip(out_left.chunks_mut(block_length), out_right.chunks_mut(block_length)) {
+            let nframes = out_left.len();
+            let mix_left = &mut self.mix_left[..nframes];
+            let mix_right = &mut self.mix_right[..nframes];
+            mix_left.iter_mut().chain(mix_right.iter_mut()).for_each(|v| *v = 0.0);
+            match &mut self.effects {
+                Some(effects) => effects.process(&mut self.regions, mix_left, mix_right),
+                None => for r in &mut self.regions {
+                    r.process(mix_left, mix_right);
+                }
+            }
+            self.master_gain.apply_gain(mix_left, mix_right);
+            if !(self.master_pan.is_settled() && self.master_pan.target() == 0.0) {
+                for (l, r) in Iterator::zip(mix_left.iter_mut(), mix_right.iter_mut()) {
+                    let pan = self.master_pan.next();
+                    *l *= (1.0 - pan).min(1.0);
+                    *r *= (1.0 + pan).min(1.0);
+                }
+            }
+            if let Some((remaining, length)) = &mut self.crossfade {
+                for (l, r) in Iterator::zip(mix_left.iter_mut(), mix_right.iter_mut()) {
+                    let gain = *remaining as f32 / *length as f32;
+                    *l *= gain;
+                    *r *= gain;
+                    *remaining = remaining.saturating_sub(1);
+                }
+            }
+            if let Some(recorder) = &self.recorder {
+                recorder.record(mix_left, mix_right);
+            }
+            for (out, mix) in out_left.iter_mut().zip(mix_left.iter()).chain(out_right.iter_mut().zip(mix_right.iter())) {
+                *out += mix;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::parser::parse_sfz_text;
+    use super::super::activity_log::ActivityLog;
+    use super::super::diagnostics::Diagnostics;
+    use super::super::observer;
+    use super::*;
+    use crate::engine::EngineTrait;
+
+    use crate::sndfile;
+    use crate::sndfile::SndFileIO;
+
+    use crate::sample::tests as sampletests;
+    use crate::sample::tests::f32_eq;
+    use crate::sample::tests::stereo;
+
+    use wmidi::*;
+
+    #[test]
+    fn region_data_default() {
+        let rd: RegionData = Default::default();
+
+        assert_eq!(rd.key_range.hi, Some(Note::HIGHEST_NOTE));
+        assert_eq!(rd.key_range.lo, Some(Note::LOWEST_NOTE));
+        assert_eq!(rd.vel_range.hi, Velocity::MAX);
+        assert_eq!(rd.vel_range.lo, Velocity::MIN);
+
+        assert_eq!(rd.amp_veltrack, 1.0);
+
+        let env = envelopes::ADSREnvelope::new(&rd.ampeg, 1.0);
+        let mut sustain_env = [0.0; 4];
+        env.process(&mut envelopes::State::attack(), &mut sustain_env);
+        assert_eq!(sustain_env, [1.0; 4]);
+
+        assert_eq!(rd.tune, 0.0)
+    }
+
+    #[test]
+    fn parse_empty_text() {
+        match parse_sfz_text("".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "General parser error: Expecting <> tag in sfz file"
+            ),
+            _ => panic!("Expected error message"),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_hikey_lokey_region_line() {
+        let regions = parse_sfz_text("<region> hikey=42 lokey=23".to_string()).unwrap();
+        assert_eq!(regions.len(), 1);
+        match &regions.get(0) {
+            Some(rd) => {
+                assert_eq!(rd.key_range.hi, Some(Note::FSharp1));
+                assert_eq!(rd.key_range.lo, Some(Note::BMinus1));
+                assert_eq!(rd.vel_range.hi, Velocity::MAX);
+                assert_eq!(rd.vel_range.lo, Velocity::MIN);
+            }
+            _ => panic!("Expected region, got somthing different."),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_key_region_line() {
+        let regions = parse_sfz_text("<region> key=42".to_string()).unwrap();
+        assert_eq!(regions.len(), 1);
+        match &regions.get(0) {
+            Some(rd) => {
+                assert_eq!(rd.key_range.hi, Some(Note::FSharp1));
+                assert_eq!(rd.key_range.lo, Some(Note::FSharp1));
+            }
+            _ => panic!("Expected region, got somthing different."),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_hikey_lokey_notefmt_region_line() {
+        let regions =
+            parse_sfz_text("<region> hikey=c#3 lokey=ab2 <region> hikey=c3 lokey=a2".to_string())
+                .unwrap();
+        assert_eq!(regions.len(), 2);
+        match &regions.get(0) {
+            Some(rd) => {
+                assert_eq!(rd.key_range.hi, Some(Note::Db2));
+                assert_eq!(rd.key_range.lo, Some(Note::GSharp1));
+                assert_eq!(rd.vel_range.hi, Velocity::MAX);
+                assert_eq!(rd.vel_range.lo, Velocity::MIN);
+            }
+            _ => panic!("Expected region, got somthing different."),
+        }
+        match &regions.get(1) {
+            Some(rd) => {
+                assert_eq!(rd.key_range.hi, Some(Note::C2));
+                assert_eq!(rd.key_range.lo, Some(Note::A1));
+                assert_eq!(rd.vel_range.hi, Velocity::MAX);
+                assert_eq!(rd.vel_range.lo, Velocity::MIN);
+            }
+            _ => panic!("Expected region, got somthing different."),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_hikey_lokey_group_line() {
+        let regions = parse_sfz_text("<group> hivel=42 lovel=23".to_string()).unwrap();
+        assert_eq!(regions.len(), 0);
+    }
+
+    #[test]
+    fn parse_sfz_invalid_header_line() {
+        match parse_sfz_text("<foo> hikey=42 lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 1, <foo>: Unknown key: foo"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_invalid_opcode_line() {
+        match parse_sfz_text("<region> foo=42 lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, foo: Unknown key: foo"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_error_location() {
+        use std::error::Error;
+        match parse_sfz_text("<group> lovel=10\n<region>\n  lokey=60 foo=1".to_string()) {
+            Err(e) => {
+                assert_eq!(e.position(), Some((3, 12)));
+                assert_eq!(e.opcode(), Some("foo"));
+                assert_eq!(format!("{}", e.source().unwrap()), "Unknown key: foo");
+            }
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_lenient() {
+        let text = "<unknown> foo=bar <region> lokey=23 foo=42 hikey=30 amp_veltrack=105 <region> hikey=aa lokey=40";
+        let (regions, warnings) = super::super::parser::parse_sfz_text_lenient(text.to_string()).unwrap();
+
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].key_range.lo.map(u8::from), Some(23));
+        assert_eq!(regions[0].key_range.hi.map(u8::from), Some(30));
+        assert_eq!(regions[1].key_range.lo.map(u8::from), Some(40));
+
+        let warnings: Vec<String> = warnings.iter().map(|w| format!("{}", w)).collect();
+        assert_eq!(warnings, vec![
+            "line 1, column 1, <unknown>: Unknown key: unknown",
+            "line 1, column 37, foo: Unknown key: foo",
+            "line 1, column 53, amp_veltrack: amp_veltrack out of range: -100 <= 105 <= 100",
+            "line 1, column 79, hikey: Invalid key: aa",
+        ]);
+
+        assert!(parse_sfz_text(text.to_string()).is_err());
+        assert!(super::super::parser::parse_sfz_text_lenient("<region lokey=23".to_string()).is_err());
+    }
+
+    #[test]
+    fn parse_sfz_malformed_values() {
+        for text in &["<region> key=G#9", "<region> key=Cb-1", "<region> key=C-1", "<region> lokey=C+",
+                      "<region> hikey=Cé", "<region> key=C#44", "<region> lokey=300", "<region> hivel=256",
+                      "<region> lovel=-3", "<region> on_hicc64=384", "<region> hikey=-300"] {
+            assert!(super::super::parser::parse_text(text, true).is_err(), "{}", text);
+        }
+        let (regions, _, _) = super::super::parser::parse_text("<region> lokey=Cb0 hikey=G9", true).unwrap();
+        assert_eq!(regions[0].key_range.lo.map(u8::from), Some(11));
+        assert_eq!(regions[0].key_range.hi.map(u8::from), Some(127));
+    }
+
+    #[test]
+    fn parse_sfz_invalid_non_int_value_line() {
+        match parse_sfz_text("<region> hikey=aa lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, hikey: Invalid key: aa"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    /* FIXME: How to test this?
+    #[test]
+    fn parse_ampeg() {
+        let regions = parse_sfz_text("<region> ampeg_attack=23 ampeg_hold=42 ampeg_decay=47 ampeg_sustain=11 ampeg_release=0.2342".to_string()).unwrap();
+        match regions.get(0) {
+            Some(rd) => {
+                assert_eq!(rd.ampeg.attack, 23.0);
+                assert_eq!(rd.ampeg.hold, 42.0);
+                assert_eq!(rd.ampeg.decay, 47.0);
+                assert_eq!(rd.ampeg.sustain, 0.11);
+                assert_eq!(rd.ampeg.release, 0.2342);
+            }
+            None => panic!("expeted region with ampeg")
+        }
+    }
+     */
+
+    #[test]
+    fn parse_out_of_range_ampeg_start() {
+        match parse_sfz_text("<region> ampeg_start=105".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_start: ampeg_start out of range: 0 <= 105 <= 100"),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_delay_oncc7=-120".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_delay_oncc7: ampeg_delay_oncc out of range: -100 <= -120 <= 100"),
+            _ => panic!("Not seen expected error"),
+        }
+        assert!(parse_sfz_text("<region> ampeg_delay=1.5 ampeg_startcc1=20".to_string()).is_ok());
+    }
+
+    #[test]
+    fn parse_out_of_range_amp_veltrack() {
+        match parse_sfz_text("<region> amp_veltrack=105 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, amp_veltrack: amp_veltrack out of range: -100 <= 105 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> amp_veltrack=-105 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, amp_veltrack: amp_veltrack out of range: -100 <= -105 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_offset_random_limit() {
+        let regions = parse_sfz_text("<region> offset_random=4294967295".to_string()).unwrap();
+        assert!(parse_sfz_text("<region> offset_random=4294967296".to_string()).is_err());
+        assert!(parse_sfz_text("<region> offset_random=-1".to_string()).is_err());
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(vec![1.0; 4]), 1.0)], 1.0, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        assert_eq!(engine.voice_count(), 1);
+    }
+
+    #[test]
+    fn parse_out_of_range_ampeg_attack() {
+        match parse_sfz_text("<region> ampeg_attack=105 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, ampeg_attack: ampeg_attack out of range: 0 <= 105 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_attack=-20 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, ampeg_attack: ampeg_attack out of range: 0 <= -20 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_attack=aa lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_attack: invalid float literal"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_out_of_range_ampeg_hold() {
+        match parse_sfz_text("<region> ampeg_hold=105 lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_hold: ampeg_hold out of range: 0 <= 105 <= 100"),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_hold=-20 lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_hold: ampeg_hold out of range: 0 <= -20 <= 100"),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_hold=aa lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_hold: invalid float literal"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_out_of_range_ampeg_decay() {
+        match parse_sfz_text("<region> ampeg_decay=105 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, ampeg_decay: ampeg_decay out of range: 0 <= 105 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_decay=-20 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, ampeg_decay: ampeg_decay out of range: 0 <= -20 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_decay=aa lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_decay: invalid float literal"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_out_of_range_ampeg_sustain() {
+        match parse_sfz_text("<region> ampeg_sustain=105 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, ampeg_sustain: ampeg_sustain out of range: 0 <= 105 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_sustain=-20 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, ampeg_sustain: ampeg_sustain out of range: 0 <= -20 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_sustain=aa lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_sustain: invalid float literal"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_out_of_range_ampeg_release() {
+        match parse_sfz_text("<region> ampeg_release=105 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, ampeg_release: ampeg_release out of range: 0 <= 105 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_release=-20 lokey=23".to_string()) {
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "line 1, column 10, ampeg_release: ampeg_release out of range: 0 <= -20 <= 100"
+            ),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> ampeg_release=aa lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, ampeg_release: invalid float literal"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_comment_in_line() {
+        let regions = parse_sfz_text("<region> hivel=42 lovel=23 // foo".to_string()).unwrap();
+        assert_eq!(regions.len(), 1);
+        match &regions.get(0) {
+            Some(rd) => {
+                assert_eq!(rd.key_range.hi, Some(Note::HIGHEST_NOTE));
+                assert_eq!(rd.key_range.lo, Some(Note::LOWEST_NOTE));
+                assert_eq!(u8::from(rd.vel_range.hi), 42);
+                assert_eq!(u8::from(rd.vel_range.lo), 23);
+            }
+            _ => panic!("Expected region, got somthing different."),
+        }
+    }
+
+    #[test]
+    fn parse_region_line_span() {
+        let regions =
+            parse_sfz_text("<region> hivel=42 lovel=23 \n hikey=43 lokey=24".to_string()).unwrap();
+        assert_eq!(regions.len(), 1);
+        match &regions.get(0) {
+            Some(rd) => {
+                assert_eq!(rd.key_range.hi, Some(Note::G1));
+                assert_eq!(rd.key_range.lo, Some(Note::C0));
+                assert_eq!(u8::from(rd.vel_range.hi), 42);
+                assert_eq!(u8::from(rd.vel_range.lo), 23);
+            }
+            _ => panic!("Expected region, got somthing different."),
+        }
+    }
+
+    #[test]
+    fn parse_region_line_span_with_coment() {
+        let regions = parse_sfz_text(
+            "<region> hivel=42 lovel=23 // foo bar foo\nhikey=43 lokey=24".to_string(),
+        )
+        .unwrap();
+        assert_eq!(regions.len(), 1);
+        match &regions.get(0) {
+            Some(rd) => {
+                assert_eq!(rd.key_range.hi, Some(Note::G1));
+                assert_eq!(rd.key_range.lo, Some(Note::C0));
+                assert_eq!(u8::from(rd.vel_range.hi), 42);
+                assert_eq!(u8::from(rd.vel_range.lo), 23);
+            }
+            _ => panic!("Expected region, got somthing different."),
+        }
+    }
+
+    #[test]
+    fn parse_two_region_line() {
+        let s = "<region> hivel=41 lovel=22 <region> hikey=42 lokey=23";
+
+        let regions = parse_sfz_text(s.to_string()).unwrap();
+        assert_eq!(regions.len(), 2)
+    }
+
+    #[test]
+    fn parse_regions_inheriting_group_data() {
+        let s = "
 <group> hivel=42
 <region> lovel=23
 <region> lovel=21
@@ -1195,7 +4677,7 @@ mod tests {
                 assert_eq!(rd.group, 0);
                 assert_eq!(rd.off_by, 0);
                 assert!(rd.on_ccs.is_empty(), (0, 0));
-                assert_eq!(rd.random_range.hi, 0.0);
+                assert_eq!(rd.random_range.hi, 1.0);
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, 0.0);
             }
@@ -1218,7 +4700,7 @@ mod tests {
                 assert_eq!(rd.group, 0);
                 assert_eq!(rd.off_by, 0);
                 assert!(rd.on_ccs.is_empty(), (0, 0));
-                assert_eq!(rd.random_range.hi, 0.0);
+                assert_eq!(rd.random_range.hi, 1.0);
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, 0.0);
             }
@@ -1241,7 +4723,7 @@ mod tests {
                 assert_eq!(rd.group, 0);
                 assert_eq!(rd.off_by, 0);
                 assert!(rd.on_ccs.is_empty(), (0, 0));
-                assert_eq!(rd.random_range.hi, 0.0);
+                assert_eq!(rd.random_range.hi, 1.0);
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, 0.0);
             }
@@ -1264,7 +4746,7 @@ mod tests {
                 assert_eq!(rd.group, 0);
                 assert_eq!(rd.off_by, 0);
                 assert!(rd.on_ccs.is_empty(), (0, 0));
-                assert_eq!(rd.random_range.hi, 0.0);
+                assert_eq!(rd.random_range.hi, 1.0);
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, 0.0);
             }
@@ -1287,7 +4769,7 @@ mod tests {
                 assert_eq!(rd.group, 0);
                 assert_eq!(rd.off_by, 0);
                 assert!(rd.on_ccs.is_empty(), (0, 0));
-                assert_eq!(rd.random_range.hi, 0.0);
+                assert_eq!(rd.random_range.hi, 1.0);
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, -4.0);
             }
@@ -1310,7 +4792,7 @@ mod tests {
                 assert_eq!(rd.group, 0);
                 assert_eq!(rd.off_by, 0);
                 assert!(rd.on_ccs.is_empty(), (0, 0));
-                assert_eq!(rd.random_range.hi, 0.0);
+                assert_eq!(rd.random_range.hi, 1.0);
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, -4.0);
             }
@@ -1333,7 +4815,7 @@ mod tests {
                 assert_eq!(rd.group, 0);
                 assert_eq!(rd.off_by, 0);
                 assert!(rd.on_ccs.is_empty(), (0, 0));
-                assert_eq!(rd.random_range.hi, 0.0);
+                assert_eq!(rd.random_range.hi, 1.0);
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, -37.0);
             }
@@ -1356,7 +4838,7 @@ mod tests {
                 assert_eq!(rd.group, 0);
                 assert_eq!(rd.off_by, 0);
                 assert!(rd.on_ccs.is_empty(), (0, 0));
-                assert_eq!(rd.random_range.hi, 0.0);
+                assert_eq!(rd.random_range.hi, 1.0);
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, -37.0);
             }
@@ -1441,120 +4923,1090 @@ mod tests {
                 assert_eq!(rd.random_range.lo, 0.0);
                 assert_eq!(rd.volume, -19.0);
             }
-            _ => panic!("Expected region, got somthing different."),
+            _ => panic!("Expected region, got somthing different."),
+        }
+        match &regions.get(11) {
+            Some(rd) => {
+                assert_eq!(rd.amp_veltrack, 1.0);
+                // FIXME: how to test this? assert_eq!(rd.ampeg.release, 0.0);
+                assert_eq!(rd.pitch_keycenter, Note::C3);
+                assert_eq!(rd.tune, 0.0);
+                assert_eq!(rd.key_range.hi, None);
+                assert_eq!(rd.key_range.lo, None);
+                assert_eq!(rd.vel_range.hi, Velocity::MAX);
+                assert_eq!(rd.vel_range.lo, Velocity::MIN);
+                assert_eq!(rd.sample, "48khz24bit\\pedalU2.wav");
+                assert_eq!(rd.trigger, Trigger::Attack);
+                assert_eq!(rd.rt_decay, 0.0);
+                assert_eq!(rd.pitch_keytrack, 1.0);
+                assert_eq!(rd.group, 2);
+                assert_eq!(rd.off_by, 0);
+                assert!(rd.on_ccs.get(&64).unwrap().covering(ControlValue::try_from(0).unwrap()));
+                assert_eq!(rd.random_range.hi, 1.0);
+                assert_eq!(rd.random_range.lo, 0.5);
+                assert_eq!(rd.volume, -19.0);
+            }
+            _ => panic!("Expected region, got somthing different.")
+        }
+    }
+
+    #[test]
+    fn simple_region_process() {
+        let sample = vec![1.0, 0.5,
+                          0.5, 1.0,
+                          1.0, 0.5];
+
+        let mut region = Region::new(RegionData::default(), stereo(sample), 1.0, 1.0, 8);
+
+        region.note_on(Note::C3, Velocity::MAX);
+
+        let mut out_left: [f32; 2] = [0.0, 0.0];
+        let mut out_right: [f32; 2] = [0.0, 0.0];
+
+        region.process(&mut out_left, &mut out_right);
+        assert!(f32_eq(out_left[0], 1.0));
+        assert!(f32_eq(out_left[1], 0.5));
+
+        assert!(f32_eq(out_right[0], 0.5));
+        assert!(f32_eq(out_right[1], 1.0));
+
+        assert!(sample::tests::is_playing_note(
+            &region.sample,
+            Note::C3
+        ));
+
+        let mut out_left: [f32; 2] = [-0.5, -0.2];
+        let mut out_right: [f32; 2] = [-0.2, -0.5];
+
+        region.process(&mut out_left, &mut out_right);
+        assert!(f32_eq(out_left[0], 0.5));
+        assert!(f32_eq(out_left[1], -0.2));
+
+        assert!(f32_eq(out_right[0], 0.3));
+        assert!(f32_eq(out_right[1], -0.5));
+
+        assert!(!sample::tests::is_playing_note(
+            &region.sample,
+            Note::C3
+        ));
+    }
+
+    #[test]
+    fn region_volume_process() {
+        let sample = vec![1.0, 1.0];
+
+        let mut region_data = RegionData::default();
+        region_data.set_volume(-20.0).unwrap();
+
+        let mut region = Region::new(region_data, stereo(sample), 1.0, 1.0, 8);
+
+        region.note_on(Note::C3, Velocity::MAX);
+
+        let mut out_left: [f32; 2] = [0.0, 0.0];
+        let mut out_right: [f32; 2] = [0.0, 0.0];
+
+        region.process(&mut out_left, &mut out_right);
+
+        assert_eq!(out_left[0], 0.1);
+        assert_eq!(out_right[0], 0.1);
+    }
+
+    #[test]
+    fn region_amp_envelope_process() {
+        let mut sample = vec![];
+        sample.resize(32, 1.0);
+        let regions = parse_sfz_text(
+            "<region> ampeg_attack=2 ampeg_hold=3 ampeg_decay=4 ampeg_sustain=60 ampeg_release=5"
+                .to_string(),
+        )
+        .unwrap();
+
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(sample), 1.0, 1.0, 16);
+        region.note_on(Note::C3, Velocity::MAX);
+
+        let mut out_left: [f32; 12] = [0.0; 12];
+        let mut out_right: [f32; 12] = [0.0; 12];
+
+        region.process(&mut out_left, &mut out_right);
+
+        let out: Vec<f32> = out_left
+            .iter()
+            .map(|v| (v * 100.0).round() / 100.0)
+            .collect();
+        assert_eq!(
+            out.as_slice(),
+            [0.0, 0.5, 1.0, 1.0, 1.0, 0.65, 0.61, 0.6, 0.6, 0.6, 0.6, 0.6]
+        );
+    }
+
+    #[test]
+    fn region_amp_envelope_velocity_modulation() {
+        let regions = parse_sfz_text(
+            "<region> ampeg_vel2attack=4 ampeg_sustain=50 ampeg_vel2sustain=50 amp_veltrack=0"
+                .to_string(),
+        )
+        .unwrap();
+
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(vec![1.0; 96]), 1.0, 1.0, 8);
+        region.note_on(Note::C3, Velocity::MAX);
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.0, 0.25, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0]);
+
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(vec![1.0; 96]), 1.0, 1.0, 8);
+        region.note_on(Note::C3, Velocity::MIN);
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.5; 8]);
+    }
+
+    fn cc_msg(num: u8, val: u8) -> MidiMessage<'static> {
+        MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(num).unwrap(), ControlValue::try_from(val).unwrap())
+    }
+
+    #[test]
+    fn region_pitch_cc() {
+        let regions = parse_sfz_text("<region> tune_cc1=1200 amp_veltrack=0".to_string()).unwrap();
+        let ramp: Vec<f32> = (0..96).map(|v| v as f32).collect();
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(ramp.iter().flat_map(|v| vec![*v, *v]).collect()), 1.0, 1.0, 4);
+        region.pass_midi_msg(&cc_msg(1, 127), 0.0);
+        region.note_on(Note::C3, Velocity::MAX);
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.0, 2.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn region_cc_modulation_interpolated() {
+        let regions = parse_sfz_text("<region> amplitude_oncc1=100 amp_veltrack=0".to_string()).unwrap();
+        let mut region = Region::new(regions[0].clone(), stereo(vec![1.0; 1024]), 1.0, 1.0, 64);
+        let cc = |val: u8| MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(1).unwrap(), ControlValue::try_from(val).unwrap());
+        let value = |region: &Region| region.source_value(modulation::Source::Controller(1));
+
+        region.pass_midi_msg(&cc(127), 0.0);
+        assert_eq!(value(&region), 1.0);
+        region.pass_midi_msg(&cc(0), 0.0);
+        region.note_on(Note::C3, Velocity::MAX);
+        pull_samples(&mut region, 64);
+
+        region.pass_midi_msg(&cc(64), 0.0);
+        assert_eq!(value(&region), 0.0);
+        let (out_left, _) = pull_samples(&mut region, 32);
+        assert!((value(&region) - 32.0 / 127.0).abs() < 1e-6);
+        assert!(out_left.windows(2).all(|w| w[1] >= w[0]));
+        assert!(out_left[31] > out_left[0]);
+        pull_samples(&mut region, 32);
+        assert_eq!(value(&region), 64.0 / 127.0);
+
+        // the ramp ends at most a block after the control change
+        pull_samples(&mut region, 960);
+        region.note_on(Note::C3, Velocity::MAX);
+        pull_samples(&mut region, 16);
+        region.pass_midi_msg(&cc(127), 0.0);
+        pull_samples(&mut region, 63);
+        assert!(value(&region) < 1.0);
+        pull_samples(&mut region, 1);
+        assert_eq!(value(&region), 1.0);
+    }
+
+    #[test]
+    fn region_aftertouch_modulation() {
+        let regions = parse_sfz_text("<region> tune_chanaft=1200 pan_polyaft=-100 amp_veltrack=0".to_string()).unwrap();
+        let ramp: Vec<f32> = (0..96).map(|v| v as f32).collect();
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(ramp.iter().flat_map(|v| vec![*v, *v]).collect()), 1.0, 1.0, 4);
+        region.note_on(Note::C3, Velocity::MAX);
+        region.pass_midi_msg(&MidiMessage::ChannelPressure(Channel::Ch1, U7::try_from(127).unwrap()), 0.0);
+        region.pass_midi_msg(&MidiMessage::PolyphonicKeyPressure(Channel::Ch1, Note::C3, U7::try_from(127).unwrap()), 0.0);
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!((out_left, out_right), ([0.0, 2.0, 4.0, 6.0], [0.0; 4]));
+
+        region.pass_midi_msg(&MidiMessage::PolyphonicKeyPressure(Channel::Ch1, Note::D3, U7::try_from(127).unwrap()), 0.0);
+        region.pass_midi_msg(&MidiMessage::PolyphonicKeyPressure(Channel::Ch1, Note::C3, U7::try_from(0).unwrap()), 0.0);
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_right, out_left);
+    }
+
+    #[test]
+    fn parse_aftertouch_opcodes() {
+        assert!(parse_sfz_text("<region> cutoff_chanaft=1200 amplitude_polyaft=50".to_string()).is_ok());
+        match parse_sfz_text("<region> foo_chanaft=1".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, foo_chanaft: Unknown key: foo_chanaft"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn mpe_pitch_bend_per_channel() {
+        let regions = parse_sfz_text("<region> amp_veltrack=0".to_string()).unwrap();
+        let ramp: Vec<f32> = (0..96).map(|v| v as f32).collect();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(ramp.iter().flat_map(|v| vec![*v, *v]).collect()), 1.0)], 1.0, 4);
+        engine.set_mpe(Some(MpeConfig::default()));
+
+        let octave_up = U14::try_from(8192 + 2048).unwrap();
+        engine.midi_event(&MidiMessage::PitchBendChange(Channel::Ch2, octave_up));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch2, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.0, 2.0, 4.0, 6.0]);
+
+        engine.midi_event(&MidiMessage::PitchBendChange(Channel::Ch3, U14::MIN));
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [8.0, 10.0, 12.0, 14.0]);
+
+        engine.midi_event(&MidiMessage::PitchBendChange(Channel::Ch2, U14::try_from(8192).unwrap()));
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [16.0, 17.0, 18.0, 19.0]);
+    }
+
+    #[test]
+    fn engine_tuning() {
+        let regions = parse_sfz_text("<region> amp_veltrack=0".to_string()).unwrap();
+        let ramp: Vec<f32> = (0..96).map(|v| v as f32).collect();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(ramp.iter().flat_map(|v| vec![*v, *v]).collect()), 1.0)], 1.0, 4);
+        let mut tuning = tuning::Tuning::default();
+        tuning.set_master_tune(1200.0);
+        engine.set_tuning(&tuning);
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        engine.process(&mut out_left, &mut out_right);
+        assert!(out_left.iter().zip([0.0, 2.0, 4.0, 6.0].iter()).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+
+    #[test]
+    fn engine_regions_info() {
+        let regions = parse_sfz_text("<region> lokey=60 hikey=62 hivel=63 sample=a.wav group=2
+<region> trigger=release sample=b.wav".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(regions.iter().map(|rd| (rd.clone(), stereo(vec![1.0; 16]), 1.0)).collect(), 1.0, 16);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(40).unwrap()));
+
+        let info = engine.regions_info();
+        assert_eq!(info.len(), 2);
+        assert_eq!(info[0].id, engine.region_id(0).unwrap());
+        assert_eq!(info[0].key_range, Some((60, 62)));
+        assert_eq!(info[0].velocity_range, (0, 63));
+        assert_eq!(info[0].sample, "a.wav");
+        assert_eq!(info[0].trigger, Trigger::Attack);
+        assert_eq!(info[0].group, 2);
+        assert!(info[0].playing);
+        assert_eq!(info[1].trigger, Trigger::Release);
+        assert!(!info[1].playing);
+        assert_eq!(info[1].sample_samplerate, 1.0);
+        assert_eq!(info[1].sample_encoding, None);
+    }
+
+    #[test]
+    fn engine_region_mutes() {
+        let regions = parse_sfz_text("<region> key=60 <region> key=60 <region> key=62".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(regions.iter().map(|rd| (rd.clone(), stereo(vec![1.0; 64]), 1000.0)).collect(), 1000.0, 16);
+        let mutes = engine.region_mutes();
+        engine.set_region_muted(1, true);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        assert!(engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+
+        engine.solo_region(Some(2));
+        assert_eq!(mutes.soloed(), Some(2));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        assert!(engine.regions[2].sample.is_playing());
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        assert_eq!(engine.voice_count(), 2);
+
+        mutes.solo(None);
+        mutes.set_muted(1, false);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        assert!(engine.regions[1].sample.is_playing());
+    }
+
+    #[test]
+    fn engine_active_notes() {
+        let regions = parse_sfz_text("<region> key=60".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(regions.iter().map(|rd| (rd.clone(), stereo(vec![1.0; 64]), 1000.0)).collect(), 1000.0, 16);
+        let telemetry = engine.telemetry();
+        engine.set_transpose(2);
+        let soft = Velocity::try_from(40).unwrap();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, soft));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::D3, Velocity::MAX));
+        let mut active_notes = vec![(Note::A0, Velocity::MIN)];
+        engine.active_notes_into(&mut active_notes);
+        assert_eq!(active_notes, vec![(Note::C3, Velocity::MAX), (Note::A3, soft)]);
+        assert!(telemetry.active_notes().is_empty());
+
+        pull_samples_engine(&mut engine, 4);
+        assert_eq!(telemetry.active_notes(), active_notes);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MIN));
+        engine.fadeout();
+        pull_samples_engine(&mut engine, 4);
+        engine.active_notes_into(&mut active_notes);
+        assert!(active_notes.is_empty());
+        assert!(telemetry.active_notes().is_empty());
+    }
+
+    #[test]
+    fn engine_telemetry() {
+        let regions = parse_sfz_text("<region> key=60 <region> key=62".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(regions.iter().map(|rd| (rd.clone(), stereo(vec![1.0; 64]), 1000.0)).collect(), 1000.0, 16);
+        let telemetry = engine.telemetry();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        assert_eq!(engine.voice_count(), 1);
+        assert_eq!(telemetry.voice_count(), 0);
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(telemetry.voice_count(), 1);
+        assert!(!telemetry.region_active(0));
+        assert!(telemetry.region_active(1));
+        assert!(!telemetry.region_active(2));
+        assert_eq!(telemetry.active_regions().collect::<Vec<_>>(), vec![1]);
+        assert!(telemetry.cpu_load() > 0.0);
+        assert_eq!(telemetry.cpu_load(), engine.cpu_load_estimate());
+
+        // voices decayed to silence are dropped before the sample ends
+        let regions = parse_sfz_text("<region> ampeg_decay=0.002 ampeg_sustain=0".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(regions.iter().map(|rd| (rd.clone(), stereo(vec![1.0; 256]), 1000.0)).collect(), 1000.0, 16);
+        let telemetry = engine.telemetry();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 16];
+        let mut out_right = [0.0; 16];
+        engine.process(&mut out_left, &mut out_right);
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(engine.voice_count(), 0);
+        assert_eq!(engine.reclaimed_voices(), 1);
+        assert_eq!(telemetry.reclaimed_voices(), 1);
+    }
+
+    #[test]
+    fn engine_reverb_send() {
+        let tail = |sfz: &str| {
+            let regions = parse_sfz_text(sfz.to_string()).unwrap();
+            let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(vec![1.0; 16]), 1000.0)], 1000.0, 16);
+            engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+            let mut out_left = [0.0; 64];
+            let mut out_right = [0.0; 64];
+            engine.process(&mut out_left, &mut out_right);
+            assert_eq!(out_left[0], 1.0);
+            out_left[32..].iter().chain(out_right[32..].iter()).map(|v| v.abs()).sum::<f32>()
+        };
+        assert_eq!(tail("<region> amp_veltrack=0"), 0.0);
+        assert!(tail("<region> amp_veltrack=0 effect1=100") > 0.0);
+        assert!(parse_sfz_text("<region> effect2=150".to_string()).is_err());
+    }
+
+    #[test]
+    fn parse_header_hierarchy_volumes() {
+        let regions = parse_sfz_text("
+<global> global_volume=-6 lovel=10
+<master> master_volume=-3
+<group> group_volume=-2 volume=-1
+<region> lokey=60
+<group> hivel=100
+<region> volume=-4
+<master>
+<region>
+".to_string()).unwrap();
+        let volumes: Vec<(f32, f32, f32, f32)> = regions.iter()
+            .map(|rd| (rd.volume, rd.group_volume, rd.master_volume, rd.global_volume))
+            .collect();
+        assert_eq!(volumes, vec![(-1.0, -2.0, -3.0, -6.0), (-4.0, 0.0, -3.0, -6.0), (0.0, 0.0, 0.0, -6.0)]);
+        assert_eq!(regions[0].total_volume(), -12.0);
+        assert!(regions.iter().all(|rd| u8::from(rd.vel_range.lo) == 10));
+        assert_eq!(u8::from(regions[1].vel_range.hi), 100);
+        assert_eq!(u8::from(regions[2].vel_range.hi), 127);
+        assert!(parse_sfz_text("<global> global_volume=10".to_string()).is_err());
+    }
+
+    struct MemorySamples;
+
+    impl SampleProvider for MemorySamples {
+        fn load(&self, sample: &str) -> Result<loader::SampleData, EngineError> {
+            match sample {
+                "one.wav" => Ok(loader::SampleData { channels: stereo(vec![1.0; 32]), samplerate: 1000.0, encoding: None, root_key: None, loop_points: None }),
+                "looped.wav" => Ok(loader::SampleData { channels: stereo(vec![1.0; 32]), samplerate: 1000.0, encoding: None, root_key: Some(57), loop_points: Some((8, 15)) }),
+                s => Err(EngineError::SampleReadError { path: s.to_string() }),
+            }
+        }
+    }
+
+    #[test]
+    fn engine_sample_file_defaults() {
+        let engine = Engine::from_sfz_text("<region> sample=looped.wav \
+                                            <region> sample=looped.wav pitch_keycenter=60 loop_mode=no_loop \
+                                            <region> sample=looped.wav loop_start=4 \
+                                            <region> sample=one.wav".to_string(), &MemorySamples, 1000.0, 16).unwrap();
+        let regions: Vec<&RegionData> = engine.region_data().collect();
+        let sample_loop = |rd: &RegionData| rd.sample_loop(32).map(|l| (l.start, l.end, l.sustain_only));
+        assert_eq!(regions[0].pitch_keycenter(), 57);
+        assert_eq!(sample_loop(regions[0]), Some((8, 16, false)));
+        assert_eq!(regions[1].pitch_keycenter(), 60);
+        assert_eq!(sample_loop(regions[1]), None);
+        assert_eq!(sample_loop(regions[2]), Some((4, 16, false)));
+        assert_eq!(regions[3].pitch_keycenter(), 60);
+        assert_eq!(sample_loop(regions[3]), None);
+    }
+
+    #[test]
+    fn engine_from_sfz_text() {
+        let mut engine = Engine::from_sfz_text("<region> sample=one.wav amp_veltrack=0".to_string(), &MemorySamples, 1000.0, 16).unwrap();
+        assert_eq!(engine.load_stats().files, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [1.0; 8]);
+
+        match Engine::from_sfz_text("<region> sample=two.wav".to_string(), &MemorySamples, 1000.0, 16) {
+            Err(e) => assert_eq!(format!("{}", e), "two.wav: Could not read the samples"),
+            _ => panic!("Not seen expected error"),
+        }
+        match Engine::from_sfz_text("<region".to_string(), &MemorySamples, 1000.0, 16) {
+            Err(e) => assert_eq!(e.path(), "<sfz text>"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn engine_tiered_loading() {
+        let samples = || Box::new(crate::testing::FixtureSamples::default()
+            .with("mid.wav", stereo(vec![1.0; 32]), 1000.0)
+            .with("loud.wav", stereo(vec![0.5; 32]), 1000.0)
+            .with("release.wav", stereo(vec![0.25; 32]), 1000.0));
+        let sfz_text = "<region> sample=mid.wav hivel=100 amp_veltrack=0 \
+                        <region> sample=mid.wav lovel=126 amp_veltrack=0 \
+                        <region> sample=loud.wav lovel=125 amp_veltrack=0 \
+                        <region> sample=release.wav trigger=release amp_veltrack=0".to_string();
+        let (engine, deferred) = Engine::from_sfz_text_tiered(sfz_text.clone(), samples(), 1000.0, 16, &Default::default()).unwrap();
+        assert!(engine.is_fully_loaded());
+        assert!(deferred.is_none());
+
+        let config = EngineConfig { load_tiers: Some(LoadTiers::default()), ..Default::default() };
+        let (mut engine, deferred) = Engine::from_sfz_text_tiered(sfz_text.clone(), samples(), 1000.0, 16, &config).unwrap();
+        let deferred = deferred.unwrap();
+        assert!(!engine.is_fully_loaded());
+        assert_eq!(engine.load_stats().files, 1);
+        assert_eq!(deferred.region_count(), 2);
+
+        let play = |engine: &mut Engine, midi_msg: MidiMessage| {
+            engine.midi_event(&midi_msg);
+            let mut out_left = [0.0; 8];
+            let mut out_right = [0.0; 8];
+            engine.process(&mut out_left, &mut out_right);
+            out_left
+        };
+        let note_on = MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(125).unwrap());
+        let note_off = MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MIN);
+        assert_eq!(play(&mut engine, note_on.clone()), [0.0; 8]);
+        assert_eq!(play(&mut engine, note_off.clone()), [0.0; 8]);
+
+        let (_, other) = Engine::from_sfz_text_tiered(sfz_text, samples(), 1000.0, 16, &config).unwrap();
+        assert!(!engine.complete_loading(other.unwrap().load().unwrap()));
+        assert!(!engine.is_fully_loaded());
+
+        assert!(engine.complete_loading(deferred.load().unwrap()));
+        assert!(engine.is_fully_loaded());
+        assert_eq!(engine.load_stats().files, 3);
+        assert_eq!(engine.region_data().nth(2).unwrap().sample, "loud.wav");
+        assert_eq!(play(&mut engine, note_on), [0.5; 8]);
+        assert_eq!(play(&mut engine, note_off)[7], 0.25);
+    }
+
+    #[test]
+    fn engine_purge_and_reload() {
+        let samples = Box::new(crate::testing::FixtureSamples::default()
+            .with("a.wav", stereo(vec![1.0; 32]), 1000.0)
+            .with("b.wav", stereo(vec![0.5; 32]), 1000.0));
+        let sfz_text = "<region> sample=a.wav key=60 amp_veltrack=0 \
+                        <region> sample=a.wav key=61 amp_veltrack=0 \
+                        <region> sample=b.wav key=62 amp_veltrack=0".to_string();
+        let (mut engine, _) = Engine::from_sfz_text_tiered(sfz_text, samples, 1000.0, 8, &Default::default()).unwrap();
+        let play = |engine: &mut Engine, key: u8, blocks: usize| {
+            let note = Note::try_from(key).unwrap();
+            engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, note, Velocity::MAX));
+            let mut out_left = [0.0; 8];
+            let mut out_right = [0.0; 8];
+            let mut first = None;
+            for _ in 0..blocks {
+                engine.process(&mut out_left, &mut out_right);
+                first.get_or_insert(out_left[0]);
+            }
+            engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, note, Velocity::MIN));
+            first.unwrap()
+        };
+        assert_eq!(play(&mut engine, 60, 10), 1.0);
+        assert_eq!(play(&mut engine, 62, 1), 0.5);
+        let memory_size = engine.instrument().memory_size();
+
+        assert!(engine.purge(0.1).is_none());
+        let request = engine.purge(0.05).unwrap();
+        assert_eq!(request.region_count(), 2);
+        assert!(engine.purge(0.05).is_none());
+        assert!(engine.complete_purge(request.release()).is_some());
+        assert!(!engine.is_fully_loaded());
+        assert!(engine.instrument().memory_size() < memory_size);
+        assert!(engine.reload_request().is_none());
+
+        assert_eq!(play(&mut engine, 61, 1), 0.0);
+        let request = engine.reload_request().unwrap();
+        assert!(engine.reload_request().is_none());
+        assert!(engine.complete_loading(request.load().unwrap()));
+        assert!(engine.is_fully_loaded());
+        assert_eq!(engine.instrument().memory_size(), memory_size);
+        assert_eq!(play(&mut engine, 60, 1), 1.0);
+    }
+
+    #[test]
+    fn engine_mapped_samples() {
+        let dir = std::env::temp_dir().join(format!("sonarigo-mapped-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..64).flat_map(|_| 16384i16.to_le_bytes().to_vec()).collect();
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&[16, 0, 0, 0, 1, 0, 1, 0, 0xe8, 0x03, 0, 0, 0xd0, 0x07, 0, 0, 2, 0, 16, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+        std::fs::write(dir.join("half.wav"), wav).unwrap();
+
+        let play = |engine: &mut Engine| {
+            engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+            let mut out_left = [0.0; 8];
+            let mut out_right = [0.0; 8];
+            engine.process(&mut out_left, &mut out_right);
+            out_left
+        };
+        let sfz_text = "<region> sample=half.wav amp_veltrack=0".to_string();
+        let config = EngineConfig { sample_storage: SampleStorage::Mapped, ..Default::default() };
+        let mut engine = Engine::from_sfz_text_with_config(sfz_text.clone(), &SampleFiles::new(&dir), 1000.0, 16, &config).unwrap();
+        assert_eq!(engine.instrument().memory_size(), 0);
+        assert_eq!(play(&mut engine), [0.5; 8]);
+        assert_eq!(engine.regions_info()[0].sample_encoding, Some(SampleEncoding::Pcm(16)));
+        assert_eq!(engine.regions_info()[0].sample_samplerate, 1000.0);
+
+        let config = EngineConfig { sample_storage: SampleStorage::Mapped, pitch_variants: Some(0), ..Default::default() };
+        let sfz_text = "<region> sample=half.wav amp_veltrack=0 pitch_keycenter=59".to_string();
+        let engine = Engine::from_sfz_text_with_config(sfz_text, &SampleFiles::new(&dir), 1000.0, 16, &config).unwrap();
+        assert!(engine.instrument().memory_size() > 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn engine_sample_storage_and_memory_limit() {
+        let sfz_text = "<region> sample=one.wav amp_veltrack=0".to_string();
+        let float_engine = Engine::from_sfz_text(sfz_text.clone(), &MemorySamples, 1000.0, 16).unwrap();
+        let required = float_engine.instrument().memory_size();
+
+        let config = EngineConfig { sample_storage: SampleStorage::Int16, ..Default::default() };
+        let mut int_engine = Engine::from_sfz_text_with_config(sfz_text.clone(), &MemorySamples, 1000.0, 16, &config).unwrap();
+        assert_eq!(2 * int_engine.instrument().memory_size(), required);
+        int_engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
+        int_engine.process(&mut out_left, &mut out_right);
+        assert!(out_left.iter().all(|v| (v - 1.0).abs() < 1e-4));
+
+        let config = EngineConfig { memory_limit: Some(required - 1), ..Default::default() };
+        match Engine::from_sfz_text_with_config(sfz_text.clone(), &MemorySamples, 1000.0, 16, &config) {
+            Err(EngineError::MemoryLimitExceeded { path, required: r, limit }) => {
+                assert_eq!((path.as_str(), r, limit), ("<sfz text>", required, required - 1));
+            }
+            _ => panic!("Not seen expected error"),
         }
-        match &regions.get(11) {
-            Some(rd) => {
-                assert_eq!(rd.amp_veltrack, 1.0);
-                // FIXME: how to test this? assert_eq!(rd.ampeg.release, 0.0);
-                assert_eq!(rd.pitch_keycenter, Note::C3);
-                assert_eq!(rd.tune, 0.0);
-                assert_eq!(rd.key_range.hi, None);
-                assert_eq!(rd.key_range.lo, None);
-                assert_eq!(rd.vel_range.hi, Velocity::MAX);
-                assert_eq!(rd.vel_range.lo, Velocity::MIN);
-                assert_eq!(rd.sample, "48khz24bit\\pedalU2.wav");
-                assert_eq!(rd.trigger, Trigger::Attack);
-                assert_eq!(rd.rt_decay, 0.0);
-                assert_eq!(rd.pitch_keytrack, 1.0);
-                assert_eq!(rd.group, 2);
-                assert_eq!(rd.off_by, 0);
-                assert!(rd.on_ccs.get(&64).unwrap().covering(ControlValue::try_from(0).unwrap()));
-                assert_eq!(rd.random_range.hi, 1.0);
-                assert_eq!(rd.random_range.lo, 0.5);
-                assert_eq!(rd.volume, -19.0);
-            }
-            _ => panic!("Expected region, got somthing different.")
+        let config = EngineConfig { memory_limit: Some(required - 1), sample_storage: SampleStorage::Int16, ..Default::default() };
+        assert!(Engine::from_sfz_text_with_config(sfz_text, &MemorySamples, 1000.0, 16, &config).is_ok());
+    }
+
+    #[test]
+    fn engine_pitch_variants() {
+        let regions = parse_sfz_text("<region> lokey=58 hikey=64 pitch_keycenter=60 <region> pitch_keycenter=60 \
+                                      <region> lokey=58 hikey=64 pitch_keycenter=60 pitch_keytrack=0".to_string()).unwrap();
+        assert_eq!(regions[0].pitch_variant_semitones(2), vec![-2, -1, 1, 2, 3, 4]);
+        assert!(regions[0].pitch_variant_semitones(4).is_empty());
+        assert_eq!(regions[1].pitch_variant_semitones(2).len(), 2 * MAX_PITCH_VARIANT_SEMITONES as usize);
+        assert!(regions[2].pitch_variant_semitones(0).is_empty());
+
+        let sfz_text = "<region> sample=one.wav amp_veltrack=0 lokey=48 hikey=72 pitch_keycenter=60".to_string();
+        let plain = Engine::from_sfz_text(sfz_text.clone(), &MemorySamples, 1000.0, 16).unwrap();
+        let config = EngineConfig { pitch_variants: Some(12), ..Default::default() };
+        assert_eq!(Engine::from_sfz_text_with_config(sfz_text.clone(), &MemorySamples, 1000.0, 16, &config).unwrap()
+                   .instrument().memory_size(), plain.instrument().memory_size());
+
+        let config = EngineConfig { pitch_variants: Some(2), ..Default::default() };
+        let mut engine = Engine::from_sfz_text_with_config(sfz_text, &MemorySamples, 1000.0, 16, &config).unwrap();
+        assert_eq!(engine.instrument().regions[0].variants.len(), 24);
+        assert!(engine.instrument().memory_size() > 20 * plain.instrument().memory_size());
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C4, Velocity::MAX));
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
+        engine.process(&mut out_left, &mut out_right);
+        assert!(out_left.iter().all(|v| (v - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn parse_loop_opcodes() {
+        let regions = parse_sfz_text("<region> loop_mode=loop_sustain loopstart=4 loop_end=7 loop_crossfade=0.5 \
+                                      <region> loopmode=one_shot".to_string()).unwrap();
+        assert_eq!(regions[0].loop_mode, Some(LoopMode::LoopSustain));
+        assert_eq!((regions[0].loop_start, regions[0].loop_end, regions[0].loop_crossfade), (Some(4), Some(7), 0.5));
+        assert_eq!(regions[0].sample_loop(100), Some(sample::Loop { start: 4, end: 8, sustain_only: true }));
+        assert_eq!(regions[0].sample_loop(6), Some(sample::Loop { start: 4, end: 6, sustain_only: true }));
+        assert_eq!(regions[0].sample_loop(4), None);
+        assert_eq!(regions[1].loop_mode, Some(LoopMode::OneShot));
+        assert_eq!(regions[1].sample_loop(100), None);
+
+        let mut trimmed = regions[0].clone();
+        trimmed.trim_loop(5);
+        assert_eq!((trimmed.loop_start, trimmed.loop_end), (Some(0), Some(2)));
+
+        assert!(parse_sfz_text("<region> loop_mode=forever".to_string()).is_err());
+        assert!(parse_sfz_text("<region> loop_crossfade=-1".to_string()).is_err());
+    }
+
+    #[test]
+    fn engine_loop_crossfade() {
+        let regions = parse_sfz_text("<region> amp_veltrack=0 loop_mode=loop_continuous loop_start=8 loop_end=15 \
+                                      loop_crossfade=0.004".to_string()).unwrap();
+        let ramp: Vec<f32> = (0..20).map(|v| v as f32).collect();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), vec![ramp.clone(), ramp], 1000.0)], 1000.0, 32);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 32];
+        let mut out_right = [0.0; 32];
+        engine.process(&mut out_left, &mut out_right);
+
+        let seam = |i: usize| {
+            let gain = (i + 1) as f32 / 5.0;
+            (12 + i) as f32 * (1.0 - gain) + (4 + i) as f32 * gain
+        };
+        let expected: Vec<f32> = (0..32).map(|i| match i {
+            i if i < 12 => i as f32,
+            i if i < 16 => seam(i - 12),
+            i => match 8 + (i - 16) % 8 {
+                f if f < 12 => f as f32,
+                f => seam(f - 12),
+            },
+        }).collect();
+        for (v, e) in Iterator::zip(out_left.iter(), expected.iter()) {
+            assert!((v - e).abs() < 1e-3, "{} {}", v, e);
         }
     }
 
     #[test]
-    fn simple_region_process() {
-        let sample = vec![1.0, 0.5,
-                          0.5, 1.0,
-                          1.0, 0.5];
+    fn engine_click_guard() {
+        let regions = parse_sfz_text("<region> key=60 amp_veltrack=0 <region> key=62 amp_veltrack=0 click_guard=0.002".to_string()).unwrap();
+        assert!(parse_sfz_text("<region> click_guard=1".to_string()).is_err());
+        let mut engine = Engine::from_region_array(regions.into_iter().map(|rd| (rd, stereo(vec![1.0; 64]), 1000.0)).collect(), 1000.0, 16);
+        let play = |engine: &mut Engine, note| {
+            engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, note, Velocity::MAX));
+            let mut out_left = [0.0; 4];
+            let mut out_right = [0.0; 4];
+            engine.process(&mut out_left, &mut out_right);
+            engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, note, Velocity::MAX));
+            let mut release_left = [0.0; 8];
+            let mut release_right = [0.0; 8];
+            engine.process(&mut release_left, &mut release_right);
+            out_left
+        };
+        assert_eq!(play(&mut engine, Note::C3), [1.0; 4]);
+        assert_eq!(play(&mut engine, Note::D3), [0.5, 1.0, 1.0, 1.0]);
 
-        let mut region = Region::new(RegionData::default(), sample, 1.0, 1.0, 8);
+        engine.set_click_guard(0.004);
+        assert_eq!(play(&mut engine, Note::C3), [0.25, 0.5, 0.75, 1.0]);
+        assert_eq!(play(&mut engine, Note::D3), [0.5, 1.0, 1.0, 1.0]);
+    }
 
-        region.note_on(Note::C3, Velocity::MAX);
+    #[test]
+    fn one_shot_ignores_note_off() {
+        let regions = parse_sfz_text("<region> loop_mode=one_shot".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(vec![1.0; 64]), 1000.0)], 1000.0, 16);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 16];
+        let mut out_right = [0.0; 16];
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(engine.voice_count(), 1);
+    }
 
-        let mut out_left: [f32; 2] = [0.0, 0.0];
-        let mut out_right: [f32; 2] = [0.0, 0.0];
+    #[test]
+    fn parse_control_header() {
+        let (regions, control, warnings) = parser::parse_instrument("<control> note_offset=2 octave_offset=-1 set_cc1=64 \
+                                                                     <region> key=60 <control> foo=1".to_string(), false).unwrap();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(control.note_shift(), -10);
+        assert_eq!(warnings.len(), 2);
+        assert!(parser::parse_instrument("<control> note_offset=200 <region>".to_string(), true).is_err());
+        assert!(parser::parse_instrument("<control> set_cc1=64 <region>".to_string(), true).is_ok());
+    }
 
-        region.process(&mut out_left, &mut out_right);
-        assert!(f32_eq(out_left[0], 1.0));
-        assert!(f32_eq(out_left[1], 0.5));
+    #[test]
+    fn engine_note_offset() {
+        let mut engine = Engine::from_sfz_text("<control> note_offset=-2 octave_offset=1 \
+                                                <region> sample=one.wav key=70 amp_veltrack=0".to_string(), &MemorySamples, 1000.0, 16).unwrap();
+        let mut play = |note| {
+            engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, note, Velocity::MAX));
+            let mut out_left = [0.0; 4];
+            let mut out_right = [0.0; 4];
+            engine.process(&mut out_left, &mut out_right);
+            engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, note, Velocity::MAX));
+            out_left[0]
+        };
+        assert_eq!(play(Note::ASharp3), 0.0);
+        assert_eq!(play(Note::C3), 1.0);
+    }
 
-        assert!(f32_eq(out_right[0], 0.5));
-        assert!(f32_eq(out_right[1], 1.0));
+    #[test]
+    fn engine_curves() {
+        let text = "<curve> curve_index=7 v000=1 v127=0 <curve> v064=1 \
+                    <region> sample=one.wav pan_oncc10=100 pan_curvecc10=7 amp_veltrack=0 \
+                    <region> sample=one.wav amp_velcurve_64=1";
+        let (_, control, warnings) = parser::parse_instrument(text.to_string(), true).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(control.curve_count(), 1);
+
+        let mut engine = Engine::from_sfz_text(text.to_string(), &MemorySamples, 1000.0, 16).unwrap();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(64).unwrap()));
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(engine.regions[0].pan, 100.0);
+        assert_eq!(engine.regions[1].gain, 1.0);
+    }
 
-        assert!(sample::tests::is_playing_note(
-            &region.sample,
-            Note::C3
-        ));
+    #[test]
+    fn engine_generators() {
+        use crate::testing::{dominant_frequency, magnitude_db, render, FixtureSamples};
+        let play = |text: &str, note: Note| {
+            let mut engine = Engine::from_sfz_text(format!("<control> default_path=samples {} amp_veltrack=0", text),
+                                                   &FixtureSamples::default(), 48000.0, 256).unwrap();
+            render(&mut engine, &[(0, MidiMessage::NoteOn(Channel::Ch1, note, Velocity::MAX))], 4800, 256)[0].clone()
+        };
+        assert_eq!(dominant_frequency(&play("<region> sample=*sine", Note::A3), 48000.0, 10.0), 440.0);
+        assert_eq!(dominant_frequency(&play("<region> sample=*square pitch_keycenter=57", Note::A3), 48000.0, 10.0), 520.0);
+        assert_eq!(dominant_frequency(&play("<region> sample=*saw", Note::A2), 48000.0, 10.0), 220.0);
+        let noise = play("<region> sample=*noise", Note::C3);
+        assert!(magnitude_db(&noise, 48000.0, 15000.0) > -60.0);
+        assert!(play("<region> sample=*silence", Note::C3).iter().all(|v| *v == 0.0));
+
+        assert!(parse_sfz_text("<region> sample=*organ".to_string()).is_err());
+    }
 
-        let mut out_left: [f32; 2] = [-0.5, -0.2];
-        let mut out_right: [f32; 2] = [-0.2, -0.5];
+    #[test]
+    fn engine_pitch_bend() {
+        use crate::testing::{dominant_frequency, render, FixtureSamples};
+        let play = |text: &str, bend: u16| {
+            let mut engine = Engine::from_sfz_text(text.to_string(), &FixtureSamples::default(), 48000.0, 256).unwrap();
+            let events = [
+                (0, MidiMessage::PitchBendChange(Channel::Ch1, U14::try_from(bend).unwrap())),
+                (0, MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX)),
+            ];
+            dominant_frequency(&render(&mut engine, &events, 4800, 256)[0], 48000.0, 10.0)
+        };
+        assert_eq!(play("<region> sample=*sine", 8192), 440.0);
+        assert_eq!(play("<region> sample=*sine", 0), 390.0);
+        assert_eq!(play("<region> sample=*sine bend_up=1200", 8192 + 4096), 620.0);
+        assert_eq!(play("<region> sample=*sine bend_up=1200 bend_step=1200", 8192 + 4096), 880.0);
+        assert_eq!(play("<region> sample=*sine bend_down=-1200 bend_step=1200", 4096), 220.0);
+    }
 
-        region.process(&mut out_left, &mut out_right);
-        assert!(f32_eq(out_left[0], 0.5));
-        assert!(f32_eq(out_left[1], -0.2));
+    #[test]
+    fn engine_portamento() {
+        use crate::testing::{dominant_frequency, render, FixtureSamples};
+        let events = [
+            (0, MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX)),
+            (4800, MidiMessage::NoteOn(Channel::Ch1, Note::A4, Velocity::MAX)),
+            (14400, MidiMessage::NoteOff(Channel::Ch1, Note::A4, Velocity::MAX)),
+        ];
+        let play = |engine: &mut Engine| {
+            let out = render(engine, &events, 24000, 256).swap_remove(0);
+            (dominant_frequency(&out[..4800], 48000.0, 10.0),
+             dominant_frequency(&out[9600..14400], 48000.0, 10.0),
+             dominant_frequency(&out[19200..], 48000.0, 10.0))
+        };
+        let text = "<region> sample=*sine portamento_time=0.05";
+        let mut engine = Engine::from_sfz_text(text.to_string(), &FixtureSamples::default(), 48000.0, 256).unwrap();
+        assert_eq!(play(&mut engine), (440.0, 880.0, 440.0));
+        assert_eq!(engine.voice_count(), 1);
+
+        let mut engine = Engine::from_sfz_text("<region> sample=*sine".to_string(), &FixtureSamples::default(), 48000.0, 256).unwrap();
+        engine.set_portamento(Some(0.05));
+        assert_eq!(play(&mut engine), (440.0, 880.0, 440.0));
+        assert_eq!(engine.voice_count(), 1);
+
+        // polyphonic without portamento
+        let mut engine = Engine::from_sfz_text("<region> sample=*sine".to_string(), &FixtureSamples::default(), 48000.0, 256).unwrap();
+        render(&mut engine, &events[..2], 9600, 256);
+        assert_eq!(engine.voice_count(), 2);
+    }
 
-        assert!(f32_eq(out_right[0], 0.3));
-        assert!(f32_eq(out_right[1], -0.5));
+    #[test]
+    fn parse_default_path() {
+        let (regions, control, _) = parser::parse_instrument("\
+<region> sample=a.wav
+<control> default_path=Piano\\samples\\
+<region> sample=b.wav
+<group> default_path=Strings
+<region> sample=c.wav
+<region> sample=d.wav default_path=Pads\\
+<region> sample=\\abs\\e.wav
+<control> default_path=Drums
+<group>
+<region> sample=f.wav
+".to_string(), true).unwrap();
+        let samples: Vec<&str> = regions.iter().map(|rd| rd.sample()).collect();
+        assert_eq!(samples, vec!["a.wav", "Piano\\samples\\b.wav", "Strings\\c.wav", "Pads\\d.wav",
+                                 "\\abs\\e.wav", "Drums\\f.wav"]);
+        assert_eq!(control.default_path(), "Drums");
+    }
 
-        assert!(!sample::tests::is_playing_note(
-            &region.sample,
-            Note::C3
-        ));
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let regions = parse_sfz_text("<region> sample=a.wav lokey=40 hikey=50 pitch_keycenter=45 hivel=90 \
+                                      on_locc64=64 on_hicc64=127 hicc1=20 lorand=0.5 hirand=1.0 trigger=release \
+                                      ampeg_attack=0.5 ampeg_delaycc1=2 cutoff=1000 cutoff_oncc2=1200 \
+                                      loop_mode=loop_continuous loop_start=10 loop_end=90".to_string()).unwrap();
+        let json = serde_json::to_string(&regions[0]).unwrap();
+        let rd: RegionData = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&rd).unwrap(), json);
+        assert_eq!(format!("{}", rd), format!("{}", regions[0]));
+        assert_eq!(rd.identity_hash(), regions[0].identity_hash());
+
+        let rd: RegionData = serde_json::from_str("{\"sample\": \"b.wav\", \"pitch_keycenter\": 70}").unwrap();
+        assert_eq!(rd.sample(), "b.wav");
+        assert_eq!(rd.pitch_keycenter(), 70);
+        assert!(serde_json::from_str::<RegionData>("{\"pitch_keycenter\": 128}").is_err());
+
+        let config: EngineConfig = serde_json::from_str("{\"strict\": true, \"sample_storage\": \"Int16\"}").unwrap();
+        assert!(config.strict);
+        assert_eq!(config.sample_storage, SampleStorage::Int16);
+        assert_eq!(config.memory_limit, None);
     }
 
     #[test]
-    fn region_volume_process() {
-        let sample = vec![1.0, 1.0];
+    fn engine_master_gain() {
+        let regions = parse_sfz_text("<region> amp_veltrack=0".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(vec![1.0; 4096]), 1000.0)], 1000.0, 1024);
+        engine.set_master_gain(-6.0);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 16];
+        let mut out_right = [0.0; 16];
+        engine.process(&mut out_left, &mut out_right);
+        assert!(out_left.iter().all(|v| f32_eq(*v, utils::dB_to_gain(-6.0))));
 
-        let mut region_data = RegionData::default();
-        region_data.set_volume(-20.0).unwrap();
+        engine.set_master_gain(-100.0);
+        let mut out_left = [0.0; 1024];
+        let mut out_right = [0.0; 1024];
+        engine.process(&mut out_left, &mut out_right);
+        assert!(out_left[0] < utils::dB_to_gain(-6.0) && out_left[0] > 0.4);
+        assert!(out_left.windows(2).all(|w| w[1] <= w[0]));
+        assert!(out_left[1023] < 1e-6);
+    }
+
+    #[test]
+    fn engine_master_pan() {
+        let regions = parse_sfz_text("<region> amp_veltrack=0".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(vec![1.0; 4096]), 1000.0)], 1000.0, 1024);
+        engine.set_master_pan(-50.0);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = [0.0; 16];
+        let mut out_right = [0.0; 16];
+        engine.process(&mut out_left, &mut out_right);
+        assert!(out_left.iter().all(|v| f32_eq(*v, 1.0)));
+        assert!(out_right.iter().all(|v| f32_eq(*v, 0.5)));
+
+        engine.set_master_pan(200.0);
+        let mut out_left = [0.0; 1024];
+        let mut out_right = [0.0; 1024];
+        engine.process(&mut out_left, &mut out_right);
+        assert!(out_left.windows(2).all(|w| w[1] <= w[0]));
+        assert!(out_left[1023] < 1e-6);
+        assert!(out_right.iter().all(|v| *v > 0.5 && *v <= 1.0));
+        assert!(f32_eq(out_right[1023], 1.0));
+    }
+
+    #[test]
+    fn engine_master_tune() {
+        use crate::testing::{dominant_frequency, render, FixtureSamples};
+        let mut engine = Engine::from_sfz_text("<region> sample=*sine".to_string(),
+                                               &FixtureSamples::default(), 48000.0, 256).unwrap();
+        engine.set_master_tune(1200.0);
+        let output = render(&mut engine, &[(0, MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX))], 4800, 256);
+        assert_eq!(dominant_frequency(&output[0], 48000.0, 10.0), 880.0);
+    }
 
-        let mut region = Region::new(region_data, sample, 1.0, 1.0, 8);
+    #[test]
+    fn mpe_zones() {
+        let mpe = MpeConfig { lower_zone_members: 3, upper_zone_members: 2, pitch_bend_range: 48.0 };
+        let members: Vec<u8> = (0..16)
+            .filter(|i| mpe.is_member_channel(Channel::from_index(*i).unwrap()))
+            .collect();
+        assert_eq!(members, vec![1, 2, 3, 13, 14]);
+    }
 
+    #[test]
+    fn region_pan_cc() {
+        let regions = parse_sfz_text("<region> pan_oncc10=100 amp_veltrack=0".to_string()).unwrap();
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(vec![1.0; 96]), 1.0, 1.0, 4);
         region.note_on(Note::C3, Velocity::MAX);
 
-        let mut out_left: [f32; 2] = [0.0, 0.0];
-        let mut out_right: [f32; 2] = [0.0, 0.0];
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!((out_left, out_right), ([1.0; 4], [1.0; 4]));
 
+        region.pass_midi_msg(&cc_msg(10, 127), 0.0);
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
         region.process(&mut out_left, &mut out_right);
+        assert_eq!((out_left, out_right), ([0.0; 4], [1.0; 4]));
+    }
 
-        assert_eq!(out_left[0], 0.1);
-        assert_eq!(out_right[0], 0.1);
+    #[test]
+    fn region_cutoff_cc() {
+        let regions = parse_sfz_text("<region> cutoff=100 cutoff_cc1=9600 amp_veltrack=0".to_string()).unwrap();
+        let first_output = |cc_value| {
+            let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(vec![1.0; 96]), 48000.0, 48000.0, 4);
+            region.pass_midi_msg(&cc_msg(1, cc_value), 0.0);
+            region.note_on(Note::C3, Velocity::MAX);
+            let mut out_left = [0.0; 4];
+            let mut out_right = [0.0; 4];
+            region.process(&mut out_left, &mut out_right);
+            out_left[0]
+        };
+        assert!(first_output(0) < 0.001);
+        assert!(first_output(127) > 0.1);
     }
 
     #[test]
-    fn region_amp_envelope_process() {
-        let mut sample = vec![];
-        sample.resize(32, 1.0);
+    fn region_gain_cc() {
+        let regions = parse_sfz_text("<region> gain_cc7=-12 amp_veltrack=0".to_string()).unwrap();
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(vec![1.0; 96]), 1.0, 1.0, 4);
+        let cc = |val: u8| MidiMessage::ControlChange(Channel::Ch1,
+                                                      ControlNumber::try_from(7).unwrap(),
+                                                      ControlValue::try_from(val).unwrap());
+        region.note_on(Note::C3, Velocity::MAX);
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [1.0; 4]);
+
+        region.pass_midi_msg(&cc(127), 0.0);
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        region.process(&mut out_left, &mut out_right);
+        let target = utils::dB_to_gain(-12.0);
+        assert!(out_left.windows(2).all(|w| w[1] < w[0]));
+        assert!(f32_eq(out_left[3], target));
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        region.process(&mut out_left, &mut out_right);
+        assert!(out_left.iter().all(|v| f32_eq(*v, target)));
+    }
+
+    #[test]
+    fn region_cc_conditions() {
+        let regions = parse_sfz_text("<region> locc1=64 hicc11=100 lokey=60 hikey=60".to_string()).unwrap();
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(vec![1.0; 96]), 1.0, 1.0, 8);
+        let cc = |num: u8, val: u8| MidiMessage::ControlChange(Channel::Ch1,
+                                                               ControlNumber::try_from(num).unwrap(),
+                                                               ControlValue::try_from(val).unwrap());
+        let note_on = MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX);
+
+        assert!(!region.pass_midi_msg(&note_on, 0.0));
+
+        region.pass_midi_msg(&cc(1, 64), 0.0);
+        assert!(region.pass_midi_msg(&note_on, 0.0));
+
+        region.pass_midi_msg(&cc(11, 101), 0.0);
+        assert!(!region.pass_midi_msg(&note_on, 0.0));
+
+        region.pass_midi_msg(&cc(11, 100), 0.0);
+        region.pass_midi_msg(&cc(1, 63), 0.0);
+        assert!(!region.pass_midi_msg(&note_on, 0.0));
+    }
+
+    #[test]
+    fn parse_cc_conditions() {
+        match parse_sfz_text("<region> locc1=64 hicc1=32".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 19, hicc1: Flipped range hiccXX: 32 <-> 64"),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> hicc1=128".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "line 1, column 10, hicc1: hiccXX out of range: 0 <= 128 <= 127"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_aria_aliases() {
+        let text = "<region> pitch_keycenter=c4 pitch=-20 polyphony_group=3 lohdcc1=0.5 hihdcc1=1.0 \
+                    ampeg_dynamic=1 bend_up=1200 amp_velcurve_127=1.0 set_cc1=64";
+        let regions = parse_sfz_text(text.to_string()).unwrap();
+        let rd = &regions[0];
+        assert_eq!(rd.pitch_keycenter, Note::C3);
+        assert_eq!(rd.tune, -0.2);
+        assert_eq!(rd.group, 3);
+        assert_eq!(rd.bend_up, 1200.0);
+        assert_eq!(rd.amp_velcurve.points(), &[(127, 1.0)]);
+        let range = rd.cc_conditions.get(&1).unwrap();
+        assert_eq!(range.lo, Some(ControlValue::try_from(64).unwrap()));
+        assert_eq!(range.hi, Some(ControlValue::MAX));
+
+        let (_, warnings) = super::super::parser::parse_sfz_text_lenient(text.to_string()).unwrap();
+        let warnings: Vec<String> = warnings.iter().map(|w| format!("{}", w)).collect();
+        assert_eq!(warnings, vec![
+            "line 1, column 81, ampeg_dynamic: Ignored opcode: ampeg_dynamic",
+            "line 1, column 131, set_cc1: Ignored opcode: set_cc1",
+        ]);
+
+        assert!(parse_sfz_text("<region> lohdcc1=1.5".to_string()).is_err());
+    }
+
+    #[test]
+    fn region_amp_envelope_cc_modulation() {
         let regions = parse_sfz_text(
-            "<region> ampeg_attack=2 ampeg_hold=3 ampeg_decay=4 ampeg_sustain=60 ampeg_release=5"
+            "<region> ampeg_delay_oncc1=2 ampeg_start=50 ampeg_attack=2 amp_veltrack=0"
                 .to_string(),
         )
         .unwrap();
 
-        let mut region = Region::new(regions.get(0).unwrap().clone(), sample, 1.0, 1.0, 16);
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(vec![1.0; 96]), 1.0, 1.0, 8);
         region.note_on(Note::C3, Velocity::MAX);
-
-        let mut out_left: [f32; 12] = [0.0; 12];
-        let mut out_right: [f32; 12] = [0.0; 12];
-
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
         region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.5, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
 
-        let out: Vec<f32> = out_left
-            .iter()
-            .map(|v| (v * 100.0).round() / 100.0)
-            .collect();
-        assert_eq!(
-            out.as_slice(),
-            [0.0, 0.5, 1.0, 1.0, 1.0, 0.65, 0.61, 0.6, 0.6, 0.6, 0.6, 0.6]
-        );
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(vec![1.0; 96]), 1.0, 1.0, 8);
+        region.pass_midi_msg(&MidiMessage::ControlChange(Channel::Ch1,
+                                                         ControlNumber::try_from(1).unwrap(),
+                                                         ControlValue::try_from(127).unwrap()), 0.0);
+        region.note_on(Note::C3, Velocity::MAX);
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.0, 0.0, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0]);
     }
 
     #[test]
@@ -1567,7 +6019,7 @@ mod tests {
         )
         .unwrap();
 
-        let mut region = Region::new(regions.get(0).unwrap().clone(), sample, 1.0, 1.0, 12);
+        let mut region = Region::new(regions.get(0).unwrap().clone(), stereo(sample), 1.0, 1.0, 12);
         region.note_on(Note::C3, Velocity::MAX);
 
         let mut out_left: [f32; 12] = [0.0; 12];
@@ -1624,8 +6076,8 @@ mod tests {
                            -0.5, -0.5,
                            0.0, 0.5];
 
-        let mut engine = Engine::from_region_array(vec![(RegionData::default(), sample1, 1.0),
-                                                        (RegionData::default(), sample2, 1.0)],
+        let mut engine = Engine::from_region_array(vec![(RegionData::default(), stereo(sample1), 1.0),
+                                                        (RegionData::default(), stereo(sample2), 1.0)],
                                                    1.0, 16);
 
         engine.regions[0].note_on(Note::C3, Velocity::MAX);
@@ -1650,7 +6102,7 @@ mod tests {
 
     fn make_dummy_region(rd: RegionData, samplerate: f64, max_block_length: usize) -> Region {
         let sample = vec![1.0; 96];
-        Region::new(rd, sample, samplerate, samplerate, max_block_length)
+        Region::new(rd, stereo(sample), samplerate, samplerate, max_block_length)
     }
 
     fn pull_samples(region: &mut Region, nsamples: usize) -> (Vec<f32>, Vec<f32>) {
@@ -1748,6 +6200,44 @@ mod tests {
 
     }
 
+    #[test]
+    fn engine_trigger_pedal_noise() {
+        use crate::testing::{sine, FixtureSamples};
+        let samples = FixtureSamples::default()
+            .with("pedalD1.wav", vec![sine(440.0, 1000.0, 1000)], 1000.0)
+            .with("pedalD2.wav", vec![sine(440.0, 1000.0, 1000)], 1000.0)
+            .with("soft.wav", vec![sine(440.0, 1000.0, 1000)], 1000.0);
+        let text = "
+<group> group=1 hikey=-1 lokey=-1 on_locc64=126 on_hicc64=127
+<region> sample=pedalD1.wav lorand=0 hirand=0.5
+<region> sample=pedalD2.wav lorand=0.5 hirand=1
+<region> sample=soft.wav on_locc67=64 on_hicc67=127
+";
+        let make = || Engine::from_sfz_text(text.to_string(), &samples, 1000.0, 16).unwrap();
+        let cc = |cnum: u8, cval: u8| MidiMessage::ControlChange(Channel::Ch1,
+                                                                 ControlNumber::try_from(cnum).unwrap(),
+                                                                 ControlValue::try_from(cval).unwrap());
+        let playing = |engine: &Engine| -> Vec<bool> { engine.regions.iter().map(|r| r.sample.is_playing()).collect() };
+
+        // one of the alternating pedal noises, the soft pedal is not down
+        let mut engine = make();
+        engine.midi_event(&cc(64, 127));
+        let pedal = playing(&engine);
+        assert!(pedal[0] ^ pedal[1]);
+        assert!(!pedal[2]);
+
+        let mut engine = make();
+        engine.midi_event(&cc(67, 100));
+        assert_eq!(playing(&engine), vec![false, false, false]);
+        engine.midi_event(&cc(64, 127));
+        assert!(playing(&engine)[2]);
+
+        let mut engine = make();
+        engine.midi_event(&cc(64, 127));
+        engine.midi_event(&cc(67, 127));
+        assert!(playing(&engine)[2]);
+    }
+
 
     #[test]
     fn note_trigger_release() {
@@ -1763,6 +6253,34 @@ mod tests {
         assert_eq!(region.gain, 0.24607849215698431397);
     }
 
+    #[test]
+    fn note_on_gain_table() {
+        let mut rd = RegionData::default();
+        rd.set_rt_decay(3.0).unwrap();
+        rd.set_trigger(Trigger::Release);
+        let mut engine = Engine::from_region_array(vec![(rd, stereo(vec![1.0; 96]), 1.0)], 1.0, 1);
+        engine.set_gain_table(Some(10));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()));
+        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        let expected = 0.24607849215698431397 * utils::dB_to_gain(-3.0);
+        assert!((engine.regions[0].gain - expected).abs() < 2e-5 * expected);
+        assert!(engine.regions[0].gain != expected);
+    }
+
+    #[test]
+    fn note_trigger_release_note_off_velocity() {
+        let mut rd = RegionData::default();
+        rd.set_trigger(Trigger::Release);
+        let mut region = make_dummy_region(rd, 1.0, 2);
+        region.note_off_velocity = true;
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
+        assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
+        assert_eq!(region.gain, 0.24607849215698431397);
+    }
+
     #[test]
     fn trigger_release_rt_decay() {
             let mut rd = RegionData::default();
@@ -1801,91 +6319,175 @@ mod tests {
 
         let mut rd = RegionData::default();
         rd.set_trigger(Trigger::Release);
-        rd.set_rt_decay(3.0).unwrap();
+        rd.set_rt_decay(3.0).unwrap();
+        let mut region = make_dummy_region(rd, 1.0, 2);
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        assert_eq!(region.gain, 1.0);
+
+        let mut out_left = [0.0];
+        let mut out_right = [0.0];
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.process(&mut out_left, &mut out_right);
+        region.process(&mut out_left, &mut out_right);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        assert_eq!(region.gain, utils::dB_to_gain(-6.0));
+    }
+
+    #[test]
+    fn trigger_release_rt_decay_per_key() {
+        let mut rd = RegionData::default();
+        rd.set_trigger(Trigger::Release);
+        rd.set_rt_decay(3.0).unwrap();
+        let mut region = make_dummy_region(rd, 1.0, 2);
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        pull_samples(&mut region, 2);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::D3, Velocity::MAX), 0.0);
+        assert_eq!(region.gain, 1.0);
+
+        pull_samples(&mut region, 1);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::E3, Velocity::MAX), 0.0);
+        pull_samples(&mut region, 1);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::E3, Velocity::MAX), 0.0);
+        assert_eq!(region.gain, utils::dB_to_gain(-3.0));
+    }
+
+    fn pedal_msg(pushed: bool) -> MidiMessage<'static> {
+        cc_msg(64, if pushed { 64 } else { 63 })
+    }
+
+    #[test]
+    fn note_trigger_release_sustain_pedal() {
+        let mut rd = RegionData::default();
+        rd.set_trigger(Trigger::Release);
+        let mut region = make_dummy_region(rd, 1.0, 2);
+
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        assert!(!region.sample.is_playing());
+
+        // the key is still held when the pedal is lifted
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
+        assert!(!region.sample.is_playing());
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        assert!(!region.sample.is_playing());
+
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
+        let (ol, _) = pull_samples(&mut region, 1);
+        assert_eq!(ol[0], 0.24607849215698431397);
+
+
+        let mut rd = RegionData::default();
+        rd.set_trigger(Trigger::Release);
+        let mut region = make_dummy_region(rd, 1.0, 2);
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
+        assert!(!sample::tests::is_playing_note(&region.sample, Note::C3));
+
+        // the key is released while the pedal is down
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        assert!(!region.sample.is_playing());
+
+        assert!(region.pass_midi_msg(&pedal_msg(false), 0.0));
+        assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
+        let (ol, _) = pull_samples(&mut region, 1);
+        assert_eq!(ol[0], 0.24607849215698431397);
+    }
+
+    #[test]
+    fn note_trigger_release_repedal() {
+        let mut rd = RegionData::default();
+        rd.set_trigger(Trigger::Release);
         let mut region = make_dummy_region(rd, 1.0, 2);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
-        assert_eq!(region.gain, 1.0);
-
-        let mut out_left = [0.0];
-        let mut out_right = [0.0];
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        assert_eq!(region.sample.voice_count(), 1);
+        assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
 
-        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
-        region.process(&mut out_left, &mut out_right);
-        region.process(&mut out_left, &mut out_right);
-        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
-        assert_eq!(region.gain, utils::dB_to_gain(-6.0));
+        // repedaling does not retrigger the released key
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        assert_eq!(region.sample.voice_count(), 1);
+
+        // restriking a key held by the pedal retriggers once on the final release
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::D3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        assert!(!sample::tests::is_playing_note(&region.sample, Note::D3));
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::D3, Velocity::MAX), 0.0);
+        assert!(sample::tests::is_playing_note(&region.sample, Note::D3));
+        assert_eq!(region.sample.voice_count(), 2);
     }
 
     #[test]
-    fn note_trigger_release_sustain_pedal() {
-            let mut rd = RegionData::default();
+    fn note_trigger_release_pedal_several_notes() {
+        let mut rd = RegionData::default();
         rd.set_trigger(Trigger::Release);
+        rd.set_rt_decay(3.0).unwrap();
         let mut region = make_dummy_region(rd, 1.0, 2);
 
-        // sustain pedal on
-        region.pass_midi_msg(&MidiMessage::ControlChange(
-            Channel::Ch1,
-            ControlNumber::try_from(64).unwrap(),
-            ControlValue::try_from(64).unwrap()
-        ), 0.0);
-
-        // sustain pedal off
-        region.pass_midi_msg(&MidiMessage::ControlChange(
-            Channel::Ch1,
-            ControlNumber::try_from(64).unwrap(),
-            ControlValue::try_from(63).unwrap()
-        ), 0.0);
-
-        assert!(!region.sample.is_playing());
-
-        // sustain pedal on
-        region.pass_midi_msg(&MidiMessage::ControlChange(
-            Channel::Ch1,
-            ControlNumber::try_from(64).unwrap(),
-            ControlValue::try_from(64).unwrap()
-        ), 0.0);
-
-        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        pull_samples(&mut region, 1);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::try_from(63).unwrap()), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::D3, Velocity::MAX), 0.0);
+        pull_samples(&mut region, 1);
         assert!(!region.sample.is_playing());
 
-        // sustain pedal off
-        region.pass_midi_msg(&MidiMessage::ControlChange(
-            Channel::Ch1,
-            ControlNumber::try_from(64).unwrap(),
-            ControlValue::try_from(63).unwrap()
-        ), 0.0);
-
+        // each note with its own velocity and held time
+        assert!(region.pass_midi_msg(&pedal_msg(false), 0.0));
         assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&region.sample, Note::D3));
         let (ol, _) = pull_samples(&mut region, 1);
-        assert_eq!(ol[0], 0.24607849215698431397);
-
+        let expected = utils::dB_to_gain(-6.0) + 0.24607849215698431397 * utils::dB_to_gain(-3.0);
+        assert!((ol[0] - expected).abs() < 1e-6, "{} != {}", ol[0], expected);
+    }
 
+    #[test]
+    fn note_trigger_release_key_ignores_pedal() {
         let mut rd = RegionData::default();
-        rd.set_trigger(Trigger::Release);
+        rd.set_trigger(Trigger::ReleaseKey);
         let mut region = make_dummy_region(rd, 1.0, 2);
 
-        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
-        assert!(!sample::tests::is_playing_note(&region.sample, Note::C3));
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        assert_eq!(region.sample.voice_count(), 1);
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        assert_eq!(region.sample.voice_count(), 1);
+    }
 
-            // sustain pedal on
-        region.pass_midi_msg(&MidiMessage::ControlChange(
-            Channel::Ch1,
-            ControlNumber::try_from(64).unwrap(),
-            ControlValue::try_from(64).unwrap()
-        ), 0.0);
+    #[test]
+    fn note_off_repedal() {
+        let mut region = make_dummy_region(RegionData::default(), 1.0, 2);
 
-        // sustain pedal off
-        region.pass_midi_msg(&MidiMessage::ControlChange(
-            Channel::Ch1,
-            ControlNumber::try_from(64).unwrap(),
-            ControlValue::try_from(63).unwrap()
-        ), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
 
+        region.pass_midi_msg(&pedal_msg(true), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
         assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
-        let (ol, _) = pull_samples(&mut region, 1);
-        assert_eq!(ol[0], 0.24607849215698431397);
+        region.pass_midi_msg(&pedal_msg(false), 0.0);
+        assert!(sample::tests::is_releasing_note(&region.sample, Note::C3));
     }
 
     #[test]
@@ -2257,7 +6859,7 @@ mod tests {
                           0.5, -0.5];
 
         let mut engine =
-            Engine::from_region_array(vec![(RegionData::default(), sample, 1.0)], 1.0, 16);
+            Engine::from_region_array(vec![(RegionData::default(), stereo(sample), 1.0)], 1.0, 16);
 
         let mut out_left: [f32; 1] = [0.0];
         let mut out_right: [f32; 1] = [0.0];
@@ -2279,622 +6881,1250 @@ mod tests {
         let mut out_left: [f32; 1] = [0.0];
         let mut out_right: [f32; 1] = [0.0];
 
-        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+
+        engine.process(&mut out_left, &mut out_right);
+
+        assert_eq!(out_left[0], 0.0);
+        assert_eq!(out_right[0], 0.0);
+    }
+
+
+    #[test]
+    fn note_on_off_adsr() {
+        let mut sample = vec![];
+        sample.resize(48, 1.0);
+        let regions = parse_sfz_text("<region> ampeg_attack=2 ampeg_hold=3 ampeg_decay=4 ampeg_sustain=60 ampeg_release=5".to_string()).unwrap();
+
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(sample), 1.0)], 1.0, 16);
+
+        let mut out_left: [f32; 12] = [0.0; 12];
+        let mut out_right: [f32; 12] = [0.0; 12];
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.process(&mut out_left, &mut out_right);
+
+        let out: Vec<f32> = out_left.iter().map(|v| (v*100.0).round()/100.0).collect();
+        assert_eq!(out.as_slice(), [0.0, 0.5, 1.0, 1.0, 1.0, 0.65, 0.61, 0.6, 0.6, 0.6, 0.6, 0.6]);
+
+        let mut out_left: [f32; 4] = [0.0; 4];
+        let mut out_right: [f32; 4] = [0.0; 4];
+
+        engine.process(&mut out_left, &mut out_right);
+
+        let out: Vec<f32> = out_left.iter().map(|v| (v*10000.0).round()/10000.0).collect();
+        assert_eq!(out.as_slice(), [0.6, 0.6, 0.6, 0.6]);
+
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+
+        let mut out_left: [f32; 8] = [0.0; 8];
+        let mut out_right: [f32; 8] = [0.0; 8];
+
+        engine.process(&mut out_left, &mut out_right);
+
+        let rel: Vec<f32> = out_left.iter().map(|v| (v*10000.0).round()/10000.0).collect();
+        assert_eq!(rel.as_slice(), [0.0727, 0.0147, 0.003, 0.0006, 0.0001, 0.0, 0.0, 0.0]);
+    }
+
+
+    #[test]
+    fn note_on_velocity() {
+        let sample = vec![1.0, 1.0];
+        let mut region = Region::new(RegionData::default(), stereo(sample), 1.0, 1.0, 16);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
+
+        let mut out_left: [f32; 1] = [0.0];
+        let mut out_right: [f32; 1] = [0.0];
+
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left[0], 0.24607849215698431397);
+        assert_eq!(out_right[0], 0.24607849215698431397);
+    }
+
+    #[test]
+    fn note_on_key_crossfade() {
+        let regions = parse_sfz_text("
+<region> xfin_lokey=60 xfin_hikey=64 xfout_lokey=70 xfout_hikey=72 xf_keycurve=gain
+<region> xfin_lokey=60 xfin_hikey=64
+".to_string()).unwrap();
+        let gain = |rd: &RegionData, key: u8| {
+            let mut region = make_dummy_region(rd.clone(), 1.0, 2);
+            region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::try_from(key).unwrap(), Velocity::MAX), 0.0);
+            region.gain
+        };
+        assert_eq!(gain(&regions[0], 59), 0.0);
+        assert_eq!(gain(&regions[0], 60), 0.0);
+        assert_eq!(gain(&regions[0], 61), 0.25);
+        assert_eq!(gain(&regions[0], 64), 1.0);
+        assert_eq!(gain(&regions[0], 70), 1.0);
+        assert_eq!(gain(&regions[0], 71), 0.5);
+        assert_eq!(gain(&regions[0], 72), 0.0);
+
+        assert_eq!(gain(&regions[1], 61), 0.5);
+        assert_eq!(gain(&regions[1], 127), 1.0);
+    }
+
+    #[test]
+    fn note_on_gain_veltrack() {
+        let sample = vec![1.0, 1.0];
+        let mut rd = RegionData::default();
+        rd.set_amp_veltrack(0.0).unwrap();
+
+        let mut region = Region::new(rd, stereo(sample.clone()), 1.0, 1.0, 16);
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+
+        let mut out_left: [f32; 1] = [0.0];
+        let mut out_right: [f32; 1] = [0.0];
+
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left[0], 1.0);
+        assert_eq!(out_right[0], 1.0);
+
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MIN), 0.0);
+
+        let mut out_left: [f32; 1] = [0.0];
+        let mut out_right: [f32; 1] = [0.0];
+
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left[0], 1.0);
+        assert_eq!(out_right[0], 1.0);
+
+        let mut rd = RegionData::default();
+        rd.set_amp_veltrack(-100.0).unwrap();
+
+        let mut region = Region::new(rd, stereo(sample.clone()), 1.0, 1.0, 16);
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MIN), 0.0);
+
+        let mut out_left: [f32; 1] = [0.0];
+        let mut out_right: [f32; 1] = [0.0];
+
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left[0], 1.0);
+        assert_eq!(out_right[0], 1.0);
+
+
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+
+        let mut out_left: [f32; 1] = [0.0];
+        let mut out_right: [f32; 1] = [0.0];
+
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left[0], utils::dB_to_gain(-160.0));
+        assert_eq!(out_right[0], utils::dB_to_gain(-160.0));
+    }
+
+    #[test]
+    fn note_on_off_key_range() {
+        let sample = vec![1.0, 1.0,
+                          0.5, 0.5];
+
+        let region = parse_sfz_text("<region> lokey=60 hikey=60".to_string()).unwrap()[0].clone();
+
+        let mut engine =
+            Engine::from_region_array(vec![(region.clone(), stereo(sample.clone()), 1.0)], 1.0, 16);
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX));
+
+        let mut out_left: [f32; 1] = [0.0];
+        let mut out_right: [f32; 1] = [0.0];
+
+        engine.process(&mut out_left, &mut out_right);
+        assert!(f32_eq(out_left[0], 0.0));
+        assert!(f32_eq(out_right[0], 0.0));
+
+        let mut engine =
+            Engine::from_region_array(vec![(region.clone(), stereo(sample.clone()), 1.0)], 1.0, 16);
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+
+        let mut out_left: [f32; 1] = [0.0];
+        let mut out_right: [f32; 1] = [0.0];
+
+        engine.process(&mut out_left, &mut out_right);
+        assert!(f32_eq(out_left[0], 1.0));
+        assert!(f32_eq(out_right[0], 1.0));
+
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::A3, Velocity::MAX));
+
+        let mut out_left: [f32; 1] = [0.0];
+        let mut out_right: [f32; 1] = [0.0];
+
+        engine.process(&mut out_left, &mut out_right);
+        assert!(f32_eq(out_left[0], 0.5));
+        assert!(f32_eq(out_right[0], 0.5));
+    }
+
+    #[test]
+    fn pitch_keytrack_frequency() {
+        let samplerate = 48000.0;
+        let nsamples = 96000;
+
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+
+        region.note_on(Note::A3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 440.0);
+
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-        engine.process(&mut out_left, &mut out_right);
+        region.note_on(Note::A4, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 880.0);
 
-        assert_eq!(out_left[0], 0.0);
-        assert_eq!(out_right[0], 0.0);
-    }
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+        rd.set_pitch_keytrack(0.0).unwrap();
 
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-    #[test]
-    fn note_on_off_adsr() {
-        let mut sample = vec![];
-        sample.resize(48, 1.0);
-        let regions = parse_sfz_text("<region> ampeg_attack=2 ampeg_hold=3 ampeg_decay=4 ampeg_sustain=60 ampeg_release=5".to_string()).unwrap();
+        region.note_on(Note::A3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 440.0);
 
-        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), sample, 1.0)], 1.0, 16);
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+        rd.set_pitch_keytrack(0.0).unwrap();
 
-        let mut out_left: [f32; 12] = [0.0; 12];
-        let mut out_right: [f32; 12] = [0.0; 12];
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
-        engine.process(&mut out_left, &mut out_right);
+        region.note_on(Note::A4, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 440.0);
 
-        let out: Vec<f32> = out_left.iter().map(|v| (v*100.0).round()/100.0).collect();
-        assert_eq!(out.as_slice(), [0.0, 0.5, 1.0, 1.0, 1.0, 0.65, 0.61, 0.6, 0.6, 0.6, 0.6, 0.6]);
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+        rd.set_pitch_keytrack(-100.0).unwrap();
 
-        let mut out_left: [f32; 4] = [0.0; 4];
-        let mut out_right: [f32; 4] = [0.0; 4];
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-        engine.process(&mut out_left, &mut out_right);
+        region.note_on(Note::A3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 440.0);
 
-        let out: Vec<f32> = out_left.iter().map(|v| (v*10000.0).round()/10000.0).collect();
-        assert_eq!(out.as_slice(), [0.6, 0.6, 0.6, 0.6]);
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+        rd.set_pitch_keytrack(-100.0).unwrap();
 
-        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-        let mut out_left: [f32; 8] = [0.0; 8];
-        let mut out_right: [f32; 8] = [0.0; 8];
+        region.note_on(Note::A4, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 220.0);
 
-        engine.process(&mut out_left, &mut out_right);
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+        rd.set_pitch_keytrack(1200.0).unwrap();
 
-        let rel: Vec<f32> = out_left.iter().map(|v| (v*10000.0).round()/10000.0).collect();
-        assert_eq!(rel.as_slice(), [0.0727, 0.0147, 0.003, 0.0006, 0.0001, 0.0, 0.0, 0.0]);
-    }
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
+        region.note_on(Note::A3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 440.0);
 
-    #[test]
-    fn note_on_velocity() {
-        let sample = vec![1.0, 1.0];
-        let mut region = Region::new(RegionData::default(), sample, 1.0, 1.0, 16);
-        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+        rd.set_pitch_keytrack(1200.0).unwrap();
 
-        let mut out_left: [f32; 1] = [0.0];
-        let mut out_right: [f32; 1] = [0.0];
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-        region.process(&mut out_left, &mut out_right);
-        assert_eq!(out_left[0], 0.24607849215698431397);
-        assert_eq!(out_right[0], 0.24607849215698431397);
+        region.note_on(Note::ASharp3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 880.0);
     }
 
     #[test]
-    fn note_on_gain_veltrack() {
-        let sample = vec![1.0, 1.0];
+    fn tune_frequency() {
+        let samplerate = 48000.0;
+        let nsamples = 96000;
+
         let mut rd = RegionData::default();
-        rd.set_amp_veltrack(0.0).unwrap();
+        rd.pitch_keycenter = Note::A3;
 
-        let mut region = Region::new(rd, sample.clone(), 1.0, 1.0, 16);
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.note_on(Note::A3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 440.0);
 
-        let mut out_left: [f32; 1] = [0.0];
-        let mut out_right: [f32; 1] = [0.0];
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+        rd.tune = 1.0;
 
-        region.process(&mut out_left, &mut out_right);
-        assert_eq!(out_left[0], 1.0);
-        assert_eq!(out_right[0], 1.0);
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
-        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MIN), 0.0);
+        region.note_on(Note::Ab3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 440.0);
 
-        let mut out_left: [f32; 1] = [0.0];
-        let mut out_right: [f32; 1] = [0.0];
+        let mut rd = RegionData::default();
+        rd.pitch_keycenter = Note::A3;
+        rd.tune = -1.0;
 
-        region.process(&mut out_left, &mut out_right);
-        assert_eq!(out_left[0], 1.0);
-        assert_eq!(out_right[0], 1.0);
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+
+        region.note_on(Note::ASharp3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 440.0);
 
         let mut rd = RegionData::default();
-        rd.set_amp_veltrack(-100.0).unwrap();
+        rd.pitch_keycenter = Note::A3;
+        rd.tune = 1.0;
 
-        let mut region = Region::new(rd, sample.clone(), 1.0, 1.0, 16);
+        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
+        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
 
-        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MIN), 0.0);
+        region.note_on(Note::A3, Velocity::MAX);
+        sampletests::assert_frequency(region.sample, samplerate, 466.16);
+    }
 
-        let mut out_left: [f32; 1] = [0.0];
-        let mut out_right: [f32; 1] = [0.0];
+    #[test]
+    fn trigger_rand() {
+        let region_text =
+            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
+                .to_string();
+        let mut engine = Engine::from_region_array(
+            parse_sfz_text(region_text)
+                .unwrap()
+                .iter()
+                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        for i in 0..2 {
+            engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX), 0.0);
+        }
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
 
-        region.process(&mut out_left, &mut out_right);
-        assert_eq!(out_left[0], 1.0);
-        assert_eq!(out_right[0], 1.0);
+        let region_text =
+            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
+                .to_string();
+        let mut engine = Engine::from_region_array(
+            parse_sfz_text(region_text)
+                .unwrap()
+                .iter()
+                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        for i in 0..2 {
+            engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX), 0.5);
+        }
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
 
+        let region_text =
+            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
+                .to_string();
+        let mut engine = Engine::from_region_array(
+            parse_sfz_text(region_text)
+                .unwrap()
+                .iter()
+                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        for i in 0..2 {
+            engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        }
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+        assert!(!engine.regions[1].sample.is_playing());
 
-        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
-        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        let region_text =
+            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
+                .to_string();
+        let mut engine = Engine::from_region_array(
+            parse_sfz_text(region_text)
+                .unwrap()
+                .iter()
+                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        for i in 0..2 {
+            engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.5);
+        }
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(sample::tests::is_playing_note(
+            &engine.regions[1].sample,
+            Note::C3
+        ));
 
-        let mut out_left: [f32; 1] = [0.0];
-        let mut out_right: [f32; 1] = [0.0];
+        let region_text =
+            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
+                .to_string();
+        let mut engine = Engine::from_region_array(
+            parse_sfz_text(region_text)
+                .unwrap()
+                .iter()
+                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        engine.midi_event(&MidiMessage::NoteOn(
+            Channel::Ch1,
+            Note::A3,
+            Velocity::MAX,
+        ));
+        assert!(!engine.regions[0].sample.is_playing() && !engine.regions[1].sample.is_playing());
 
-        region.process(&mut out_left, &mut out_right);
-        assert_eq!(out_left[0], utils::dB_to_gain(-160.0));
-        assert_eq!(out_right[0], utils::dB_to_gain(-160.0));
+        let region_text =
+            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
+                .to_string();
+        let mut engine = Engine::from_region_array(
+            parse_sfz_text(region_text)
+                .unwrap()
+                .iter()
+                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        engine.midi_event(&MidiMessage::NoteOn(
+            Channel::Ch1,
+            Note::C3,
+            Velocity::MAX,
+        ));
+        assert!(
+            sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3)
+                ^ sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3)
+        );
     }
 
     #[test]
-    fn note_on_off_key_range() {
-        let sample = vec![1.0, 1.0,
-                          0.5, 0.5];
-
-        let region = parse_sfz_text("<region> lokey=60 hikey=60".to_string()).unwrap()[0].clone();
-
-        let mut engine =
-            Engine::from_region_array(vec![(region.clone(), sample.clone(), 1.0)], 1.0, 16);
+    fn random_range() {
+        let regions = parse_sfz_text("<region> lorand=0.5 <region> hirand=0.25 <region>".to_string()).unwrap();
+        assert!(!regions[0].random_range.covering(0.25));
+        assert!(regions[0].random_range.covering(0.75));
+        assert!(regions[1].random_range.covering(0.0));
+        assert!(!regions[1].random_range.covering(0.25));
+        assert!(regions[2].random_range.covering(0.0));
+        assert!(regions[2].random_range.covering(0.999));
+
+        assert!(parse_sfz_text("<region> lorand=-0.5".to_string()).is_err());
+        assert!(parse_sfz_text("<region> hirand=1.5".to_string()).is_err());
+        assert!(parse_sfz_text("<region> lorand=0.6 hirand=0.4".to_string()).is_err());
+    }
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX));
+    #[test]
+    fn engine_random_seed() {
+        use crate::testing::{render, sine, FixtureSamples};
+        let samples = FixtureSamples::default()
+            .with("a.wav", vec![sine(440.0, 48000.0, 4800); 2], 48000.0)
+            .with("b.wav", vec![sine(660.0, 48000.0, 4800); 2], 48000.0);
+        let text = "<region> sample=a.wav hirand=0.5 <region> sample=b.wav lorand=0.5";
+        let events: Vec<(usize, MidiMessage<'static>)> = (0..16)
+            .flat_map(|i| vec![(i * 600, MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX)),
+                               (i * 600 + 300, MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX))])
+            .collect();
+        let play = |seed: u64| {
+            let config = EngineConfig { random_seed: Some(seed), ..Default::default() };
+            let mut engine = Engine::from_sfz_text_with_config(text.to_string(), &samples, 48000.0, 64, &config).unwrap();
+            render(&mut engine, &events, 9600, 64)
+        };
+        assert_eq!(play(1), play(1));
+        assert_ne!(play(1), play(2));
 
-        let mut out_left: [f32; 1] = [0.0];
-        let mut out_right: [f32; 1] = [0.0];
+        let mut engine = Engine::from_sfz_text(text.to_string(), &samples, 48000.0, 64).unwrap();
+        engine.set_random_seed(1);
+        assert_eq!(render(&mut engine, &events, 9600, 64), play(1));
+    }
 
-        engine.process(&mut out_left, &mut out_right);
-        assert!(f32_eq(out_left[0], 0.0));
-        assert!(f32_eq(out_right[0], 0.0));
+    #[test]
+    fn engine_offset_random() {
+        let regions = parse_sfz_text("<region> offset_random=50 amp_veltrack=0".to_string()).unwrap();
+        let ramp = (0..100).flat_map(|i| vec![i as f32, i as f32]).collect();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(ramp), 1.0)], 1.0, 1);
+        let mut starts = |seed| {
+            engine.set_random_seed(seed);
+            (0..8).map(|_| {
+                engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+                let mut out_left = [0.0];
+                let mut out_right = [0.0];
+                engine.process(&mut out_left, &mut out_right);
+                engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+                pull_samples_engine(&mut engine, 4);
+                out_left[0]
+            }).collect::<Vec<f32>>()
+        };
+        let first = starts(1);
+        assert!(first.iter().all(|s| *s >= 0.0 && *s <= 50.0 && s.fract() == 0.0), "{:?}", first);
+        assert!(first.iter().any(|s| *s != first[0]));
+        assert_eq!(starts(1), first);
+    }
 
-        let mut engine =
-            Engine::from_region_array(vec![(region.clone(), sample.clone(), 1.0)], 1.0, 16);
+    #[test]
+    fn engine_channel_ranges() {
+        let regions = parse_sfz_text("<region> lochan=10 hichan=10 <region> hichan=9".to_string()).unwrap();
+        assert_eq!(regions[0].opcodes()[1..].to_vec(), vec![("lochan".to_string(), "10".to_string()), ("hichan".to_string(), "10".to_string())]);
+        assert!(!regions[0].overlaps(&regions[1]));
+        let mut engine = Engine::from_region_array(regions.into_iter().map(|rd| (rd, stereo(vec![1.0; 16]), 1.0)).collect(), 1.0, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch10, Note::C3, Velocity::MAX));
+        assert_eq!(engine.regions.iter().map(|r| r.sample.voice_count()).collect::<Vec<_>>(), vec![1, 0]);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch2, Note::D3, Velocity::MAX));
+        assert_eq!(engine.regions.iter().map(|r| r.sample.voice_count()).collect::<Vec<_>>(), vec![1, 1]);
+        assert!(engine.regions[0].sample.has_voice_for(Note::C3));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch11, Note::E3, Velocity::MAX));
+        assert_eq!(engine.voice_count(), 2);
+
+        assert!(parse_sfz_text("<region> lochan=0".to_string()).is_err());
+        assert!(parse_sfz_text("<region> hichan=4 lochan=5".to_string()).is_err());
+    }
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+    #[test]
+    fn engine_tempo_ranges() {
+        let regions = parse_sfz_text("<region> hibpm=100 <region> lobpm=100 hibpm=140.5".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(regions.into_iter().map(|rd| (rd, stereo(vec![1.0; 16]), 1.0)).collect(), 1.0, 1);
+        let voices = |engine: &mut Engine, bpm| {
+            engine.set_tempo(bpm);
+            engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+            let voices = engine.regions.iter().map(|r| r.sample.voice_count()).collect::<Vec<_>>();
+            engine.regions.iter_mut().for_each(|r| r.all_notes_off());
+            pull_samples_engine(engine, 4);
+            voices
+        };
+        assert_eq!(voices(&mut engine, DEFAULT_TEMPO), vec![0, 1]);
+        assert_eq!(voices(&mut engine, 99.9), vec![1, 0]);
+        assert_eq!(voices(&mut engine, 100.0), vec![0, 1]);
+        assert_eq!(voices(&mut engine, 140.5), vec![0, 0]);
 
-        let mut out_left: [f32; 1] = [0.0];
-        let mut out_right: [f32; 1] = [0.0];
+        assert!(parse_sfz_text("<region> hibpm=501".to_string()).is_err());
+    }
 
-        engine.process(&mut out_left, &mut out_right);
-        assert!(f32_eq(out_left[0], 1.0));
-        assert!(f32_eq(out_right[0], 1.0));
+    #[test]
+    fn engine_humanize_random() {
+        let regions = parse_sfz_text("<region> amp_random=6 pitch_random=100 delay_random=0.5 amp_veltrack=0".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(vec![1.0; 64]), 16.0)], 16.0, 16);
+        let mut notes = |seed| {
+            engine.set_random_seed(seed);
+            (0..8).map(|_| {
+                engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+                let mut out_left = [0.0; 16];
+                let mut out_right = [0.0; 16];
+                engine.process(&mut out_left, &mut out_right);
+                engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+                pull_samples_engine(&mut engine, 64);
+                let delay = out_left.iter().position(|s| *s != 0.0).unwrap();
+                (delay, out_left[delay + 1])
+            }).collect::<Vec<(usize, f32)>>()
+        };
+        let first = notes(1);
+        assert!(first.iter().all(|(delay, gain)| *delay <= 8 && *gain >= 1.0 && *gain <= 2.0), "{:?}", first);
+        assert!(first.iter().any(|note| note.0 != first[0].0));
+        assert!(first.iter().any(|note| note.1 != first[0].1));
+        assert_eq!(notes(1), first);
+    }
 
-        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::A3, Velocity::MAX));
+    #[test]
+    fn region_pitch_random() {
+        let regions = parse_sfz_text("<region> pitch_random=100".to_string()).unwrap();
+        let mut region = Region::new(regions[0].clone(), stereo(vec![1.0; 4]), 1.0, 1.0, 1);
+        let mut rng = StdRng::seed_from_u64(3);
+        let cents: Vec<f32> = (0..16).map(|_| {
+            region.randomize(&mut rng);
+            region.random_cents
+        }).collect();
+        assert!(cents.iter().all(|c| c.abs() <= 100.0), "{:?}", cents);
+        assert!(cents.iter().any(|c| *c < 0.0) && cents.iter().any(|c| *c > 0.0));
+    }
 
-        let mut out_left: [f32; 1] = [0.0];
-        let mut out_right: [f32; 1] = [0.0];
+    fn pull_samples_engine(engine: &mut Engine, nsamples: usize) {
+        let mut out_left = Vec::new();
+        out_left.resize(nsamples, 0.0);
+        let mut out_right = Vec::new();
+        out_right.resize(nsamples, 0.0);
 
         engine.process(&mut out_left, &mut out_right);
-        assert!(f32_eq(out_left[0], 0.5));
-        assert!(f32_eq(out_right[0], 0.5));
     }
 
     #[test]
-    fn pitch_keytrack_frequency() {
-        let samplerate = 48000.0;
-        let nsamples = 96000;
-
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
-
-        region.note_on(Note::A3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 440.0);
-
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
-
-        region.note_on(Note::A4, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 880.0);
-
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.set_pitch_keytrack(0.0).unwrap();
-
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
-
-        region.note_on(Note::A3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 440.0);
-
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.set_pitch_keytrack(0.0).unwrap();
-
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
-
-        region.note_on(Note::A4, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 440.0);
-
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.set_pitch_keytrack(-100.0).unwrap();
-
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+    fn note_on_off_multiple_regions_key() {
+        let region_text = "
+<region> lokey=a3 hikey=a3 pitch_keycenter=57
+<region> lokey=60 hikey=60 pitch_keycenter=60
+<region> lokey=58 hikey=60 pitch_keycenter=60
+<region> lokey=60 hikey=62 pitch_keycenter=61
+"
+        .to_string();
+        let regions = parse_sfz_text(region_text).unwrap();
 
-        region.note_on(Note::A3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 440.0);
+        let mut engine = Engine::from_region_array(regions.iter().map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0)).collect(), 1.0, 1);
 
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.set_pitch_keytrack(-100.0).unwrap();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A1, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
 
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A2, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::A2));
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
 
-        region.note_on(Note::A4, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 220.0);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::Db3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::A2));
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
 
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.set_pitch_keytrack(1200.0).unwrap();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::A2));
+        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
 
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::A2, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
 
-        region.note_on(Note::A3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 440.0);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::Db3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
+        assert!(!sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
 
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.set_pitch_keytrack(1200.0).unwrap();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::B2, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::B2));
+        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
+        assert!(!sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
 
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::B2));
+        assert!(!engine.regions[3].sample.is_playing());
 
-        region.note_on(Note::ASharp3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 880.0);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::B2, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
     }
 
     #[test]
-    fn tune_frequency() {
-        let samplerate = 48000.0;
-        let nsamples = 96000;
-
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
-
-        region.note_on(Note::A3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 440.0);
-
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.tune = 1.0;
+    fn note_on_off_multiple_regions_vel() {
+        let region_text = "
+<region> lovel=30 hivel=30 amp_veltrack=0
+<region> lovel=50 hivel=50 amp_veltrack=0
+<region> lovel=40 hivel=50 amp_veltrack=0
+<region> lovel=50 hivel=60 amp_veltrack=0
+".to_string();
 
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let regions = parse_sfz_text(region_text).unwrap();
 
-        region.note_on(Note::Ab3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 440.0);
+        let mut engine = Engine::from_region_array(regions.iter().map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0)).collect(), 1.0, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(20).unwrap()));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
 
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.tune = -1.0;
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(30).unwrap()));
+        pull_samples_engine(&mut engine, 1);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
 
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(55).unwrap()));
+        pull_samples_engine(&mut engine, 1);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
 
-        region.note_on(Note::ASharp3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 440.0);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(50).unwrap()));
+        pull_samples_engine(&mut engine, 1);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
 
-        let mut rd = RegionData::default();
-        rd.pitch_keycenter = Note::A3;
-        rd.tune = 1.0;
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MIN));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
 
-        let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(45).unwrap()));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
+        assert!(!engine.regions[3].sample.is_playing());
 
-        region.note_on(Note::A3, Velocity::MAX);
-        sampletests::assert_frequency(region.sample, samplerate, 466.16);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MIN));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
     }
 
     #[test]
-    fn trigger_rand() {
-        let region_text =
-            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
-                .to_string();
+    fn region_group() {
+        let region_text = "
+<region> key=a3
+<region> key=b3 group=1
+<region> key=c4 group=2
+<region> key=d4 off_by=2
+<region> key=e4 group=1
+"
+        .to_string();
+
+        let regions = parse_sfz_text(region_text).unwrap();
+
         let mut engine = Engine::from_region_array(
-            parse_sfz_text(region_text)
-                .unwrap()
+            regions
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
                 .collect(),
             1.0,
             1,
         );
-        for i in 0..2 {
-            engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX), 0.0);
-        }
-        assert!(!engine.regions[0].sample.is_playing());
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A2, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
+        assert!(!engine.regions[4].sample.is_playing());
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(engine.regions[0].sample.is_playing());
         assert!(!engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(engine.regions[3].sample.is_playing());
+        assert!(!engine.regions[4].sample.is_playing());
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::B2, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(engine.regions[0].sample.is_playing());
+        assert!(engine.regions[1].sample.is_playing());
+        assert!(!engine.regions[2].sample.is_playing());
+        assert!(engine.regions[3].sample.is_playing());
+        assert!(!engine.regions[4].sample.is_playing());
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(engine.regions[0].sample.is_playing());
+        assert!(engine.regions[1].sample.is_playing());
+        assert!(engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
+        assert!(!engine.regions[4].sample.is_playing());
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::E3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(engine.regions[0].sample.is_playing());
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(engine.regions[2].sample.is_playing());
+        assert!(!engine.regions[3].sample.is_playing());
+        assert!(engine.regions[4].sample.is_playing());
+    }
+
+    #[test]
+    fn note_fold_and_transpose() {
+        let region_text = "
+<region> key=c4
+"
+        .to_string();
+
+        let regions = parse_sfz_text(region_text).unwrap();
 
-        let region_text =
-            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
-                .to_string();
         let mut engine = Engine::from_region_array(
-            parse_sfz_text(region_text)
-                .unwrap()
+            regions
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
                 .collect(),
             1.0,
             1,
         );
-        for i in 0..2 {
-            engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX), 0.5);
-        }
+        engine.set_note_fold(Some((Note::C3, Note::B3)));
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C5, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(sampletests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        engine.set_note_fold(None);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C5, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!sampletests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        engine.set_transpose(-12);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C4, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(sampletests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        engine.set_transpose(0);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C4, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert!(!sampletests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        engine.set_note_fold(Some((Note::C3, Note::E3)));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::G3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
         assert!(!engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
+    }
+
+    #[test]
+    fn max_voices_steals_oldest() {
+        let region_text = "
+<region> lokey=c3 hikey=c5 ampeg_release=10
+"
+        .to_string();
+
+        let regions = parse_sfz_text(region_text).unwrap();
 
-        let region_text =
-            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
-                .to_string();
         let mut engine = Engine::from_region_array(
-            parse_sfz_text(region_text)
-                .unwrap()
+            regions
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
                 .collect(),
             1.0,
             1,
         );
-        for i in 0..2 {
-            engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
-        }
-        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
-        assert!(!engine.regions[1].sample.is_playing());
+        engine.set_max_voices(Some(2));
 
-        let region_text =
-            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
-                .to_string();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::E3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+
+        let sample = &engine.regions[0].sample;
+        assert_eq!(sample.voice_count(), 2);
+        assert!(!sampletests::is_playing_note(sample, Note::C3));
+        assert!(sampletests::is_playing_note(sample, Note::D3));
+        assert!(sampletests::is_playing_note(sample, Note::E3));
+
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::E3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::F3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+
+        let sample = &engine.regions[0].sample;
+        assert_eq!(sample.voice_count(), 2);
+        assert!(sampletests::is_playing_note(sample, Note::D3));
+        assert!(!sampletests::is_releasing_note(sample, Note::E3));
+        assert!(sampletests::is_playing_note(sample, Note::F3));
+    }
+
+    #[test]
+    fn engine_midi_filter() {
+        let regions = parse_sfz_text("<region> lokey=60 hikey=72 hivel=63 locc1=64".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(vec![1.0; 96]), 1.0)], 1.0, 1);
+        engine.set_midi_filter(MidiFilter {
+            channel_pressure_cc: Some(1),
+            velocity_curve: VelocityCurve::Gamma(2.0),
+            ..Default::default()
+        });
+        engine.midi_event(&MidiMessage::ChannelPressure(Channel::Ch1, ControlValue::try_from(100).unwrap()));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(80).unwrap()));
+        pull_samples_engine(&mut engine, 1);
+        assert!(engine.regions[0].sample.is_playing());
+
+        let regions = parse_sfz_text("<region> lokey=60 hikey=72 lovel=90".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), stereo(vec![1.0; 96]), 1.0)], 1.0, 1);
+        engine.set_velocity_curve(VelocityCurve::Fixed(100));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(10).unwrap()));
+        pull_samples_engine(&mut engine, 1);
+        assert!(engine.regions[0].sample.is_playing());
+    }
+
+    #[test]
+    fn engine_release_rt_dead() {
+        let region_text = "
+<region> key=60
+<group> trigger=release
+<region> key=60
+<region> key=60 rt_dead=1
+<region> key=62
+"
+        .to_string();
+        let regions = parse_sfz_text(region_text).unwrap();
+        assert!(regions[2].rt_dead);
         let mut engine = Engine::from_region_array(
-            parse_sfz_text(region_text)
-                .unwrap()
-                .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
-                .collect(),
+            regions.iter().map(|reg| (reg.clone(), stereo(vec![1.0; 16]), 1.0)).collect(),
             1.0,
             1,
         );
-        for i in 0..2 {
-            engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.5);
-        }
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        assert!(engine.regions[1].sample.is_playing());
+        assert!(engine.regions[2].sample.is_playing());
+        pull_samples_engine(&mut engine, 20);
+        assert!(!engine.regions.iter().any(|r| r.sample.is_playing()));
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 20);
         assert!(!engine.regions[0].sample.is_playing());
-        assert!(sample::tests::is_playing_note(
-            &engine.regions[1].sample,
-            Note::C3
-        ));
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::D3, Velocity::MAX));
+        assert!(!engine.regions[1].sample.is_playing());
+        assert!(engine.regions[2].sample.is_playing());
+        assert!(engine.regions[3].sample.is_playing());
+    }
+
+    #[test]
+    fn engine_keyswitches() {
+        let region_text = "
+<group> sw_lokey=24 sw_hikey=26 sw_default=24
+<region> sw_last=24 sw_label=Sustain lokey=60 hikey=72
+<region> sw_last=25 sw_label=Staccato lokey=60 hikey=72
+<region> sw_last=26 lokey=60 hikey=72
+<group>
+<region> lokey=60 hikey=72
+"
+        .to_string();
+
+        let regions = parse_sfz_text(region_text).unwrap();
+        assert_eq!(regions[0].sw_lokey, Some(24));
+        assert_eq!(regions[0].sw_hikey, Some(26));
+        assert_eq!(regions[1].sw_label.as_deref(), Some("Staccato"));
+        assert_eq!(regions[3].sw_last, None);
 
-        let region_text =
-            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
-                .to_string();
         let mut engine = Engine::from_region_array(
-            parse_sfz_text(region_text)
-                .unwrap()
+            regions
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
                 .collect(),
             1.0,
             1,
         );
-        engine.midi_event(&MidiMessage::NoteOn(
-            Channel::Ch1,
-            Note::A3,
-            Velocity::MAX,
-        ));
-        assert!(!engine.regions[0].sample.is_playing() && !engine.regions[1].sample.is_playing());
+        assert_eq!(engine.keyswitch(), Some(24));
+        assert_eq!(engine.keyswitch_label(), Some("Sustain"));
+        assert_eq!(engine.keyswitch_labels(), vec![(24, "Sustain".to_string()), (25, "Staccato".to_string())]);
+
+        let playing = |engine: &Engine| engine.regions.iter().map(|r| r.sample.is_playing()).collect::<Vec<_>>();
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert_eq!(playing(&engine), vec![true, false, false, true]);
 
-        let region_text =
-            "<region> key=c4 lorand=0.0 hirand=0.5 <region> key=c4 lorand=0.5 hirand=1.0"
-                .to_string();
         let mut engine = Engine::from_region_array(
-            parse_sfz_text(region_text)
-                .unwrap()
+            regions
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
                 .collect(),
             1.0,
             1,
         );
-        engine.midi_event(&MidiMessage::NoteOn(
-            Channel::Ch1,
-            Note::C3,
-            Velocity::MAX,
-        ));
-        assert!(
-            sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3)
-                ^ sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3)
-        );
-    }
-
-    fn pull_samples_engine(engine: &mut Engine, nsamples: usize) {
-        let mut out_left = Vec::new();
-        out_left.resize(nsamples, 0.0);
-        let mut out_right = Vec::new();
-        out_right.resize(nsamples, 0.0);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::try_from(25).unwrap(), Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::try_from(25).unwrap(), Velocity::MAX));
+        assert_eq!(engine.keyswitch(), Some(25));
+        assert_eq!(engine.keyswitch_label(), Some("Staccato"));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        assert_eq!(playing(&engine), vec![false, true, false, true]);
 
-        engine.process(&mut out_left, &mut out_right);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::try_from(26).unwrap(), Velocity::MAX));
+        assert_eq!(engine.keyswitch_label(), None);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::try_from(30).unwrap(), Velocity::MAX));
+        assert_eq!(engine.keyswitch(), Some(26));
     }
 
     #[test]
-    fn note_on_off_multiple_regions_key() {
-        let region_text = "
-<region> lokey=a3 hikey=a3 pitch_keycenter=57
-<region> lokey=60 hikey=60 pitch_keycenter=60
-<region> lokey=58 hikey=60 pitch_keycenter=60
-<region> lokey=60 hikey=62 pitch_keycenter=61
-"
-        .to_string();
-        let regions = parse_sfz_text(region_text).unwrap();
+    fn engine_sw_vel_previous() {
+        let regions = parse_sfz_text("
+<region> sw_vel=previous lovel=1 hivel=63 lokey=60 hikey=60
+<region> sw_vel=current lovel=1 hivel=63 lokey=62 hikey=62
+".to_string()).unwrap();
+        assert!(regions[0].sw_vel_previous);
+        assert!(!regions[1].sw_vel_previous);
 
-        let mut engine = Engine::from_region_array(regions.iter().map(|reg| (reg.clone(), vec![1.0; 96], 1.0)).collect(), 1.0, 1);
-
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A1, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
+        let mut engine = Engine::from_region_array(
+            regions
+                .iter()
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        let note_on = |key: u8, vel: u8| MidiMessage::NoteOn(Channel::Ch1, Note::try_from(key).unwrap(), Velocity::try_from(vel).unwrap());
+
+        // the first note uses its own velocity
+        engine.midi_event(&note_on(60, 100));
         assert!(!engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A2, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
-        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::A2));
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
+        engine.midi_event(&note_on(62, 40));
+        engine.midi_event(&note_on(60, 100));
+        assert!(engine.regions[0].sample.is_playing());
+        assert!(engine.regions[1].sample.is_playing());
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::Db3, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
-        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::A2));
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
+        engine.midi_event(&note_on(62, 100));
+        assert_eq!(engine.regions[1].sample.voice_count(), 1);
+    }
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
-        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::A2));
-        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
+    #[test]
+    fn engine_observer() {
+        let region_text = "
+<region> lokey=60 hikey=72 ampeg_release=10
+<region> key=60 trigger=release rt_dead=1
+"
+        .to_string();
 
-        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::A2, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
+        let regions = parse_sfz_text(region_text).unwrap();
 
-        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::Db3, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
-        assert!(!sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
+        let mut engine = Engine::from_region_array(
+            regions
+                .iter()
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        engine.set_max_voices(Some(1));
+        let queue = observer::EventQueue::new(16);
+        engine.set_observer(Some(Box::new(queue.clone())));
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::B2, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
         pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::B2));
-        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
-        assert!(!sample::tests::is_playing_note(&engine.regions[3].sample, Note::Db3));
-
         engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
         pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::B2));
-        assert!(!engine.regions[3].sample.is_playing());
 
-        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::B2, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
+        use observer::EngineEvent::*;
+        assert_eq!(queue.drain(), vec![
+            NoteOn(Note::C3, Velocity::MAX),
+            RegionTriggered(0),
+            NoteOn(Note::D3, Velocity::MAX),
+            RegionTriggered(0),
+            VoiceStolen(0),
+            NoteOff(Note::C3, Velocity::MAX),
+            RegionTriggered(1),
+            VoiceStolen(0),
+        ]);
+        assert!(queue.drain().is_empty());
+
+        engine.set_observer(None);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        assert!(queue.drain().is_empty());
     }
 
     #[test]
-    fn note_on_off_multiple_regions_vel() {
+    fn quality_scaling_hysteresis() {
         let region_text = "
-<region> lovel=30 hivel=30 amp_veltrack=0
-<region> lovel=50 hivel=50 amp_veltrack=0
-<region> lovel=40 hivel=50 amp_veltrack=0
-<region> lovel=50 hivel=60 amp_veltrack=0
-".to_string();
+<region> lokey=c3 hikey=c5 ampeg_release=1
+"
+        .to_string();
 
         let regions = parse_sfz_text(region_text).unwrap();
 
-        let mut engine = Engine::from_region_array(regions.iter().map(|reg| (reg.clone(), vec![1.0; 96], 1.0)).collect(), 1.0, 1);
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(20).unwrap()));
-        pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
-
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(30).unwrap()));
-        pull_samples_engine(&mut engine, 1);
-        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
+        let mut engine = Engine::from_region_array(
+            regions
+                .iter()
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        engine.set_quality_scaling(Some(QualityScaling { high_watermark: 2, low_watermark: 1 }));
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(55).unwrap()));
+        let notes = [Note::C3, Note::D3, Note::E3];
+        for note in &notes[..2] {
+            engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, *note, Velocity::MAX));
+        }
         pull_samples_engine(&mut engine, 1);
-        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
+        assert_eq!(engine.interpolation, sample::Interpolation::Cubic);
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(50).unwrap()));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, notes[2], Velocity::MAX));
         pull_samples_engine(&mut engine, 1);
-        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[1].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
-        assert!(sample::tests::is_playing_note(&engine.regions[3].sample, Note::C3));
+        assert_eq!(engine.interpolation, sample::Interpolation::Linear);
+        assert_eq!(engine.regions[0].sample.voice_count(), 3);
 
-        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MIN));
+        engine.regions[0].sample.note_off(notes[2]);
+        for _ in 0..4 {
+            pull_samples_engine(&mut engine, 1);
+        }
+        assert_eq!(engine.regions[0].sample.voice_count(), 2);
         pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
+        assert_eq!(engine.interpolation, sample::Interpolation::Linear);
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(45).unwrap()));
+        engine.regions[0].sample.note_off(notes[1]);
+        for _ in 0..4 {
+            pull_samples_engine(&mut engine, 1);
+        }
+        assert_eq!(engine.regions[0].sample.voice_count(), 1);
         pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(sample::tests::is_playing_note(&engine.regions[2].sample, Note::C3));
-        assert!(!engine.regions[3].sample.is_playing());
+        assert_eq!(engine.interpolation, sample::Interpolation::Cubic);
 
-        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MIN));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, notes[1], Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, notes[2], Velocity::MAX));
         pull_samples_engine(&mut engine, 1);
-        assert!(!engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
+        assert_eq!(engine.interpolation, sample::Interpolation::Linear);
+        engine.set_quality_scaling(None);
+        assert_eq!(engine.interpolation, sample::Interpolation::Cubic);
     }
 
     #[test]
-    fn region_group() {
+    fn region_choked_by_keys() {
         let region_text = "
-<region> key=a3
-<region> key=b3 group=1
-<region> key=c4 group=2
-<region> key=d4 off_by=2
-<region> key=e4 group=1
+<region> key=62 off_lokey=58 off_hikey=60 off_mode=time off_time=4
+<region> key=58
+<region> key=60
 "
         .to_string();
 
         let regions = parse_sfz_text(region_text).unwrap();
+        assert_eq!(regions[0].off_mode, OffMode::Time);
+        assert_eq!(regions[1].off_mode, OffMode::Fast);
 
         let mut engine = Engine::from_region_array(
             regions
                 .iter()
-                .map(|reg| (reg.clone(), vec![1.0; 96], 1.0))
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
                 .collect(),
             1.0,
             1,
         );
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A2, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
         pull_samples_engine(&mut engine, 1);
         assert!(engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
-        assert!(!engine.regions[4].sample.is_playing());
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 2);
         assert!(engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(engine.regions[3].sample.is_playing());
-        assert!(!engine.regions[4].sample.is_playing());
+        assert!(engine.regions[2].sample.is_playing());
+        pull_samples_engine(&mut engine, 2);
+        assert!(!engine.regions[0].sample.is_playing());
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::B2, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::CSharp3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 8);
         assert!(engine.regions[0].sample.is_playing());
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::ASharp2, Velocity::MAX));
+        pull_samples_engine(&mut engine, 4);
+        assert!(!engine.regions[0].sample.is_playing());
         assert!(engine.regions[1].sample.is_playing());
-        assert!(!engine.regions[2].sample.is_playing());
-        assert!(engine.regions[3].sample.is_playing());
-        assert!(!engine.regions[4].sample.is_playing());
+    }
+
+    #[test]
+    fn activity_log_triggered_and_choked() {
+        let region_text = "
+<region> key=a3 group=1
+<region> key=a3
+<region> key=b3 off_by=1
+"
+        .to_string();
+
+        let regions = parse_sfz_text(region_text).unwrap();
+
+        let mut engine = Engine::from_region_array(
+            regions
+                .iter()
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        let log = ActivityLog::shared(2);
+        engine.set_activity_log(Some(log.clone()));
 
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::B2, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A2, Velocity::MAX));
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
-        assert!(engine.regions[0].sample.is_playing());
-        assert!(engine.regions[1].sample.is_playing());
-        assert!(engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
-        assert!(!engine.regions[4].sample.is_playing());
 
-        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::E3, Velocity::MAX));
-        pull_samples_engine(&mut engine, 1);
-        assert!(engine.regions[0].sample.is_playing());
-        assert!(!engine.regions[1].sample.is_playing());
-        assert!(engine.regions[2].sample.is_playing());
-        assert!(!engine.regions[3].sample.is_playing());
-        assert!(engine.regions[4].sample.is_playing());
+        let log = log.lock().unwrap();
+        let entries: Vec<&ActivityEntry> = log.entries().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].triggered.indices(), &[0, 1]);
+        assert_eq!(entries[0].choked.indices(), &[2]);
+        assert!(entries[1].triggered.is_empty());
+        assert!(entries[1].choked.is_empty());
+    }
+
+    #[test]
+    fn region_ids_stable_across_reparse() {
+        let region_text = "
+<region> key=a3 sample=a.wav
+<region> key=a3 sample=a.wav
+<region> key=b3 sample=b.wav group=1 on_locc64=64 on_hicc64=127
+<region> lokey=c4 hikey=e4 sample=c.wav
+"
+        .to_string();
+        let ids = region_ids(parse_sfz_text(region_text.clone()).unwrap().iter());
+        assert_eq!(ids, region_ids(parse_sfz_text(region_text).unwrap().iter()));
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(ids[0].to_string().parse::<RegionId>(), Ok(ids[0]));
+
+        let edited_text = "
+<region> lokey=c4 hikey=e4 sample=c.wav
+<region> key=a3 sample=a.wav
+<region> key=b3 sample=b.wav group=1 on_locc64=64 on_hicc64=127 volume=-3
+<region> key=a3 sample=a.wav
+<region> key=a3 sample=a.wav
+"
+        .to_string();
+        let edited_ids = region_ids(parse_sfz_text(edited_text).unwrap().iter());
+        assert_eq!(edited_ids[0], ids[3]);
+        assert_eq!(edited_ids[1], ids[0]);
+        assert_eq!(edited_ids[2], ids[2]);
+        assert_eq!(edited_ids[3], ids[1]);
+        assert!(!ids.contains(&edited_ids[4]));
+    }
+
+    #[test]
+    fn region_index_by_id() {
+        let region_text = "
+<region> key=a3 sample=a.wav
+<region> key=b3 sample=b.wav
+"
+        .to_string();
+        let regions = parse_sfz_text(region_text).unwrap();
+        let engine = Engine::from_region_array(
+            regions
+                .iter()
+                .map(|reg| (reg.clone(), stereo(vec![1.0; 96]), 1.0))
+                .collect(),
+            1.0,
+            1,
+        );
+        let id = engine.region_id(1).unwrap();
+        assert_eq!(engine.region_index(id), Some(1));
+        assert_eq!(engine.region_id(2), None);
     }
 
     #[test]
@@ -3082,7 +8312,7 @@ mod tests {
     fn test_unreasonable_process_calls_zero_length_buffer() {
         let sample = vec![0.1, -0.1];
         let mut engine =
-            Engine::from_region_array(vec![(RegionData::default(), sample, 1.0)], 1.0, 16);
+            Engine::from_region_array(vec![(RegionData::default(), stereo(sample), 1.0)], 1.0, 16);
 
         let mut out_left = Vec::new();
         let mut out_right = Vec::new();
@@ -3092,6 +8322,123 @@ mod tests {
         engine.process(&mut out_left, &mut out_right);
     }
 
+    #[test]
+    fn engine_crossfade_out() {
+        let mut sample = Vec::new();
+        sample.resize(1024, 1.0);
+
+        let mut engine = Engine::from_region_array(vec![(RegionData::default(), stereo(sample), 100.0)], 100.0, 8);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 8);
+
+        engine.crossfade_out(0.1);
+        assert!(!engine.fadeout_finished());
+        let mut out_left = vec![0.0; 12];
+        let mut out_right = vec![0.0; 12];
+        engine.process(&mut out_left, &mut out_right);
+        assert!(sampletests::is_playing_note(&engine.regions[0].sample, Note::C3));
+        for (i, v) in out_left[..10].iter().enumerate() {
+            assert!(f32_eq(*v, out_left[0] * (10 - i) as f32 / 10.0));
+        }
+        assert_eq!(out_left[10..], [0.0, 0.0]);
+        assert!(engine.fadeout_finished());
+
+        let mut engine = Engine::from_region_array(vec![(RegionData::default(), stereo(vec![1.0; 1024]), 100.0)], 100.0, 8);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.crossfade_out(0.0);
+        assert!(sampletests::is_releasing_note(&engine.regions[0].sample, Note::C3));
+    }
+
+    #[test]
+    fn engines_share_instrument() {
+        let mut engine = Engine::from_region_array(vec![(RegionData::default(), stereo(vec![1.0; 1024]), 100.0)], 100.0, 8);
+        let mut other = Engine::from_instrument(engine.instrument().clone(), 100.0, 16);
+        assert_eq!(Arc::strong_count(engine.instrument()), 2);
+        assert_eq!(other.instrument().region_count(), 1);
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let mut out_left = vec![0.0; 16];
+        let mut out_right = vec![0.0; 16];
+        other.process(&mut out_left, &mut out_right);
+        assert!(out_left.iter().all(|v| *v == 0.0));
+
+        other.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        other.process(&mut out_left, &mut out_right);
+        assert!(out_left.iter().all(|v| *v > 0.0));
+        assert_eq!(engine.voice_count(), 1);
+        assert_eq!(other.voice_count(), 1);
+
+        drop(engine);
+        assert_eq!(Arc::strong_count(other.instrument()), 1);
+    }
+
+    #[test]
+    fn engine_midi_event_at() {
+        let mut engine = Engine::from_region_array(vec![(RegionData::default(), stereo(vec![1.0; 1024]), 100.0)], 100.0, 8);
+        engine.midi_event_at(14, &MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        engine.midi_event_at(5, &MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+
+        let mut out_left = vec![0.0; 10];
+        let mut out_right = vec![0.0; 10];
+        engine.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left[..5], [0.0; 5]);
+        assert!(out_left[5..].iter().all(|v| *v > 0.0));
+        assert!(sampletests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        let mut out_left = vec![0.0; 3];
+        let mut out_right = vec![0.0; 3];
+        engine.process(&mut out_left, &mut out_right);
+        assert!(sampletests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        pull_samples_engine(&mut engine, 2);
+        assert!(!sampletests::is_playing_note(&engine.regions[0].sample, Note::C3));
+        assert!(engine.event_queue.is_empty());
+    }
+
+    #[test]
+    fn engine_diagnostics() {
+        let regions = parse_sfz_text("<region> key=60 cutoff=1000 <region> key=62 <region> key=64".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(
+            regions.iter().map(|reg| {
+                // the filter decays into denormals after the impulse
+                let mut sample = vec![0.0; 4000];
+                sample[0] = 1.0;
+                (reg.clone(), stereo(sample), 1000.0)
+            }).collect(),
+            1000.0,
+            8,
+        );
+        assert!(engine.drain_diagnostics().is_empty());
+        let diagnostics = Diagnostics::shared(16);
+        engine.set_diagnostics(Some(diagnostics.clone()));
+        engine.set_max_voices(Some(1));
+        engine.regions[2].pending = true;
+
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        pull_samples_engine(&mut engine, 1);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::E3, Velocity::MAX));
+        assert_eq!(diagnostics.drain(), vec![Diagnostic::VoiceStolen { region: 0 }]);
+        pull_samples_engine(&mut engine, 8);
+        assert_eq!(engine.drain_diagnostics(), vec![Diagnostic::SampleNotLoaded { region: 2 }]);
+
+        engine.set_max_voices(None);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        let blocks = (0..1000).take_while(|_| {
+            pull_samples_engine(&mut engine, 8);
+            diagnostics.is_empty()
+        }).count();
+        assert!(blocks < 1000);
+        assert_eq!(diagnostics.drain(), vec![Diagnostic::DenormalsFlushed { region: 0 }]);
+
+        for _ in 0..=engine.event_queue.capacity() {
+            engine.midi_event_at(8, &MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX));
+        }
+        assert_eq!(engine.event_queue.len(), engine.event_queue.capacity());
+        assert_eq!(diagnostics.drain(), vec![Diagnostic::EventQueueOverflow]);
+        assert_eq!(diagnostics.lost(), 0);
+    }
+
     #[test]
     fn engine_fade_out() {
         let mut sample = Vec::new();
@@ -3100,7 +8447,7 @@ mod tests {
         let mut rd = RegionData::default();
         rd.ampeg.set_release(0.2).unwrap();
 
-        let mut engine = Engine::from_region_array(vec![(rd, sample, 100.0)], 100.0, 24);
+        let mut engine = Engine::from_region_array(vec![(rd, stereo(sample), 100.0)], 100.0, 24);
 
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
 
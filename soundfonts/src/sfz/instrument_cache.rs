@@ -0,0 +1,131 @@
+// Process wide cache of the loaded instruments, so that several plugin
+// instances playing the same SFZ file keep its samples in memory only once.
+// The cache holds weak references, an instrument is freed as soon as the
+// last engine playing it is dropped.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+
+use super::archive;
+use super::engine::{EngineConfig, EngineError, InstrumentData, SampleStorage};
+
+#[derive(Debug, PartialEq)]
+struct CacheKey {
+    path: PathBuf,
+    // the file is loaded again when it has been changed
+    modified: Option<SystemTime>,
+    samplerate: u64,
+    trim_silence_threshold: Option<u32>,
+    strict: bool,
+    load_threads: Option<usize>,
+    sample_storage: SampleStorage,
+    memory_limit: Option<usize>,
+    pitch_variants: Option<u32>,
+    random_seed: Option<u64>,
+}
+
+impl CacheKey {
+    fn new(sfz_file: &str, host_samplerate: f64, config: &EngineConfig) -> CacheKey {
+        let (file, path) = match archive::split_path(sfz_file) {
+            Some((archive, entry)) => {
+                let mut path = canonical(archive).into_os_string();
+                path.push("#");
+                path.push(entry);
+                (archive, PathBuf::from(path))
+            }
+            None => (sfz_file, canonical(sfz_file)),
+        };
+        CacheKey {
+            path,
+            modified: fs::metadata(file).and_then(|m| m.modified()).ok(),
+            samplerate: host_samplerate.to_bits(),
+            trim_silence_threshold: config.trim_silence_threshold.map(f32::to_bits),
+            strict: config.strict,
+            load_threads: config.load_threads,
+            sample_storage: config.sample_storage,
+            memory_limit: config.memory_limit,
+            pitch_variants: config.pitch_variants,
+            random_seed: config.random_seed,
+        }
+    }
+}
+
+fn canonical(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| Path::new(path).to_path_buf())
+}
+
+static INSTRUMENTS: Mutex<Vec<(CacheKey, Weak<InstrumentData>)>> = Mutex::new(Vec::new());
+
+/// The instrument of `sfz_file` at `host_samplerate` if it is still used by
+/// an engine, otherwise the one returned by `load`. Other instruments are
+/// not loaded at the same time, so that an instrument requested again while
+/// it is being loaded is loaded only once.
+pub(super) fn instrument<F>(sfz_file: &str,
+                            host_samplerate: f64,
+                            config: &EngineConfig,
+                            load: F) -> Result<Arc<InstrumentData>, EngineError>
+where
+    F: FnOnce() -> Result<InstrumentData, EngineError>,
+{
+    let key = CacheKey::new(sfz_file, host_samplerate, config);
+    let mut instruments = INSTRUMENTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    instruments.retain(|(_, instrument)| instrument.strong_count() > 0);
+    if let Some(instrument) = instruments.iter().find(|(k, _)| *k == key).and_then(|(_, i)| i.upgrade()) {
+        return Ok(instrument);
+    }
+    let instrument = Arc::new(load()?);
+    instruments.push((key, Arc::downgrade(&instrument)));
+    Ok(instrument)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_instrument() -> Result<InstrumentData, EngineError> {
+        Ok(InstrumentData::new(Vec::new(), 16, &EngineConfig::default()))
+    }
+
+    #[test]
+    fn share_loaded_instruments() {
+        let dir = std::env::temp_dir().join(format!("sonarigo-instrument-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let sfz_file = dir.join("piano.sfz");
+        fs::write(&sfz_file, "<region> sample=a.wav").unwrap();
+        let sfz_file = sfz_file.to_string_lossy().to_string();
+        let config = EngineConfig::default();
+
+        let first = instrument(&sfz_file, 48000.0, &config, empty_instrument).unwrap();
+        let same = instrument(&sfz_file, 48000.0, &config, || panic!("loaded twice")).unwrap();
+        assert!(Arc::ptr_eq(&first, &same));
+
+        let other_samplerate = instrument(&sfz_file, 44100.0, &config, empty_instrument).unwrap();
+        assert!(!Arc::ptr_eq(&first, &other_samplerate));
+
+        let trimmed = EngineConfig { trim_silence_threshold: Some(-90.0), ..Default::default() };
+        let trimmed = instrument(&sfz_file, 48000.0, &trimmed, empty_instrument).unwrap();
+        assert!(!Arc::ptr_eq(&first, &trimmed));
+
+        let limited = EngineConfig { memory_limit: Some(1 << 20), ..Default::default() };
+        let limited = instrument(&sfz_file, 48000.0, &limited, empty_instrument).unwrap();
+        assert!(!Arc::ptr_eq(&first, &limited));
+
+        let failed = instrument(&format!("{}/missing.sfz", dir.display()), 48000.0, &config, || {
+            Err(EngineError::SampleReadError { path: "missing.sfz".to_string() })
+        });
+        assert!(failed.is_err());
+
+        drop(first);
+        drop(same);
+        let mut loaded = false;
+        let _reloaded = instrument(&sfz_file, 48000.0, &config, || {
+            loaded = true;
+            empty_instrument()
+        }).unwrap();
+        assert!(loaded);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
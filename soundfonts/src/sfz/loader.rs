@@ -0,0 +1,348 @@
+// Decoding the sample files of an instrument in parallel worker threads.
+// Compressed formats like FLAC and Ogg Vorbis are decoded by sndfile, which
+// dominates the loading time of large instruments.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::errors::SampleFileError;
+use crate::mapped::MappedFrames;
+use crate::sample;
+use crate::sndfile;
+use crate::sndfile::SndFileIO;
+use crate::utils;
+
+use super::engine::{self, EngineError};
+use super::smpl;
+
+/// How the frames are stored in a sample file. Integers of all bit depths are
+/// scaled to floats by `utils::pcm_to_float()`, floats are taken as they are.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SampleEncoding {
+    /// integers of the given bits
+    Pcm(u32),
+    /// floats of the given bits
+    Float(u32),
+}
+
+impl SampleEncoding {
+    fn of(subtype: sndfile::SubtypeFormat) -> Option<SampleEncoding> {
+        match subtype {
+            sndfile::SubtypeFormat::PCM_S8 | sndfile::SubtypeFormat::PCM_U8 => Some(SampleEncoding::Pcm(8)),
+            sndfile::SubtypeFormat::PCM_16 => Some(SampleEncoding::Pcm(16)),
+            sndfile::SubtypeFormat::PCM_24 => Some(SampleEncoding::Pcm(24)),
+            sndfile::SubtypeFormat::PCM_32 => Some(SampleEncoding::Pcm(32)),
+            sndfile::SubtypeFormat::FLOAT => Some(SampleEncoding::Float(32)),
+            sndfile::SubtypeFormat::DOUBLE => Some(SampleEncoding::Float(64)),
+            _ => None,
+        }
+    }
+
+    pub fn bit_depth(&self) -> u32 {
+        match self {
+            SampleEncoding::Pcm(bits) | SampleEncoding::Float(bits) => *bits,
+        }
+    }
+}
+
+/// The decoded frames of a sample, one vector per channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleData {
+    pub channels: Vec<Vec<f32>>,
+    pub samplerate: f64,
+    /// the encoding of the file, `None` if it is not known or lossy like Ogg
+    /// Vorbis, which sndfile converts to floats itself
+    pub encoding: Option<SampleEncoding>,
+    /// the key the sample was recorded at as given in the file
+    pub root_key: Option<u8>,
+    /// the first and the last frame of the loop given in the file
+    pub loop_points: Option<(usize, usize)>,
+}
+
+/// Source of the samples referenced by the `sample` opcodes of an instrument.
+/// Called from several threads at once when the engine is loaded.
+pub trait SampleProvider: Sync {
+    /// The sample `sample` as written in the SFZ text
+    fn load(&self, sample: &str) -> Result<SampleData, EngineError>;
+
+    /// The sample `sample` mapped into memory instead of decoded, with empty
+    /// `channels`, or `None` if it can't be mapped. Only tried with
+    /// `SampleStorage::Mapped`.
+    fn map(&self, _sample: &str) -> Option<(SampleData, MappedFrames)> {
+        None
+    }
+}
+
+/// Provides the sample files relative to the directory of the SFZ file
+pub struct SampleFiles {
+    dir: PathBuf,
+}
+
+impl SampleFiles {
+    pub fn new<P: AsRef<Path>>(dir: P) -> SampleFiles {
+        SampleFiles { dir: dir.as_ref().to_path_buf() }
+    }
+}
+
+impl SampleProvider for SampleFiles {
+    fn load(&self, sample: &str) -> Result<SampleData, EngineError> {
+        let path = engine::sample_path(&self.dir, sample)?;
+        debug!("loading {}", path.display());
+        decode_file(&path)
+    }
+
+    fn map(&self, sample: &str) -> Option<(SampleData, MappedFrames)> {
+        let path = engine::sample_path(&self.dir, sample).ok()?;
+        let frames = MappedFrames::open(&path).ok()??;
+        let sampler = smpl::read(&path);
+        let data = SampleData {
+            channels: Vec::new(),
+            samplerate: frames.samplerate(),
+            encoding: Some(frames.encoding()),
+            root_key: sampler.as_ref().and_then(|s| s.root_key),
+            loop_points: sampler.and_then(|s| s.loop_points),
+        };
+        Some((data, frames))
+    }
+}
+
+/// Decodes the sample file at `path` with sndfile, which handles WAV, FLAC
+/// and Ogg Vorbis among others. Integer PCM is read as integers and scaled by
+/// `utils::pcm_to_float()`, so that 16 and 24 bit files are converted exactly.
+/// The root key and loop points are read from the sampler chunk of WAV files.
+pub fn decode_file(path: &Path) -> Result<SampleData, EngineError> {
+    let path_string = || path.to_string_lossy().to_string();
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    if let Some("wv") = extension.as_deref() {
+        return Err(EngineError::UnsupportedSampleFormat { path: path_string(), format: "WavPack".to_string() });
+    }
+
+    let mut snd = sndfile::OpenOptions::ReadOnly(sndfile::ReadOptions::Auto)
+        .from_path(path)
+        .map_err(|sfe| EngineError::SampleFileError { path: path_string(), error: SampleFileError(sfe) })?;
+    let encoding = SampleEncoding::of(snd.get_subtype_format());
+    let interleaved: Vec<f32> = match encoding {
+        Some(SampleEncoding::Pcm(_)) => SndFileIO::<i32>::read_all_to_vec(&mut snd)
+            .map(|values| values.into_iter().map(utils::pcm_to_float).collect()),
+        _ => snd.read_all_to_vec(),
+    }.map_err(|_| EngineError::SampleReadError { path: path_string() })?;
+    let sampler = smpl::read(path);
+    Ok(SampleData {
+        channels: utils::deinterleave(&interleaved, snd.get_channels().max(1)),
+        samplerate: snd.get_samplerate() as f64,
+        encoding,
+        root_key: sampler.as_ref().and_then(|s| s.root_key),
+        loop_points: sampler.and_then(|s| s.loop_points),
+    })
+}
+
+/// Statistics of decoding the sample files of an instrument
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoadStats {
+    /// number of distinct sample files
+    pub files: usize,
+    pub frames: usize,
+    pub threads: usize,
+    pub decode_time: Duration,
+}
+
+impl LoadStats {
+    /// Decoded frames per second of wall clock time
+    pub fn frames_per_second(&self) -> f64 {
+        match self.decode_time.as_secs_f64() {
+            t if t > 0.0 => self.frames as f64 / t,
+            _ => 0.0,
+        }
+    }
+}
+
+// the built-in oscillators like `*sine` are not loaded, the engine makes
+// their buffers
+fn load(provider: &dyn SampleProvider, sample: &str, map: bool) -> Result<(SampleData, Option<MappedFrames>), EngineError> {
+    if sample::Waveform::from_sample_name(sample).is_some() {
+        let data = SampleData {
+            channels: Vec::new(),
+            samplerate: engine::generator_samplerate(),
+            encoding: None,
+            root_key: None,
+            loop_points: None,
+        };
+        return Ok((data, None));
+    }
+    match provider.map(sample).filter(|_| map) {
+        Some((data, frames)) => Ok((data, Some(frames))),
+        None => provider.load(sample).map(|data| (data, None)),
+    }
+}
+
+/// The decoded samples in the order they were requested, and the mapped
+/// frames of the ones mapped instead
+pub(super) type LoadedSamples = (Vec<Arc<SampleData>>, Vec<Option<Arc<MappedFrames>>>);
+
+// a sample and its mapped frames if it is mapped
+type LoadedSample = (Arc<SampleData>, Option<Arc<MappedFrames>>);
+
+/// Loads the `samples` from `provider` using up to `threads` threads, all
+/// CPUs if `None`, mapping them into memory if `map` and the provider can.
+/// Samples referenced more than once are loaded only once. The samples are
+/// returned in the order of `samples`, the error is the one of the first
+/// sample failing.
+pub(super) fn load_samples(provider: &dyn SampleProvider,
+                           samples: &[&str],
+                           threads: Option<usize>,
+                           map: bool) -> Result<(LoadedSamples, LoadStats), EngineError> {
+    let mut unique: Vec<&str> = Vec::new();
+    let mut index_of: HashMap<&str, usize> = HashMap::new();
+    let indices: Vec<usize> = samples.iter().map(|s| {
+        *index_of.entry(s).or_insert_with(|| {
+            unique.push(s);
+            unique.len() - 1
+        })
+    }).collect();
+
+    let threads = threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1)
+        .min(unique.len().max(1));
+
+    let start = Instant::now();
+    let next = AtomicUsize::new(0);
+    let mut loaded: Vec<Option<Result<LoadedSample, EngineError>>> = (0..unique.len()).map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(|| {
+            let mut done = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                match unique.get(i) {
+                    Some(sample) => done.push((i, load(provider, sample, map)
+                        .map(|(data, frames)| (Arc::new(data), frames.map(Arc::new))))),
+                    None => break done,
+                }
+            }
+        })).collect();
+        for worker in workers {
+            for (i, result) in worker.join().expect("sample loading thread panicked") {
+                loaded[i] = Some(result);
+            }
+        }
+    });
+    let decode_time = start.elapsed();
+
+    let loaded: Vec<LoadedSample> = loaded.into_iter()
+        .map(|result| result.expect("every sample is loaded"))
+        .collect::<Result<_, _>>()?;
+    let stats = LoadStats {
+        files: loaded.len(),
+        frames: loaded.iter().map(|(s, _)| s.channels.get(0).map_or(0, |c| c.len())).sum(),
+        threads,
+        decode_time,
+    };
+    let samples = indices.iter().map(|i| loaded[*i].0.clone()).collect();
+    let mapped = indices.iter().map(|i| loaded[*i].1.clone()).collect();
+    Ok(((samples, mapped), stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_flac_in_threads() {
+        let provider = SampleFiles::new("assets");
+        let samples = ["sine-440Hz-48000sr.flac", "sine-440Hz-44100sr.flac", "sine-440Hz-48000sr.flac"];
+        let ((samples, _), stats) = load_samples(&provider, &samples, Some(4), false).unwrap();
+
+        assert_eq!(samples.len(), 3);
+        assert!(Arc::ptr_eq(&samples[0], &samples[2]));
+        assert_eq!(samples[0].samplerate, 48000.0);
+        assert_eq!(samples[1].samplerate, 44100.0);
+        assert!(samples[0].channels[0].len() > 0);
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.threads, 2);
+        assert_eq!(stats.frames, samples[0].channels[0].len() + samples[1].channels[0].len());
+    }
+
+    fn write_wav(path: &Path, format_tag: u16, bits: u16, data: &[u8]) {
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&format_tag.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&44100u32.to_le_bytes());
+        wav.extend_from_slice(&(44100 * (bits / 8) as u32).to_le_bytes());
+        wav.extend_from_slice(&(bits / 8).to_le_bytes());
+        wav.extend_from_slice(&bits.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(data);
+        std::fs::write(path, wav).unwrap();
+    }
+
+    #[test]
+    fn decode_bit_depths_exactly() {
+        let dir = std::env::temp_dir().join(format!("sonarigo-bit-depths-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // full scale and the lowest bits as left by dithering
+        let pcm16 = [i16::MIN, i16::MAX, 1, -1, 0, 2];
+        let pcm24: [i32; 6] = [-0x80_0000, 0x7f_ffff, 1, -1, 0, 3];
+        let pcm32 = [i32::MIN, 0x4000_0000, 0, -0x4000_0000];
+        let float32 = [-1.0f32, 0.5, 1.5, 1e-7];
+
+        let files: [(&str, u16, u16, Vec<u8>); 4] = [
+            ("16.wav", 1, 16, pcm16.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()),
+            ("24.wav", 1, 24, pcm24.iter().flat_map(|v| v.to_le_bytes()[..3].to_vec()).collect()),
+            ("32.wav", 1, 32, pcm32.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()),
+            ("float.wav", 3, 32, float32.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()),
+        ];
+        for (name, format_tag, bits, data) in files.iter() {
+            write_wav(&dir.join(name), *format_tag, *bits, data);
+        }
+        let decode = |name: &str| decode_file(&dir.join(name)).unwrap();
+
+        let sample = decode("16.wav");
+        assert_eq!(sample.encoding, Some(SampleEncoding::Pcm(16)));
+        assert_eq!(sample.samplerate, 44100.0);
+        let expected: Vec<f32> = pcm16.iter().map(|v| *v as f32 / 32768.0).collect();
+        assert_eq!(sample.channels, vec![expected]);
+
+        let sample = decode("24.wav");
+        assert_eq!(sample.encoding, Some(SampleEncoding::Pcm(24)));
+        let expected: Vec<f32> = pcm24.iter().map(|v| *v as f32 / 8_388_608.0).collect();
+        assert_eq!(sample.channels, vec![expected]);
+
+        let sample = decode("32.wav");
+        assert_eq!(sample.encoding, Some(SampleEncoding::Pcm(32)));
+        assert_eq!(sample.channels, vec![vec![-1.0, 0.5, 0.0, -0.5]]);
+
+        let sample = decode("float.wav");
+        assert_eq!(sample.encoding, Some(SampleEncoding::Float(32)));
+        assert_eq!(sample.encoding.unwrap().bit_depth(), 32);
+        assert_eq!(sample.channels, vec![float32.to_vec()]);
+
+        // mapping the files gives the same frames
+        for (name, _, _, _) in files.iter() {
+            let frames = MappedFrames::open(&dir.join(name)).unwrap().unwrap();
+            assert_eq!(frames.to_channels(), decode(name).channels, "{}", name);
+            assert_eq!(Some(frames.encoding()), decode(name).encoding, "{}", name);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wavpack_unsupported() {
+        match decode_file(Path::new("samples/piano.WV")) {
+            Err(e) => assert_eq!(format!("{}", e), "samples/piano.WV: WavPack samples are not supported"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+}
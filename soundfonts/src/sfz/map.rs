@@ -0,0 +1,111 @@
+// Machine readable description of the key, velocity and controller layout of
+// an instrument, for GUIs and documentation generators
+
+use std::fmt::Write;
+use std::fs;
+
+use super::engine::{EngineError, RegionData, Trigger};
+use super::parser;
+
+/// The layout of `regions` as JSON: an object with the array `regions`, one
+/// object per region in the order of the SFZ file. Keys and velocities are
+/// MIDI numbers, `keys` is `null` for regions not triggered by keys. The
+/// `on_cc` ranges trigger the region, the `cc` ranges must contain the
+/// controller values to play it. Unset bounds are `null`.
+pub fn export_map(regions: &[RegionData]) -> String {
+    let mut json = String::from("{\n  \"regions\": [");
+    for (i, rd) in regions.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str("\n    {");
+        write!(json, "\"index\": {}, \"sample\": {}", i, string(rd.sample())).unwrap();
+        match rd.key_bounds() {
+            Some((lo, hi)) => write!(json, ", \"keys\": [{}, {}]", lo, hi).unwrap(),
+            None => json.push_str(", \"keys\": null"),
+        }
+        write!(json, ", \"pitch_keycenter\": {}", rd.pitch_keycenter()).unwrap();
+        let (lo, hi) = rd.velocity_bounds();
+        write!(json, ", \"velocities\": [{}, {}]", lo, hi).unwrap();
+        write!(json, ", \"trigger\": \"{}\"", trigger_name(rd.trigger())).unwrap();
+        write!(json, ", \"group\": {}, \"off_by\": {}", rd.group(), rd.off_by()).unwrap();
+        let (lo, hi) = rd.random_bounds();
+        write!(json, ", \"random\": [{}, {}]", lo, hi).unwrap();
+        write!(json, ", \"on_cc\": {}", cc_ranges(&rd.on_cc_bounds())).unwrap();
+        write!(json, ", \"cc\": {}", cc_ranges(&rd.cc_condition_bounds())).unwrap();
+        json.push('}');
+    }
+    if !regions.is_empty() {
+        json.push_str("\n  ");
+    }
+    json.push_str("]\n}");
+    json
+}
+
+/// Parses the SFZ file at `path` leniently and returns the layout of its
+/// regions as `export_map()` does
+pub fn map_file(path: &str) -> Result<String, EngineError> {
+    let text = fs::read_to_string(path)
+        .map_err(|error| EngineError::IOError { path: path.to_string(), error })?;
+    let (regions, _) = parser::parse_sfz_text_lenient(text)
+        .map_err(|error| EngineError::ParserError { path: path.to_string(), error })?;
+    Ok(export_map(&regions))
+}
+
+fn trigger_name(trigger: Trigger) -> &'static str {
+    match trigger {
+        Trigger::Attack => "attack",
+        Trigger::Release => "release",
+        Trigger::First => "first",
+        Trigger::Legato => "legato",
+        Trigger::ReleaseKey => "release_key",
+    }
+}
+
+fn cc_ranges(bounds: &[(u8, Option<u8>, Option<u8>)]) -> String {
+    let bound = |b: Option<u8>| b.map_or("null".to_string(), |v| v.to_string());
+    let ranges: Vec<String> = bounds.iter()
+        .map(|(cc, lo, hi)| format!("{{\"cc\": {}, \"lo\": {}, \"hi\": {}}}", cc, bound(*lo), bound(*hi)))
+        .collect();
+    format!("[{}]", ranges.join(", "))
+}
+
+fn string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_key_velocity_cc_layout() {
+        let (regions, _) = parser::parse_sfz_text_lenient("
+<region> sample=a\\c4.wav lokey=60 hikey=62 pitch_keycenter=61 lovel=10 hivel=100 locc1=20 hicc1=90
+<region> sample=pedal.wav trigger=release hikey=-1 on_locc64=64 on_hicc64=127 group=2 off_by=3 lorand=0.5 hirand=1
+".to_string()).unwrap();
+
+        assert_eq!(export_map(&regions), "\
+{
+  \"regions\": [
+    {\"index\": 0, \"sample\": \"a\\\\c4.wav\", \"keys\": [60, 62], \"pitch_keycenter\": 61, \"velocities\": [10, 100], \
+\"trigger\": \"attack\", \"group\": 0, \"off_by\": 0, \"random\": [0, 1], \"on_cc\": [], \
+\"cc\": [{\"cc\": 1, \"lo\": 20, \"hi\": 90}]},
+    {\"index\": 1, \"sample\": \"pedal.wav\", \"keys\": null, \"pitch_keycenter\": 60, \"velocities\": [0, 127], \
+\"trigger\": \"release\", \"group\": 2, \"off_by\": 3, \"random\": [0.5, 1], \
+\"on_cc\": [{\"cc\": 64, \"lo\": 64, \"hi\": 127}], \"cc\": []}
+  ]
+}");
+        assert_eq!(export_map(&[]), "{\n  \"regions\": []\n}");
+    }
+}
@@ -1,2 +1,29 @@
+mod archive;
+mod automap;
+#[cfg(test)]
+mod conformance;
+mod instrument_cache;
+mod loader;
+mod map;
 mod parser;
+mod sample_path;
+mod smpl;
+mod watch;
+mod writer;
 pub mod engine;
+pub mod activity_log;
+pub mod diagnostics;
+pub mod mutes;
+pub mod observer;
+pub mod telemetry;
+pub mod validation;
+
+pub use self::archive::ZipSamples;
+pub use self::automap::{automap, AutoMap};
+pub use self::loader::{decode_file, LoadStats, SampleData, SampleEncoding, SampleFiles, SampleProvider};
+pub use crate::mapped::MappedFrames;
+pub use self::map::{export_map, map_file};
+pub use self::parser::{parse_text, ParserError};
+pub use self::validation::{validate, ValidationReport};
+pub use self::watch::SfzWatch;
+pub use self::writer::write_sfz;
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// no region soloed
+const NO_SOLO: usize = usize::MAX;
+
+/// Regions muted or soloed to audition parts of an instrument. Set from any
+/// thread without locking, the engine checks them when notes are played, so
+/// voices already sounding are not cut off.
+pub struct RegionMutes {
+    muted: Vec<AtomicBool>,
+    solo: AtomicUsize,
+}
+
+pub type SharedRegionMutes = Arc<RegionMutes>;
+
+impl RegionMutes {
+    pub(super) fn shared(regions: usize) -> SharedRegionMutes {
+        Arc::new(RegionMutes {
+            muted: (0..regions).map(|_| AtomicBool::new(false)).collect(),
+            solo: AtomicUsize::new(NO_SOLO),
+        })
+    }
+
+    pub fn set_muted(&self, index: usize, muted: bool) {
+        if let Some(flag) = self.muted.get(index) {
+            flag.store(muted, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_muted(&self, index: usize) -> bool {
+        self.muted.get(index).map_or(false, |flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Mutes all regions but the one at `index`, `None` ends the solo
+    pub fn solo(&self, index: Option<usize>) {
+        let index = index.filter(|i| *i < self.muted.len()).unwrap_or(NO_SOLO);
+        self.solo.store(index, Ordering::Relaxed);
+    }
+
+    pub fn soloed(&self) -> Option<usize> {
+        match self.solo.load(Ordering::Relaxed) {
+            NO_SOLO => None,
+            index => Some(index),
+        }
+    }
+
+    /// Whether the region at `index` is muted itself or by the solo of another
+    /// region
+    pub fn is_silenced(&self, index: usize) -> bool {
+        self.is_muted(index) || self.soloed().map_or(false, |solo| solo != index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mute_and_solo() {
+        let mutes = RegionMutes::shared(3);
+        assert!(!(0..3).any(|i| mutes.is_silenced(i)));
+        mutes.set_muted(1, true);
+        mutes.set_muted(7, true);
+        assert!(mutes.is_muted(1));
+        assert!(!mutes.is_muted(7));
+        assert_eq!((0..3).filter(|i| mutes.is_silenced(*i)).collect::<Vec<_>>(), vec![1]);
+
+        mutes.solo(Some(2));
+        assert_eq!(mutes.soloed(), Some(2));
+        assert_eq!((0..3).filter(|i| mutes.is_silenced(*i)).collect::<Vec<_>>(), vec![0, 1]);
+        mutes.solo(Some(1));
+        assert!(mutes.is_silenced(1));
+
+        mutes.solo(Some(3));
+        assert_eq!(mutes.soloed(), None);
+        mutes.set_muted(1, false);
+        assert!(!(0..3).any(|i| mutes.is_silenced(i)));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Receives the note and region activity of an engine, see
+/// `Engine::set_observer()`. The methods are called on the audio thread
+/// after a MIDI message has been handled, so they must not block. Regions are
+/// given by their index in the SFZ file. Observers are `Sync` like the
+/// engine holding them.
+pub trait EngineObserver: Send + Sync {
+    fn note_on(&mut self, _note: wmidi::Note, _velocity: wmidi::Velocity) {}
+    fn note_off(&mut self, _note: wmidi::Note, _velocity: wmidi::Velocity) {}
+    fn region_triggered(&mut self, _region: usize) {}
+    /// A voice of the region was stopped to stay within the voice limit
+    fn voice_stolen(&mut self, _region: usize) {}
+}
+
+/// A call of an `EngineObserver` method
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineEvent {
+    NoteOn(wmidi::Note, wmidi::Velocity),
+    NoteOff(wmidi::Note, wmidi::Velocity),
+    RegionTriggered(usize),
+    VoiceStolen(usize),
+}
+
+/// Observer queuing the events for a thread other than the audio thread.
+/// Clones share the queue, so one is given to the engine and another one is
+/// drained by the consumer. When the queue is full or locked by the consumer
+/// events are dropped.
+#[derive(Clone)]
+pub struct EventQueue {
+    events: Arc<Mutex<VecDeque<EngineEvent>>>,
+    capacity: usize,
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize) -> EventQueue {
+        EventQueue {
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Takes the queued events, oldest first
+    pub fn drain(&self) -> Vec<EngineEvent> {
+        match self.events.lock() {
+            Ok(mut events) => events.drain(..).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn push(&mut self, event: EngineEvent) {
+        if let Ok(mut events) = self.events.try_lock() {
+            if events.len() < self.capacity {
+                events.push_back(event);
+            }
+        }
+    }
+}
+
+impl EngineObserver for EventQueue {
+    fn note_on(&mut self, note: wmidi::Note, velocity: wmidi::Velocity) {
+        self.push(EngineEvent::NoteOn(note, velocity));
+    }
+
+    fn note_off(&mut self, note: wmidi::Note, velocity: wmidi::Velocity) {
+        self.push(EngineEvent::NoteOff(note, velocity));
+    }
+
+    fn region_triggered(&mut self, region: usize) {
+        self.push(EngineEvent::RegionTriggered(region));
+    }
+
+    fn voice_stolen(&mut self, region: usize) {
+        self.push(EngineEvent::VoiceStolen(region));
+    }
+}
@@ -7,6 +7,8 @@ use std::str::Chars;
 
 use super::engine;
 use crate::errors::*;
+use crate::modulation;
+use crate::sample;
 
 #[derive(Debug)]
 pub enum ParserError {
@@ -15,7 +17,47 @@ pub enum ParserError {
     ParseIntError(ParseIntError),
     ParseFloatError(ParseFloatError),
     NoteParseError(NoteParseError),
-    General(String)
+    General(String),
+    /// An opcode that is accepted but not implemented
+    IgnoredOpcode(String),
+    /// The error `error` in the opcode or header `opcode` at the given line
+    /// and column of the SFZ text, both counting from 1
+    Located {
+        line: usize,
+        column: usize,
+        opcode: String,
+        error: Box<ParserError>,
+    },
+}
+
+impl ParserError {
+    /// Line and column of the offending opcode, if known
+    pub fn position(&self) -> Option<(usize, usize)> {
+        match self {
+            ParserError::Located { line, column, .. } => Some((*line, *column)),
+            _ => None,
+        }
+    }
+
+    /// The offending opcode, if known
+    pub fn opcode(&self) -> Option<&str> {
+        match self {
+            ParserError::Located { opcode, .. } => Some(opcode),
+            _ => None,
+        }
+    }
+
+    // Wraps the error with the position in `text` where `remaining` bytes of
+    // `text` are left
+    fn located(self, text: &str, remaining: usize, opcode: &str) -> ParserError {
+        let before = &text[..text.len() - remaining];
+        ParserError::Located {
+            line: before.matches('\n').count() + 1,
+            column: before.rsplit('\n').next().unwrap_or("").chars().count() + 1,
+            opcode: opcode.to_string(),
+            error: Box::new(self),
+        }
+    }
 }
 
 impl fmt::Display for ParserError{
@@ -27,6 +69,10 @@ impl fmt::Display for ParserError{
             ParserError::ParseFloatError(e) => e.fmt(f),
             ParserError::NoteParseError(e) => e.fmt(f),
             ParserError::General(s) => write!(f, "General parser error: {}", s),
+            ParserError::IgnoredOpcode(s) => write!(f, "Ignored opcode: {}", s),
+            ParserError::Located { line, column, opcode, error } => {
+                write!(f, "line {}, column {}, {}: {}", line, column, opcode, error)
+            }
         }
     }
 }
@@ -39,7 +85,9 @@ impl error::Error for ParserError {
             ParserError::ParseIntError(ref e) =>  Some(e),
             ParserError::ParseFloatError(ref e) =>  Some(e),
             ParserError::NoteParseError(ref e) => Some(e),
-            ParserError::General(_) => None
+            ParserError::General(_) => None,
+            ParserError::IgnoredOpcode(_) => None,
+            ParserError::Located { ref error, .. } => Some(error.as_ref()),
         }
     }
 }
@@ -67,7 +115,7 @@ impl NoteParseError {
 }
 
 
-fn parse_key(key: &str) -> Result<i32, NoteParseError> {
+pub(super) fn parse_key(key: &str) -> Result<i32, NoteParseError> {
     match key.parse::<i32>() {
         Ok(v) => Ok(v),
         Err(_) => {
@@ -103,11 +151,15 @@ fn parse_key(key: &str) -> Result<i32, NoteParseError> {
                     Some(v) => v
                 }
             };
-            let octave = (octave_char - '0' as u8) as i8;
-            if octave < 0 || octave > 9 {
+            if !octave_char.is_ascii_digit() || bytes.next().is_some() {
+                return Err(NoteParseError::new(key))
+            }
+            let octave = (octave_char - b'0') as i32;
+            let note = (octave + 1) * 12 + note_val + sign;
+            if note < 0 || note > 127 {
                 return Err(NoteParseError::new(key))
             }
-            Ok(((octave + 1) * 12 + (note_val + sign)) as i32)
+            Ok(note)
         }
     }
 }
@@ -158,10 +210,16 @@ fn parse_header(chars: &mut Chars) -> Result<String, ParserError> {
     }
 }
 
-fn parse_opcode(chars: &mut Chars) -> Result<(Option<(String, String)>, NextChar), ParserError> {
+// Returns the opcode and its value along with the number of bytes left in the
+// text at the beginning of the opcode
+fn parse_opcode(chars: &mut Chars) -> Result<(Option<(String, String, usize)>, NextChar), ParserError> {
     let mut opcode_string = String::new();
 
     let mut nc = next_char_skip_whitespace(chars);
+    let remaining = match nc {
+        NextChar::Some(c) => chars.as_str().len() + c.len_utf8(),
+        _ => chars.as_str().len(),
+    };
     while let NextChar::Some(c) = nc {
         match c {
             '=' => break,
@@ -193,7 +251,7 @@ fn parse_opcode(chars: &mut Chars) -> Result<(Option<(String, String)>, NextChar
         nc = next_char(chars);
     };
 
-    Ok((Some((opcode_string.trim().to_string(), value_string.trim().to_string())), nc))
+    Ok((Some((opcode_string.trim().to_string(), value_string.trim().to_string(), remaining)), nc))
 }
 
 
@@ -202,7 +260,13 @@ fn take_opcode(region: &mut engine::RegionData, key: &str, value: &str) -> Resul
     match key {
         "lokey" => region.key_range.set_lo(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
         "hikey" => region.key_range.set_hi(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
-        "pitch_keycenter" => region.set_pitch_keycenter(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "pitch_keycenter" => {
+            let key = parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?;
+            match key {
+                k if k < 0 => Err(RangeError::out_of_range("pitch_keycenter", 0, 127, key)),
+                k => region.set_pitch_keycenter(k as u32)
+            }
+        }.map_err(|re| ParserError::RangeError(re)),
         "key" => {
             let key = parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?;
             match key {
@@ -210,24 +274,104 @@ fn take_opcode(region: &mut engine::RegionData, key: &str, value: &str) -> Resul
                 k => region.key_range.set_hi(k).and_then(|_| region.key_range.set_lo(k)).and_then(|_| region.set_pitch_keycenter((k as u8).into()))
             }
         }.map_err(|re| ParserError::RangeError(re)),
+        "lochan" => region.channel_range.set_lo(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "hichan" => region.channel_range.set_hi(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "lovel" => region.vel_range.set_lo(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "hivel" => region.vel_range.set_hi(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "lorand" => region.random_range.set_lo(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "hirand" => region.random_range.set_hi(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "tune" => region.set_tune(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "tune" | "pitch" => region.set_tune(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "volume" => region.set_volume(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "group_volume" => region.set_group_volume(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "master_volume" => region.set_master_volume(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "global_volume" => region.set_global_volume(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "rt_decay" => region.set_rt_decay(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "rt_dead" => match value {
+            "0" | "off" => { region.set_rt_dead(false); Ok(()) },
+            "1" | "on" => { region.set_rt_dead(true); Ok(()) },
+            _ => Err(ParserError::KeyError(value.to_string())),
+        },
+        "offset_random" => { region.set_offset_random(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?); Ok(()) },
         "pitch_keytrack" => region.set_pitch_keytrack(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "lobpm" => region.set_lobpm(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "hibpm" => region.set_hibpm(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "amp_random" => region.set_amp_random(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "pitch_random" => region.set_pitch_random(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "delay_random" => region.set_delay_random(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "amp_veltrack" => region.set_amp_veltrack(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "cutoff" => region.set_cutoff(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "effect1" => region.set_effect1(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "effect2" => region.set_effect2(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "ampeg_delay" => region.ampeg.set_delay(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "ampeg_start" => region.ampeg.set_start(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "ampeg_attack" => region.ampeg.set_attack(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "ampeg_hold" => region.ampeg.set_hold(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "ampeg_decay" => region.ampeg.set_decay(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "ampeg_sustain" => region.ampeg.set_sustain(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
         "ampeg_release" => region.ampeg.set_release(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "group" => { region.set_group(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?); Ok(()) },
+        "ampeg_vel2attack" => region.ampeg.set_vel2attack(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "ampeg_vel2hold" => region.ampeg.set_vel2hold(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "ampeg_vel2decay" => region.ampeg.set_vel2decay(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "ampeg_vel2sustain" => region.ampeg.set_vel2sustain(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "ampeg_vel2release" => region.ampeg.set_vel2release(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "group" | "polyphony_group" => { region.set_group(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?); Ok(()) },
         "off_by" => { region.set_off_by(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?); Ok(()) },
-        "sample" => { region.set_sample(value); Ok(()) },
+        "off_mode" => match value {
+            "fast" => { region.set_off_mode(engine::OffMode::Fast); Ok(()) },
+            "normal" => { region.set_off_mode(engine::OffMode::Normal); Ok(()) },
+            "time" => { region.set_off_mode(engine::OffMode::Time); Ok(()) },
+            _ => Err(ParserError::KeyError(value.to_string())),
+        },
+        "off_time" => region.set_off_time(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "off_lokey" => region.set_off_lokey(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "off_hikey" => region.set_off_hikey(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "sw_lokey" => region.set_sw_lokey(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "sw_hikey" => region.set_sw_hikey(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "sw_last" => region.set_sw_last(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "sw_default" => region.set_sw_default(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "sw_label" => { region.set_sw_label(value); Ok(()) },
+        "bend_up" => region.set_bend_up(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "bend_down" => region.set_bend_down(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "bend_step" => region.set_bend_step(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "bend_smooth" => region.set_bend_smooth(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "portamento_time" => region.set_portamento_time(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "xfin_lokey" => region.set_xfin_lokey(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "xfin_hikey" => region.set_xfin_hikey(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "xfout_lokey" => region.set_xfout_lokey(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "xfout_hikey" => region.set_xfout_hikey(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
+        "xf_keycurve" => { region.set_xf_keycurve(parse_xf_curve(value)?); Ok(()) },
+        "sw_vel" => match value {
+            "current" => { region.set_sw_vel_previous(false); Ok(()) },
+            "previous" => { region.set_sw_vel_previous(true); Ok(()) },
+            _ => Err(ParserError::KeyError(value.to_string())),
+        },
+        "sample" => match value.starts_with('*') && sample::Waveform::from_sample_name(value).is_none() {
+            true => Err(ParserError::KeyError(value.to_string())),
+            false => { region.set_sample(value); Ok(()) },
+        },
+        "default_path" => { region.set_default_path(value); Ok(()) },
         "trigger" => { region.set_trigger(parse_trigger(value)?); Ok(()) },
+        "loop_mode" | "loopmode" => { region.set_loop_mode(parse_loop_mode(value)?); Ok(()) },
+        "loop_start" | "loopstart" => { region.set_loop_start(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?); Ok(()) },
+        "loop_end" | "loopend" => { region.set_loop_end(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?); Ok(()) },
+        "click_guard" => region.set_click_guard(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "loop_crossfade" => region.set_loop_crossfade(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        s if s.starts_with("amp_velcurve_") => {
+            let velocity = s["amp_velcurve_".len()..].parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?;
+            region.set_amp_velcurve_point(velocity, value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re))
+        }
+        s if is_ignored_opcode(s) => Err(ParserError::IgnoredOpcode(s.to_string())),
+        s if s.ends_with("_chanaft") || s.ends_with("_polyaft") => {
+            let (destination, source) = s.split_at(s.rfind('_').unwrap());
+            let source = match source {
+                "_chanaft" => modulation::Source::ChannelAftertouch,
+                _ => modulation::Source::PolyAftertouch,
+            };
+            match parse_modulation_destination(destination) {
+                Some(destination) => region.push_modulation(source, destination, value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+                None => Err(ParserError::KeyError(s.to_string()))
+            }
+        }
         s => match s.find("cc") {
             Some(n) => {
                 let (key_cc, ns) = s.split_at(n);
@@ -235,12 +379,29 @@ fn take_opcode(region: &mut engine::RegionData, key: &str, value: &str) -> Resul
                 if cc_num > 127 {
                     Err(ParserError::RangeError(RangeError::out_of_range("cc number", 0, 127, cc_num)))
                 } else {
-                    let value = value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?;
-
                     match key_cc {
-                        "on_lo" => region.push_on_lo_cc(cc_num, value).map_err(|re| ParserError::RangeError(re)),
-                        "on_hi" => region.push_on_hi_cc(cc_num, value).map_err(|re| ParserError::RangeError(re)),
-                        _ => Err(ParserError::KeyError(key_cc.to_string()))
+                        "on_lo" => region.push_on_lo_cc(cc_num, value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+                        "on_hi" => region.push_on_hi_cc(cc_num, value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+                        "lo" => region.push_lo_cc(cc_num, value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+                        "hi" => region.push_hi_cc(cc_num, value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+                        "on_lohd" => region.push_on_lo_cc(cc_num, parse_hdcc_value(value, "on_lohdccXX")?).map_err(|re| ParserError::RangeError(re)),
+                        "on_hihd" => region.push_on_hi_cc(cc_num, parse_hdcc_value(value, "on_hihdccXX")?).map_err(|re| ParserError::RangeError(re)),
+                        "lohd" => region.push_lo_cc(cc_num, parse_hdcc_value(value, "lohdccXX")?).map_err(|re| ParserError::RangeError(re)),
+                        "hihd" => region.push_hi_cc(cc_num, parse_hdcc_value(value, "hihdccXX")?).map_err(|re| ParserError::RangeError(re)),
+                        "ampeg_delay_on" | "ampeg_delay" => region.ampeg.set_delay_oncc(cc_num as u8, value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+                        "ampeg_start_on" | "ampeg_start" => region.ampeg.set_start_oncc(cc_num as u8, value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+                        k if k.ends_with("_curve") => match parse_modulation_destination(k.trim_end_matches("_curve")) {
+                            Some(destination) => {
+                                let curve_index = value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?;
+                                region.set_modulation_curve(modulation::Source::Controller(cc_num as u8), destination, curve_index);
+                                Ok(())
+                            }
+                            None => Err(ParserError::KeyError(key_cc.to_string()))
+                        },
+                        _ => match parse_modulation_destination(key_cc.trim_end_matches("on").trim_end_matches('_')) {
+                            Some(destination) => region.push_modulation(modulation::Source::Controller(cc_num as u8), destination, value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
+                            None => Err(ParserError::KeyError(key_cc.to_string()))
+                        }
                     }
                 }
             }
@@ -249,6 +410,69 @@ fn take_opcode(region: &mut engine::RegionData, key: &str, value: &str) -> Resul
     }
 }
 
+// Opcodes of ARIA and other SFZ extensions that are not implemented, but
+// accepted so that instruments using them load
+fn take_control_opcode(control: &mut engine::ControlData, key: &str, value: &str) -> Result<(), ParserError> {
+    match key {
+        "note_offset" => control.set_note_offset(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "octave_offset" => control.set_octave_offset(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "default_path" => { control.set_default_path(value); Ok(()) },
+        s if is_ignored_opcode(s) => Err(ParserError::IgnoredOpcode(s.to_string())),
+        s => Err(ParserError::KeyError(s.to_string())),
+    }
+}
+
+// a `<curve>` header, which is only stored if it has a `curve_index`
+#[derive(Default)]
+struct CurveHeader {
+    index: Option<u32>,
+    curve: modulation::Curve,
+}
+
+fn take_curve_opcode(header: &mut CurveHeader, key: &str, value: &str) -> Result<(), ParserError> {
+    match key {
+        "curve_index" => {
+            let index = value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?;
+            header.index = Some(range_check(index, 0, 255, "curve_index").map_err(|re| ParserError::RangeError(re))?);
+            Ok(())
+        }
+        k if k.starts_with('v') => {
+            let point = k[1..].parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?;
+            let point = range_check(point, 0, 127, "curve point").map_err(|re| ParserError::RangeError(re))?;
+            let v = value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?;
+            header.curve.set_point(point as u8, range_check(v, -1.0, 1.0, "curve value").map_err(|re| ParserError::RangeError(re))?);
+            Ok(())
+        }
+        k => Err(ParserError::KeyError(k.to_string())),
+    }
+}
+
+fn is_ignored_opcode(key: &str) -> bool {
+    match key {
+        "ampeg_dynamic" | "fileg_dynamic" | "pitcheg_dynamic" => true,
+        k => ["label_cc", "set_cc", "set_hdcc"].iter().any(|prefix| k.starts_with(prefix)),
+    }
+}
+
+// Values of high definition CCs range from 0.0 to 1.0 and are mapped to the
+// 7 bit range of the MIDI controllers
+fn parse_hdcc_value(value: &str, name: &'static str) -> Result<i32, ParserError> {
+    let v = value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?;
+    let v = range_check(v, 0.0, 1.0, name).map_err(|re| ParserError::RangeError(re))?;
+    Ok((v * 127.0).round() as i32)
+}
+
+fn parse_modulation_destination(s: &str) -> Option<modulation::Destination> {
+    match s {
+        "gain" | "volume" => Some(modulation::Destination::Volume),
+        "amplitude" => Some(modulation::Destination::Amplitude),
+        "pitch" | "tune" => Some(modulation::Destination::Pitch),
+        "cutoff" => Some(modulation::Destination::Cutoff),
+        "pan" => Some(modulation::Destination::Pan),
+        _ => None
+    }
+}
+
 fn parse_trigger(s: &str) -> Result<engine::Trigger, ParserError> {
          match s {
             "attack" => Ok(engine::Trigger::Attack),
@@ -260,16 +484,51 @@ fn parse_trigger(s: &str) -> Result<engine::Trigger, ParserError> {
         }
 }
 
+fn parse_xf_curve(s: &str) -> Result<engine::XfCurve, ParserError> {
+         match s {
+            "gain" => Ok(engine::XfCurve::Gain),
+            "power" => Ok(engine::XfCurve::Power),
+            _ => Err(ParserError::KeyError(s.to_string()))
+        }
+}
 
-fn parse_region(chars: &mut Chars, mut region: engine::RegionData) -> Result<(engine::RegionData, NextChar), ParserError> {
+fn parse_loop_mode(s: &str) -> Result<engine::LoopMode, ParserError> {
+         match s {
+            "no_loop" => Ok(engine::LoopMode::NoLoop),
+            "one_shot" => Ok(engine::LoopMode::OneShot),
+            "loop_continuous" => Ok(engine::LoopMode::LoopContinuous),
+            "loop_sustain" => Ok(engine::LoopMode::LoopSustain),
+            _ => Err(ParserError::KeyError(s.to_string()))
+        }
+}
+
+
+// Errors of single opcodes are collected in `warnings` unless `warnings` is
+// `None`. Ignored opcodes are only reported as warnings.
+fn parse_region<T>(text: &str,
+                   chars: &mut Chars,
+                   mut region: T,
+                   take_opcode: fn(&mut T, &str, &str) -> Result<(), ParserError>,
+                   warnings: &mut Option<&mut Vec<ParserError>>) -> Result<(T, NextChar), ParserError> {
 
     let nc = loop {
         match parse_opcode(chars) {
             Err(e) => return Err(e),
             Ok((nop, nc)) => {
                 match nop {
-                    Some((opcode, value)) => {
-                        take_opcode(&mut region, opcode.trim(), value.trim())?
+                    Some((opcode, value, remaining)) => {
+                        if let Err(e) = take_opcode(&mut region, opcode.trim(), value.trim()) {
+                            let ignored = match e {
+                                ParserError::IgnoredOpcode(_) => true,
+                                _ => false,
+                            };
+                            let e = e.located(text, remaining, opcode.trim());
+                            match warnings {
+                                Some(warnings) => warnings.push(e),
+                                None if ignored => {}
+                                None => return Err(e),
+                            }
+                        }
                     }
                     None => break nc
                 }
@@ -284,11 +543,48 @@ fn parse_region(chars: &mut Chars, mut region: engine::RegionData) -> Result<(en
     Ok((region, nc))
 }
 
+#[cfg(test)]
 pub(super) fn parse_sfz_text(text: String) -> Result<Vec<engine::RegionData>, ParserError> {
+    parse_sfz(text, None).map(|(regions, _)| regions)
+}
+
+/// Parses the SFZ text skipping unknown headers and opcodes as well as
+/// opcodes with invalid values, which are returned as warnings. Only syntax
+/// errors are fatal.
+pub(super) fn parse_sfz_text_lenient(text: String) -> Result<(Vec<engine::RegionData>, Vec<ParserError>), ParserError> {
+    let mut warnings = Vec::new();
+    let (regions, _) = parse_sfz(text, Some(&mut warnings))?;
+    Ok((regions, warnings))
+}
+
+/// Parses the SFZ `text` without loading any samples into the regions, the
+/// opcodes of the `<control>` header and, unless `strict`, the skipped
+/// opcodes as warnings. Does not panic on any input.
+pub fn parse_text(text: &str, strict: bool)
+                  -> Result<(Vec<engine::RegionData>, engine::ControlData, Vec<ParserError>), ParserError> {
+    parse_instrument(text.to_string(), strict)
+}
+
+/// The regions and the `<control>` header of the SFZ text. Unless `strict`
+/// invalid opcodes are skipped and returned as warnings like by
+/// `parse_sfz_text_lenient()`.
+pub(super) fn parse_instrument(text: String, strict: bool)
+                               -> Result<(Vec<engine::RegionData>, engine::ControlData, Vec<ParserError>), ParserError> {
+    let mut warnings = Vec::new();
+    let (regions, control) = parse_sfz(text, if strict { None } else { Some(&mut warnings) })?;
+    Ok((regions, control, warnings))
+}
+
+fn parse_sfz(text: String, mut warnings: Option<&mut Vec<ParserError>>)
+             -> Result<(Vec<engine::RegionData>, engine::ControlData), ParserError> {
     let mut chars = text.chars();
 
+    // the opcodes of each header level are inherited by the levels below
+    let mut current_global = engine::RegionData::default();
+    let mut current_master = engine::RegionData::default();
     let mut current_group = engine::RegionData::default();
 
+    let mut control = engine::ControlData::default();
     let mut regions = vec![];
 
     match next_char_skip_whitespace(&mut chars) {
@@ -297,20 +593,58 @@ pub(super) fn parse_sfz_text(text: String) -> Result<Vec<engine::RegionData>, Pa
     };
 
     loop {
-        let header_string = parse_header(&mut chars)?;
+        // the '<' is already consumed
+        let remaining = chars.as_str().len() + 1;
+        let header_string = parse_header(&mut chars).map_err(|e| e.located(&text, remaining, "<>"))?;
 
         let nc = match header_string.trim() {
+            "control" => {
+                let (c, nc) = parse_region(&text, &mut chars, control, take_control_opcode, &mut warnings)?;
+                control = c;
+                nc
+            }
+            "curve" => {
+                let (header, nc) = parse_region(&text, &mut chars, CurveHeader::default(), take_curve_opcode, &mut warnings)?;
+                if let Some(index) = header.index {
+                    control.push_curve(index, header.curve);
+                }
+                nc
+            }
+            "global" => {
+                let (global, nc) = parse_region(&text, &mut chars, engine::RegionData::default(), take_opcode, &mut warnings)?;
+                current_global = global;
+                current_master = current_global.clone();
+                current_group = current_global.clone();
+                nc
+            }
+            "master" => {
+                let (master, nc) = parse_region(&text, &mut chars, current_global.clone(), take_opcode, &mut warnings)?;
+                current_master = master;
+                current_group = current_master.clone();
+                nc
+            }
             "group" => {
-                let (grp, nc) = parse_region(&mut chars, engine::RegionData::default())?;
+                let (grp, nc) = parse_region(&text, &mut chars, current_master.clone(), take_opcode, &mut warnings)?;
                 current_group = grp;
                 nc
             }
             "region" => {
-                let (reg, nc) = parse_region(&mut chars, current_group.clone())?;
+                let (mut reg, nc) = parse_region(&text, &mut chars, current_group.clone(), take_opcode, &mut warnings)?;
+                reg.apply_default_path(&control);
                 regions.push(reg);
                 nc
             }
-            s => return Err(ParserError::KeyError(s.to_string()))
+            s => {
+                let header = format!("<{}>", s);
+                let e = ParserError::KeyError(s.to_string()).located(&text, remaining, &header);
+                match warnings.as_mut() {
+                    Some(warnings) => warnings.push(e),
+                    None => return Err(e),
+                }
+                // the opcodes of an unknown header are skipped silently
+                let (_, nc) = parse_region(&text, &mut chars, engine::RegionData::default(), take_opcode, &mut Some(&mut Vec::new()))?;
+                nc
+            }
         };
 
         match nc {
@@ -319,5 +653,5 @@ pub(super) fn parse_sfz_text(text: String) -> Result<Vec<engine::RegionData>, Pa
         }
     }
 
-    Ok(regions)
+    Ok((regions, control))
 }
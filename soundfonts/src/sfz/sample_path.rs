@@ -0,0 +1,109 @@
+// Locating the sample files referenced in SFZ files, which are often written
+// on Windows with backslashes and without caring about the case of the names
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// The path of the sample file `sample` referenced in an SFZ file in
+/// `sfz_dir`. If the file does not exist, it is looked up percent-decoded and
+/// ignoring the case of the path components. Returns the candidate paths
+/// tried if none is found.
+pub(super) fn resolve(sfz_dir: &Path, sample: &str) -> Result<PathBuf, Vec<PathBuf>> {
+    let sample = sample.trim().replace("\\", &std::path::MAIN_SEPARATOR.to_string());
+    let mut candidates = vec![sfz_dir.join(&sample)];
+    if let Some(decoded) = percent_decode(&sample) {
+        candidates.push(sfz_dir.join(decoded));
+    }
+
+    if let Some(path) = candidates.iter().find(|p| p.is_file()) {
+        return Ok(path.clone());
+    }
+    candidates.iter()
+        .filter_map(|p| find_ignoring_case(sfz_dir, p.strip_prefix(sfz_dir).unwrap_or(p)))
+        .next()
+        .ok_or(candidates)
+}
+
+fn find_ignoring_case(dir: &Path, relative: &Path) -> Option<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for component in relative.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            other => {
+                path.push(other);
+                continue;
+            }
+        };
+        let exact = path.join(name);
+        if exact.exists() {
+            path = exact;
+            continue;
+        }
+        let name = name.to_string_lossy().to_lowercase();
+        path = fs::read_dir(&path).ok()?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().to_string_lossy().to_lowercase() == name)?
+            .path();
+    }
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+// `None` if there is nothing to decode or the result is not valid UTF-8
+pub(super) fn percent_decode(s: &str) -> Option<String> {
+    if !s.contains('%') {
+        return None;
+    }
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok().filter(|d| d != s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("piano.wav"), None);
+        assert_eq!(percent_decode("grand%20piano%2fC4.wav"), Some("grand piano/C4.wav".to_string()));
+        assert_eq!(percent_decode("100%.wav"), None);
+        assert_eq!(percent_decode("%C3%A9t%C3%A9.wav"), Some("été.wav".to_string()));
+    }
+
+    #[test]
+    fn resolve_case_and_encoding() {
+        let dir = std::env::temp_dir().join(format!("sonarigo-sample-path-{}", std::process::id()));
+        fs::create_dir_all(dir.join("Samples")).unwrap();
+        fs::write(dir.join("Samples").join("grand piano.wav"), b"").unwrap();
+
+        let expected = dir.join("Samples").join("grand piano.wav");
+        assert_eq!(resolve(&dir, "Samples\\grand piano.wav"), Ok(expected.clone()));
+        assert_eq!(resolve(&dir, " samples\\Grand Piano.WAV "), Ok(expected.clone()));
+        assert_eq!(resolve(&dir, "SAMPLES/grand%20piano.wav"), Ok(expected));
+
+        let tried = resolve(&dir, "samples/upright%20piano.wav").unwrap_err();
+        assert_eq!(tried, vec![dir.join("samples/upright%20piano.wav"), dir.join("samples/upright piano.wav")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
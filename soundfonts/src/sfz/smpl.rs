@@ -0,0 +1,96 @@
+// Reading the sampler chunk of WAV files, which holds the key the sample was
+// recorded at and its loops
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// larger chunks are not sampler chunks of reasonable files
+const MAX_CHUNK_SIZE: u32 = 1 << 16;
+
+/// The values of the `smpl` chunk of a WAV file used by the engine
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct SamplerChunk {
+    pub(super) root_key: Option<u8>,
+    /// the first frame and the last frame of the first loop
+    pub(super) loop_points: Option<(usize, usize)>,
+}
+
+/// The sampler chunk of the file at `path`, `None` if it is not a WAV file
+/// or has no valid sampler chunk
+pub(super) fn read(path: &Path) -> Option<SamplerChunk> {
+    let mut file = File::open(path).ok()?;
+    read_from(&mut file).ok()?
+}
+
+fn read_from<R: Read + Seek>(reader: &mut R) -> io::Result<Option<SamplerChunk>> {
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Ok(None);
+    }
+    loop {
+        let mut chunk = [0u8; 8];
+        if reader.read_exact(&mut chunk).is_err() {
+            return Ok(None);
+        }
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        if &chunk[0..4] == b"smpl" && size <= MAX_CHUNK_SIZE {
+            let mut data = vec![0u8; size as usize];
+            reader.read_exact(&mut data)?;
+            return Ok(parse(&data));
+        }
+        // chunks are padded to an even size
+        reader.seek(SeekFrom::Current(size as i64 + (size & 1) as i64))?;
+    }
+}
+
+// The chunk has nine words followed by the loops of six words each, the
+// third and fourth being the loop points.
+fn parse(data: &[u8]) -> Option<SamplerChunk> {
+    let word = |i: usize| data.get(i * 4..i * 4 + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let root_key = match word(3)? {
+        key if key <= 127 => Some(key as u8),
+        _ => None,
+    };
+    let loop_points = match (word(7)?, word(11), word(12)) {
+        (loops, Some(start), Some(end)) if loops > 0 && start <= end => Some((start as usize, end as usize)),
+        _ => None,
+    };
+    match (root_key, loop_points) {
+        (None, None) => None,
+        _ => Some(SamplerChunk { root_key, loop_points }),
+    }
+}
+
+#[cfg(test)]
+pub(super) fn wav_with_smpl(root_key: u32, loop_points: Option<(u32, u32)>) -> Vec<u8> {
+    let mut smpl = vec![0u32; 9];
+    smpl[3] = root_key;
+    if let Some((start, end)) = loop_points {
+        smpl[7] = 1;
+        smpl.extend_from_slice(&[0, 0, start, end, 0, 0]);
+    }
+    let mut wav = b"RIFF\0\0\0\0WAVEfmt \x03\0\0\0abc\0".to_vec();
+    wav.extend_from_slice(b"smpl");
+    wav.extend_from_slice(&(smpl.len() as u32 * 4).to_le_bytes());
+    wav.extend(smpl.iter().flat_map(|w| w.to_le_bytes().to_vec()));
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_sampler_chunk() {
+        assert_eq!(read_from(&mut Cursor::new(wav_with_smpl(57, None))).unwrap(),
+                   Some(SamplerChunk { root_key: Some(57), loop_points: None }));
+        assert_eq!(read_from(&mut Cursor::new(wav_with_smpl(200, Some((100, 499))))).unwrap(),
+                   Some(SamplerChunk { root_key: None, loop_points: Some((100, 499)) }));
+        assert_eq!(read_from(&mut Cursor::new(wav_with_smpl(200, None))).unwrap(), None);
+        assert_eq!(read_from(&mut Cursor::new(b"RIFF\0\0\0\0WAVEdata\0\0\0\0".to_vec())).unwrap(), None);
+        assert_eq!(read_from(&mut Cursor::new(b"fLaC\0\0\0\0\0\0\0\0".to_vec())).unwrap(), None);
+    }
+}
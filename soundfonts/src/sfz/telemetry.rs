@@ -0,0 +1,84 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Voice statistics and held keys of an engine for meters and keyboard views,
+/// updated after every processed block and readable from any thread without
+/// locking
+pub struct Telemetry {
+    voices: AtomicUsize,
+    reclaimed_voices: AtomicUsize,
+    // f32 bits of the fraction of the block duration spent in processing
+    cpu_load: AtomicU32,
+    active_regions: Vec<AtomicBool>,
+    // velocity of each held key, 0 if released
+    notes: [AtomicU8; 128],
+}
+
+pub type SharedTelemetry = Arc<Telemetry>;
+
+impl Telemetry {
+    pub(super) fn shared(regions: usize) -> SharedTelemetry {
+        Arc::new(Telemetry {
+            voices: AtomicUsize::new(0),
+            reclaimed_voices: AtomicUsize::new(0),
+            cpu_load: AtomicU32::new(0.0f32.to_bits()),
+            active_regions: (0..regions).map(|_| AtomicBool::new(false)).collect(),
+            notes: [(); 128].map(|_| AtomicU8::new(0)),
+        })
+    }
+
+    pub(super) fn publish<I: Iterator<Item = bool>>(&self,
+                                                    voices: usize,
+                                                    reclaimed_voices: usize,
+                                                    cpu_load: f32,
+                                                    active_regions: I,
+                                                    notes: &[u8; 128]) {
+        self.voices.store(voices, Ordering::Relaxed);
+        self.reclaimed_voices.store(reclaimed_voices, Ordering::Relaxed);
+        self.cpu_load.store(cpu_load.to_bits(), Ordering::Relaxed);
+        for (flag, active) in Iterator::zip(self.active_regions.iter(), active_regions) {
+            flag.store(active, Ordering::Relaxed);
+        }
+        for (published, velocity) in Iterator::zip(self.notes.iter(), notes.iter()) {
+            published.store(*velocity, Ordering::Relaxed);
+        }
+    }
+
+    pub fn voice_count(&self) -> usize {
+        self.voices.load(Ordering::Relaxed)
+    }
+
+    /// Voices dropped so far because they became inaudible before their
+    /// sample ended
+    pub fn reclaimed_voices(&self) -> usize {
+        self.reclaimed_voices.load(Ordering::Relaxed)
+    }
+
+    /// Time spent processing relative to the duration of the processed
+    /// audio, 1.0 meaning the engine just keeps up with realtime
+    pub fn cpu_load(&self) -> f32 {
+        f32::from_bits(self.cpu_load.load(Ordering::Relaxed))
+    }
+
+    /// Whether the region at `index` has sounding voices
+    pub fn region_active(&self, index: usize) -> bool {
+        self.active_regions.get(index).map_or(false, |flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// The indices of the regions with sounding voices
+    pub fn active_regions(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.active_regions.len()).filter(move |i| self.region_active(*i))
+    }
+
+    /// The keys held as of the last processed block and their velocities,
+    /// see `Engine::active_notes_into()`
+    pub fn active_notes(&self) -> Vec<(wmidi::Note, wmidi::Velocity)> {
+        self.notes.iter()
+            .map(|velocity| velocity.load(Ordering::Relaxed))
+            .enumerate()
+            .filter(|(_, velocity)| *velocity > 0)
+            .filter_map(|(key, velocity)| Some((wmidi::Note::try_from(key as u8).ok()?, wmidi::Velocity::try_from(velocity).ok()?)))
+            .collect()
+    }
+}
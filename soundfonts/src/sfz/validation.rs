@@ -0,0 +1,204 @@
+// Checking SFZ files for problems without loading the samples into an engine
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::errors::SampleFileError;
+use crate::sample;
+use crate::sndfile;
+
+use super::engine::{self, EngineError};
+use super::parser::{self, ParserError};
+
+/// A problem found in an SFZ file
+#[derive(Debug)]
+pub enum Issue {
+    /// The file could not be read or has a syntax error, no further checks
+    /// are done
+    Fatal(EngineError),
+    /// A header or opcode not supported by the engine
+    UnsupportedOpcode(ParserError),
+    /// An opcode of an SFZ extension that is accepted but has no effect
+    IgnoredOpcode(ParserError),
+    /// An opcode with an invalid value
+    InvalidValue(ParserError),
+    /// The sample file of the region with the index `region` is missing or
+    /// not readable
+    Sample { region: usize, error: EngineError },
+    /// Two regions of the exclusive group `group` are triggered by the same
+    /// notes and so sound at the same time
+    GroupOverlap { group: u32, regions: (usize, usize) },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::Fatal(e) => e.fmt(f),
+            Issue::UnsupportedOpcode(e) => write!(f, "unsupported opcode: {}", e),
+            Issue::IgnoredOpcode(e) => write!(f, "ignored opcode: {}", e),
+            Issue::InvalidValue(e) => write!(f, "invalid value: {}", e),
+            Issue::Sample { region, error } => write!(f, "region {}: {}", region, error),
+            Issue::GroupOverlap { group, regions: (a, b) } => {
+                write!(f, "regions {} and {} of exclusive group {} overlap", a, b, group)
+            }
+        }
+    }
+}
+
+/// The result of `validate()`
+#[derive(Debug)]
+pub struct ValidationReport {
+    pub path: String,
+    /// number of regions parsed
+    pub regions: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    /// Whether there are no issues but ignored opcodes
+    pub fn is_ok(&self) -> bool {
+        self.issues.iter().all(|issue| match issue {
+            Issue::IgnoredOpcode(_) => true,
+            _ => false,
+        })
+    }
+}
+
+/// Parses the SFZ file at `path` and checks it for unsupported opcodes,
+/// invalid values, missing or unreadable sample files and overlapping regions
+/// in exclusive groups
+pub fn validate(path: &str) -> ValidationReport {
+    let mut report = ValidationReport {
+        path: path.to_string(),
+        regions: 0,
+        issues: Vec::new(),
+    };
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let sfz_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+            validate_text(text, sfz_dir, &mut report);
+        }
+        Err(error) => report.issues.push(Issue::Fatal(EngineError::IOError { path: path.to_string(), error })),
+    }
+    report
+}
+
+fn validate_text(text: String, sfz_dir: &Path, report: &mut ValidationReport) {
+    let (regions, warnings) = match parser::parse_sfz_text_lenient(text) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            report.issues.push(Issue::Fatal(EngineError::ParserError { path: report.path.clone(), error }));
+            return;
+        }
+    };
+    report.regions = regions.len();
+
+    for warning in warnings {
+        let issue = match &warning {
+            ParserError::Located { error, .. } => match **error {
+                ParserError::KeyError(_) => Issue::UnsupportedOpcode,
+                ParserError::IgnoredOpcode(_) => Issue::IgnoredOpcode,
+                _ => Issue::InvalidValue,
+            },
+            _ => Issue::InvalidValue,
+        };
+        report.issues.push(issue(warning));
+    }
+
+    for (i, rd) in regions.iter().enumerate().filter(|(_, rd)| sample::Waveform::from_sample_name(rd.sample()).is_none()) {
+        let error = match engine::sample_path(sfz_dir, rd.sample()) {
+            Err(error) => Some(error),
+            Ok(path) => sndfile::OpenOptions::ReadOnly(sndfile::ReadOptions::Auto)
+                .from_path(&path)
+                .err()
+                .map(|sfe| EngineError::SampleFileError {
+                    path: path.to_string_lossy().to_string(),
+                    error: SampleFileError(sfe),
+                }),
+        };
+        if let Some(error) = error {
+            report.issues.push(Issue::Sample { region: i, error });
+        }
+    }
+
+    for (i, a) in regions.iter().enumerate() {
+        for (j, b) in regions.iter().enumerate().skip(i + 1) {
+            if a.group() != 0 && a.group() == b.group() && a.overlaps(b) {
+                report.issues.push(Issue::GroupOverlap { group: a.group(), regions: (i, j) });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_str(text: &str) -> ValidationReport {
+        let mut report = ValidationReport {
+            path: "test.sfz".to_string(),
+            regions: 0,
+            issues: Vec::new(),
+        };
+        validate_text(text.to_string(), Path::new("/nonexistent"), &mut report);
+        report
+    }
+
+    #[test]
+    fn validate_opcodes_and_groups() {
+        let report = validate_str("
+<group> group=1 lovel=64
+<region> lokey=60 hikey=64 foo=1
+<region> lokey=64 hikey=70 amp_veltrack=105
+<region> lokey=71 hikey=72
+<group> group=2
+<region> lokey=60 hikey=64 lovel=0 hivel=63
+<region> lokey=60 hikey=64 lovel=64 hivel=127
+");
+        assert_eq!(report.regions, 5);
+        assert!(!report.is_ok());
+
+        let opcode_issues: Vec<String> = report.issues.iter()
+            .filter(|issue| match issue {
+                Issue::UnsupportedOpcode(_) | Issue::InvalidValue(_) => true,
+                _ => false,
+            })
+            .map(|issue| format!("{}", issue))
+            .collect();
+        assert_eq!(opcode_issues, vec![
+            "unsupported opcode: line 3, column 28, foo: Unknown key: foo",
+            "invalid value: line 4, column 28, amp_veltrack: amp_veltrack out of range: -100 <= 105 <= 100",
+        ]);
+
+        let overlaps: Vec<(u32, (usize, usize))> = report.issues.iter()
+            .filter_map(|issue| match issue {
+                Issue::GroupOverlap { group, regions } => Some((*group, *regions)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(overlaps, vec![(1, (0, 1))]);
+
+        let missing_samples = report.issues.iter().filter(|issue| match issue {
+            Issue::Sample { .. } => true,
+            _ => false,
+        }).count();
+        assert_eq!(missing_samples, 5);
+    }
+
+    #[test]
+    fn validate_fatal() {
+        let report = validate_str("lokey=60");
+        assert_eq!(report.regions, 0);
+        match report.issues.as_slice() {
+            [Issue::Fatal(_)] => {}
+            _ => panic!("Not seen expected error"),
+        }
+
+        let report = validate("/nonexistent/test.sfz");
+        match report.issues.as_slice() {
+            [Issue::Fatal(EngineError::IOError { .. })] => {}
+            _ => panic!("Not seen expected error"),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// Detecting changes to an SFZ file and its samples to reload the instrument
+// while it is being developed
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::archive;
+use super::parser;
+use super::sample_path;
+
+#[derive(Clone, Debug, PartialEq)]
+struct FileState {
+    modified: Option<SystemTime>,
+    length: Option<u64>,
+}
+
+impl FileState {
+    fn of(path: &Path) -> FileState {
+        let metadata = fs::metadata(path).ok();
+        FileState {
+            modified: metadata.as_ref().and_then(|m| m.modified().ok()),
+            length: metadata.map(|m| m.len()),
+        }
+    }
+}
+
+/// Remembers the state of an SFZ file and optionally its sample files to
+/// tell when they have been changed. An instrument in an archive is watched
+/// as the archive file.
+pub struct SfzWatch {
+    files: Vec<(PathBuf, FileState)>,
+}
+
+impl SfzWatch {
+    pub fn new(sfz_file: &str, samples: bool) -> SfzWatch {
+        let sfz_path = match archive::split_path(sfz_file) {
+            Some((archive, _)) => PathBuf::from(archive),
+            None => PathBuf::from(sfz_file),
+        };
+        let mut paths = vec![sfz_path.clone()];
+        if samples && archive::split_path(sfz_file).is_none() {
+            paths.extend(sample_paths(&sfz_path));
+        }
+        SfzWatch {
+            files: paths.into_iter().map(|p| {
+                let state = FileState::of(&p);
+                (p, state)
+            }).collect(),
+        }
+    }
+
+    /// The watched files
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(p, _)| p.as_path())
+    }
+
+    /// The directories of the watched files, each only once. Editors often
+    /// replace a file when saving it, so file system notifications are more
+    /// reliable for the directories than for the files.
+    pub fn directories(&self) -> Vec<&Path> {
+        let mut dirs: Vec<&Path> = Vec::new();
+        for dir in self.files().filter_map(|p| p.parent()) {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+        dirs
+    }
+
+    /// Whether any of the watched files has been modified, created or
+    /// removed since the last call or since the watch was created
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, state) in self.files.iter_mut() {
+            let current = FileState::of(path);
+            if current != *state {
+                *state = current;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+// the sample files referenced in the SFZ file that can be found
+fn sample_paths(sfz_path: &Path) -> Vec<PathBuf> {
+    let text = match fs::read_to_string(sfz_path) {
+        Ok(t) => t,
+        Err(_) => return Vec::new(),
+    };
+    let regions = match parser::parse_sfz_text_lenient(text) {
+        Ok((regions, _)) => regions,
+        Err(_) => return Vec::new(),
+    };
+    let sfz_dir = sfz_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for path in regions.iter().filter_map(|rd| sample_path::resolve(sfz_dir, rd.sample()).ok()) {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watch_sfz_and_samples() {
+        let dir = std::env::temp_dir().join(format!("sonarigo-watch-{}", std::process::id()));
+        fs::create_dir_all(dir.join("samples")).unwrap();
+        let sfz_file = dir.join("piano.sfz");
+        fs::write(&sfz_file, "<region> sample=samples\\c4.wav <region> sample=samples\\c4.wav").unwrap();
+        fs::write(dir.join("samples").join("c4.wav"), b"RIFF").unwrap();
+
+        let mut sfz_only = SfzWatch::new(&sfz_file.to_string_lossy(), false);
+        let mut with_samples = SfzWatch::new(&sfz_file.to_string_lossy(), true);
+        assert_eq!(sfz_only.files().count(), 1);
+        assert_eq!(with_samples.files().collect::<Vec<_>>(), vec![sfz_file.as_path(), &dir.join("samples").join("c4.wav")]);
+        assert_eq!(with_samples.directories(), vec![dir.as_path(), &dir.join("samples")]);
+        assert!(!with_samples.changed());
+
+        fs::write(dir.join("samples").join("c4.wav"), b"RIFF0000").unwrap();
+        assert!(!sfz_only.changed());
+        assert!(with_samples.changed());
+        assert!(!with_samples.changed());
+
+        fs::remove_file(&sfz_file).unwrap();
+        assert!(sfz_only.changed());
+        assert!(with_samples.changed());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_archive() {
+        let watch = SfzWatch::new("instrument.zip#Piano/Piano.sfz", true);
+        assert_eq!(watch.files().collect::<Vec<_>>(), vec![Path::new("instrument.zip")]);
+    }
+}
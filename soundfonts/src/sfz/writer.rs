@@ -0,0 +1,69 @@
+// Writing regions back to SFZ text, for tools generating or cleaning up
+// instruments
+
+use super::engine::RegionData;
+
+/// SFZ text of `regions`, one `<region>` header per line with all the
+/// opcodes not at their defaults. The values of the `<group>`, `<master>`
+/// and `<global>` headers are written to each region, so parsing the text
+/// gives the same regions.
+pub fn write_sfz(regions: &[RegionData]) -> String {
+    let mut text = String::new();
+    for rd in regions {
+        text.push_str("<region>");
+        for (opcode, value) in rd.opcodes() {
+            text.push(' ');
+            text.push_str(&opcode);
+            text.push('=');
+            text.push_str(&value);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sfz::parser;
+
+    #[test]
+    fn write_minimal_regions() {
+        let regions = parser::parse_sfz_text("<group> lokey=60 hikey=62 <region> sample=a.wav <region> sample=b.wav key=64".to_string()).unwrap();
+        assert_eq!(write_sfz(&regions), "\
+<region> sample=a.wav lokey=60 hikey=62
+<region> sample=b.wav lokey=64 hikey=64 pitch_keycenter=64
+");
+        assert_eq!(write_sfz(&[]), "");
+    }
+
+    #[test]
+    fn parse_written_regions() {
+        let text = "
+<global> global_volume=-3
+<master> master_volume=-2
+<group> group=2 off_by=3 group_volume=-1 trigger=release ampeg_release=0.3 ampeg_sustain=33.3
+<region> sample=a.wav lokey=40 hikey=50 pitch_keycenter=45 lovel=10 hivel=90 lorand=0.25 hirand=0.75
+         tune=-12 pitch_keytrack=50 amp_veltrack=80 volume=-6.5 rt_decay=3 effect1=20 effect2=30 cutoff=1000
+         on_locc64=64 on_hicc64=127 locc1=20 hicc1=90 hicc2=10
+         loop_mode=loop_sustain loop_start=10 loop_end=90 loop_crossfade=0.01 click_guard=0.005
+         ampeg_delay=0.1 ampeg_start=10 ampeg_attack=0.5 ampeg_hold=0.2 ampeg_decay=1.5
+         ampeg_vel2attack=-0.3 ampeg_vel2hold=0.1 ampeg_vel2decay=0.4 ampeg_vel2sustain=-20 ampeg_vel2release=0.6
+         ampeg_delay_oncc3=1.5 ampeg_start_oncc4=25
+         cutoff_oncc5=1200 pitch_chanaft=100 volume_polyaft=-6 pan_oncc10=50 amplitude_oncc11=-30
+<region> sample=b.wav hikey=-1 on_hicc67=63
+";
+        let regions = parser::parse_sfz_text(text.to_string()).unwrap();
+        let written = write_sfz(&regions);
+        let parsed = parser::parse_sfz_text(written.clone()).unwrap();
+
+        assert_eq!(write_sfz(&parsed), written);
+        assert_eq!(parsed.len(), regions.len());
+        for (rd, original) in Iterator::zip(parsed.iter(), regions.iter()) {
+            assert_eq!(format!("{}", rd), format!("{}", original));
+            assert_eq!(rd.opcodes(), original.opcodes());
+        }
+        assert!(written.contains(" ampeg_sustain=33.3 "));
+        assert!(written.contains(" global_volume=-3 "));
+    }
+}
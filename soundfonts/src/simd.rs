@@ -0,0 +1,240 @@
+// Vectorized building blocks of the sample processing
+//
+// On x86_64 SSE2 is part of the base instruction set, so it is used without
+// runtime detection. Other architectures use the scalar implementations. Both
+// paths perform the same floating point operations in the same order, so
+// they give identical results.
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::sample::{cubic, linear, SampleValue};
+
+/// Cubic interpolation of a stereo sample at `frames` with the fractional
+/// parts `remainders` into `out_left` and `out_right`
+///
+/// Two frames are interpolated at once, the coefficients computed from the
+/// remainders are shared by both channels.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn cubic_stereo<T: SampleValue>(left: &[T],
+                                           right: &[T],
+                                           frames: &[usize],
+                                           remainders: &[f64],
+                                           out_left: &mut [f32],
+                                           out_right: &mut [f32]) {
+    let n = frames.len().min(remainders.len()).min(out_left.len()).min(out_right.len());
+    let len = left.len();
+    let pairs = n - n % 2;
+
+    for i in (0..pairs).step_by(2) {
+        let (f0, f1) = (frames[i], frames[i + 1]);
+        let (prev0, prev1) = (((f0 + len) - 1) % len, ((f1 + len) - 1) % len);
+        unsafe {
+            let va = _mm_loadu_pd(remainders.as_ptr().add(i));
+            let vb = _mm_sub_pd(_mm_set1_pd(1.0), va);
+            let c = _mm_mul_pd(va, vb);
+            let k1 = _mm_add_pd(_mm_set1_pd(1.0), _mm_mul_pd(_mm_set1_pd(1.5), c));
+            let k2 = _mm_mul_pd(_mm_set1_pd(0.5), c);
+
+            let cubic_pair = |data: &[T]| {
+                let p0 = _mm_set_pd(data[prev1].value(), data[prev0].value());
+                let p1 = _mm_set_pd(data[f1].value(), data[f0].value());
+                let p2 = _mm_set_pd(data[f1 + 1].value(), data[f0 + 1].value());
+                let p3 = _mm_set_pd(data[f1 + 2].value(), data[f0 + 2].value());
+                let inner = _mm_add_pd(_mm_mul_pd(p1, vb), _mm_mul_pd(p2, va));
+                let outer = _mm_add_pd(_mm_add_pd(_mm_add_pd(_mm_mul_pd(p0, vb), p1), p2), _mm_mul_pd(p3, va));
+                unpack(_mm_cvtpd_ps(_mm_sub_pd(_mm_mul_pd(k1, inner), _mm_mul_pd(k2, outer))))
+            };
+            let (l0, l1) = cubic_pair(left);
+            let (r0, r1) = cubic_pair(right);
+            out_left[i] = l0;
+            out_left[i + 1] = l1;
+            out_right[i] = r0;
+            out_right[i + 1] = r1;
+        }
+    }
+
+    for i in pairs..n {
+        out_left[i] = cubic(left, frames[i], remainders[i]);
+        out_right[i] = cubic(right, frames[i], remainders[i]);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub(crate) fn cubic_stereo<T: SampleValue>(left: &[T],
+                                           right: &[T],
+                                           frames: &[usize],
+                                           remainders: &[f64],
+                                           out_left: &mut [f32],
+                                           out_right: &mut [f32]) {
+    let n = frames.len().min(remainders.len()).min(out_left.len()).min(out_right.len());
+    for i in 0..n {
+        out_left[i] = cubic(left, frames[i], remainders[i]);
+        out_right[i] = cubic(right, frames[i], remainders[i]);
+    }
+}
+
+/// Linear interpolation of a stereo sample at `frames` with the fractional
+/// parts `remainders` into `out_left` and `out_right`, two frames at once
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn linear_stereo<T: SampleValue>(left: &[T],
+                                            right: &[T],
+                                            frames: &[usize],
+                                            remainders: &[f64],
+                                            out_left: &mut [f32],
+                                            out_right: &mut [f32]) {
+    let n = frames.len().min(remainders.len()).min(out_left.len()).min(out_right.len());
+    let pairs = n - n % 2;
+
+    for i in (0..pairs).step_by(2) {
+        let (f0, f1) = (frames[i], frames[i + 1]);
+        unsafe {
+            let remainder = _mm_loadu_pd(remainders.as_ptr().add(i));
+            let linear_pair = |data: &[T]| {
+                let p1 = _mm_set_pd(data[f1].value(), data[f0].value());
+                let p2 = _mm_set_pd(data[f1 + 1].value(), data[f0 + 1].value());
+                unpack(_mm_cvtpd_ps(_mm_add_pd(p1, _mm_mul_pd(_mm_sub_pd(p2, p1), remainder))))
+            };
+            let (l0, l1) = linear_pair(left);
+            let (r0, r1) = linear_pair(right);
+            out_left[i] = l0;
+            out_left[i + 1] = l1;
+            out_right[i] = r0;
+            out_right[i + 1] = r1;
+        }
+    }
+
+    for i in pairs..n {
+        out_left[i] = linear(left, frames[i], remainders[i]);
+        out_right[i] = linear(right, frames[i], remainders[i]);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub(crate) fn linear_stereo<T: SampleValue>(left: &[T],
+                                            right: &[T],
+                                            frames: &[usize],
+                                            remainders: &[f64],
+                                            out_left: &mut [f32],
+                                            out_right: &mut [f32]) {
+    let n = frames.len().min(remainders.len()).min(out_left.len()).min(out_right.len());
+    for i in 0..n {
+        out_left[i] = linear(left, frames[i], remainders[i]);
+        out_right[i] = linear(right, frames[i], remainders[i]);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn unpack(v: __m128) -> (f32, f32) {
+    let mut lanes = [0.0f32; 4];
+    _mm_storeu_ps(lanes.as_mut_ptr(), v);
+    (lanes[0], lanes[1])
+}
+
+/// `envelope[i] = gain * envelope[i] * release_start_gain`
+pub(crate) fn envelope_gains(envelope: &mut [f32], gain: f32, release_start_gain: f32) {
+    let vectorized = vectorized_len(envelope.len());
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let (g, r) = (_mm_set1_ps(gain), _mm_set1_ps(release_start_gain));
+        for i in (0..vectorized).step_by(4) {
+            let e = _mm_loadu_ps(envelope.as_ptr().add(i));
+            _mm_storeu_ps(envelope.as_mut_ptr().add(i), _mm_mul_ps(_mm_mul_ps(g, e), r));
+        }
+    }
+
+    for e in envelope[vectorized..].iter_mut() {
+        *e = gain * *e * release_start_gain;
+    }
+}
+
+/// `out[i] += gains[i] * signal[i]`
+pub(crate) fn mix(out: &mut [f32], signal: &[f32], gains: &[f32]) {
+    let n = out.len().min(signal.len()).min(gains.len());
+    let (out, signal, gains) = (&mut out[..n], &signal[..n], &gains[..n]);
+    let vectorized = vectorized_len(n);
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        for i in (0..vectorized).step_by(4) {
+            let o = _mm_loadu_ps(out.as_ptr().add(i));
+            let s = _mm_loadu_ps(signal.as_ptr().add(i));
+            let g = _mm_loadu_ps(gains.as_ptr().add(i));
+            _mm_storeu_ps(out.as_mut_ptr().add(i), _mm_add_ps(o, _mm_mul_ps(g, s)));
+        }
+    }
+
+    let tail = Iterator::zip(out[vectorized..].iter_mut(),
+                             Iterator::zip(signal[vectorized..].iter(), gains[vectorized..].iter()));
+    for (o, (s, g)) in tail {
+        *o += g * s;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn vectorized_len(n: usize) -> usize {
+    n - n % 4
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn vectorized_len(_n: usize) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data(seed: usize) -> Vec<f32> {
+        (0..32).map(|i| (((i + seed) * 7919) % 101) as f32 / 50.0 - 1.0).collect()
+    }
+
+    // frames and remainders as played by a detuned voice, crossing the
+    // start of the sample for the wrap around of `cubic()`
+    fn positions() -> (Vec<usize>, Vec<f64>) {
+        let positions: Vec<f64> = (0..27).map(|i| i as f64 * 1.07).collect();
+        (positions.iter().map(|p| *p as usize).collect(), positions.iter().map(|p| p.fract()).collect())
+    }
+
+    #[test]
+    fn cubic_stereo_matches_scalar() {
+        let (left, right) = (test_data(0), test_data(13));
+        let (frames, remainders) = positions();
+        let (mut out_left, mut out_right) = (vec![0.0; frames.len()], vec![0.0; frames.len()]);
+        cubic_stereo(&left, &right, &frames, &remainders, &mut out_left, &mut out_right);
+        for i in 0..frames.len() {
+            assert_eq!(out_left[i], cubic(&left, frames[i], remainders[i]));
+            assert_eq!(out_right[i], cubic(&right, frames[i], remainders[i]));
+        }
+    }
+
+    #[test]
+    fn linear_stereo_matches_scalar() {
+        let (left, right) = (test_data(0), test_data(13));
+        let (frames, remainders) = positions();
+        let (mut out_left, mut out_right) = (vec![0.0; frames.len()], vec![0.0; frames.len()]);
+        linear_stereo(&left, &right, &frames, &remainders, &mut out_left, &mut out_right);
+        for i in 0..frames.len() {
+            assert_eq!(out_left[i], linear(&left, frames[i], remainders[i]));
+            assert_eq!(out_right[i], linear(&right, frames[i], remainders[i]));
+        }
+    }
+
+    #[test]
+    fn gains_and_mix_with_tail() {
+        let envelope: Vec<f32> = (0..11).map(|i| i as f32 / 10.0).collect();
+        let mut gains = envelope.clone();
+        envelope_gains(&mut gains, 0.5, 0.3);
+        for (g, e) in Iterator::zip(gains.iter(), envelope.iter()) {
+            assert_eq!(*g, 0.5 * e * 0.3);
+        }
+
+        let signal = test_data(0);
+        let mut out = vec![1.0; 11];
+        mix(&mut out, &signal, &gains);
+        for i in 0..11 {
+            assert_eq!(out[i], 1.0 + gains[i] * signal[i]);
+        }
+    }
+}
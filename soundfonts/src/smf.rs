@@ -0,0 +1,281 @@
+// Standard MIDI file reader
+//
+// Reads the channel messages of all tracks of format 0 and 1 files and
+// converts their tick times into frames using the tempo map. SysEx and meta
+// events other than tempo changes are skipped.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::render::MidiEvent;
+
+const DEFAULT_TEMPO: u32 = 500_000;
+
+#[derive(Debug)]
+pub enum SmfError {
+    IOError(io::Error),
+    InvalidHeader,
+    InvalidTrack(usize),
+    UnexpectedEnd(usize),
+}
+
+impl fmt::Display for SmfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmfError::IOError(e) => e.fmt(f),
+            SmfError::InvalidHeader => write!(f, "Not a standard MIDI file"),
+            SmfError::InvalidTrack(n) => write!(f, "Invalid track chunk {}", n),
+            SmfError::UnexpectedEnd(n) => write!(f, "Unexpected end of track {}", n),
+        }
+    }
+}
+
+impl error::Error for SmfError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SmfError::IOError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+enum Division {
+    TicksPerQuarter(u32),
+    Smpte(f64),
+}
+
+enum TrackEvent {
+    Tempo(u32),
+    Midi(Vec<u8>),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let b = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(b)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn variable_length(&mut self) -> Option<u32> {
+        let mut v: u32 = 0;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            v = (v << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+fn read_track(data: &[u8], events: &mut Vec<(u64, TrackEvent)>) -> Option<()> {
+    let mut reader = Reader { data, pos: 0 };
+    let mut tick: u64 = 0;
+    let mut running_status: Option<u8> = None;
+
+    while !reader.at_end() {
+        tick += reader.variable_length()? as u64;
+        let first = reader.byte()?;
+        match first {
+            0xff => {
+                let meta_type = reader.byte()?;
+                let len = reader.variable_length()? as usize;
+                let payload = reader.bytes(len)?;
+                match meta_type {
+                    0x2f => break,
+                    0x51 if len == 3 => {
+                        let tempo = u32::from_be_bytes([0, payload[0], payload[1], payload[2]]);
+                        events.push((tick, TrackEvent::Tempo(tempo)));
+                    }
+                    _ => {}
+                }
+            }
+            0xf0 | 0xf7 => {
+                let len = reader.variable_length()? as usize;
+                reader.bytes(len)?;
+                running_status = None;
+            }
+            _ => {
+                let (status, first_data) = if first & 0x80 != 0 {
+                    running_status = Some(first);
+                    (first, None)
+                } else {
+                    (running_status?, Some(first))
+                };
+                let nbytes = match status & 0xf0 {
+                    0xc0 | 0xd0 => 1,
+                    _ => 2,
+                };
+                let mut msg = vec![status];
+                if let Some(d) = first_data {
+                    msg.push(d);
+                }
+                while msg.len() <= nbytes {
+                    msg.push(reader.byte()?);
+                }
+                events.push((tick, TrackEvent::Midi(msg)));
+            }
+        }
+    }
+    Some(())
+}
+
+pub fn parse_smf(data: &[u8], samplerate: f64) -> Result<Vec<MidiEvent>, SmfError> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.bytes(4) != Some(b"MThd") || reader.u32() != Some(6) {
+        return Err(SmfError::InvalidHeader);
+    }
+    let _format = reader.u16().ok_or(SmfError::InvalidHeader)?;
+    let ntracks = reader.u16().ok_or(SmfError::InvalidHeader)? as usize;
+    let division = match reader.u16().ok_or(SmfError::InvalidHeader)? {
+        0 => return Err(SmfError::InvalidHeader),
+        d if d & 0x8000 != 0 => {
+            let fps = -(((d >> 8) as i8) as i32) as f64;
+            let ticks_per_frame = (d & 0xff) as f64;
+            if ticks_per_frame == 0.0 {
+                return Err(SmfError::InvalidHeader);
+            }
+            Division::Smpte(1.0 / (fps * ticks_per_frame))
+        }
+        d => Division::TicksPerQuarter(d as u32),
+    };
+
+    let mut events = Vec::new();
+    for n in 0..ntracks {
+        let chunk_type = reader.bytes(4).ok_or(SmfError::InvalidTrack(n))?;
+        let len = reader.u32().ok_or(SmfError::InvalidTrack(n))? as usize;
+        let chunk = reader.bytes(len).ok_or(SmfError::UnexpectedEnd(n))?;
+        if chunk_type != b"MTrk" {
+            continue;
+        }
+        read_track(chunk, &mut events).ok_or(SmfError::UnexpectedEnd(n))?;
+    }
+
+    // stable, so events of the same tick stay in track order
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut tempo = DEFAULT_TEMPO;
+    let mut last_tick = 0;
+    let mut seconds = 0.0;
+    let mut midi_events = Vec::new();
+    for (tick, event) in events {
+        let seconds_per_tick = match division {
+            Division::TicksPerQuarter(tpq) => tempo as f64 / 1_000_000.0 / tpq as f64,
+            Division::Smpte(spt) => spt,
+        };
+        seconds += (tick - last_tick) as f64 * seconds_per_tick;
+        last_tick = tick;
+
+        match event {
+            TrackEvent::Tempo(t) => tempo = t,
+            TrackEvent::Midi(bytes) => midi_events.push(MidiEvent {
+                frame: (seconds * samplerate).round() as usize,
+                bytes,
+            }),
+        }
+    }
+
+    Ok(midi_events)
+}
+
+pub fn read_smf<P: AsRef<Path>>(path: P, samplerate: f64) -> Result<Vec<MidiEvent>, SmfError> {
+    let data = fs::read(path).map_err(SmfError::IOError)?;
+    parse_smf(&data, samplerate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smf(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut data = b"MThd".to_vec();
+        data.extend_from_slice(&6u32.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        data.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            data.extend_from_slice(b"MTrk");
+            data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            data.extend_from_slice(track);
+        }
+        data
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(matches!(parse_smf(b"RIFF0000", 48000.0), Err(SmfError::InvalidHeader)));
+        assert!(matches!(parse_smf(&smf(0xe700, &[]), 48000.0), Err(SmfError::InvalidHeader)));
+        assert!(matches!(parse_smf(&smf(0x8000, &[]), 48000.0), Err(SmfError::InvalidHeader)));
+        assert!(parse_smf(&smf(0xe728, &[]), 48000.0).is_ok());
+    }
+
+    #[test]
+    fn default_tempo_and_running_status() {
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100,
+            0x60, 62, 100,          // running status, one quarter later
+            0x81, 0x40, 0x80, 60, 0, // two quarters later
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let events = parse_smf(&smf(96, &[track]), 1000.0).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].frame, 0);
+        assert_eq!(events[0].bytes, vec![0x90, 60, 100]);
+        assert_eq!(events[1].frame, 500);
+        assert_eq!(events[1].bytes, vec![0x90, 62, 100]);
+        assert_eq!(events[2].frame, 1500);
+        assert_eq!(events[2].bytes, vec![0x80, 60, 0]);
+    }
+
+    #[test]
+    fn tempo_map_across_tracks() {
+        let tempo_track: &[u8] = &[
+            0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 1 s per quarter
+            0x60, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 0.5 s per quarter after one quarter
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let note_track: &[u8] = &[
+            0x60, 0x90, 60, 100,
+            0x60, 0xc0, 5,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let events = parse_smf(&smf(96, &[tempo_track, note_track]), 1000.0).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].frame, 1000);
+        assert_eq!(events[1].frame, 1500);
+        assert_eq!(events[1].bytes, vec![0xc0, 5]);
+    }
+
+    #[test]
+    fn truncated_track() {
+        let track: &[u8] = &[0x00, 0x90, 60];
+        assert!(matches!(parse_smf(&smf(96, &[track]), 1000.0), Err(SmfError::UnexpectedEnd(0))));
+    }
+}
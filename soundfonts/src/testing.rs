@@ -0,0 +1,235 @@
+// Test fixtures and golden file regression checks for the DSP code. The
+// fixtures are synthesized deterministically, so that the tests do not depend
+// on sample files. The output of a test is compared to its golden file in
+// `assets/golden` within a tolerance and by its spectrum. Missing golden files
+// are written by the test, set SONARIGO_UPDATE_GOLDEN to rewrite all of them
+// after an intended change of the output.
+
+use std::f64::consts::PI;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::engine::EngineTrait;
+use crate::sfz::engine::EngineError;
+use crate::sfz::{SampleData, SampleProvider};
+
+const UPDATE_GOLDEN: &str = "SONARIGO_UPDATE_GOLDEN";
+
+// number of frequency bands compared by `assert_golden()`
+const SPECTRUM_BANDS: usize = 64;
+// bands this far below the loudest one are not compared
+const SPECTRUM_FLOOR_DB: f32 = -60.0;
+
+pub(crate) fn sine(frequency: f64, samplerate: f64, frames: usize) -> Vec<f32> {
+    let omega = frequency / samplerate * 2.0 * PI;
+    (0..frames).map(|t| (omega * t as f64).sin() as f32).collect()
+}
+
+/// White noise between -1.0 and 1.0, the same for the same `seed`
+pub(crate) fn noise(seed: u64, frames: usize) -> Vec<f32> {
+    // xorshift64*
+    let mut state = seed.max(1);
+    (0..frames).map(|_| {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        let v = state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 40;
+        (v as f64 / (1u64 << 23) as f64 - 1.0) as f32
+    }).collect()
+}
+
+/// Sample provider serving synthesized fixtures by their names
+#[derive(Default)]
+pub(crate) struct FixtureSamples {
+    samples: Vec<(String, SampleData)>,
+}
+
+impl FixtureSamples {
+    pub(crate) fn with(mut self, name: &str, channels: Vec<Vec<f32>>, samplerate: f64) -> FixtureSamples {
+        self.samples.push((name.to_string(), SampleData { channels, samplerate, encoding: None, root_key: None, loop_points: None }));
+        self
+    }
+}
+
+impl SampleProvider for FixtureSamples {
+    fn load(&self, sample: &str) -> Result<SampleData, EngineError> {
+        self.samples.iter()
+            .find(|(name, _)| name == sample)
+            .map(|(_, data)| data.clone())
+            .ok_or_else(|| EngineError::SampleReadError { path: sample.to_string() })
+    }
+}
+
+/// Renders `frames` frames in blocks of `block_length` frames, dispatching
+/// each of the `events` at its frame
+pub(crate) fn render<E: EngineTrait>(engine: &mut E,
+                                     events: &[(usize, wmidi::MidiMessage<'static>)],
+                                     frames: usize,
+                                     block_length: usize) -> Vec<Vec<f32>> {
+    let mut left = vec![0.0; frames];
+    let mut right = vec![0.0; frames];
+    let mut position = 0;
+    while position < frames {
+        let end = (position + block_length).min(frames);
+        for (frame, event) in events.iter().filter(|(frame, _)| *frame >= position && *frame < end) {
+            engine.midi_event_at(frame - position, event);
+        }
+        engine.process(&mut left[position..end], &mut right[position..end]);
+        position = end;
+    }
+    vec![left, right]
+}
+
+/// The magnitude at `frequency` in dB relative to a full scale sine
+pub(crate) fn magnitude_db(signal: &[f32], samplerate: f64, frequency: f64) -> f32 {
+    // Goertzel algorithm over the Hann windowed signal
+    let n = signal.len();
+    let coeff = 2.0 * (2.0 * PI * frequency / samplerate).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for (i, x) in signal.iter().enumerate() {
+        let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / n as f64).cos();
+        let s = *x as f64 * window + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+    let power = s1 * s1 + s2 * s2 - coeff * s1 * s2;
+    // a full scale sine gives a magnitude of n/4 with the window
+    (20.0 * (power.sqrt() / (n as f64 / 4.0)).max(1e-10).log10()) as f32
+}
+
+/// The frequency with the highest magnitude, `resolution` Hz apart
+pub(crate) fn dominant_frequency(signal: &[f32], samplerate: f64, resolution: f64) -> f64 {
+    let steps = (samplerate / 2.0 / resolution) as usize;
+    (1..steps)
+        .map(|i| i as f64 * resolution)
+        .map(|f| (f, magnitude_db(signal, samplerate, f)))
+        .fold((0.0, f32::MIN), |best, (f, m)| if m > best.1 { (f, m) } else { best })
+        .0
+}
+
+/// Allowed deviations of the output from the golden file
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Tolerance {
+    /// of any single sample value
+    pub max_difference: f32,
+    /// in dB of the magnitudes of the frequency bands
+    pub spectrum_db: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Tolerance {
+        Tolerance { max_difference: 1e-4, spectrum_db: 0.5 }
+    }
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets").join("golden").join(format!("{}.f32", name))
+}
+
+// the channel count, the frame count and the frames of each channel, all
+// little endian
+fn write_golden(name: &str, channels: &[Vec<f32>]) {
+    let frames = channels.first().map_or(0, |c| c.len());
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(channels.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(frames as u32).to_le_bytes());
+    for v in channels.iter().flat_map(|c| c.iter()) {
+        bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    let path = golden_path(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, bytes).unwrap();
+}
+
+fn read_golden(name: &str) -> Option<Vec<Vec<f32>>> {
+    let bytes = fs::read(golden_path(name)).ok()?;
+    let word = |i: usize| bytes.get(i * 4..i * 4 + 4).map(|b| [b[0], b[1], b[2], b[3]]);
+    let channels = u32::from_le_bytes(word(0)?) as usize;
+    let frames = u32::from_le_bytes(word(1)?) as usize;
+    (0..channels).map(|c| {
+        (0..frames).map(|i| word(2 + c * frames + i).map(f32::from_le_bytes)).collect()
+    }).collect()
+}
+
+// magnitudes of evenly spaced frequency bands in dB
+fn band_magnitudes(signal: &[f32]) -> Vec<f32> {
+    (1..=SPECTRUM_BANDS).map(|b| magnitude_db(signal, 1.0, b as f64 * 0.5 / (SPECTRUM_BANDS + 1) as f64)).collect()
+}
+
+/// Compares `channels` to the golden file `name`, writing the file if it is
+/// missing or SONARIGO_UPDATE_GOLDEN is set
+pub(crate) fn assert_golden(name: &str, channels: &[Vec<f32>], tolerance: Tolerance) {
+    let golden = match read_golden(name) {
+        Some(golden) if std::env::var_os(UPDATE_GOLDEN).is_none() => golden,
+        _ => {
+            write_golden(name, channels);
+            return;
+        }
+    };
+    assert_eq!(golden.len(), channels.len(), "{}: channel count differs from the golden file", name);
+    for (c, (expected, actual)) in Iterator::zip(golden.iter(), channels.iter()).enumerate() {
+        assert_eq!(expected.len(), actual.len(), "{}: length of channel {} differs from the golden file", name, c);
+        let (frame, difference) = Iterator::zip(expected.iter(), actual.iter())
+            .map(|(e, a)| (e - a).abs())
+            .enumerate()
+            .fold((0, 0.0), |worst, (i, d)| if d > worst.1 { (i, d) } else { worst });
+        assert!(difference <= tolerance.max_difference,
+                "{}: channel {} differs by {} at frame {}, allowed {}", name, c, difference, frame, tolerance.max_difference);
+
+        let expected_bands = band_magnitudes(expected);
+        let actual_bands = band_magnitudes(actual);
+        let loudest = expected_bands.iter().cloned().fold(f32::MIN, f32::max);
+        for (b, (e, a)) in Iterator::zip(expected_bands.iter(), actual_bands.iter()).enumerate() {
+            if *e < loudest + SPECTRUM_FLOOR_DB {
+                continue;
+            }
+            assert!((e - a).abs() <= tolerance.spectrum_db,
+                    "{}: band {} of channel {} is {} dB instead of {} dB", name, b, c, a, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sfz::engine::Engine;
+    use wmidi::{Channel, MidiMessage, Note, Velocity};
+
+    const SAMPLERATE: f64 = 48000.0;
+
+    #[test]
+    fn fixtures() {
+        assert_eq!(noise(7, 64), noise(7, 64));
+        assert_ne!(noise(7, 64), noise(8, 64));
+        assert!(noise(1, 4096).iter().all(|v| *v >= -1.0 && *v < 1.0));
+        let sine = sine(1000.0, SAMPLERATE, 4800);
+        assert!((magnitude_db(&sine, SAMPLERATE, 1000.0)).abs() < 0.1);
+        assert!(magnitude_db(&sine, SAMPLERATE, 3000.0) < -60.0);
+        assert_eq!(dominant_frequency(&sine, SAMPLERATE, 10.0), 1000.0);
+    }
+
+    #[test]
+    fn golden_transposed_sine() {
+        let samples = FixtureSamples::default().with("a.wav", vec![sine(440.0, SAMPLERATE, 48000); 2], SAMPLERATE);
+        let mut engine = Engine::from_sfz_text("<region> sample=a.wav pitch_keycenter=69 amp_veltrack=0 \
+                                                ampeg_attack=0.01 ampeg_release=0.02".to_string(), &samples, SAMPLERATE, 256).unwrap();
+        let output = render(&mut engine, &[
+            (0, MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX)),
+            (1000, MidiMessage::NoteOn(Channel::Ch1, Note::E4, Velocity::MAX)),
+            (3000, MidiMessage::NoteOff(Channel::Ch1, Note::A3, Velocity::MAX)),
+            (3000, MidiMessage::NoteOff(Channel::Ch1, Note::E4, Velocity::MAX)),
+        ], 6000, 256);
+        assert_eq!(dominant_frequency(&output[0][..960], SAMPLERATE, 10.0), 440.0);
+        assert_golden("transposed_sine", &output, Tolerance::default());
+    }
+
+    #[test]
+    fn golden_filtered_noise() {
+        let samples = FixtureSamples::default().with("noise.wav", vec![noise(1, 8192), noise(2, 8192)], SAMPLERATE);
+        let mut engine = Engine::from_sfz_text("<region> sample=noise.wav amp_veltrack=0 cutoff=2000".to_string(),
+                                               &samples, SAMPLERATE, 512).unwrap();
+        let output = render(&mut engine, &[(0, MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX))], 4096, 512);
+        assert!(magnitude_db(&output[0], SAMPLERATE, 12000.0) < magnitude_db(&output[0], SAMPLERATE, 500.0) - 12.0);
+        assert_golden("filtered_noise", &output, Tolerance::default());
+    }
+}
@@ -0,0 +1,334 @@
+// Tuning of the MIDI notes: reference pitch and Scala scales / keyboard maps
+//
+// The formats are described at http://www.huygens-fokker.org/scala/scl_format.html
+// and http://www.huygens-fokker.org/scala/help.htm#mappings
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use wmidi;
+
+#[derive(Debug)]
+pub enum TuningError {
+    IOError(io::Error),
+    ParseError(String),
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TuningError::IOError(e) => fmt::Display::fmt(e, f),
+            TuningError::ParseError(msg) => write!(f, "Invalid tuning file: {}", msg),
+        }
+    }
+}
+
+impl error::Error for TuningError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TuningError::IOError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn parse_error<T>(msg: String) -> Result<T, TuningError> {
+    Err(TuningError::ParseError(msg))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct KeyboardMap {
+    // 0 maps the notes linearly to the scale degrees
+    size: usize,
+    first_note: u8,
+    last_note: u8,
+    middle_note: i32,
+    reference_note: i32,
+    reference_frequency: f64,
+    octave_degree: i32,
+    mapping: Vec<Option<i32>>,
+}
+
+impl KeyboardMap {
+    fn linear(scale_size: usize) -> KeyboardMap {
+        KeyboardMap {
+            size: 0,
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: scale_size as i32,
+            mapping: Vec::new(),
+        }
+    }
+
+    fn degree(&self, note: i32) -> Option<i32> {
+        if note < self.first_note as i32 || note > self.last_note as i32 {
+            return None;
+        }
+        let offset = note - self.middle_note;
+        if self.size == 0 {
+            return Some(offset);
+        }
+        let size = self.size as i32;
+        let key = self.mapping.get(offset.rem_euclid(size) as usize).copied().flatten()?;
+        Some(offset.div_euclid(size) * self.octave_degree + key)
+    }
+}
+
+/// The frequencies of the MIDI notes. By default twelve tone equal
+/// temperament with note 69 (A) at 440 Hz.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    // cents of the scale degrees 1 to n, the last one being the period
+    scale: Option<Vec<f64>>,
+    keyboard_map: KeyboardMap,
+    master_tune: f64,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            scale: None,
+            keyboard_map: KeyboardMap::linear(12),
+            master_tune: 0.0,
+        }
+    }
+}
+
+// The lines of a Scala file without comments
+fn scala_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().map(|l| l.trim_end_matches('\r')).filter(|l| !l.starts_with('!'))
+}
+
+fn parse_pitch(s: &str) -> Result<f64, TuningError> {
+    let token = s.split_whitespace().next().unwrap_or("");
+    let invalid = || TuningError::ParseError(format!("invalid pitch {}", s.trim()));
+    if token.contains('.') {
+        return token.parse::<f64>().map_err(|_| invalid());
+    }
+    let mut parts = token.splitn(2, '/');
+    let numerator: f64 = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+    let denominator: f64 = match parts.next() {
+        Some(d) => d.parse().map_err(|_| invalid())?,
+        None => 1.0,
+    };
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return Err(invalid());
+    }
+    Ok(1200.0 * (numerator / denominator).log2())
+}
+
+fn parse_scale(text: &str) -> Result<Vec<f64>, TuningError> {
+    let mut lines = scala_lines(text).skip(1);
+    let count: usize = match lines.next().map(|l| l.trim().parse()) {
+        Some(Ok(n)) => n,
+        _ => return parse_error("missing number of notes".to_string()),
+    };
+    let scale = lines.take(count).map(parse_pitch).collect::<Result<Vec<f64>, _>>()?;
+    if scale.is_empty() || scale.len() < count {
+        return parse_error(format!("expected {} notes, found {}", count, scale.len()));
+    }
+    Ok(scale)
+}
+
+fn parse_keyboard_map(text: &str) -> Result<KeyboardMap, TuningError> {
+    let mut lines = scala_lines(text).map(|l| l.split_whitespace().next().unwrap_or(""));
+    let mut header = |name: &str| -> Result<f64, TuningError> {
+        match lines.next().map(|l| l.parse::<f64>()) {
+            Some(Ok(v)) => Ok(v),
+            _ => parse_error(format!("missing or invalid {}", name)),
+        }
+    };
+    let size = header("map size")? as usize;
+    let first_note = header("first note")? as u8;
+    let last_note = header("last note")? as u8;
+    let middle_note = header("middle note")? as i32;
+    let reference_note = header("reference note")? as i32;
+    let reference_frequency = header("reference frequency")?;
+    let octave_degree = header("octave degree")? as i32;
+
+    let mapping = lines.take(size)
+        .map(|l| match l {
+            "x" => Ok(None),
+            l => l.parse::<i32>().map(Some).map_err(|_| TuningError::ParseError(format!("invalid key {}", l))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if mapping.len() < size {
+        return parse_error(format!("expected {} keys, found {}", size, mapping.len()));
+    }
+
+    Ok(KeyboardMap {
+        size,
+        first_note,
+        last_note,
+        middle_note,
+        reference_note,
+        reference_frequency,
+        octave_degree,
+        mapping,
+    })
+}
+
+impl Tuning {
+    /// Tuning from the contents of a .scl and optionally a .kbm file
+    pub fn from_scala(scl: &str, kbm: Option<&str>) -> Result<Tuning, TuningError> {
+        let scale = parse_scale(scl)?;
+        let keyboard_map = match kbm {
+            Some(kbm) => parse_keyboard_map(kbm)?,
+            None => KeyboardMap::linear(scale.len()),
+        };
+        Ok(Tuning {
+            scale: Some(scale),
+            keyboard_map,
+            master_tune: 0.0,
+        })
+    }
+
+    pub fn from_scala_files(scl_path: &str, kbm_path: Option<&str>) -> Result<Tuning, TuningError> {
+        let scl = fs::read_to_string(scl_path).map_err(TuningError::IOError)?;
+        let kbm = match kbm_path {
+            Some(path) => Some(fs::read_to_string(path).map_err(TuningError::IOError)?),
+            None => None,
+        };
+        Self::from_scala(&scl, kbm.as_deref())
+    }
+
+    /// Shifts all notes by `cents`
+    pub fn set_master_tune(&mut self, cents: f64) {
+        self.master_tune = cents;
+    }
+
+    /// Shifts all notes so that note 69 (A) has the frequency `hz` in equal
+    /// temperament
+    pub fn set_reference_pitch(&mut self, hz: f64) {
+        self.master_tune = 1200.0 * (hz / 440.0).log2();
+    }
+
+    fn degree_cents(scale: &[f64], degree: i32) -> f64 {
+        let size = scale.len() as i32;
+        let period = scale[scale.len() - 1];
+        let step = degree.rem_euclid(size);
+        let cents = if step == 0 { 0.0 } else { scale[step as usize - 1] };
+        degree.div_euclid(size) as f64 * period + cents
+    }
+
+    /// Frequency of `note`, notes unmapped by a keyboard map fall back to
+    /// equal temperament
+    pub fn frequency(&self, note: wmidi::Note) -> f64 {
+        let et = note.to_freq_f64();
+        let freq = match &self.scale {
+            None => et,
+            Some(scale) => {
+                let map = &self.keyboard_map;
+                let degree = map.degree(u8::from(note) as i32);
+                let reference = map.degree(map.reference_note).unwrap_or(map.reference_note - map.middle_note);
+                match degree {
+                    Some(degree) => {
+                        let cents = Self::degree_cents(scale, degree) - Self::degree_cents(scale, reference);
+                        map.reference_frequency * 2.0f64.powf(cents / 1200.0)
+                    }
+                    None => et,
+                }
+            }
+        };
+        if self.master_tune == 0.0 {
+            freq
+        } else {
+            freq * 2.0f64.powf(self.master_tune / 1200.0)
+        }
+    }
+
+    pub(crate) fn frequency_table(&self) -> [f64; 128] {
+        let mut table = [0.0; 128];
+        for (i, f) in table.iter_mut().enumerate() {
+            *f = self.frequency(unsafe { wmidi::Note::from_u8_unchecked(i as u8) });
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn note(n: u8) -> wmidi::Note {
+        wmidi::Note::try_from(n).unwrap()
+    }
+
+    const TWELVE_TET: &str = "! 12tet.scl
+!
+12 tone equal temperament
+ 12
+!
+ 100.0
+ 200.
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn default_is_equal_temperament() {
+        let tuning = Tuning::default();
+        for n in 0..128 {
+            let note = note(n);
+            assert_eq!(tuning.frequency(note), note.to_freq_f64());
+        }
+    }
+
+    #[test]
+    fn master_tune() {
+        let mut tuning = Tuning::default();
+        tuning.set_reference_pitch(442.0);
+        assert!(close(tuning.frequency(note(69)), 442.0));
+        tuning.set_master_tune(-1200.0);
+        assert!(close(tuning.frequency(note(69)), 220.0));
+    }
+
+    #[test]
+    fn scala_twelve_tet() {
+        let tuning = Tuning::from_scala(TWELVE_TET, None).unwrap();
+        for n in 0..128 {
+            let note = note(n);
+            assert!(close(tuning.frequency(note), note.to_freq_f64()));
+        }
+    }
+
+    #[test]
+    fn scala_just_fifths_with_keyboard_map() {
+        let scl = "just\n 2\n 3/2\n 2/1\n";
+        // three keys per period of two scale degrees starting at note 60,
+        // which is the reference at 261.6 Hz, the third key unmapped
+        let kbm = "! test.kbm\n 3\n 0\n 127\n 60\n 60\n 261.6\n 2\n 0\n 1\n x\n";
+        let tuning = Tuning::from_scala(scl, Some(kbm)).unwrap();
+        assert!(close(tuning.frequency(note(60)), 261.6));
+        assert!(close(tuning.frequency(note(61)), 261.6 * 1.5));
+        assert_eq!(tuning.frequency(note(62)), note(62).to_freq_f64());
+        assert!(close(tuning.frequency(note(63)), 523.2));
+        assert!(close(tuning.frequency(note(57)), 261.6 / 2.0));
+    }
+
+    #[test]
+    fn scala_parse_errors() {
+        assert!(Tuning::from_scala("only description\n", None).is_err());
+        assert!(Tuning::from_scala("desc\n 2\n 100.0\n", None).is_err());
+        assert!(Tuning::from_scala("desc\n 1\n foo\n", None).is_err());
+        assert!(Tuning::from_scala(TWELVE_TET, Some("1\n0\n127\n60\n")).is_err());
+    }
+}